@@ -0,0 +1,93 @@
+// The boot module makes the reset ROM, which boots a machine like the QEMU virt
+// machine: the hart starts at ROM_BASE, where the reset code sets
+//
+// - a0 to the hart ID
+// - a1 to the address of the DTB
+// - a2 to the address of the fw_dynamic info, which tells an OpenSBI firmware
+//   built with FW_DYNAMIC where the kernel is and in which mode to start it
+//
+// and jumps to the firmware at the start of the memory. The kernel is at
+// DEFAULT_KERNEL_OFFSET from the start of the memory.
+//
+// A Linux kernel is an Image file, a flat binary with a header which gives the
+// offset from the start of the memory where it must be loaded. See
+// Documentation/arch/riscv/boot-image-header.rst of Linux.
+
+use std::convert::TryInto;
+
+use crate::cpu::*;
+
+// The offset of the kernel from the start of the memory when there is firmware,
+// where OpenSBI expects it on RV64
+pub const DEFAULT_KERNEL_OFFSET: u64 = 0x20_0000;
+
+// The magic number of the fw_dynamic info, "OSBI"
+pub const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+// The version of the fw_dynamic info which has boot_hart
+pub const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+
+// The reset code, from riscv_setup_rom_reset_vec of QEMU
+const RESET_CODE: [u32; 6] = [
+    // auipc t0, 0
+    0x0000_0297,
+    // addi a2, t0, 40: the fw_dynamic info after the code and the addresses
+    0x0282_8613,
+    // csrr a0, mhartid
+    0xf140_2573,
+    // ld a1, 32(t0): the address of the DTB
+    0x0202_b583,
+    // ld t0, 24(t0): the address of the firmware
+    0x0182_b283,
+    // jr t0
+    0x0002_8067,
+];
+
+// The fw_dynamic info for the firmware, struct fw_dynamic_info of OpenSBI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FwDynamicInfo {
+    // The address of the kernel
+    pub next_addr: u64,
+    // The mode the kernel starts in
+    pub next_mode: Mode,
+    // The hart which boots, the others wait
+    pub boot_hart: u64,
+}
+
+// The magic numbers of the Image header: "RISCV\0\0\0" at 0x30, deprecated, and
+// "RSC\x05" at 0x38
+const LINUX_IMAGE_MAGIC: &[u8] = b"RISCV\0\0\0";
+const LINUX_IMAGE_MAGIC2: &[u8] = b"RSC\x05";
+// The offset of the field text_offset in the Image header
+const LINUX_IMAGE_TEXT_OFFSET: usize = 0x8;
+
+// The offset from the start of the memory where the Linux Image `image` is
+// loaded, or None if it is not an Image.
+pub fn linux_image_offset(image: &[u8]) -> Option<u64> {
+    let magic = image.get(0x30..0x38)? == LINUX_IMAGE_MAGIC || image.get(0x38..0x3c)? == LINUX_IMAGE_MAGIC2;
+    if !magic {
+        return None;
+    }
+    let text_offset = image.get(LINUX_IMAGE_TEXT_OFFSET..LINUX_IMAGE_TEXT_OFFSET + 8)?;
+    Some(u64::from_le_bytes(text_offset.try_into().ok()?))
+}
+
+// The contents of the reset ROM which jumps to `start` with the DTB at `dtb`,
+// 0 if there is none.
+pub fn reset_rom(start: u64, dtb: u64, info: &FwDynamicInfo) -> Vec<u8> {
+    let mut rom: Vec<u8> = RESET_CODE.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let words = [
+        start,
+        dtb,
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        info.next_addr,
+        info.next_mode as u64,
+        // options
+        0,
+        info.boot_hart,
+    ];
+    for word in words.iter() {
+        rom.extend_from_slice(&word.to_le_bytes());
+    }
+    rom
+}
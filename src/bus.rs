@@ -1,10 +1,16 @@
+use std::io;
+
 use crate::memory::*;
+use crate::snapshot::*;
 use crate::trap::*;
 use crate::plic::*;
 use crate::clint::*;
 use crate::uart::*;
 use crate::virtio::*;
 
+// The maximum number of harts, for which the CLINT and the PLIC have registers
+pub const MAX_HARTS: usize = 8;
+
 // The address which the core-local interruptor (CLINT) starts. 
 // It contains the timer and generates per-hart software 
 // interrupts and timer interrupts.
@@ -31,68 +37,428 @@ pub const VIRTIO_SIZE: u64 = 0x1000;
 /// The address which memory starts, same as QEMU virt machine.
 pub const MEMORY_BASE: u64 = 0x8000_0000;
 
+// The address of the mask ROM, which holds the reset code, same as QEMU virt machine.
+pub const ROM_BASE: u64 = 0x1000;
+// The size of the mask ROM
+pub const ROM_SIZE: u64 = 0xf000;
+
+// What a store to a memory region does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    // The store writes the memory.
+    Ram,
+    // The store raises an access fault.
+    Rom,
+    // The store is ignored, e.g. for a flash which is only written by a command.
+    WriteIgnored,
+}
+
+// A memory region other than the main memory, which is given to Bus::new
+#[derive(Debug)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub memory: Memory,
+}
+
 // trait is like imterface
+// A device on the bus. The built-in devices implement it, and so can devices of the
+// users of the library, which are attached by Bus::attach.
 pub trait Device {
     fn load(&mut self, addr:u64,size: u64) -> Result<u64, Exception>;
     fn store(&mut self,addr: u64,size:u64,value:u64) -> Result<(),Exception>;
+
+    // Return true if the device raises its interrupt. It is called after every
+    // instruction, and the interrupt goes to the PLIC.
+    fn is_interrupting(&mut self) -> bool {
+        false
+    }
+
+    // Save the state for snapshots and reverse execution.
+    fn save(&self, _writer: &mut Writer) {}
+
+    fn restore(&mut self, _reader: &mut Reader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A device attached by a user of the library
+struct Attached {
+    name: String,
+    // The interrupt source of the PLIC, if the device has an interrupt
+    irq: Option<u64>,
+    device: Box<dyn Device>,
+}
+
+// Which device a range of addresses goes to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Clint,
+    Plic,
+    Uart,
+    Virtio,
+    // The index in Bus::attached
+    Attached(usize),
+}
+
+// A range of addresses of a device
+#[derive(Debug, Clone)]
+struct Mapping {
+    name: String,
+    base: u64,
+    size: u64,
+    target: Target,
+}
+
+// Where the devices are mapped on the bus, None if the machine does not have the
+// device. A device sees the addresses relative to its default base, e.g. UART_BASE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceMap {
+    pub clint: Option<u64>,
+    pub plic: Option<u64>,
+    pub uart: Option<u64>,
+    pub virtio: Option<u64>,
+}
+
+impl Default for DeviceMap {
+    // All the devices at the addresses of the QEMU virt machine
+    fn default() -> Self {
+        Self {
+            clint: Some(CLINT_BASE),
+            plic: Some(PLIC_BASE),
+            uart: Some(UART_BASE),
+            virtio: Some(VIRTIO_BASE),
+        }
+    }
+}
+
+impl DeviceMap {
+    // No devices, only the memory
+    pub fn none() -> Self {
+        Self {
+            clint: None,
+            plic: None,
+            uart: None,
+            virtio: None,
+        }
+    }
 }
 
 // The system bus
 pub struct Bus {
-    clint: Clint,
-    plic: Plic,
+    pub devices: DeviceMap,
+    // The ranges of the devices sorted by address
+    mappings: Vec<Mapping>,
+    attached: Vec<Attached>,
+    pub clint: Clint,
+    pub plic: Plic,
     pub uart: Uart,
     pub virtio: Virtio,
-    memory: Memory,
+    // The main memory, where the program is loaded
+    pub memory: Memory,
+    // The other memory regions, e.g. a boot ROM
+    pub regions: Vec<Region>,
 }
 
 impl Bus {
-    pub fn new(binary:Vec<u8>) -> Bus {
-        Self {
-            memory: Memory::new(binary),
+    pub fn new(binary:Vec<u8>, disk_image: Vec<u8>) -> Bus {
+        Self::with_layout(binary, disk_image, MEMORY_BASE, MEMORY_SIZE, DeviceMap::default())
+            .expect("the default devices overlap")
+    }
+
+    // A bus with the memory of `memory_size` bytes at `memory_base` and the devices
+    // at the addresses of `devices`, which must not overlap.
+    pub fn with_layout(
+        binary: Vec<u8>,
+        disk_image: Vec<u8>,
+        memory_base: u64,
+        memory_size: u64,
+        devices: DeviceMap,
+    ) -> io::Result<Bus> {
+        let mut bus = Self {
+            devices,
+            mappings: Vec::new(),
+            attached: Vec::new(),
+            memory: Memory::new(binary, memory_base, memory_size),
+            regions: Vec::new(),
             clint: Clint::new(),
             plic: Plic::new(),
             uart: Uart::new(),
             virtio: Virtio::new(disk_image),
+        };
+        let builtins = [
+            ("clint", devices.clint, CLINT_SIZE, Target::Clint),
+            ("plic", devices.plic, PLIC_SIZE, Target::Plic),
+            ("uart", devices.uart, UART_SIZE, Target::Uart),
+            ("virtio", devices.virtio, VIRTIO_SIZE, Target::Virtio),
+        ];
+        for (name, base, size, target) in builtins {
+            if let Some(base) = base {
+                bus.map(name, base, size, target)?;
+            }
         }
+        Ok(bus)
     }
 
-    pub fn load(&mut self, addr:u64,size:u64) -> Result<u64,Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.load(addr, size);
+    // Check that the range of `size` bytes at `base` is not used by the memory or
+    // a device.
+    fn check_free(&self, name: &str, base: u64, size: u64) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let end = match base.checked_add(size) {
+            Some(end) if size != 0 => end,
+            _ => return Err(invalid(format!("the size of {} at 0x{:x} is invalid", name, base))),
+        };
+        let used = self
+            .memories()
+            .map(|memory| ("the memory".to_string(), memory.base(), memory.size()))
+            .chain(self.mappings.iter().map(|mapping| (mapping.name.clone(), mapping.base, mapping.size)));
+        for (other, other_base, other_size) in used {
+            // A range which wraps around at the end of the address space
+            // conflicts with any other.
+            let other_end = match other_base.checked_add(other_size) {
+                Some(other_end) => other_end,
+                None => {
+                    return Err(invalid(format!(
+                        "{} at 0x{:x}-0x{:x} conflicts with {} at 0x{:x}, whose size 0x{:x} overflows",
+                        name, base, end, other, other_base, other_size
+                    )))
+                }
+            };
+            if base < other_end && other_base < end {
+                return Err(invalid(format!(
+                    "{} at 0x{:x}-0x{:x} overlaps {} at 0x{:x}-0x{:x}",
+                    name, base, end, other, other_base, other_end
+                )));
+            }
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.load(addr, size);
+        Ok(())
+    }
+
+    // Map the range of a device, keeping the mappings sorted.
+    fn map(&mut self, name: &str, base: u64, size: u64, target: Target) -> io::Result<()> {
+        self.check_free(name, base, size)?;
+        let index = self.mappings.partition_point(|mapping| mapping.base < base);
+        self.mappings.insert(
+            index,
+            Mapping {
+                name: name.to_string(),
+                base,
+                size,
+                target,
+            },
+        );
+        Ok(())
+    }
+
+    // Attach a device to the `size` bytes at `base`, which must not be used by the
+    // memory or another device. The device sees the offsets from `base` as the
+    // addresses. If the device has an interrupt, it is the source `irq` of the PLIC.
+    pub fn attach(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) -> io::Result<()> {
+        self.map(name, base, size, Target::Attached(self.attached.len()))?;
+        self.attached.push(Attached {
+            name: name.to_string(),
+            irq,
+            device,
+        });
+        Ok(())
+    }
+
+    // The device at `addr` with the address the device sees: the address relative
+    // to the default base for the built-in devices, the offset for attached ones.
+    fn device_at(&self, addr: u64) -> Option<(Target, u64)> {
+        let index = self.mappings.partition_point(|mapping| mapping.base <= addr);
+        let mapping = &self.mappings[index.checked_sub(1)?];
+        let offset = addr - mapping.base;
+        if offset >= mapping.size {
+            return None;
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.load(addr, size);
+        let addr = match mapping.target {
+            Target::Clint => CLINT_BASE + offset,
+            Target::Plic => PLIC_BASE + offset,
+            Target::Uart => UART_BASE + offset,
+            Target::Virtio => VIRTIO_BASE + offset,
+            Target::Attached(_) => offset,
+        };
+        Some((mapping.target, addr))
+    }
+
+    fn device_mut(&mut self, target: Target) -> &mut dyn Device {
+        match target {
+            Target::Clint => &mut self.clint,
+            Target::Plic => &mut self.plic,
+            Target::Uart => &mut self.uart,
+            Target::Virtio => &mut self.virtio,
+            Target::Attached(index) => self.attached[index].device.as_mut(),
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.load(addr, size);
+    }
+
+    // Pass the interrupt levels of the devices to the PLIC. It is called after
+    // every instruction.
+    pub fn update_irqs(&mut self) {
+        if self.devices.uart.is_some() {
+            let level = self.uart.is_interrupting();
+            self.plic.set_level(UART_IRQ, level);
         }
-        if MEMORY_BASE <= addr {
-            return self.memory.load(addr,size);
+        if self.devices.virtio.is_some() {
+            let level = self.virtio.is_interrupting();
+            self.plic.set_level(VIRTIO_IRQ, level);
+        }
+        for attached in self.attached.iter_mut() {
+            if let Some(irq) = attached.irq {
+                let level = attached.device.is_interrupting();
+                self.plic.set_level(irq, level);
+            }
         }
-        println!("load {:#x} {}", addr, size);
-        Err(Exception::LoadAccessFault)
     }
 
-    pub fn store(&mut self,addr:u64,size:u64,value:u64) -> Result<(),Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.store(addr, size, value);
+    // Add a memory region of `size` bytes, a multiple of PAGE_SIZE, at `base` with
+    // the image at the start. The region must not overlap the other memory or a
+    // device.
+    pub fn add_region(&mut self, kind: RegionKind, base: u64, size: u64, image: &[u8]) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if !size.is_multiple_of(PAGE_SIZE as u64) || !base.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(invalid(format!(
+                "the region at 0x{:x} must be aligned to {} bytes",
+                base, PAGE_SIZE
+            )));
+        }
+        if image.len() as u64 > size {
+            return Err(invalid(format!("the image is larger than the region at 0x{:x}", base)));
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.store(addr, size, value);
+        self.check_free("the region", base, size)?;
+        let mut memory = Memory::new(Vec::new(), base, size);
+        memory.write_bytes(base, image);
+        self.regions.push(Region { kind, memory });
+        Ok(())
+    }
+
+    // The main memory and then the memory of the regions
+    pub fn memories(&self) -> impl Iterator<Item = &Memory> {
+        std::iter::once(&self.memory).chain(self.regions.iter().map(|region| &region.memory))
+    }
+
+    pub fn memories_mut(&mut self) -> impl Iterator<Item = &mut Memory> {
+        std::iter::once(&mut self.memory)
+            .chain(self.regions.iter_mut().map(|region| &mut region.memory))
+    }
+
+    // The memory which has all the `length` bytes at `addr`, with the kind of its region
+    fn memory_at(&mut self, addr: u64, length: u64) -> Option<(RegionKind, &mut Memory)> {
+        let contains = |memory: &Memory| {
+            addr >= memory.base() && memory.size() >= length && addr - memory.base() <= memory.size() - length
+        };
+        if contains(&self.memory) {
+            return Some((RegionKind::Ram, &mut self.memory));
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.store(addr, size, value);
+        self.regions
+            .iter_mut()
+            .find(|region| contains(&region.memory))
+            .map(|region| (region.kind, &mut region.memory))
+    }
+
+    // Copy an image to the memory at `addr`, also to a ROM. The image must be in
+    // one memory region.
+    pub fn load_image(&mut self, addr: u64, image: &[u8]) -> io::Result<()> {
+        match self.memory_at(addr, image.len() as u64) {
+            Some((_, memory)) => {
+                memory.write_bytes(addr, image);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the image at 0x{:x}-0x{:x} is not in memory",
+                    addr,
+                    addr + image.len() as u64
+                ),
+            )),
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.store(addr, size, value);
+    }
+
+    // Save the state of the memory and the devices.
+    pub fn save(&self, writer: &mut Writer) {
+        self.memory.save(writer);
+        writer.section("regions");
+        writer.u64(self.regions.len() as u64);
+        for region in self.regions.iter() {
+            region.memory.save(writer);
         }
-        if MEMORY_BASE <= addr {
-            return self.memory.store(addr,size,value);
+        self.save_devices(writer);
+    }
+
+    pub fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        self.memory.restore(reader)?;
+        reader.section("regions")?;
+        let count = reader.u64()?;
+        if count != self.regions.len() as u64 {
+            return Err(invalid(format!(
+                "the machine has {} memory regions, expected {}",
+                count,
+                self.regions.len()
+            )));
+        }
+        for region in self.regions.iter_mut() {
+            region.memory.restore(reader)?;
+        }
+        self.restore_devices(reader)
+    }
+
+    // Save the state of the devices other than the memory.
+    pub fn save_devices(&self, writer: &mut Writer) {
+        self.clint.save(writer);
+        self.plic.save(writer);
+        self.uart.save(writer);
+        self.virtio.save(writer);
+        for attached in self.attached.iter() {
+            writer.section(&attached.name);
+            attached.device.save(writer);
+        }
+    }
+
+    pub fn restore_devices(&mut self, reader: &mut Reader) -> io::Result<()> {
+        self.clint.restore(reader)?;
+        self.plic.restore(reader)?;
+        self.uart.restore(reader)?;
+        self.virtio.restore(reader)?;
+        for attached in self.attached.iter_mut() {
+            reader.section(&attached.name)?;
+            attached.device.restore(reader)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(&mut self, addr:u64,size:u64) -> Result<u64,Exception> {
+        if let Some((_, memory)) = self.memory_at(addr, size / 8) {
+            return memory.load(addr,size);
+        }
+        if let Some((target, addr)) = self.device_at(addr) {
+            return self.device_mut(target).load(addr, size);
+        }
+        Err(Exception::LoadAccessFault)
+    }
+
+    pub fn store(&mut self,addr:u64,size:u64,value:u64) -> Result<(),Exception> {
+        match self.memory_at(addr, size / 8) {
+            Some((RegionKind::Ram, memory)) => return memory.store(addr,size,value),
+            Some((RegionKind::WriteIgnored, _)) => return Ok(()),
+            Some((RegionKind::Rom, _)) => return Err(Exception::StoreAMOAccessFault),
+            None => {}
+        }
+        if let Some((target, addr)) = self.device_at(addr) {
+            self.device_mut(target).store(addr, size, value)?;
+            // The virtio block device does the requests when the driver notifies it,
+            // with the access to the memory the other devices do not have.
+            if target == Target::Virtio && self.virtio.take_notification() {
+                Virtio::process_queue(self);
+            }
+            return Ok(());
         }
         Err(Exception::StoreAMOAccessFault)
     }
-}
\ No newline at end of file
+}
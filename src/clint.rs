@@ -2,60 +2,256 @@
 // block holds memory-mapped control and status registers associated with
 // software and timer interrupts. It generates per-hart software interrupts and timer.
 
+use std::io;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
+// The address of the msip register of hart 0. The machine software interrupt is
+// pending while its lowest bit is set. The msip of hart i is at CLINT_MSIP + 4 * i.
+pub const CLINT_MSIP: u64 = CLINT_BASE;
 // The address of a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
 // compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
+// The mtimecmp of hart i is at CLINT_MTIMECMP + 8 * i.
 pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 // The address of a timer register. A mtime is a machine mode timer register which runs at a
 // constant frequency.
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
+// The frequency of mtime in real-time mode, the same as the QEMU virt machine.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+// The number of instructions per tick of mtime in deterministic mode by default
+pub const DEFAULT_INSTRUCTIONS_PER_TICK: u64 = 10;
+// Read the host clock every this number of instructions in real-time mode.
+pub const HOST_TIME_INTERVAL: u64 = 1000;
+
+// How mtime advances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeMode {
+    // mtime advances by one every this number of retired instructions, so that
+    // every run is the same regardless of the speed of the host.
+    Deterministic(u64),
+    // mtime follows the host clock at TIMEBASE_FREQUENCY.
+    RealTime,
+}
+
+impl TimeMode {
+    // Parse a time mode given as "realtime", "deterministic" or
+    // "deterministic:<instructions per tick>".
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "realtime" => Some(TimeMode::RealTime),
+            "deterministic" => Some(TimeMode::Deterministic(DEFAULT_INSTRUCTIONS_PER_TICK)),
+            _ => match mode.strip_prefix("deterministic:")?.parse() {
+                Ok(n) if n > 0 => Some(TimeMode::Deterministic(n)),
+                _ => None,
+            },
+        }
+    }
+}
+
 // The core-local interrupter (CLINT)
 pub struct Clint {
+    // The registers of each hart
+    msip: [u32; MAX_HARTS],
     mtime: u64,
-    mtimecmp: u64,
+    mtimecmp: [u64; MAX_HARTS],
+    mode: TimeMode,
+    // The instructions retired since the last tick in deterministic mode
+    instructions: u64,
+    // mtime at `start` in real-time mode
+    base: u64,
+    start: Instant,
+    // The date of the host when the machine started, in nanoseconds since the Unix
+    // epoch
+    start_date: u64,
 }
 
 impl Device for Clint {
     fn load(&mut self, addr:u64, size:u64) -> Result<u64,Exception> {
-        match size {
-            64 => Ok(self.load64(addr)),
+        match (Self::hart(addr, CLINT_MSIP, 4), size) {
+            (Some(hart), 32) => Ok(self.msip[hart] as u64),
+            (_, 64) => Ok(self.load64(addr)),
             _ => Err(Exception::LoadAccessFault),
         }
     }
 
     fn store(&mut self, addr:u64, size:u64, value:u64) -> Result<(),Exception> {
-        match size {
-            64 => Ok(self.store64(addr,value)),
+        match (Self::hart(addr, CLINT_MSIP, 4), size) {
+            (Some(hart), 32) => {
+                // Only the lowest bit is implemented.
+                self.msip[hart] = value as u32 & 1;
+                Ok(())
+            }
+            (_, 64) => {
+                self.store64(addr,value);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    fn save(&self, writer: &mut Writer) {
+        writer.section("clint");
+        for hart in 0..MAX_HARTS {
+            writer.u32(self.msip[hart]);
+            writer.u64(self.mtimecmp[hart]);
+        }
+        // The instructions per tick, or 0 in real-time mode
+        writer.u64(match self.mode {
+            TimeMode::Deterministic(instructions_per_tick) => instructions_per_tick,
+            TimeMode::RealTime => 0,
+        });
+        writer.u64(self.mtime);
+        writer.u64(self.instructions);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("clint")?;
+        for hart in 0..MAX_HARTS {
+            self.msip[hart] = reader.u32()?;
+            self.mtimecmp[hart] = reader.u64()?;
+        }
+        self.mode = match reader.u64()? {
+            0 => TimeMode::RealTime,
+            instructions_per_tick => TimeMode::Deterministic(instructions_per_tick),
+        };
+        self.set_mtime(reader.u64()?);
+        self.instructions = reader.u64()?;
+        Ok(())
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Clint {
     // create a new Clint object
     pub fn new() -> Self {
         Self {
+            msip: [0; MAX_HARTS],
             mtime: 0,
-            mtimecmp: 0,
+            mtimecmp: [0; MAX_HARTS],
+            mode: TimeMode::Deterministic(DEFAULT_INSTRUCTIONS_PER_TICK),
+            instructions: 0,
+            base: 0,
+            start: Instant::now(),
+            start_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |date| date.as_nanos() as u64),
+        }
+    }
+
+    pub fn time_mode(&self) -> TimeMode {
+        self.mode
+    }
+
+    pub fn set_time_mode(&mut self, mode: TimeMode) {
+        self.mode = mode;
+        self.set_mtime(self.mtime);
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    // Set mtime. In real-time mode, mtime follows the host clock from this value.
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.base = mtime;
+        self.start = Instant::now();
+    }
+
+    // Return true if the machine software interrupt of `hart` is pending.
+    pub fn msip(&self, hart: usize) -> bool {
+        self.msip[hart] != 0
+    }
+
+    pub fn mtimecmp(&self, hart: usize) -> u64 {
+        self.mtimecmp[hart]
+    }
+
+    pub fn set_mtimecmp(&mut self, hart: usize, mtimecmp: u64) {
+        self.mtimecmp[hart] = mtimecmp;
+    }
+
+    // Return true if the timer interrupt of `hart` is pending.
+    pub fn timer_interrupt(&self, hart: usize) -> bool {
+        self.mtime >= self.mtimecmp[hart]
+    }
+
+    // The value of mtime by the host clock in real-time mode
+    pub fn host_time(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        let ticks = elapsed.as_secs() * TIMEBASE_FREQUENCY
+            + elapsed.subsec_nanos() as u64 * TIMEBASE_FREQUENCY / 1_000_000_000;
+        self.base.wrapping_add(ticks)
+    }
+
+    pub fn start_date(&self) -> u64 {
+        self.start_date
+    }
+
+    // Set the date of the start, e.g. to the one of a recorded run.
+    pub fn set_start_date(&mut self, date: u64) {
+        self.start_date = date;
+    }
+
+    // The date in nanoseconds since the Unix epoch for a program which reads it:
+    // the date of the start plus the time of mtime, so that a run in deterministic
+    // mode or replayed reads the same dates.
+    pub fn date(&self) -> u64 {
+        let elapsed = self.mtime as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128;
+        self.start_date.wrapping_add(elapsed as u64)
+    }
+
+    // Advance the time by a retired instruction in deterministic mode. In
+    // real-time mode, mtime is updated by the caller with the host time, so that
+    // the host time can be recorded.
+    pub fn tick(&mut self) {
+        if let TimeMode::Deterministic(instructions_per_tick) = self.mode {
+            self.instructions += 1;
+            if self.instructions >= instructions_per_tick {
+                self.instructions = 0;
+                self.mtime = self.mtime.wrapping_add(1);
+            }
+        }
+    }
+
+    // The hart of the register at `addr` in the registers of the harts from `base`,
+    // `size` bytes each.
+    fn hart(addr: u64, base: u64, size: u64) -> Option<usize> {
+        let offset = addr.checked_sub(base)?;
+        let hart = (offset / size) as usize;
+        if offset.is_multiple_of(size) && hart < MAX_HARTS {
+            Some(hart)
+        } else {
+            None
         }
     }
 
     fn load64(&self, addr:u64) -> u64 {
+        if let Some(hart) = Self::hart(addr, CLINT_MTIMECMP, 8) {
+            return self.mtimecmp[hart];
+        }
         match addr {
-            CLINT_MTIMECMP => self.mtimecmp,
             CLINT_MTIME => self.mtime,
             _ => 0,
         }
     }
 
     fn store64(&mut self, addr:u64, value:u64) {
-        match addr {
-            CLINT_MTIMECMP => self.mtimecmp = value,
-            CLINT_MTIME => self.mtime = value,
-            _ => {}
+        if let Some(hart) = Self::hart(addr, CLINT_MTIMECMP, 8) {
+            self.mtimecmp[hart] = value;
+            return;
+        }
+        if addr == CLINT_MTIME {
+            self.set_mtime(value);
         }
     }
 }
\ No newline at end of file
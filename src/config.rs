@@ -0,0 +1,389 @@
+// The config module reads a machine description, so that a machine is set up by
+// a file instead of a patched build:
+//
+//   [machine]
+//   harts = 1
+//   isa = "rv64ia_zicsr"
+//   bootargs = "console=ttyS0"
+//   reset_vector = true
+//   sbi = false
+//   virtio_version = 1
+//   semihosting = "sandbox"
+//
+//   [memory]
+//   base = 0x8000_0000
+//   size = "256M"
+//
+//   [images]
+//   firmware = "fw_dynamic.elf"
+//   kernel = "Image"
+//   kernel_addr = 0x8020_0000
+//   initrd = "rootfs.cpio"
+//   disk = "fs.img"
+//   dtb = "virt.dtb"
+//
+//   [devices]
+//   uart = 0x1000_0000
+//   clint = 0x200_0000
+//
+//   [[regions]]
+//   kind = "rom"
+//   base = 0x1000
+//   size = 0xf000
+//   image = "reset.bin"
+//
+// The file is a subset of TOML: tables, arrays of tables, and keys with strings,
+// integers and booleans. Relative paths are relative to the directory of the
+// file. When [devices] is given, only the devices listed in it are instantiated.
+//
+// The command line options override the values of the file.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bus::*;
+use crate::emulator::*;
+use crate::memory::*;
+
+// The value of a key
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    Boolean(bool),
+}
+
+// A memory region other than the main memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionConfig {
+    pub kind: RegionKind,
+    pub base: u64,
+    pub size: u64,
+    // The image at the start of the region
+    pub image: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub harts: usize,
+    pub isa: String,
+    pub memory_base: u64,
+    pub memory_size: u64,
+    // The program run first, e.g. OpenSBI. Without firmware, the kernel is run
+    // directly.
+    pub firmware: Option<PathBuf>,
+    pub kernel: Option<PathBuf>,
+    // Where a raw kernel is loaded for the firmware, DEFAULT_KERNEL_OFFSET from the
+    // start of the memory by default
+    pub kernel_addr: Option<u64>,
+    pub initrd: Option<PathBuf>,
+    // Where the initrd is loaded, at the end of the memory by default
+    pub initrd_addr: Option<u64>,
+    pub disk: Option<PathBuf>,
+    // A DTB to use instead of the generated one
+    pub dtb: Option<PathBuf>,
+    // Boot from the reset ROM also without firmware
+    pub reset_vector: bool,
+    // Run the kernel in S-mode with the built-in SBI instead of firmware
+    pub sbi: bool,
+    // The version of the virtio-mmio interface of the disk, 1 (legacy) or 2
+    pub virtio_version: u32,
+    // The directory of the files of the semihosting calls, which are not serviced
+    // without it
+    pub semihosting: Option<PathBuf>,
+    // Generate a DTB when none is given, true by default
+    pub generate_dtb: bool,
+    // The command line of the kernel
+    pub bootargs: Option<String>,
+    pub devices: DeviceMap,
+    pub regions: Vec<RegionConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            harts: 1,
+            isa: DEFAULT_ISA.to_string(),
+            memory_base: MEMORY_BASE,
+            memory_size: MEMORY_SIZE,
+            firmware: None,
+            kernel: None,
+            kernel_addr: None,
+            initrd: None,
+            initrd_addr: None,
+            disk: None,
+            dtb: None,
+            reset_vector: false,
+            sbi: false,
+            virtio_version: 1,
+            semihosting: None,
+            generate_dtb: true,
+            bootargs: None,
+            devices: DeviceMap::default(),
+            regions: Vec::new(),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Parse an integer in decimal or hexadecimal with 0x, with `_` between digits.
+pub fn parse_integer(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Parse a size in bytes, with the suffix K, M or G for KiB, MiB or GiB.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (number, shift) = match text.chars().last()?.to_ascii_uppercase() {
+        'K' => (&text[..text.len() - 1], 10),
+        'M' => (&text[..text.len() - 1], 20),
+        'G' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    parse_integer(number)?.checked_mul(1 << shift)
+}
+
+// Parse a value: a string in double quotes, an integer or a boolean.
+fn parse_value(text: &str) -> Option<Value> {
+    if let Some(string) = text.strip_prefix('"') {
+        let string = string.strip_suffix('"')?;
+        if string.contains('"') || string.contains('\\') {
+            return None;
+        }
+        return Some(Value::String(string.to_string()));
+    }
+    match text {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        _ => parse_integer(text).map(Value::Integer),
+    }
+}
+
+// Remove a comment from a line, outside of strings.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+impl Config {
+    // Read the machine description in the file `path`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, dir).map_err(|e| invalid(format!("{}:{}", path, e)))
+    }
+
+    // Parse a machine description whose relative paths are relative to `dir`. An
+    // error starts with the line number.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut table = String::new();
+        let mut devices = None;
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| format!("{}: {}", i + 1, message);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("[[") {
+                table = match name.strip_suffix("]]") {
+                    Some(name) => name.trim().to_string(),
+                    None => return Err(error(format!("invalid table {}", line))),
+                };
+                match table.as_str() {
+                    "regions" => config.regions.push(RegionConfig {
+                        kind: RegionKind::Ram,
+                        base: 0,
+                        size: 0,
+                        image: None,
+                    }),
+                    _ => return Err(error(format!("unknown array of tables [[{}]]", table))),
+                }
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                table = match name.strip_suffix(']') {
+                    Some(name) => name.trim().to_string(),
+                    None => return Err(error(format!("invalid table {}", line))),
+                };
+                match table.as_str() {
+                    "machine" | "memory" | "images" => {}
+                    "devices" => devices = Some(DeviceMap::none()),
+                    _ => return Err(error(format!("unknown table [{}]", table))),
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(error(format!("expected key = value: {}", line))),
+            };
+            let value = match parse_value(value) {
+                Some(value) => value,
+                None => return Err(error(format!("invalid value {}", value))),
+            };
+            let path = |value: &Value| match value {
+                Value::String(path) => Ok(dir.join(path)),
+                _ => Err(error(format!("{} must be a path in double quotes", key))),
+            };
+            let integer = |value: &Value| match value {
+                Value::Integer(n) => Ok(*n),
+                _ => Err(error(format!("{} must be an integer", key))),
+            };
+            let size = |value: &Value| match value {
+                Value::Integer(size) => Ok(*size),
+                Value::String(size) => match parse_size(size) {
+                    Some(size) => Ok(size),
+                    None => Err(error(format!("invalid size {}", size))),
+                },
+                _ => Err(error(format!("{} must be a size", key))),
+            };
+            match (table.as_str(), key) {
+                ("machine", "harts") => config.harts = integer(&value)? as usize,
+                ("machine", "isa") => match value {
+                    Value::String(isa) => config.isa = isa,
+                    _ => return Err(error("isa must be a string".to_string())),
+                },
+                ("machine", "reset_vector") => match value {
+                    Value::Boolean(reset_vector) => config.reset_vector = reset_vector,
+                    _ => return Err(error("reset_vector must be true or false".to_string())),
+                },
+                ("machine", "sbi") => match value {
+                    Value::Boolean(sbi) => config.sbi = sbi,
+                    _ => return Err(error("sbi must be true or false".to_string())),
+                },
+                ("machine", "virtio_version") => config.virtio_version = integer(&value)? as u32,
+                ("machine", "semihosting") => config.semihosting = Some(path(&value)?),
+                ("machine", "bootargs") => match value {
+                    Value::String(bootargs) => config.bootargs = Some(bootargs),
+                    _ => return Err(error("bootargs must be a string".to_string())),
+                },
+                ("memory", "base") => config.memory_base = integer(&value)?,
+                ("memory", "size") => config.memory_size = size(&value)?,
+                ("images", "firmware") => config.firmware = Some(path(&value)?),
+                ("images", "kernel") => config.kernel = Some(path(&value)?),
+                ("images", "kernel_addr") => config.kernel_addr = Some(integer(&value)?),
+                ("images", "initrd") => config.initrd = Some(path(&value)?),
+                ("images", "initrd_addr") => config.initrd_addr = Some(integer(&value)?),
+                ("images", "disk") => config.disk = Some(path(&value)?),
+                // A path, or false for no DTB
+                ("images", "dtb") => match value {
+                    Value::Boolean(generate) => config.generate_dtb = generate,
+                    _ => config.dtb = Some(path(&value)?),
+                },
+                ("devices", _) => {
+                    let devices = devices.as_mut().unwrap();
+                    let addr = Some(integer(&value)?);
+                    match key {
+                        "clint" => devices.clint = addr,
+                        "plic" => devices.plic = addr,
+                        "uart" => devices.uart = addr,
+                        "virtio" => devices.virtio = addr,
+                        _ => return Err(error(format!("unknown device {}", key))),
+                    }
+                }
+                ("regions", _) => {
+                    let region = config.regions.last_mut().unwrap();
+                    match key {
+                        "kind" => {
+                            region.kind = match value {
+                                Value::String(kind) if kind == "ram" => RegionKind::Ram,
+                                Value::String(kind) if kind == "rom" => RegionKind::Rom,
+                                Value::String(kind) if kind == "write-ignored" => {
+                                    RegionKind::WriteIgnored
+                                }
+                                _ => {
+                                    return Err(error(
+                                        "kind must be \"ram\", \"rom\" or \"write-ignored\""
+                                            .to_string(),
+                                    ))
+                                }
+                            }
+                        }
+                        "base" => region.base = integer(&value)?,
+                        "size" => region.size = size(&value)?,
+                        "image" => region.image = Some(path(&value)?),
+                        _ => return Err(error(format!("unknown key {} in [[regions]]", key))),
+                    }
+                }
+                ("", _) => return Err(error(format!("{} is not in a table", key))),
+                _ => return Err(error(format!("unknown key {} in [{}]", key, table))),
+            }
+        }
+        if let Some(devices) = devices {
+            config.devices = devices;
+        }
+        Ok(config)
+    }
+
+    // The builder of the machine with the images read from their files
+    pub fn builder(&self) -> io::Result<EmulatorBuilder> {
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        };
+        let mut builder = Emulator::builder()
+            .harts(self.harts)
+            .isa(&self.isa)
+            .memory_base(self.memory_base)
+            .memory_size(self.memory_size)
+            .devices(self.devices);
+        if let Some(firmware) = &self.firmware {
+            builder = builder.firmware(read(firmware)?);
+        }
+        if let Some(kernel) = &self.kernel {
+            builder = builder.binary(read(kernel)?);
+        }
+        if let Some(addr) = self.kernel_addr {
+            builder = builder.kernel_addr(addr);
+        }
+        builder = builder.reset_vector(self.reset_vector).sbi(self.sbi);
+        if let Some(initrd) = &self.initrd {
+            builder = builder.initrd(read(initrd)?, self.initrd_addr);
+        }
+        builder = match &self.dtb {
+            Some(dtb) => builder.device_tree(DeviceTree::Blob(read(dtb)?)),
+            None if !self.generate_dtb => builder.device_tree(DeviceTree::None),
+            None => builder,
+        };
+        if let Some(bootargs) = &self.bootargs {
+            builder = builder.bootargs(bootargs);
+        }
+        for region in self.regions.iter() {
+            let image = match &region.image {
+                Some(image) => read(image)?,
+                None => Vec::new(),
+            };
+            builder = builder.region(region.kind, region.base, region.size, image);
+        }
+        if let Some(disk) = &self.disk {
+            builder = builder.disk_image(read(disk)?);
+        }
+        builder = builder.virtio_version(self.virtio_version);
+        if let Some(root) = &self.semihosting {
+            // The command line of the program is its file name and the bootargs, as
+            // in QEMU.
+            let mut cmdline = self
+                .kernel
+                .as_ref()
+                .map_or(String::new(), |kernel| kernel.display().to_string());
+            if let Some(bootargs) = &self.bootargs {
+                cmdline = format!("{} {}", cmdline, bootargs);
+            }
+            builder = builder.semihosting(root, &cmdline);
+        }
+        Ok(builder)
+    }
+}
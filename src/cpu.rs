@@ -1,32 +1,57 @@
+use std::io;
+
 use crate::bus::*;
+use crate::fpu::*;
+use crate::mmu::{self, AccessType, Tlb};
+use crate::rvc;
+use crate::snapshot::*;
 use crate::trap::*;
-use crate::uart::*;
-use crate::virtio::*;
 use crate::plic::*;
-use crate::memory::*;
+use crate::symbols::*;
 
-// Defalt memory size(128MB)
-pub const MEMORY_SIZE: u64 = 1024 * 1024 * 128;
 pub const REGISTER_NUMBER: usize = 32;
 // CSRs 2^12
 pub const CSRS_NUMBER: usize = 4096;
 
 // CRSs
+// Unprivileged floating-point CSRs
+// Floating-point accrued exceptions, a view of fcsr
+pub const FFLAGS: usize = 0x001;
+// Floating-point dynamic rounding mode, a view of fcsr
+pub const FRM: usize = 0x002;
+// Floating-point control and status register: frm and fflags
+pub const FCSR: usize = 0x003;
 // Machine level CSRs
 // Machine information register
+// Vendor ID
+pub const MVENDORID: usize = 0xf11;
+// Architecture ID
+pub const MARCHID: usize = 0xf12;
+// Implementation ID
+pub const MIMPID: usize = 0xf13;
 // Hardware thread ID
 pub const MHARTID: usize = 0xf14;
+// Pointer to configuration data structure
+pub const MCONFIGPTR: usize = 0xf15;
 // Machine trap setup
 // Machine status register
 pub const MSTATUS: usize = 0x300;
+// Machine ISA register
+pub const MISA: usize = 0x301;
 // Machine exception delefation register
 pub const MEDELEG: usize = 0x302;
+// Machine interrupt delegation register
+pub const MIDELEG: usize = 0x303;
 // Machine interrupt-enable register
 pub const MIE: usize = 0x304;
 // Machine trap-handler base address.
 pub const MTVEC: usize = 0x305;
 // Machine conuter enable 
 pub const MCOUNTEREN: usize = 0x306;
+// Machine environment configuration register
+pub const MENVCFG: usize = 0x30a;
+// Machine counter-inhibit register
+pub const MCOUNTINHIBIT: usize = 0x320;
 // Machine trap handling
 // Scratch register for machine trap handlers
 pub const MSCRATCH: usize = 0x340;
@@ -36,6 +61,64 @@ pub const MEPC: usize = 0x341;
 pub const MCAUSE: usize = 0x342;
 // Machine bad address or instruction
 pub const MTVAL: usize = 0x343;
+// Machine interrupt pending
+pub const MIP: usize = 0x344;
+// Machine memory protection
+// Physical memory protection configuration, the even ones on RV64
+pub const PMPCFG0: usize = 0x3a0;
+// Physical memory protection address registers, 64 of them
+pub const PMPADDR0: usize = 0x3b0;
+// Machine counter/timers
+// Machine cycle counter
+pub const MCYCLE: usize = 0xb00;
+// Machine instructions-retired counter
+pub const MINSTRET: usize = 0xb02;
+
+// MSTATUS fields.
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_SXL: u64 = 0b11 << 34;
+pub const MSTATUS_SD: u64 = 1 << 63;
+// The fields of mstatus which software can write. UXL and SXL are fixed to 64 bits,
+// and FS is writable only with the F extension.
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+// The fields of mstatus which sstatus shows
+const SSTATUS_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_UXL
+    | MSTATUS_SD;
+// The values of FS: the floating-point unit is off, or its state is initial, clean
+// or dirty.
+pub const FS_OFF: u64 = 0;
+pub const FS_INITIAL: u64 = 1 << 13;
+pub const FS_DIRTY: u64 = 0b11 << 13;
 
 // MIP fields.
 pub const MIP_SSIP: u64 = 1 << 1;
@@ -44,6 +127,13 @@ pub const MIP_STIP: u64 = 1 << 5;
 pub const MIP_MTIP: u64 = 1 << 7;
 pub const MIP_SEIP: u64 = 1 << 9;
 pub const MIP_MEIP: u64 = 1 << 11;
+// The interrupts which can be delegated to S-mode
+const MIP_SUPERVISOR: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// The STCE bit of menvcfg, which enables stimecmp of Sstc
+pub const MENVCFG_STCE: u64 = 1 << 63;
+// The exceptions which can be delegated to S-mode: all but the ecall from M-mode
+const MEDELEG_WRITABLE: u64 = 0xb3ff;
 
 // Surpervisor-level CSRs
 // Surpervisor status register
@@ -53,6 +143,10 @@ pub const SSTATUS: usize = 0x100;
 pub const SIE:usize = 0x104;
 // Surpervisor trap hander base address
 pub const STVEC: usize = 0x105;
+// Surpervisor counter enable
+pub const SCOUNTEREN: usize = 0x106;
+// Surpervisor environment configuration register
+pub const SENVCFG: usize = 0x10a;
 // Surpervisor trap handling
 // Scratch register for surpervisor trap hander
 pub const SSCRACH: usize = 0x140;
@@ -64,9 +158,19 @@ pub const SCAUSE: usize = 0x142;
 pub const STVAL: usize = 0x143;
 // Surpervisor interrupt pending
 pub const SIP: usize = 0x144;
+// Surpervisor timer compare register of the Sstc extension
+pub const STIMECMP: usize = 0x14d;
 // Surpervisor address translation and protection
 pub const SATP: usize = 0x180;
 
+// Unprivileged counters and timers
+// Cycle counter, a read-only shadow of mcycle
+pub const CYCLE: usize = 0xc00;
+// Timer, a read-only shadow of mtime of the CLINT
+pub const TIME: usize = 0xc01;
+// Instructions-retired counter, a read-only shadow of minstret
+pub const INSTRET: usize = 0xc02;
+
 // The CPU mode
 #[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
 pub enum Mode {
@@ -80,11 +184,63 @@ pub enum Mode {
     Machine = 0b11,
 }
 
+// The state of a hart other than the current one. The harts take turns to run as
+// the current hart, whose state is in Cpu.
+pub struct Hart {
+    pub regs: [u64; REGISTER_NUMBER],
+    pub fregs: [u64; REGISTER_NUMBER],
+    pub pc: u64,
+    pub csrs: Box<[u64; CSRS_NUMBER]>,
+    pub mode: Mode,
+    pub reservation: Option<u64>,
+    pub tlb: Tlb,
+}
+
+impl Hart {
+    fn save(&self, writer: &mut Writer) {
+        for reg in self.regs.iter().chain(self.fregs.iter()) {
+            writer.u64(*reg);
+        }
+        writer.u64(self.pc);
+        for csr in self.csrs.iter() {
+            writer.u64(*csr);
+        }
+        writer.u8(self.mode as u8);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        for reg in self.regs.iter_mut().chain(self.fregs.iter_mut()) {
+            *reg = reader.u64()?;
+        }
+        self.pc = reader.u64()?;
+        for csr in self.csrs.iter_mut() {
+            *csr = reader.u64()?;
+        }
+        self.mode = read_mode(reader)?;
+        self.reservation = None;
+        self.tlb.flush();
+        Ok(())
+    }
+}
+
+// Read a privilege mode saved as its number.
+fn read_mode(reader: &mut Reader) -> io::Result<Mode> {
+    match reader.u8()? {
+        0b00 => Ok(Mode::User),
+        0b01 => Ok(Mode::Surpervisor),
+        0b11 => Ok(Mode::Machine),
+        mode => Err(invalid(format!("unknown privilege mode {}", mode))),
+    }
+}
+
 // CPU
 // it connent via system bus
 pub struct Cpu{
     //register 64bit & 32 registers
     pub regs:[u64; REGISTER_NUMBER], 
+    // The floating-point registers f0-f31 of the F and D extensions. A
+    // single-precision value is NaN-boxed: the upper 32 bits are all ones.
+    pub fregs: [u64; REGISTER_NUMBER],
     // programm counter
     pub pc:u64, 
     // System Bus
@@ -92,24 +248,141 @@ pub struct Cpu{
     // Control and status registers. RISC-V ISA sets 
     // aside a 12-bit encoding space (csr[11:0]) for
     // up to 4096 CSRs.
-    pub csrs: [u64; CSRS_NUMBER],
+    pub csrs: Box<[u64; CSRS_NUMBER]>,
     // Privilege mode
     pub mode : Mode,
+    // Symbols of the loaded ELF file, to print addresses as function+offset
+    pub symbols: Symbols,
+    // The ecalls from S-mode are serviced by the built-in SBI (see the sbi module)
+    // instead of M-mode firmware.
+    pub sbi: bool,
+    // The value of mtval or stval for the exception being raised: the faulting
+    // address or the illegal instruction
+    pub tval: u64,
+    // The address reserved by the last lr, which a sc needs to succeed
+    pub reservation: Option<u64>,
+    // The cached translations of the mmu
+    pub tlb: Tlb,
+    // The other harts, which share the bus
+    pub harts: Vec<Hart>,
 }
 
 impl Cpu{
-    pub fn new(binary: Vec<u8>) -> Self {
+    pub fn new(binary: Vec<u8>, disk_image: Vec<u8>) -> Self {
+        Self::with_bus(Bus::new(binary, disk_image))
+    }
+
+    // A cpu which starts at the start of the memory of the bus.
+    pub fn with_bus(bus: Bus) -> Self {
         let mut regs = [0; REGISTER_NUMBER];
         // regs[2](x2) is a stack pointer
-        regs[2] = MEMORY_BASE + MEMORY_SIZE;
+        regs[2] = bus.memory.base() + bus.memory.size();
+        let mut csrs = Box::new([0; CSRS_NUMBER]);
+        // XLEN is 64 in U-mode and S-mode.
+        csrs[MSTATUS] = 2 << 32 | 2 << 34;
 
         Self {
             regs,
-            pc: MEMORY_BASE,
-            bus: Bus::new(binary),
-            csrs: [0; CSRS_NUMBER],
+            fregs: [0; REGISTER_NUMBER],
+            pc: bus.memory.base(),
+            bus,
+            csrs,
             mode:Mode::Machine,
+            symbols: Symbols::default(),
+            sbi: false,
+            tval: 0,
+            reservation: None,
+            tlb: Tlb::new(),
+            harts: Vec::new(),
+        }
+    }
+
+    // The ID of the current hart
+    pub fn hartid(&self) -> usize {
+        self.csrs[MHARTID] as usize
+    }
+
+    // The number of harts
+    pub fn hart_count(&self) -> usize {
+        self.harts.len() + 1
+    }
+
+    // Add a hart as a copy of the current one but for its hart ID, the next one,
+    // e.g. to start all the harts at the same entry.
+    pub fn add_hart(&mut self) -> &mut Hart {
+        let mut csrs = self.csrs.clone();
+        csrs[MHARTID] = self.hart_count() as u64;
+        self.harts.push(Hart {
+            regs: self.regs,
+            fregs: self.fregs,
+            pc: self.pc,
+            csrs,
+            mode: self.mode,
+            reservation: None,
+            tlb: Tlb::new(),
+        });
+        self.harts.last_mut().unwrap()
+    }
+
+    // Make `hartid` the current hart.
+    pub fn switch_hart(&mut self, hartid: usize) {
+        let hart = match self.harts.iter_mut().find(|hart| hart.csrs[MHARTID] == hartid as u64) {
+            Some(hart) => hart,
+            None => return,
+        };
+        std::mem::swap(&mut self.regs, &mut hart.regs);
+        std::mem::swap(&mut self.fregs, &mut hart.fregs);
+        std::mem::swap(&mut self.pc, &mut hart.pc);
+        std::mem::swap(&mut self.csrs, &mut hart.csrs);
+        std::mem::swap(&mut self.mode, &mut hart.mode);
+        std::mem::swap(&mut self.reservation, &mut hart.reservation);
+        std::mem::swap(&mut self.tlb, &mut hart.tlb);
+    }
+
+    // Save the state of the cpu and the bus.
+    pub fn save(&self, writer: &mut Writer) {
+        writer.section("cpu");
+        for reg in self.regs.iter().chain(self.fregs.iter()) {
+            writer.u64(*reg);
+        }
+        writer.u64(self.pc);
+        for csr in self.csrs.iter() {
+            writer.u64(*csr);
         }
+        writer.u8(self.mode as u8);
+        writer.section("harts");
+        writer.u64(self.harts.len() as u64);
+        for hart in self.harts.iter() {
+            hart.save(writer);
+        }
+        self.bus.save(writer);
+    }
+
+    pub fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("cpu")?;
+        for reg in self.regs.iter_mut().chain(self.fregs.iter_mut()) {
+            *reg = reader.u64()?;
+        }
+        self.pc = reader.u64()?;
+        for csr in self.csrs.iter_mut() {
+            *csr = reader.u64()?;
+        }
+        self.mode = read_mode(reader)?;
+        self.reservation = None;
+        self.tlb.flush();
+        reader.section("harts")?;
+        let count = reader.u64()?;
+        if count != self.harts.len() as u64 {
+            return Err(invalid(format!(
+                "the machine has {} harts, expected {}",
+                self.hart_count(),
+                count + 1
+            )));
+        }
+        for hart in self.harts.iter_mut() {
+            hart.restore(reader)?;
+        }
+        self.bus.restore(reader)
     }
 
     // print values in all registers (x0-x31)
@@ -123,10 +396,8 @@ impl Cpu{
         ];
         for i in (0..32).step_by(4){
             output = format!(
-                "{}\n{}",
+                "{}\nx{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 output,
-                format!(
-                "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 i,
                 abi[i],
                 self.regs[i],
@@ -139,61 +410,245 @@ impl Cpu{
                 i + 3,
                 abi[i + 3],
                 self.regs[i + 3],
-                )
             );
         }
         println!("{}",output);
+        println!("pc={} ra={}", self.symbols.format(self.pc), self.symbols.format(self.regs[1]));
     }
     
     // Print values in some csrs
     pub fn dump_csrs(&self){
         let output = format!(
-            "{}\n{}",
-            format!(
-                "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}",
-                self.csrs[MSTATUS], self.csrs[MTVEC], self.csrs[MEPC], self.csrs[MCAUSE],
-            ),
-            format!(
-                "sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
-                self.csrs[SSTATUS], self.csrs[STVEC], self.csrs[SEPC], self.csrs[SCAUSE],
-            ),
+            "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}\nsstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
+            self.csrs[MSTATUS], self.csrs[MTVEC], self.csrs[MEPC], self.csrs[MCAUSE],
+            self.read_csr(SSTATUS), self.csrs[STVEC], self.csrs[SEPC], self.csrs[SCAUSE],
         );
         println!("{}", output);
+        // Where the last traps happened, if the symbols are known
+        if !self.symbols.is_empty() {
+            println!(
+                "mepc={} sepc={}",
+                self.symbols.format(self.csrs[MEPC]),
+                self.symbols.format(self.csrs[SEPC])
+            );
+        }
     }
 
-    pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
-        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
-        // "When a hart is executing in privilege mode x, interrupts are globally enabled when x
-        // IE=1 and globally disabled when x IE=0."
-        match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if (self.csrs[MSTATUS] >> 3) & 1 == 0 {
-                    return None;
+    // Return true if the CSR at `addr` is implemented. An access to another CSR
+    // raises an illegal instruction exception.
+    fn is_csr_implemented(addr: usize) -> bool {
+        matches!(
+            addr,
+            FFLAGS | FRM | FCSR
+                | SSTATUS | SIE | STVEC | SCOUNTEREN | SENVCFG
+                | SSCRACH | SEPC | SCAUSE | STVAL | SIP | STIMECMP | SATP
+                | MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN | MENVCFG
+                | MCOUNTINHIBIT | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP
+                | MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR
+                // The hardware performance monitor: mhpmevent3-31, mcycle, minstret,
+                // mhpmcounter3-31 and their unprivileged shadows
+                | 0x323..=0x33f
+                | 0xb00 | 0xb02..=0xb1f
+                | 0xc00..=0xc1f
+                // pmpaddr0-63
+                | 0x3b0..=0x3ef
+        ) || ((PMPCFG0..=0x3ae).contains(&addr) && addr.is_multiple_of(2))
+    }
+
+    // The addresses of the implemented CSRs, in order.
+    pub fn implemented_csrs() -> impl Iterator<Item = usize> {
+        (0..CSRS_NUMBER).filter(|addr| Self::is_csr_implemented(*addr))
+    }
+
+    // Check that the CSR at `addr` can be read, and written if `write`, in the
+    // current privilege mode.
+    fn check_csr_access(&self, addr: usize, write: bool) -> Result<(), Exception> {
+        // "The top two bits (csr[11:10]) indicate whether the register is read/write
+        // (00, 01, or 10) or read-only (11). The next two bits (csr[9:8]) encode the
+        // lowest privilege level that can access the CSR."
+        let read_only = (addr >> 10) & 0b11 == 0b11;
+        let privilege = ((addr >> 8) & 0b11) as u64;
+        if !Self::is_csr_implemented(addr) || privilege > self.mode as u64 || (write && read_only) {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The counters are accessible to S-mode if enabled in mcounteren, and to
+        // U-mode if also enabled in scounteren.
+        if (CYCLE..=0xc1f).contains(&addr) {
+            let bit = 1 << (addr - CYCLE);
+            if (self.mode < Mode::Machine && self.csrs[MCOUNTEREN] & bit == 0)
+                || (self.mode == Mode::User && self.csrs[SCOUNTEREN] & bit == 0)
+            {
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+        // stimecmp is accessible to S-mode if enabled in menvcfg and by the TM bit of
+        // mcounteren.
+        if addr == STIMECMP
+            && self.mode < Mode::Machine
+            && (self.csrs[MENVCFG] & MENVCFG_STCE == 0 || self.csrs[MCOUNTEREN] & 0b10 == 0)
+        {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The floating-point CSRs are accessible while the floating-point unit is on.
+        if (FFLAGS..=FCSR).contains(&addr) && self.csrs[MSTATUS] & MSTATUS_FS == FS_OFF {
+            return Err(Exception::IllegalInstruction);
+        }
+        // With TVM, S-mode cannot access satp.
+        if addr == SATP && self.mode == Mode::Surpervisor && self.csrs[MSTATUS] & MSTATUS_TVM != 0 {
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    // Read a CSR. sstatus, sie and sip are views of mstatus, mie and mip, fflags and
+    // frm are views of fcsr, and the counters are shadows of the machine ones.
+    pub fn read_csr(&self, addr: usize) -> u64 {
+        match addr {
+            FFLAGS => self.csrs[FCSR] & 0x1f,
+            FRM => self.csrs[FCSR] >> 5,
+            SSTATUS => self.csrs[MSTATUS] & SSTATUS_MASK,
+            SIE => self.csrs[MIE] & self.csrs[MIDELEG],
+            SIP => self.csrs[MIP] & self.csrs[MIDELEG],
+            CYCLE => self.csrs[MCYCLE],
+            INSTRET => self.csrs[MINSTRET],
+            _ => self.csrs[addr],
+        }
+    }
+
+    // Write a CSR. Only the fields which software can write change, and the other
+    // bits keep their values (WARL).
+    pub fn write_csr(&mut self, addr: usize, value: u64) {
+        // Replace the bits of `mask` in the CSR at `addr`.
+        let write_masked = |csr: &mut u64, mask: u64| {
+            *csr = (*csr & !mask) | (value & mask);
+        };
+        match addr {
+            MSTATUS => {
+                let old = self.csrs[MSTATUS];
+                let mask = MSTATUS_WRITABLE | self.fs_mask();
+                write_masked(&mut self.csrs[MSTATUS], mask);
+                // MPP cannot be 2, the hypervisor mode, which is not implemented.
+                if (self.csrs[MSTATUS] & MSTATUS_MPP) >> 11 == 2 {
+                    self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MSTATUS_MPP) | (old & MSTATUS_MPP);
                 }
+                self.update_sd();
+            }
+            SSTATUS => {
+                let mask = SSTATUS_MASK & !(MSTATUS_UXL | MSTATUS_FS | MSTATUS_SD) | self.fs_mask();
+                write_masked(&mut self.csrs[MSTATUS], mask);
+                self.update_sd();
+            }
+            // fflags and frm are the bits [4:0] and [7:5] of fcsr. A write makes the
+            // state of the floating-point unit dirty.
+            FFLAGS | FRM | FCSR => {
+                let (shift, mask) = match addr {
+                    FFLAGS => (0, 0x1f),
+                    FRM => (5, 0xe0),
+                    _ => (0, 0xff),
+                };
+                self.csrs[FCSR] = self.csrs[FCSR] & !mask | (value << shift) & mask;
+                self.set_fs_dirty();
             }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if (self.csrs[SSTATUS] >> 1) & 1 == 0 {
-                    return None;
+            MIE => write_masked(&mut self.csrs[MIE], MIP_SUPERVISOR | MIP_MSIP | MIP_MTIP | MIP_MEIP),
+            SIE => {
+                let mask = self.csrs[MIDELEG];
+                write_masked(&mut self.csrs[MIE], mask);
+            }
+            MIP => write_masked(&mut self.csrs[MIP], MIP_SUPERVISOR),
+            SIP => {
+                let mask = self.csrs[MIDELEG] & MIP_SSIP;
+                write_masked(&mut self.csrs[MIP], mask);
+            }
+            MIDELEG => self.csrs[MIDELEG] = value & MIP_SUPERVISOR,
+            MEDELEG => self.csrs[MEDELEG] = value & MEDELEG_WRITABLE,
+            // The low bit is 0, since instructions are aligned to 2 bytes.
+            MEPC | SEPC => self.csrs[addr] = value & !1,
+            // The modes other than direct (0) and vectored (1) are reserved.
+            MTVEC | STVEC => self.csrs[addr] = value & !0b10,
+            SATP => {
+                // Only Bare and Sv39 are supported, and a write of another mode is
+                // ignored. ASIDs are not implemented, so the ASID field is 0.
+                let mode = value >> mmu::SATP_MODE_SHIFT;
+                if mode == mmu::SATP_MODE_BARE || mode == mmu::SATP_MODE_SV39 {
+                    self.csrs[SATP] = value & (0xf << mmu::SATP_MODE_SHIFT | mmu::SATP_PPN_MASK);
+                    self.tlb.flush();
                 }
             }
-            _ => {}
+            // misa is fixed, and so are the hardware performance monitors other
+            // than mcycle and minstret, which read as 0.
+            MISA | 0x323..=0x33f | 0xb03..=0xb1f => {}
+            _ => self.csrs[addr] = value,
         }
+    }
 
-        // Check external interrupt for uart and virtio.
-        let irq;
-        if self.bus.uart.is_interrupting() {
-            irq = UART_IRQ;
+    // True if misa has the extension of `letter`.
+    pub fn has_extension(&self, letter: u8) -> bool {
+        self.csrs[MISA] & 1 << (letter - b'a') != 0
+    }
+
+    // FS of mstatus, if it is writable: it is read-only 0 without the F extension.
+    fn fs_mask(&self) -> u64 {
+        if self.has_extension(b'f') {
+            MSTATUS_FS
+        } else {
+            0
+        }
+    }
+
+    // SD of mstatus summarizes FS: it is set if the state is dirty.
+    fn update_sd(&mut self) {
+        if self.csrs[MSTATUS] & MSTATUS_FS == FS_DIRTY {
+            self.csrs[MSTATUS] |= MSTATUS_SD;
         } else {
-            irq = 0;
+            self.csrs[MSTATUS] &= !MSTATUS_SD;
         }
+    }
+
+    // Mark the state of the floating-point unit as changed.
+    fn set_fs_dirty(&mut self) {
+        if self.csrs[MSTATUS] & MSTATUS_FS != FS_OFF {
+            self.csrs[MSTATUS] |= FS_DIRTY | MSTATUS_SD;
+        }
+    }
 
-        if irq != 0 {
-            self.bus
-                .store(PLIC_SCLAIM, 32, irq)
-                .expect("failed to write an IRQ to the PLIC_SCLAIM");
-            self.csrs[MIP] = self.csrs[MIP] | MIP_SEIP;
+    // Set the bits of `bits` in mip if `pending`, or clear them.
+    fn set_pending(&mut self, bits: u64, pending: bool) {
+        if pending {
+            self.csrs[MIP] |= bits;
+        } else {
+            self.csrs[MIP] &= !bits;
+        }
+    }
+
+    pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
+        // Advance the time by the instruction which just retired, even when the
+        // CLINT is not mapped, e.g. for the clocks of a Linux program. The machine
+        // timer interrupt is pending while the CLINT is mapped and mtime >= mtimecmp
+        // of the hart. With the built-in SBI, it is the supervisor timer interrupt,
+        // as the firmware would forward it. The machine software interrupt is
+        // pending while msip of the hart in the CLINT is set.
+        let hartid = self.hartid();
+        let timer = if self.sbi { MIP_STIP } else { MIP_MTIP };
+        let clint = self.bus.devices.clint.is_some();
+        self.bus.clint.tick();
+        self.set_pending(timer, clint && self.bus.clint.timer_interrupt(hartid));
+        self.set_pending(MIP_MSIP, self.bus.clint.msip(hartid));
+        self.csrs[TIME] = self.bus.clint.mtime();
+        self.csrs[MCYCLE] = self.csrs[MCYCLE].wrapping_add(1);
+        self.csrs[MINSTRET] = self.csrs[MINSTRET].wrapping_add(1);
+        // With Sstc, the supervisor timer interrupt is pending while time >= stimecmp.
+        if self.csrs[MENVCFG] & MENVCFG_STCE != 0 {
+            self.set_pending(MIP_STIP, self.csrs[TIME] >= self.csrs[STIMECMP]);
+        }
+
+        // The external interrupts of M-mode and S-mode are pending while the PLIC
+        // has an interrupt for the context of the mode of the hart.
+        if self.bus.devices.plic.is_some() {
+            self.bus.update_irqs();
+            for (mode, bit) in [(PLIC_CONTEXT_M, MIP_MEIP), (PLIC_CONTEXT_S, MIP_SEIP)] {
+                let pending = self.bus.plic.has_interrupt(plic_context(hartid, mode));
+                self.set_pending(bit, pending);
+            }
         }
 
         // "An interrupt i will be taken if bit i is set in both mip and mie, and if interrupts are globally enabled.
@@ -203,48 +658,149 @@ impl Cpu{
         // privilege mode equals the delegated privilege mode (S or U) and that mode’s interrupt enable bit
         // (SIE or UIE in mstatus) is set, or if the current privilege mode is less than the delegated privilege
         // mode."
-
         let pending = self.csrs[MIE] & self.csrs[MIP];
-
-        if (pending & MIP_MEIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_MEIP;
-            return Some(Interrupt::MachineExternalInterrupt);
-        }
-        if (pending & MIP_MSIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_MSIP;
-            return Some(Interrupt::MachineSoftwareInterrupt);
+        if pending == 0 {
+            return None;
         }
-        if (pending & MIP_MTIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_MTIP;
-            return Some(Interrupt::MachineTimerInterrupt);
+        let status = self.csrs[MSTATUS];
+        let machine_enabled = self.mode < Mode::Machine || status & MSTATUS_MIE != 0;
+        let supervisor_enabled = self.mode < Mode::Surpervisor
+            || (self.mode == Mode::Surpervisor && status & MSTATUS_SIE != 0);
+        let mut enabled = 0;
+        if machine_enabled {
+            enabled |= pending & !self.csrs[MIDELEG];
         }
-        if (pending & MIP_SEIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_SEIP;
-            return Some(Interrupt::SupervisorExternalInterrupt);
+        if supervisor_enabled {
+            enabled |= pending & self.csrs[MIDELEG];
         }
-        if (pending & MIP_SSIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_SSIP;
-            return Some(Interrupt::SupervisorSoftwareInterrupt);
-        }
-        if (pending & MIP_STIP) != 0 {
-            self.csrs[MIP] = self.csrs[MIP] & !MIP_STIP;
-            return Some(Interrupt::SupervisorTimerInterrupt);
+
+        // The interrupts stay pending while their source raises them, e.g. until
+        // mtimecmp is written, so the bits of mip are not cleared here.
+        // "Multiple simultaneous interrupts destined for M-mode are handled in the
+        // following decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI."
+        let interrupts = [
+            (MIP_MEIP, Interrupt::MachineExternalInterrupt),
+            (MIP_MSIP, Interrupt::MachineSoftwareInterrupt),
+            (MIP_MTIP, Interrupt::MachineTimerInterrupt),
+            (MIP_SEIP, Interrupt::SupervisorExternalInterrupt),
+            (MIP_SSIP, Interrupt::SupervisorSoftwareInterrupt),
+            (MIP_STIP, Interrupt::SupervisorTimerInterrupt),
+        ];
+        for (bit, interrupt) in interrupts {
+            if enabled & bit != 0 {
+                return Some(interrupt);
+            }
         }
         None
     }
 
+    // Translate a virtual address with the mmu. tval is the address if it faults.
+    fn translate(&mut self, addr: u64, access: AccessType) -> Result<u64, Exception> {
+        mmu::translate(self, addr, access).inspect_err(|_| self.tval = addr)
+    }
+
+    // Fetch 2 bytes of an instruction at the virtual address `addr`.
+    fn fetch16(&mut self, addr: u64) -> Result<u64, Exception> {
+        let paddr = self.translate(addr, AccessType::Instruction)?;
+        self.bus.load(paddr, 16).map_err(|_| {
+            self.tval = addr;
+            Exception::InstructionAccessFault
+        })
+    }
+
     // Get an instruction from memory
     // | means OR in Rust
     // this is a little-endian system
+    // A compressed instruction is 2 bytes, and the other instructions are 4 bytes,
+    // which may cross a page boundary.
     pub fn fetch(&mut self) -> Result<u64,Exception>{
-        match self.bus.load(self.pc,32){
-            Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault)
+        self.fetch_at(self.pc)
+    }
+
+    // Get the instruction at the virtual address `addr`.
+    pub fn fetch_at(&mut self, addr: u64) -> Result<u64, Exception> {
+        // Both halves of an instruction in the page are fetched at once.
+        if addr & 0xfff != 0xffe {
+            let paddr = self.translate(addr, AccessType::Instruction)?;
+            if let Ok(inst) = self.bus.load(paddr, 32) {
+                return Ok(match rvc::inst_len(inst) {
+                    4 => inst,
+                    _ => inst & 0xffff,
+                });
+            }
+        }
+        let low = self.fetch16(addr)?;
+        if rvc::inst_len(low) == 2 {
+            return Ok(low);
+        }
+        let high = self.fetch16(addr.wrapping_add(2))?;
+        Ok(high << 16 | low)
+    }
+
+    // Load `size` bits from the virtual address `addr`. An access which crosses a
+    // page boundary is done byte by byte, since the pages may not be contiguous.
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let bytes = size / 8;
+        if (addr & 0xfff) + bytes > 0x1000 {
+            let mut value = 0;
+            for i in 0..bytes {
+                value |= self.load(addr.wrapping_add(i), 8)? << (i * 8);
+            }
+            return Ok(value);
         }
+        let paddr = self.translate(addr, AccessType::Load)?;
+        self.bus.load(paddr, size).inspect_err(|_| self.tval = addr)
+    }
+
+    // Store `size` bits to the virtual address `addr`.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let bytes = size / 8;
+        if (addr & 0xfff) + bytes > 0x1000 {
+            for i in 0..bytes {
+                self.store(addr.wrapping_add(i), 8, value >> (i * 8))?;
+            }
+            return Ok(());
+        }
+        let paddr = self.translate(addr, AccessType::Store)?;
+        self.bus.store(paddr, size, value).inspect_err(|_| self.tval = addr)?;
+        self.break_reservations(addr);
+        Ok(())
+    }
+
+    // A store to `addr` breaks the reservations of the other harts on it, so that
+    // their sc fails. The reservations are of virtual addresses, which is enough for
+    // the kernels which share their mappings of the memory.
+    fn break_reservations(&mut self, addr: u64) {
+        for hart in self.harts.iter_mut() {
+            if hart.reservation == Some(addr) {
+                hart.reservation = None;
+            }
+        }
+    }
+
+    // Decode and execute an instruction, whose pc is `self.pc` minus its length.
+    // A compressed instruction is executed as the instruction it expands to. The
+    // illegal instruction is the tval of an illegal instruction exception.
+    pub fn execute(&mut self, inst: u64) -> Result<(), Exception> {
+        let len = rvc::inst_len(inst);
+        let expanded = match len {
+            4 => Some(inst),
+            _ => rvc::expand(inst as u16).map(|inst| inst as u64),
+        };
+        let result = match expanded {
+            Some(expanded) => self.execute_expanded(expanded, len),
+            None => Err(Exception::IllegalInstruction),
+        };
+        if result == Err(Exception::IllegalInstruction) {
+            self.tval = inst;
+        }
+        // x0 is hardwired to 0: a write to it is dropped.
+        self.regs[0] = 0;
+        result
     }
 
     //  Return true if an error happens, otherwise false.
-    pub fn execute(&mut self, inst:u64) -> Result<(),Exception>{
+    fn execute_expanded(&mut self, inst:u64, len: u64) -> Result<(),Exception>{
         // decode 
         // get opcode,rd,rs1,rs2
         //let inst = inst as u64;
@@ -255,7 +811,8 @@ impl Cpu{
         let rs1 = ((inst & 0x000f8000) >> 15 ) as usize;
         let rs2 = ((inst & 0x01f00000) >> 20 ) as usize;
 
-        println!("{}",opcode);
+        // The pc of the instruction. self.pc already points to the next one.
+        let pc = self.pc.wrapping_sub(len);
 
         // regs[0](x0) is always 0 (hardwired)
         self.regs[0] = 0;
@@ -275,7 +832,7 @@ impl Cpu{
                         // and sign-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,8)?;
+                        let val = self.load(addr,8)?;
                         self.regs[rd] = val as i8 as i64 as u64;
                     }
                     0x1 => {
@@ -284,7 +841,7 @@ impl Cpu{
                         // and sign-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,16)?;
+                        let val = self.load(addr,16)?;
                         self.regs[rd] = val as i16 as i64 as u64;
                     }
                     0x2 => {
@@ -293,14 +850,14 @@ impl Cpu{
                         // and sign-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,32)?;
+                        let val = self.load(addr,32)?;
                         self.regs[rd] = val as i32 as i64 as u64;
                     }
                     0x3 => {
                         // ld
                         // Loads a 64-bit value from memory 
                         // into register rd for RV64I.
-                        let val = self.bus.load(addr,64)?;
+                        let val = self.load(addr,64)?;
                         self.regs[rd] = val;
                     }
                     0x4 => {
@@ -309,7 +866,7 @@ impl Cpu{
                         // and zero-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,8)?;
+                        let val = self.load(addr,8)?;
                         self.regs[rd] = val;
                     }
                     0x5 => {
@@ -318,7 +875,7 @@ impl Cpu{
                         // and zero-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,16)?;
+                        let val = self.load(addr,16)?;
                         self.regs[rd] = val;
                     }
                     0x6 => {
@@ -327,19 +884,16 @@ impl Cpu{
                         // and zero-extends this to XLEN bits 
                         // before string it in register rd.
                         // finally store it register rd.
-                        let val = self.bus.load(addr,32)?;
+                        let val = self.load(addr,32)?;
                         self.regs[rd] = val;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             0x0f => {
                 // A fence instruction does nothing because this emulator executes an
                 // instruction sequentially on a single thread.
-                match funct3 {
-                    0x0 => {} // fence
-                    _ => {}
-                }
+                // fence (funct3 = 0x0) and fence.i (funct3 = 0x1)
             }
             0x13 => {
                 // imm[11:0] = inst[31:20]
@@ -392,7 +946,7 @@ impl Cpu{
                             0x10 => {
                                 self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
                     // ori
@@ -405,14 +959,14 @@ impl Cpu{
                     0x7 => {
                         self.regs[rd] = self.regs[rs1] & imm;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             // auipc
             // 🍫 add upper immediate to pc
             0x17 => {
                 let imm = (inst & 0xfffff000) as i32 as i64 as u64;
-                self.regs[rd] = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.regs[rd] = pc.wrapping_add(imm);
             }
             0x1b => {
                 // imm[11:0] = inst[31:20]
@@ -434,38 +988,38 @@ impl Cpu{
                             0x00 => {
                                 // srliw
                                 // 🍫 Shift right logical word immediate
-                                self.regs[rd] = self.regs[rs1].wrapping_shr(shamt) as i32 as i64 as u64;
+                                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as i64 as u64;
                             }
                             0x20 => {
                                 // sraiw
                                 // 🍫 Shift right arithmetic word immediate.
                                 self.regs[rd] = (self.regs[rs1] as i32).wrapping_shr(shamt) as i64 as u64;
                             }
-                            _=>{}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             0x23 => {
                     // imm[11:5|4:0] = inst[31:25|11:7]
                     let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64)
-                        | (((inst >> 7) & 0x1f));
+                        | ((inst >> 7) & 0x1f);
                     let addr = self.regs[rs1].wrapping_add(imm);
                     match funct3 {
                         // sb 
                         // Store 8-bit, values from the low bits of register rs2 to memory.
-                        0x0 => self.bus.store(addr,8,self.regs[rs2])?, 
+                        0x0 => self.store(addr,8,self.regs[rs2])?, 
                         // sh
                         // Store 16-bit, values from the low bits of register rs2 to memory.
-                        0x1 => self.bus.store(addr,16,self.regs[rs2])?,
+                        0x1 => self.store(addr,16,self.regs[rs2])?,
                         // sw
                         // Store 32-bit, values from the low bits of register rs2 to memory.
-                        0x2 => self.bus.store(addr,32,self.regs[rs2])?,
+                        0x2 => self.store(addr,32,self.regs[rs2])?,
                         // sd
                         // Store 64-bit, values from the low bits of register rs2 to memory.
-                        0x3 => self.bus.store(addr,64,self.regs[rs2])?,
-                        _ => {}
+                        0x3 => self.store(addr,64,self.regs[rs2])?,
+                        _ => return Err(Exception::IllegalInstruction),
                     }
             }
             // RV64A: "A" standard extension for atmic instructions
@@ -476,40 +1030,83 @@ impl Cpu{
                 let _aq = (funct7 & 0b0000010) >> 1;
                 // release access
                 let _rl = funct7 & 0b0000001;
-                match (funct3,funct5){
-                    (0x2,0x00) => {
-                        // amoadd.w
-                        // loads and store 32 bits data
-                        let t = self.bus.load(self.regs[rs1],32)?;
-                        self.bus.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3,0x00) => {
-                        // amoadd.d
-                        // loads and store 64 bits data
-                        let t = self.bus.load(self.regs[rs1],64)?;
-                        self.bus.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
+                // .w (funct3 = 0x2) accesses 32 bits and .d (funct3 = 0x3) 64 bits.
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 => 64,
+                    _ => return Err(Exception::IllegalInstruction),
+                };
+                // The loaded value and the value of rs2, sign-extended for .w
+                let extend = |value: u64| if size == 32 { value as i32 as i64 as u64 } else { value };
+                let addr = self.regs[rs1];
+                // The address must be aligned to the size.
+                if !addr.is_multiple_of(size / 8) {
+                    self.tval = addr;
+                    return Err(match funct5 {
+                        0x02 => Exception::LoadAddressMisaligned,
+                        _ => Exception::StoreAMOAddressMisaligned,
+                    });
+                }
+                match funct5 {
+                    0x02 => {
+                        // lr.w and lr.d
+                        // load reserved: loads the data and reserves the address.
+                        if rs2 != 0 {
+                            return Err(Exception::IllegalInstruction);
+                        }
+                        let t = self.load(addr, size)?;
+                        self.reservation = Some(addr);
+                        self.regs[rd] = extend(t);
                     }
-                    (0x2,0x01) => {
-                        // amoswap.w
-                        // swap 32 bits data
-                        let t = self.bus.load(self.regs[rs1],32)?;
-                        self.bus.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = t ;
+                    0x03 => {
+                        // sc.w and sc.d
+                        // store conditional: stores the data only if the address is
+                        // still reserved, and writes 0 to rd on success, 1 on failure.
+                        if self.reservation == Some(addr) {
+                            self.store(addr, size, self.regs[rs2])?;
+                            self.regs[rd] = 0;
+                        } else {
+                            self.regs[rd] = 1;
+                        }
+                        self.reservation = None;
                     }
-                    (0x3, 0x01) => {
-                        // amowap.d
-                        // swap and store 64 bits data
-                        let t = self.bus.load(self.regs[rs1],64)?;
-                        self.bus.store(self.regs[rs1], 64,self.regs[rs2])?;
-                        self.regs[rd] = t;
+                    _ => {
+                        // amoswap, amoadd, amoxor, amoand, amoor, amomin, amomax,
+                        // amominu and amomaxu
+                        // loads the data, stores the result of the operation with rs2
+                        // and writes the loaded data to rd. An AMO raises the exceptions
+                        // of a store.
+                        let paddr = self.translate(addr, AccessType::Store)?;
+                        let t = self.bus.load(paddr, size).map_err(|_| {
+                            self.tval = addr;
+                            Exception::StoreAMOAccessFault
+                        })?;
+                        let (a, b) = (extend(t), extend(self.regs[rs2]));
+                        // The unsigned comparison of .w is done on 32 bits.
+                        let (ua, ub) = if size == 32 { (a as u32 as u64, b as u32 as u64) } else { (a, b) };
+                        let value = match funct5 {
+                            0x00 => a.wrapping_add(b),
+                            0x01 => b,
+                            0x04 => a ^ b,
+                            0x08 => a | b,
+                            0x0c => a & b,
+                            0x10 => (a as i64).min(b as i64) as u64,
+                            0x14 => (a as i64).max(b as i64) as u64,
+                            0x18 => if ua <= ub { a } else { b },
+                            0x1c => if ua >= ub { a } else { b },
+                            _ => return Err(Exception::IllegalInstruction),
+                        };
+                        self.bus.store(paddr, size, value).map_err(|_| {
+                            self.tval = addr;
+                            Exception::StoreAMOAccessFault
+                        })?;
+                        self.break_reservations(addr);
+                        self.regs[rd] = a;
                     }
-                    _ => {}
                 }
             }
             0x33 => {
-                let shamt = ((self.regs[rs2] & 0x3f) as u64) as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 match (funct3, funct7) {
                     (0x0,0x00) => {
                         // add 
@@ -567,7 +1164,65 @@ impl Cpu{
                         // and
                         self.regs[rd] = self.regs[rs1] & self.regs[rs2];
                     }
-                    _ => {}
+                    // RV64M: "M" standard extension for integer multiplication and division
+                    (0x0, 0x01) => {
+                        // mul
+                        // The lower 64 bits of rs1 * rs2
+                        self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]);
+                    }
+                    (0x1, 0x01) => {
+                        // mulh
+                        // The upper 64 bits of the signed rs1 * signed rs2
+                        let product = (self.regs[rs1] as i64 as i128) * (self.regs[rs2] as i64 as i128);
+                        self.regs[rd] = (product >> 64) as u64;
+                    }
+                    (0x2, 0x01) => {
+                        // mulhsu
+                        // The upper 64 bits of the signed rs1 * unsigned rs2
+                        let product = (self.regs[rs1] as i64 as i128).wrapping_mul(self.regs[rs2] as i128);
+                        self.regs[rd] = (product >> 64) as u64;
+                    }
+                    (0x3, 0x01) => {
+                        // mulhu
+                        // The upper 64 bits of the unsigned rs1 * unsigned rs2
+                        let product = (self.regs[rs1] as u128) * (self.regs[rs2] as u128);
+                        self.regs[rd] = (product >> 64) as u64;
+                    }
+                    (0x4, 0x01) => {
+                        // div
+                        // Signed division. The division by zero gives -1, and the
+                        // overflow (the most negative number / -1) gives the dividend.
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => u64::MAX,
+                            divisor => (self.regs[rs1] as i64).wrapping_div(divisor as i64) as u64,
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divu
+                        // Unsigned division. The division by zero gives 2^64 - 1.
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => u64::MAX,
+                            divisor => self.regs[rs1] / divisor,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // rem
+                        // Signed remainder. The remainder of the division by zero is
+                        // the dividend, and the one of the overflow is 0.
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => self.regs[rs1],
+                            divisor => (self.regs[rs1] as i64).wrapping_rem(divisor as i64) as u64,
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remu
+                        // Unsigned remainder
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => self.regs[rs1],
+                            divisor => self.regs[rs1] % divisor,
+                        };
+                    }
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             0x37 => {
@@ -605,29 +1260,67 @@ impl Cpu{
                         // 🍫 what is arithmetic ?
                         self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
                     }
-                    _ => {}
+                    (0x0, 0x01) => {
+                        // mulw
+                        // Multiply the lower 32 bits and sign-extend the lower 32 bits
+                        // of the result.
+                        self.regs[rd] = (self.regs[rs1] as i32).wrapping_mul(self.regs[rs2] as i32) as i64 as u64;
+                    }
+                    (0x4, 0x01) => {
+                        // divw
+                        // Signed division of the lower 32 bits
+                        self.regs[rd] = match self.regs[rs2] as i32 {
+                            0 => u64::MAX,
+                            divisor => (self.regs[rs1] as i32).wrapping_div(divisor) as i64 as u64,
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divuw
+                        // Unsigned division of the lower 32 bits
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => u64::MAX,
+                            divisor => ((self.regs[rs1] as u32) / divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // remw
+                        // Signed remainder of the lower 32 bits
+                        self.regs[rd] = match self.regs[rs2] as i32 {
+                            0 => self.regs[rs1] as i32 as i64 as u64,
+                            divisor => (self.regs[rs1] as i32).wrapping_rem(divisor) as i64 as u64,
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remuw
+                        // Unsigned remainder of the lower 32 bits
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => self.regs[rs1] as i32 as i64 as u64,
+                            divisor => ((self.regs[rs1] as u32) % divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             0x63 => {
                 // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
                 let imm = (((inst & 0x80000000) as i32 as i64 >> 19) as u64)
-                    | ((inst & 0x80) << 4) as u64 // imm[11]
-                    | ((inst >> 20) & 0x7e0) as u64 // imm[10:5]
-                    | ((inst >> 7) & 0x1e) as u64; // imm[4:1]
+                    | ((inst & 0x80) << 4) // imm[11]
+                    | ((inst >> 20) & 0x7e0) // imm[10:5]
+                    | ((inst >> 7) & 0x1e); // imm[4:1]
 
                 match funct3 {
                     0x0 => {
                         // beq
                         // Take the branch if registers rs1 and rs2 are equal.
                         if self.regs[rs1] == self.regs[rs2]{
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
                     0x1 => {
                         // bne
                         // Take the branch if registers rs1 and rs2 are not equal.
                         if self.regs[rs1] != self.regs[rs2]{
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
                     0x4 => {
@@ -635,7 +1328,7 @@ impl Cpu{
                         // Take the branch if registers rs1 is less than rs2, 
                         // using signed comparison.
                         if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
                     0x5 => {
@@ -643,7 +1336,7 @@ impl Cpu{
                         // Take the branch if registers rs1 is greater than rs2, 
                         // using signed comparison.
                         if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
                     0x6 => {
@@ -651,7 +1344,7 @@ impl Cpu{
                         // Take the branch if registers rs1 is less than rs2, 
                         // using unsigned comparison.
                         if self.regs[rs1] < self.regs[rs2]{
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
                     0x7 => {
@@ -659,10 +1352,10 @@ impl Cpu{
                         // Take the branch if registers rs1 is greater than rs2,
                         // using unsigned comparison.
                         if self.regs[rs1] >= self.regs[rs2]{
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                            self.pc = pc.wrapping_add(imm);
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
             // jalr
@@ -670,7 +1363,7 @@ impl Cpu{
             0x67 => {
                 let t = self.pc;
 
-                let imm = (((inst & 0xfff00000) as i64) >> 20) as u64;
+                let imm = ((inst as i32 as i64) >> 20) as u64;
                 self.pc = (self.regs[rs1].wrapping_add(imm)) & !1;
 
                 // 🍫
@@ -683,11 +1376,11 @@ impl Cpu{
 
                 // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
                 let imm = (((inst & 0x80000000) as i32 as i64 >> 11) as u64) // imm[20]
-                    | (inst & 0xff000) as u64 // imm[19:12]
-                    | ((inst >> 9) & 0x800) as u64 // imm[11]
-                    | ((inst >> 20) & 0x7fe) as u64; // imm[10:1]
+                    | (inst & 0xff000) // imm[19:12]
+                    | ((inst >> 9) & 0x800) // imm[11]
+                    | ((inst >> 20) & 0x7fe); // imm[10:1]
 
-                self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.pc = pc.wrapping_add(imm);
             }
             0x73 => {
                 let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
@@ -727,24 +1420,32 @@ impl Cpu{
                                 // - Sets CSRs[sstatus].SIE to CSRs[sstatus].SPIE.
                                 // - Sets CSRs[sstatus].SPIE to 1.
                                 // - Sets CSRs[sstatus].SPP to 0.
+                                // It is illegal in U-mode, and in S-mode with TSR.
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Surpervisor && self.csrs[MSTATUS] & MSTATUS_TSR != 0)
+                                {
+                                    return Err(Exception::IllegalInstruction);
+                                }
                                 self.pc = self.csrs[SEPC];
                                 // When the SRET instruction is executed to return from the trap
                                 // handler, the privilege level is set to user mode if the SPP
                                 // bit is 0, or supervisor mode if the SPP bit is 1. The SPP bit
-                                // is the 8th of the SSTATUS csr.
-                                self.mode = match (self.csrs[SSTATUS] >> 8) & 1 {
+                                // is the 8th of the SSTATUS csr, which is a view of MSTATUS.
+                                self.mode = match (self.csrs[MSTATUS] >> 8) & 1 {
                                     1 => Mode::Surpervisor,
                                     _ => Mode::User,
                                 };
                                 // The SPIE bit is the 5th and the SIE bit is the 1st of the
                                 // SSTATUS csr.
-                                self.csrs[SSTATUS] = if ((self.csrs[SSTATUS] >> 5) & 1) == 1 {
-                                    self.csrs[SSTATUS] | (1 << 1)
+                                self.csrs[MSTATUS] = if ((self.csrs[MSTATUS] >> 5) & 1) == 1 {
+                                    self.csrs[MSTATUS] | MSTATUS_SIE
                                 }else{
-                                    self.csrs[SSTATUS] & !(1 << 1)
+                                    self.csrs[MSTATUS] & !MSTATUS_SIE
                                 };
-                                self.csrs[SSTATUS] = self.csrs[SSTATUS] | (1 << 5);
-                                self.csrs[SSTATUS] = self.csrs[SSTATUS] & !(1 << 8);
+                                self.csrs[MSTATUS] |= MSTATUS_SPIE;
+                                self.csrs[MSTATUS] &= !MSTATUS_SPP;
+                                // Returning to a mode other than M-mode clears MPRV.
+                                self.csrs[MSTATUS] &= !MSTATUS_MPRV;
                             }
                             (0x2, 0x18) => {
                                 // 🍫 mret
@@ -756,50 +1457,84 @@ impl Cpu{
                                 // - Sets CSRs[mstatus].MIE to CSRs[mstatus].MPIE.
                                 // - Sets CSRs[mstatus].MPIE to 1.
                                 // - Sets CSRs[mstatus].MPP to 0.
+                                // It is illegal in the modes other than M-mode.
+                                if self.mode != Mode::Machine {
+                                    return Err(Exception::IllegalInstruction);
+                                }
                                 self.pc = self.csrs[MEPC];
                                 // MPP is two bits wide at [11..12] of the MSTATUS csr.
                                 self.mode = match (self.csrs[MSTATUS] >> 11) & 0b11 {
-                                    2 => Mode::Machine,
+                                    3 => Mode::Machine,
                                     1 => Mode::Surpervisor,
                                     _ => Mode::User,
                                 };
                                 // The MPIE bit is the 7th and the MIE bit is the 3rd of the
                                 // MSTATUS csr. 
                                 self.csrs[MSTATUS] = if((self.csrs[MSTATUS] >> 7) & 1) == 1 {
-                                    self.csrs[MSTATUS] | (1 << 3)
+                                    self.csrs[MSTATUS] | MSTATUS_MIE
                                 }else{
-                                    self.csrs[MSTATUS] & !(1 << 3)
+                                    self.csrs[MSTATUS] & !MSTATUS_MIE
                                 };
-                                self.csrs[MSTATUS] = self.csrs[MSTATUS] | (1 << 7);
-                                self.csrs[MSTATUS] = self.csrs[MSTATUS] & !(0b11 << 11);
+                                self.csrs[MSTATUS] |= MSTATUS_MPIE;
+                                self.csrs[MSTATUS] &= !MSTATUS_MPP;
+                                // Returning to a mode other than M-mode clears MPRV.
+                                if self.mode != Mode::Machine {
+                                    self.csrs[MSTATUS] &= !MSTATUS_MPRV;
+                                }
+                            }
+                            (0x5, 0x8) => {
+                                // wfi
+                                // Wait for an interrupt. It does nothing, since the
+                                // interrupts are checked after every instruction. It is
+                                // illegal in U-mode, and in S-mode with TW.
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Surpervisor && self.csrs[MSTATUS] & MSTATUS_TW != 0)
+                                {
+                                    return Err(Exception::IllegalInstruction);
+                                }
                             }
                             (_,0x9) => {
                                 // sfence.vma
-                                // Do nothing
+                                // Flush the TLB. It is illegal in U-mode, and in S-mode
+                                // with TVM.
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Surpervisor && self.csrs[MSTATUS] & MSTATUS_TVM != 0)
+                                {
+                                    return Err(Exception::IllegalInstruction);
+                                }
+                                self.tlb.flush();
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
                     0x1 => {
                         // csrrw
                         // atomic read/write CSR.
                         // Atomically swaps values in the CSRs and integer registers.
-                        let t = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = self.regs[rs1];
+                        self.check_csr_access(csr_addr, true)?;
+                        let t = self.read_csr(csr_addr);
+                        self.write_csr(csr_addr, self.regs[rs1]);
                         self.regs[rd] = t;
                     }
                     0x2 => {
                         // csrrs
                         // atomic read and set bits in CSR.
-                        let t = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = t | self.regs[rs1];
+                        // The CSR is not written if rs1 is x0.
+                        self.check_csr_access(csr_addr, rs1 != 0)?;
+                        let t = self.read_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.write_csr(csr_addr, t | self.regs[rs1]);
+                        }
                         self.regs[rd] = t;
                     }
                     0x3 => {
                         // csrrc
                         // atomic read and clear bits in CSR.
-                        let t = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = t & (!self.regs[rs1]);
+                        self.check_csr_access(csr_addr, rs1 != 0)?;
+                        let t = self.read_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.write_csr(csr_addr, t & (!self.regs[rs1]));
+                        }
                         self.regs[rd] = t;
                     }
                     0x5 => {
@@ -807,16 +1542,20 @@ impl Cpu{
                         // Update the CSR using an XLEN-bit value obtained by zero-extending 
                         // a 5-bit unsigned immediate (uimm[4:0]) field encoded in the rs1 field.
                         let zimm = rs1 as u64;
-                        self.regs[rd] = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = zimm;
+                        self.check_csr_access(csr_addr, true)?;
+                        self.regs[rd] = self.read_csr(csr_addr);
+                        self.write_csr(csr_addr, zimm);
                     }
                     0x06 => {
                         // csrrsi
                         // Set CSR bit using an XLEN-bit value obtained by zero-extending
                         // a 5-bit unsigned immediate (uimm[4:0]) field encoded in the rs1 field.
                         let zimm =rs1 as u64;
-                        let t = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = t | zimm;
+                        self.check_csr_access(csr_addr, zimm != 0)?;
+                        let t = self.read_csr(csr_addr);
+                        if zimm != 0 {
+                            self.write_csr(csr_addr, t | zimm);
+                        }
                         self.regs[rd] = t;
                     }
                     0x07 => {
@@ -824,18 +1563,234 @@ impl Cpu{
                         // Clear CSR bit using an XLEN-bit value obtained by zero-extending
                         // a 5-bit unsigned immediate (uimm[4:0]) field encoded in the rs1 field.
                         let zimm = rs1 as u64;
-                        let t = self.csrs[csr_addr];
-                        self.csrs[csr_addr] = t & (!zimm);
+                        self.check_csr_access(csr_addr, zimm != 0)?;
+                        let t = self.read_csr(csr_addr);
+                        if zimm != 0 {
+                            self.write_csr(csr_addr, t & (!zimm));
+                        }
                         self.regs[rd] = t;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
+            // The instructions of the F and D extensions
+            0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => self.execute_float(inst)?,
             _ => {
-                dbg!(format!("not implemented yet: opcade {:#x}",opcode));
                 return Err(Exception::IllegalInstruction);
             }
         }
-        return Ok(());
+        Ok(())
+    }
+
+    // Read a floating-point register as a number of `format`. A single-precision
+    // value which is not NaN-boxed is the canonical NaN.
+    fn read_freg(&self, reg: usize, format: Format) -> u64 {
+        let value = self.fregs[reg];
+        match format {
+            Format::Double => value,
+            Format::Single if value >> 32 == 0xffff_ffff => value & 0xffff_ffff,
+            Format::Single => format.canonical_nan(),
+        }
     }
-}
\ No newline at end of file
+
+    // Write a number of `format` to a floating-point register, NaN-boxed if it is
+    // single-precision.
+    fn write_freg(&mut self, reg: usize, format: Format, value: u64) {
+        self.fregs[reg] = match format {
+            Format::Double => value,
+            Format::Single => 0xffff_ffff_0000_0000 | value,
+        };
+        self.set_fs_dirty();
+    }
+
+    // Execute an instruction of the F or D extension. They are illegal while the
+    // floating-point unit is off, and so are the reserved formats and rounding modes.
+    fn execute_float(&mut self, inst: u64) -> Result<(), Exception> {
+        let opcode = inst & 0x0000007f;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct5 = (inst & 0xf8000000) >> 27;
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let rs3 = ((inst & 0xf8000000) >> 27) as usize;
+
+        if self.csrs[MSTATUS] & MSTATUS_FS == FS_OFF {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The format is funct3 of the loads and the stores, and fmt, inst[26:25], of
+        // the others.
+        let format = match opcode {
+            0x07 | 0x27 => match funct3 {
+                0x2 => Format::Single,
+                0x3 => Format::Double,
+                _ => return Err(Exception::IllegalInstruction),
+            },
+            _ => match (inst >> 25) & 0b11 {
+                0b00 => Format::Single,
+                0b01 => Format::Double,
+                _ => return Err(Exception::IllegalInstruction),
+            },
+        };
+        let extension = match format {
+            Format::Single => b'f',
+            Format::Double => b'd',
+        };
+        if !self.has_extension(extension) {
+            return Err(Exception::IllegalInstruction);
+        }
+        // The rounding mode of rm, funct3, where 7 is the dynamic one of frm.
+        let rm = if funct3 == 0x7 { self.read_csr(FRM) } else { funct3 };
+        let rounding = Rounding::from_bits(rm);
+        let mut fpu = Fpu::new(rounding.unwrap_or(Rounding::NearestEven));
+        // The instructions which round check that the rounding mode is valid.
+        let check_rounding = || match rounding {
+            Some(_) => Ok(()),
+            None => Err(Exception::IllegalInstruction),
+        };
+
+        match opcode {
+            0x07 => {
+                // flw and fld
+                let imm = ((inst as i32 as i64) >> 20) as u64;
+                let addr = self.regs[rs1].wrapping_add(imm);
+                let value = match format {
+                    Format::Single => self.load(addr, 32)?,
+                    Format::Double => self.load(addr, 64)?,
+                };
+                self.write_freg(rd, format, value);
+            }
+            0x27 => {
+                // fsw and fsd store the bits of the register as they are.
+                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
+                let addr = self.regs[rs1].wrapping_add(imm);
+                match format {
+                    Format::Single => self.store(addr, 32, self.fregs[rs2])?,
+                    Format::Double => self.store(addr, 64, self.fregs[rs2])?,
+                }
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                // fmadd: rs1 * rs2 + rs3, fmsub: rs1 * rs2 - rs3,
+                // fnmsub: -(rs1 * rs2) + rs3 and fnmadd: -(rs1 * rs2) - rs3
+                let (a, b, c) = (
+                    self.read_freg(rs1, format),
+                    self.read_freg(rs2, format),
+                    self.read_freg(rs3, format),
+                );
+                let negate_product = opcode == 0x4b || opcode == 0x4f;
+                let negate_addend = opcode == 0x47 || opcode == 0x4f;
+                check_rounding()?;
+                let value = fpu.fused_multiply_add(format, a, b, c, negate_product, negate_addend);
+                self.write_freg(rd, format, value);
+            }
+            _ => {
+                let (a, b) = (self.read_freg(rs1, format), self.read_freg(rs2, format));
+                let sign = match format {
+                    Format::Single => 1 << 31,
+                    Format::Double => 1 << 63,
+                };
+                match (funct5, funct3) {
+                    // fadd, fsub, fmul and fdiv
+                    (0x00, _) => {
+                        check_rounding()?;
+                        let value = fpu.add(format, a, b);
+                        self.write_freg(rd, format, value);
+                    }
+                    (0x01, _) => {
+                        check_rounding()?;
+                        let value = fpu.sub(format, a, b);
+                        self.write_freg(rd, format, value);
+                    }
+                    (0x02, _) => {
+                        check_rounding()?;
+                        let value = fpu.mul(format, a, b);
+                        self.write_freg(rd, format, value);
+                    }
+                    (0x03, _) => {
+                        check_rounding()?;
+                        let value = fpu.div(format, a, b);
+                        self.write_freg(rd, format, value);
+                    }
+                    // fsqrt
+                    (0x0b, _) if rs2 == 0 => {
+                        check_rounding()?;
+                        let value = fpu.sqrt(format, a);
+                        self.write_freg(rd, format, value);
+                    }
+                    // fsgnj, fsgnjn and fsgnjx: the bits of rs1 with the sign of rs2,
+                    // its opposite, or the xor of both signs
+                    (0x04, 0x0..=0x2) => {
+                        let sign_b = match funct3 {
+                            0x0 => b & sign,
+                            0x1 => !b & sign,
+                            _ => (a ^ b) & sign,
+                        };
+                        self.write_freg(rd, format, a & !sign | sign_b);
+                    }
+                    // fmin and fmax
+                    (0x05, 0x0..=0x1) => {
+                        let value = fpu.min_max(format, a, b, funct3 == 0x1);
+                        self.write_freg(rd, format, value);
+                    }
+                    // fcvt.s.d and fcvt.d.s
+                    (0x08, _) => {
+                        let from = match (format, rs2) {
+                            (Format::Single, 1) if self.has_extension(b'd') => Format::Double,
+                            (Format::Double, 0) => Format::Single,
+                            _ => return Err(Exception::IllegalInstruction),
+                        };
+                        let value = self.read_freg(rs1, from);
+                        check_rounding()?;
+                        let value = fpu.convert(from, format, value);
+                        self.write_freg(rd, format, value);
+                    }
+                    // fle, flt and feq
+                    (0x14, 0x0..=0x2) => {
+                        let value = match funct3 {
+                            0x0 => fpu.compare(format, a, b).is_some_and(|order| order.is_le()),
+                            0x1 => fpu.compare(format, a, b).is_some_and(|order| order.is_lt()),
+                            _ => fpu.eq(format, a, b),
+                        };
+                        self.regs[rd] = value as u64;
+                    }
+                    // fcvt.w, fcvt.wu, fcvt.l and fcvt.lu to an integer
+                    (0x18, _) if rs2 < 4 => {
+                        let width = if rs2 < 2 { 32 } else { 64 };
+                        check_rounding()?;
+                        self.regs[rd] = fpu.to_int(format, a, rs2 & 1 == 0, width);
+                    }
+                    // fcvt from an integer: w, wu, l and lu
+                    (0x1a, _) if rs2 < 4 => {
+                        let width = if rs2 < 2 { 32 } else { 64 };
+                        check_rounding()?;
+                        let value = fpu.from_int(format, self.regs[rs1], rs2 & 1 == 0, width);
+                        self.write_freg(rd, format, value);
+                    }
+                    // fmv.x.w and fmv.x.d move the bits of the register, sign-extended.
+                    (0x1c, 0x0) if rs2 == 0 => {
+                        self.regs[rd] = match format {
+                            Format::Single => self.fregs[rs1] as i32 as i64 as u64,
+                            Format::Double => self.fregs[rs1],
+                        };
+                    }
+                    // fclass
+                    (0x1c, 0x1) if rs2 == 0 => self.regs[rd] = format.classify(a),
+                    // fmv.w.x and fmv.d.x
+                    (0x1e, 0x0) if rs2 == 0 => {
+                        let value = match format {
+                            Format::Single => self.regs[rs1] & 0xffff_ffff,
+                            Format::Double => self.regs[rs1],
+                        };
+                        self.write_freg(rd, format, value);
+                    }
+                    _ => return Err(Exception::IllegalInstruction),
+                }
+            }
+        }
+        // The exceptions accrue in fflags.
+        if fpu.flags != 0 {
+            self.csrs[FCSR] |= fpu.flags;
+            self.set_fs_dirty();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+// The disasm module turns a raw instruction into the assembly text printed by
+// Spike (riscv-isa-sim), so that traces of both simulators can be compared
+// line by line. Pseudo-instructions (li, mv, j, ret, csrr, ...) are printed the
+// same way Spike prints them.
+
+// ABI names of the integer registers (x0-x31)
+pub const XREG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// ABI names of the floating-point registers (f0-f31)
+pub const FREG_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+// Return the name of a CSR, or None if the address is not a known CSR.
+pub fn csr_name(addr: usize) -> Option<&'static str> {
+    let name = match addr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x3a0 => "pmpcfg0",
+        0x3a2 => "pmpcfg2",
+        0x3b0 => "pmpaddr0",
+        0x3b1 => "pmpaddr1",
+        0x3b2 => "pmpaddr2",
+        0x3b3 => "pmpaddr3",
+        0x7a0 => "tselect",
+        0x7a1 => "tdata1",
+        0x7a2 => "tdata2",
+        0x7a3 => "tdata3",
+        0x7b0 => "dcsr",
+        0x7b1 => "dpc",
+        0x7b2 => "dscratch0",
+        0x7b3 => "dscratch1",
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Name of a CSR as an operand. Unknown CSRs are printed as a hex number.
+fn csr(addr: usize) -> String {
+    match csr_name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("{:#x}", addr),
+    }
+}
+
+fn x(reg: usize) -> &'static str {
+    XREG_NAMES[reg]
+}
+
+fn f(reg: usize) -> &'static str {
+    FREG_NAMES[reg]
+}
+
+// A pc-relative target, printed as "pc + 8" or "pc - 8".
+fn target(offset: i64) -> String {
+    if offset < 0 {
+        format!("pc - {}", -offset)
+    } else {
+        format!("pc + {}", offset)
+    }
+}
+
+// Join a mnemonic and its operands the way Spike does: the mnemonic is padded
+// to 8 characters and operands are separated by ", ".
+fn format_inst(name: &str, args: &[String]) -> String {
+    if args.is_empty() {
+        return name.to_string();
+    }
+    let pad = if name.len() < 8 { 8 - name.len() } else { 1 };
+    format!("{}{}{}", name, " ".repeat(pad), args.join(", "))
+}
+
+// Spike prints ".aq", ".rl" and ".aqrl" after the mnemonic of atomic instructions.
+fn ordering(funct7: u64) -> &'static str {
+    match funct7 & 0b11 {
+        0b10 => ".aq",
+        0b01 => ".rl",
+        0b11 => ".aqrl",
+        _ => "",
+    }
+}
+
+// Disassemble a 32-bit instruction. Unknown encodings are printed as "unknown".
+pub fn disassemble(inst: u64) -> String {
+    let opcode = inst & 0x0000007f;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    let rd = ((inst & 0x00000f80) >> 7) as usize;
+    let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+    let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+
+    // imm[11:0] = inst[31:20]
+    let i_imm = (inst as i32 as i64) >> 20;
+    // imm[11:5|4:0] = inst[31:25|11:7]
+    let s_imm = (((inst & 0xfe000000) as i32 as i64) >> 20) | ((inst >> 7) & 0x1f) as i64;
+    // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
+    let b_imm = (((inst & 0x80000000) as i32 as i64) >> 19)
+        | ((inst & 0x80) << 4) as i64
+        | ((inst >> 20) & 0x7e0) as i64
+        | ((inst >> 7) & 0x1e) as i64;
+    // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+    let j_imm = (((inst & 0x80000000) as i32 as i64) >> 11)
+        | (inst & 0xff000) as i64
+        | ((inst >> 9) & 0x800) as i64
+        | ((inst >> 20) & 0x7fe) as i64;
+    let u_imm = format!("{:#x}", (inst >> 12) & 0xfffff);
+
+    let unknown = || "unknown".to_string();
+    let r_type = |name: &str| format_inst(name, &[x(rd).into(), x(rs1).into(), x(rs2).into()]);
+    let i_type = |name: &str| format_inst(name, &[x(rd).into(), x(rs1).into(), i_imm.to_string()]);
+    let shift = |name: &str, shamt: u64| {
+        format_inst(name, &[x(rd).into(), x(rs1).into(), shamt.to_string()])
+    };
+    let load = |name: &str| format_inst(name, &[x(rd).into(), format!("{}({})", i_imm, x(rs1))]);
+    let store = |name: &str| format_inst(name, &[x(rs2).into(), format!("{}({})", s_imm, x(rs1))]);
+
+    match opcode {
+        0x03 => match funct3 {
+            0x0 => load("lb"),
+            0x1 => load("lh"),
+            0x2 => load("lw"),
+            0x3 => load("ld"),
+            0x4 => load("lbu"),
+            0x5 => load("lhu"),
+            0x6 => load("lwu"),
+            _ => unknown(),
+        },
+        0x07 => match funct3 {
+            0x2 => format_inst("flw", &[f(rd).into(), format!("{}({})", i_imm, x(rs1))]),
+            0x3 => format_inst("fld", &[f(rd).into(), format!("{}({})", i_imm, x(rs1))]),
+            _ => unknown(),
+        },
+        0x0f => match funct3 {
+            0x0 => {
+                let pred = (inst >> 24) & 0xf;
+                let succ = (inst >> 20) & 0xf;
+                if pred == 0xf && succ == 0xf {
+                    "fence".to_string()
+                } else {
+                    let set = |bits: u64| {
+                        ["i", "o", "r", "w"]
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, _)| (bits >> (3 - i)) & 1 == 1)
+                            .map(|(_, s)| *s)
+                            .collect::<String>()
+                    };
+                    format_inst("fence", &[format!("{},{}", set(pred), set(succ))])
+                }
+            }
+            0x1 => "fence.i".to_string(),
+            _ => unknown(),
+        },
+        0x13 => {
+            let shamt = (inst >> 20) & 0x3f;
+            match funct3 {
+                0x0 => {
+                    if rd == 0 && rs1 == 0 && i_imm == 0 {
+                        "nop".to_string()
+                    } else if rs1 == 0 {
+                        format_inst("li", &[x(rd).into(), i_imm.to_string()])
+                    } else if i_imm == 0 {
+                        format_inst("mv", &[x(rd).into(), x(rs1).into()])
+                    } else {
+                        i_type("addi")
+                    }
+                }
+                0x1 => shift("slli", shamt),
+                0x2 => i_type("slti"),
+                0x3 => {
+                    if i_imm == 1 {
+                        format_inst("seqz", &[x(rd).into(), x(rs1).into()])
+                    } else {
+                        i_type("sltiu")
+                    }
+                }
+                0x4 => {
+                    if i_imm == -1 {
+                        format_inst("not", &[x(rd).into(), x(rs1).into()])
+                    } else {
+                        i_type("xori")
+                    }
+                }
+                0x5 => match funct7 >> 1 {
+                    0x00 => shift("srli", shamt),
+                    0x10 => shift("srai", shamt),
+                    _ => unknown(),
+                },
+                0x6 => i_type("ori"),
+                0x7 => i_type("andi"),
+                _ => unknown(),
+            }
+        }
+        0x17 => format_inst("auipc", &[x(rd).into(), u_imm]),
+        0x1b => {
+            let shamt = (inst >> 20) & 0x1f;
+            match (funct3, funct7) {
+                (0x0, _) => {
+                    if i_imm == 0 {
+                        format_inst("sext.w", &[x(rd).into(), x(rs1).into()])
+                    } else {
+                        i_type("addiw")
+                    }
+                }
+                (0x1, 0x00) => shift("slliw", shamt),
+                (0x5, 0x00) => shift("srliw", shamt),
+                (0x5, 0x20) => shift("sraiw", shamt),
+                _ => unknown(),
+            }
+        }
+        0x23 => match funct3 {
+            0x0 => store("sb"),
+            0x1 => store("sh"),
+            0x2 => store("sw"),
+            0x3 => store("sd"),
+            _ => unknown(),
+        },
+        0x27 => match funct3 {
+            0x2 => format_inst("fsw", &[f(rs2).into(), format!("{}({})", s_imm, x(rs1))]),
+            0x3 => format_inst("fsd", &[f(rs2).into(), format!("{}({})", s_imm, x(rs1))]),
+            _ => unknown(),
+        },
+        0x2f => {
+            let funct5 = (funct7 & 0b1111100) >> 2;
+            let width = match funct3 {
+                0x2 => "w",
+                0x3 => "d",
+                _ => return unknown(),
+            };
+            let name = match funct5 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return unknown(),
+            };
+            let name = format!("{}.{}{}", name, width, ordering(funct7));
+            let addr = format!("({})", x(rs1));
+            match funct5 {
+                0x02 => format_inst(&name, &[x(rd).into(), addr]),
+                _ => format_inst(&name, &[x(rd).into(), x(rs2).into(), addr]),
+            }
+        }
+        0x33 => match (funct3, funct7) {
+            (0x0, 0x00) => r_type("add"),
+            (0x0, 0x20) => {
+                if rs1 == 0 {
+                    format_inst("neg", &[x(rd).into(), x(rs2).into()])
+                } else {
+                    r_type("sub")
+                }
+            }
+            (0x1, 0x00) => r_type("sll"),
+            (0x2, 0x00) => {
+                if rs2 == 0 {
+                    format_inst("sltz", &[x(rd).into(), x(rs1).into()])
+                } else if rs1 == 0 {
+                    format_inst("sgtz", &[x(rd).into(), x(rs2).into()])
+                } else {
+                    r_type("slt")
+                }
+            }
+            (0x3, 0x00) => {
+                if rs1 == 0 {
+                    format_inst("snez", &[x(rd).into(), x(rs2).into()])
+                } else {
+                    r_type("sltu")
+                }
+            }
+            (0x4, 0x00) => r_type("xor"),
+            (0x5, 0x00) => r_type("srl"),
+            (0x5, 0x20) => r_type("sra"),
+            (0x6, 0x00) => r_type("or"),
+            (0x7, 0x00) => r_type("and"),
+            (0x0, 0x01) => r_type("mul"),
+            (0x1, 0x01) => r_type("mulh"),
+            (0x2, 0x01) => r_type("mulhsu"),
+            (0x3, 0x01) => r_type("mulhu"),
+            (0x4, 0x01) => r_type("div"),
+            (0x5, 0x01) => r_type("divu"),
+            (0x6, 0x01) => r_type("rem"),
+            (0x7, 0x01) => r_type("remu"),
+            _ => unknown(),
+        },
+        0x37 => format_inst("lui", &[x(rd).into(), u_imm]),
+        0x3b => match (funct3, funct7) {
+            (0x0, 0x00) => r_type("addw"),
+            (0x0, 0x20) => {
+                if rs1 == 0 {
+                    format_inst("negw", &[x(rd).into(), x(rs2).into()])
+                } else {
+                    r_type("subw")
+                }
+            }
+            (0x1, 0x00) => r_type("sllw"),
+            (0x5, 0x00) => r_type("srlw"),
+            (0x5, 0x20) => r_type("sraw"),
+            (0x0, 0x01) => r_type("mulw"),
+            (0x4, 0x01) => r_type("divw"),
+            (0x5, 0x01) => r_type("divuw"),
+            (0x6, 0x01) => r_type("remw"),
+            (0x7, 0x01) => r_type("remuw"),
+            _ => unknown(),
+        },
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let name = match opcode {
+                0x43 => "fmadd",
+                0x47 => "fmsub",
+                0x4b => "fnmsub",
+                _ => "fnmadd",
+            };
+            let format = match funct7 & 0b11 {
+                0b00 => "s",
+                0b01 => "d",
+                _ => return unknown(),
+            };
+            let rs3 = (funct7 >> 2) as usize;
+            format_inst(
+                &format!("{}.{}", name, format),
+                &[f(rd).into(), f(rs1).into(), f(rs2).into(), f(rs3).into()],
+            )
+        }
+        0x53 => disassemble_float(funct7, funct3, rd, rs1, rs2),
+        0x63 => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return unknown(),
+            };
+            match (funct3, rs1, rs2) {
+                (0x0, _, 0) => format_inst("beqz", &[x(rs1).into(), target(b_imm)]),
+                (0x1, _, 0) => format_inst("bnez", &[x(rs1).into(), target(b_imm)]),
+                (0x4, _, 0) => format_inst("bltz", &[x(rs1).into(), target(b_imm)]),
+                (0x5, _, 0) => format_inst("bgez", &[x(rs1).into(), target(b_imm)]),
+                (0x4, 0, _) => format_inst("bgtz", &[x(rs2).into(), target(b_imm)]),
+                (0x5, 0, _) => format_inst("blez", &[x(rs2).into(), target(b_imm)]),
+                _ => format_inst(name, &[x(rs1).into(), x(rs2).into(), target(b_imm)]),
+            }
+        }
+        0x67 => {
+            if rd == 0 && rs1 == 1 && i_imm == 0 {
+                "ret".to_string()
+            } else if rd == 0 && i_imm == 0 {
+                format_inst("jr", &[x(rs1).into()])
+            } else if rd == 1 && i_imm == 0 {
+                format_inst("jalr", &[x(rs1).into()])
+            } else {
+                format_inst("jalr", &[x(rd).into(), format!("{}({})", i_imm, x(rs1))])
+            }
+        }
+        0x6f => match rd {
+            0 => format_inst("j", &[target(j_imm)]),
+            1 => format_inst("jal", &[target(j_imm)]),
+            _ => format_inst("jal", &[x(rd).into(), target(j_imm)]),
+        },
+        0x73 => {
+            let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+            let zimm = (rs1 as u64).to_string();
+            match funct3 {
+                0x0 => match (rs2, funct7) {
+                    (0x0, 0x0) => "ecall".to_string(),
+                    (0x1, 0x0) => "ebreak".to_string(),
+                    (0x2, 0x8) => "sret".to_string(),
+                    (0x2, 0x18) => "mret".to_string(),
+                    (0x5, 0x8) => "wfi".to_string(),
+                    (_, 0x9) => format_inst("sfence.vma", &[x(rs1).into(), x(rs2).into()]),
+                    _ => unknown(),
+                },
+                0x1 if rd == 0 => format_inst("csrw", &[csr(csr_addr), x(rs1).into()]),
+                0x1 => format_inst("csrrw", &[x(rd).into(), csr(csr_addr), x(rs1).into()]),
+                0x2 if rs1 == 0 => format_inst("csrr", &[x(rd).into(), csr(csr_addr)]),
+                0x2 if rd == 0 => format_inst("csrs", &[csr(csr_addr), x(rs1).into()]),
+                0x2 => format_inst("csrrs", &[x(rd).into(), csr(csr_addr), x(rs1).into()]),
+                0x3 if rd == 0 => format_inst("csrc", &[csr(csr_addr), x(rs1).into()]),
+                0x3 => format_inst("csrrc", &[x(rd).into(), csr(csr_addr), x(rs1).into()]),
+                0x5 if rd == 0 => format_inst("csrwi", &[csr(csr_addr), zimm]),
+                0x5 => format_inst("csrrwi", &[x(rd).into(), csr(csr_addr), zimm]),
+                0x6 if rd == 0 => format_inst("csrsi", &[csr(csr_addr), zimm]),
+                0x6 => format_inst("csrrsi", &[x(rd).into(), csr(csr_addr), zimm]),
+                0x7 if rd == 0 => format_inst("csrci", &[csr(csr_addr), zimm]),
+                0x7 => format_inst("csrrci", &[x(rd).into(), csr(csr_addr), zimm]),
+                _ => unknown(),
+            }
+        }
+        _ => unknown(),
+    }
+}
+
+// Disassemble an instruction of OP-FP, the floating-point instructions other than
+// the loads, the stores and the fused multiply-adds.
+fn disassemble_float(funct7: u64, funct3: u64, rd: usize, rs1: usize, rs2: usize) -> String {
+    let unknown = || "unknown".to_string();
+    let format = match funct7 & 0b11 {
+        0b00 => "s",
+        0b01 => "d",
+        _ => return unknown(),
+    };
+    // The integer formats of the conversions
+    let integer = match rs2 {
+        0 => "w",
+        1 => "wu",
+        2 => "l",
+        3 => "lu",
+        _ => "",
+    };
+    let name = |name: &str| format!("{}.{}", name, format);
+    let r_type = |name: &str| format_inst(name, &[f(rd).into(), f(rs1).into(), f(rs2).into()]);
+    match (funct7 >> 2, funct3) {
+        (0x00, _) => r_type(&name("fadd")),
+        (0x01, _) => r_type(&name("fsub")),
+        (0x02, _) => r_type(&name("fmul")),
+        (0x03, _) => r_type(&name("fdiv")),
+        (0x0b, _) => format_inst(&name("fsqrt"), &[f(rd).into(), f(rs1).into()]),
+        // fmv, fneg and fabs are fsgnj, fsgnjn and fsgnjx of the same register.
+        (0x04, 0x0..=0x2) if rs1 == rs2 => {
+            let pseudo = ["fmv", "fneg", "fabs"][funct3 as usize];
+            format_inst(&name(pseudo), &[f(rd).into(), f(rs1).into()])
+        }
+        (0x04, 0x0..=0x2) => r_type(&name(["fsgnj", "fsgnjn", "fsgnjx"][funct3 as usize])),
+        (0x05, 0x0) => r_type(&name("fmin")),
+        (0x05, 0x1) => r_type(&name("fmax")),
+        (0x08, _) => {
+            let from = match (format, rs2) {
+                ("s", 1) => "d",
+                ("d", 0) => "s",
+                _ => return unknown(),
+            };
+            format_inst(&format!("fcvt.{}.{}", format, from), &[f(rd).into(), f(rs1).into()])
+        }
+        (0x14, 0x0..=0x2) => {
+            let compare = ["fle", "flt", "feq"][funct3 as usize];
+            format_inst(&name(compare), &[x(rd).into(), f(rs1).into(), f(rs2).into()])
+        }
+        (0x18, _) if rs2 < 4 => {
+            format_inst(&format!("fcvt.{}.{}", integer, format), &[x(rd).into(), f(rs1).into()])
+        }
+        (0x1a, _) if rs2 < 4 => {
+            format_inst(&format!("fcvt.{}.{}", format, integer), &[f(rd).into(), x(rs1).into()])
+        }
+        (0x1c, 0x0) => {
+            let width = if format == "s" { "w" } else { "d" };
+            format_inst(&format!("fmv.x.{}", width), &[x(rd).into(), f(rs1).into()])
+        }
+        (0x1c, 0x1) => format_inst(&name("fclass"), &[x(rd).into(), f(rs1).into()]),
+        (0x1e, 0x0) => {
+            let width = if format == "s" { "w" } else { "d" };
+            format_inst(&format!("fmv.{}.x", width), &[f(rd).into(), x(rs1).into()])
+        }
+        _ => unknown(),
+    }
+}
@@ -0,0 +1,239 @@
+// The elf module reads an ELF64 little-endian executable: the entry point, the
+// loadable segments and the symbol table.
+//
+// See the spec: https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+
+use std::io;
+
+use crate::bus::*;
+
+// The magic number at the start of an ELF file.
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+// e_ident[EI_CLASS] of a 64-bit object.
+const ELFCLASS64: u8 = 2;
+// e_ident[EI_DATA] of a little-endian object.
+const ELFDATA2LSB: u8 = 1;
+// e_type of an executable file.
+const ET_EXEC: u64 = 2;
+// e_machine of RISC-V.
+const EM_RISCV: u64 = 243;
+// The program header types of a loadable segment and of the interpreter of a
+// dynamically linked executable
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+// The section header type of a symbol table.
+const SHT_SYMTAB: u32 = 2;
+// Symbol types
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_FUNC: u8 = 2;
+
+// A segment to be loaded to memory.
+pub struct Segment {
+    // The physical address to load the segment
+    pub addr: u64,
+    // The bytes of the segment in the file
+    pub data: Vec<u8>,
+    // The size of the segment in memory. The bytes after `data`, e.g. bss, are
+    // filled with zero.
+    pub mem_size: u64,
+}
+
+// An entry of the symbol table.
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    // The symbol type, e.g. STT_FUNC
+    pub kind: u8,
+}
+
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+    // The address of the program headers in memory, if a segment loads them, with
+    // their size and number, for the auxiliary vector of a Linux program
+    pub program_headers: Option<u64>,
+    pub program_header_size: u64,
+    pub program_header_count: u64,
+    // True if the executable is dynamically linked, i.e. needs an interpreter
+    pub interpreter: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid ELF file: {}", message))
+}
+
+// Add an offset or a size read from the file, failing if it overflows.
+fn add(base: u64, offset: u64) -> io::Result<u64> {
+    base.checked_add(offset).ok_or_else(|| invalid("offset out of range"))
+}
+
+// Read little-endian integers from the file, failing if the file is too short.
+fn read(binary: &[u8], offset: u64, size: usize) -> io::Result<u64> {
+    let bytes = slice(binary, offset, size as u64)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+fn read16(binary: &[u8], offset: u64) -> io::Result<u64> {
+    read(binary, offset, 2)
+}
+
+fn read32(binary: &[u8], offset: u64) -> io::Result<u64> {
+    read(binary, offset, 4)
+}
+
+fn read64(binary: &[u8], offset: u64) -> io::Result<u64> {
+    read(binary, offset, 8)
+}
+
+fn slice(binary: &[u8], offset: u64, size: u64) -> io::Result<&[u8]> {
+    let end = add(offset, size)?;
+    binary
+        .get(offset as usize..end as usize)
+        .ok_or_else(|| invalid("unexpected end of file"))
+}
+
+// Return true if the binary starts with the ELF magic number.
+pub fn is_elf(binary: &[u8]) -> bool {
+    binary.starts_with(&ELF_MAGIC)
+}
+
+impl Elf {
+    pub fn parse(binary: &[u8]) -> io::Result<Elf> {
+        if !is_elf(binary) {
+            return Err(invalid("no ELF magic number"));
+        }
+
+        // ELF header
+        if binary.get(4) != Some(&ELFCLASS64) {
+            return Err(invalid("not a 64-bit ELF file"));
+        }
+        if binary.get(5) != Some(&ELFDATA2LSB) {
+            return Err(invalid("not a little-endian ELF file"));
+        }
+        let machine = read16(binary, 0x12)?;
+        if machine != EM_RISCV {
+            return Err(invalid(&format!(
+                "not a RISC-V ELF file (e_machine is {})",
+                machine
+            )));
+        }
+        if read16(binary, 0x10)? != ET_EXEC {
+            return Err(invalid("not an executable file"));
+        }
+        let entry = read64(binary, 0x18)?;
+        let phoff = read64(binary, 0x20)?;
+        let shoff = read64(binary, 0x28)?;
+        let phentsize = read16(binary, 0x36)?;
+        let phnum = read16(binary, 0x38)?;
+        let shentsize = read16(binary, 0x3a)?;
+        let shnum = read16(binary, 0x3c)?;
+
+        // Program headers
+        let mut segments = Vec::new();
+        let mut program_headers = None;
+        let mut interpreter = false;
+        for i in 0..phnum {
+            let header = add(phoff, i * phentsize)?;
+            match read32(binary, header)? as u32 {
+                PT_LOAD => {}
+                PT_INTERP => {
+                    interpreter = true;
+                    continue;
+                }
+                _ => continue,
+            }
+            let offset = read64(binary, header + 0x08)?;
+            let paddr = read64(binary, header + 0x18)?;
+            let filesz = read64(binary, header + 0x20)?;
+            let memsz = read64(binary, header + 0x28)?;
+            if filesz > memsz {
+                return Err(invalid("a segment is larger in the file than in memory"));
+            }
+            if offset <= phoff && phoff < add(offset, filesz)? {
+                program_headers = Some(add(paddr, phoff - offset)?);
+            }
+            segments.push(Segment {
+                addr: paddr,
+                data: slice(binary, offset, filesz)?.to_vec(),
+                mem_size: memsz,
+            });
+        }
+
+        // Section headers, to find the symbol table and its string table
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let header = add(shoff, i * shentsize)?;
+            if read32(binary, add(header, 0x04)?)? as u32 != SHT_SYMTAB {
+                continue;
+            }
+            let offset = read64(binary, header + 0x18)?;
+            let size = read64(binary, header + 0x20)?;
+            let link = read32(binary, header + 0x28)?;
+            let entsize = read64(binary, header + 0x38)?;
+            let strtab_header = add(shoff, link * shentsize)?;
+            let strtab = read64(binary, add(strtab_header, 0x18)?)?;
+            let strtab_size = read64(binary, strtab_header + 0x20)?;
+            let strtab = slice(binary, strtab, strtab_size)?;
+
+            for j in 0..size / entsize.max(1) {
+                let symbol = add(offset, j * entsize)?;
+                let name = read32(binary, symbol)? as usize;
+                let name = strtab
+                    .get(name..)
+                    .and_then(|s| s.split(|c| *c == 0).next())
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .unwrap_or_default();
+                if name.is_empty() {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name,
+                    value: read64(binary, symbol + 0x08)?,
+                    size: read64(binary, symbol + 0x10)?,
+                    kind: read(binary, symbol + 0x04, 1)? as u8 & 0xf,
+                });
+            }
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+            program_headers,
+            program_header_size: phentsize,
+            program_header_count: phnum,
+            interpreter,
+        })
+    }
+
+    // Copy the segments to memory at their physical addresses and fill the rest of
+    // each segment with zero.
+    pub fn load(&self, bus: &mut Bus) -> io::Result<()> {
+        for segment in self.segments.iter() {
+            for i in 0..segment.mem_size {
+                let byte = segment.data.get(i as usize).copied().unwrap_or(0);
+                if bus.store(segment.addr + i, 8, byte as u64).is_err() {
+                    return Err(invalid(&format!(
+                        "the segment at 0x{:x}-0x{:x} is not in memory",
+                        segment.addr,
+                        segment.addr + segment.mem_size
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Return the address of a symbol.
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.value)
+    }
+}
@@ -0,0 +1,1017 @@
+// The emulator module is the API to embed the emulator, e.g. in a test harness:
+//
+//   let mut emulator = Emulator::builder().binary(elf).build()?;
+//   let reason = emulator.run(1_000_000);
+//   assert_eq!(emulator.reg(10), 42);
+//
+// An Emulator runs the cpu instruction by instruction and stops for a reason
+// given as a StopReason. The command line interface in main.rs is built on it.
+
+use std::io;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use crate::boot::*;
+use crate::bus::*;
+use crate::clint::*;
+use crate::cpu::*;
+use crate::elf::*;
+use crate::fdt::*;
+use crate::htif::{self, Htif, Request};
+use crate::lockstep::*;
+use crate::memory::*;
+use crate::replay::*;
+use crate::rvc::*;
+use crate::sbi::{self, ResetType};
+use crate::semihosting::{self, Semihosting};
+use crate::snapshot;
+use crate::symbols::*;
+use crate::syscall::{self, Process};
+use crate::trace::*;
+use crate::trap::*;
+
+// Why the emulator stopped.
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    // The number of instructions given to `run` or `run_until` were executed.
+    InstructionLimit,
+    // The pc is at a breakpoint.
+    Breakpoint(u64),
+    // The condition of `run_until` became true.
+    Condition,
+    // An exception happened at the pc. The trap was taken.
+    Exception(Exception, u64),
+    // The program wrote the value to `tohost`.
+    ToHost(u64),
+    // The pc became 0, where there is no program.
+    ZeroPc,
+    // The state differs from the reference of lockstep.
+    Lockstep(Box<Mismatch>),
+    // The events of the replay do not match the run.
+    Replay(String),
+    // The kernel asked the built-in SBI to reset the system, with the reason.
+    SystemReset(ResetType, u64),
+    // The program exited with the exit code, e.g. by the semihosting SYS_EXIT, the
+    // exit_group of a Linux program or the HTIF.
+    Exit(u64),
+}
+
+// The extensions which can be given in an ISA string, in the canonical order
+pub const SUPPORTED_EXTENSIONS: &str = "imafdc";
+// The ISA of the cpu when none is given
+pub const DEFAULT_ISA: &str = "rv64imafdc";
+
+// The value of misa for an ISA string such as "rv64ia_zicsr": MXL of RV64, the
+// bits of the extensions and S and U, which are always implemented.
+pub fn parse_isa(isa: &str) -> Result<u64, String> {
+    let isa = isa.to_ascii_lowercase();
+    let mut parts = isa.split('_');
+    let base = parts.next().unwrap_or_default();
+    let letters = match base.strip_prefix("rv64") {
+        Some(letters) if letters.starts_with('i') => letters,
+        _ => return Err(format!("{}: only rv64i is supported as the base ISA", isa)),
+    };
+    let mut misa = 2 << 62 | 1 << (b's' - b'a') | 1 << (b'u' - b'a');
+    for letter in letters.chars() {
+        if !SUPPORTED_EXTENSIONS.contains(letter) {
+            return Err(format!("{}: the extension {} is not supported", isa, letter));
+        }
+        misa |= 1 << (letter as u8 - b'a');
+    }
+    // D extends the registers of F.
+    if misa & 1 << (b'd' - b'a') != 0 && misa & 1 << (b'f' - b'a') == 0 {
+        return Err(format!("{}: the extension d needs f", isa));
+    }
+    for extension in parts {
+        match extension {
+            "zicsr" | "zifencei" | "sstc" => {}
+            _ => return Err(format!("{}: the extension {} is not supported", isa, extension)),
+        }
+    }
+    Ok(misa)
+}
+
+// The device tree which the program is started with
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceTree {
+    // Generate the DTB of the machine.
+    Generate,
+    // A DTB given by the user
+    Blob(Vec<u8>),
+    // No DTB, e.g. for a bare-metal program
+    None,
+}
+
+// A device of the user: the name, the base, the size, the interrupt and the device
+type AttachedDevice = (String, u64, u64, Option<u64>, Box<dyn Device>);
+
+// Configures and builds an Emulator.
+pub struct EmulatorBuilder {
+    memory_base: u64,
+    memory_size: u64,
+    devices: DeviceMap,
+    harts: usize,
+    isa: String,
+    binary: Vec<u8>,
+    // The firmware, which starts the binary as the kernel
+    firmware: Option<Vec<u8>>,
+    // Where a flat kernel is loaded for the firmware
+    kernel_addr: Option<u64>,
+    // Start at the reset ROM, which jumps to the program
+    reset_vector: bool,
+    // Run the program as an S-mode kernel with the built-in SBI
+    sbi: bool,
+    // The root directory of the semihosting files and the command line
+    semihosting: Option<(PathBuf, String)>,
+    // Run the binary as a Linux program with the arguments and the environment
+    user: Option<(Vec<String>, Vec<String>)>,
+    // Service the HTIF of the program, with its arguments
+    htif: Option<Vec<String>>,
+    // Raw images loaded at addresses after the binary, e.g. a kernel for the
+    // firmware
+    images: Vec<(Vec<u8>, u64)>,
+    // The initrd and its address, at the end of the memory if None
+    initrd: Option<(Vec<u8>, Option<u64>)>,
+    device_tree: DeviceTree,
+    // The command line of the kernel in the DTB
+    bootargs: Option<String>,
+    // The memory regions other than the main memory with their images
+    regions: Vec<(RegionKind, u64, u64, Vec<u8>)>,
+    // The devices of the user with their names, ranges and interrupts
+    attached: Vec<AttachedDevice>,
+    disk_image: Vec<u8>,
+    // The version of the virtio-mmio interface of the disk, 1 (legacy) or 2
+    virtio_version: u32,
+    time_mode: TimeMode,
+    stop_on_exception: bool,
+    // The state to start from instead of the state after loading the program
+    restore: Option<String>,
+    tracer: Option<Tracer>,
+    // The commit log of the reference to compare every instruction with
+    lockstep: Option<Box<dyn BufRead>>,
+    // The files to record the events to and to replay them from
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+pub struct Emulator {
+    pub cpu: Cpu,
+    // The address and the size of the DTB
+    device_tree: Option<(u64, u64)>,
+    breakpoints: Vec<u64>,
+    // The address where the program writes to halt
+    tohost: Option<u64>,
+    // Stop at an exception instead of going on with the trap handler
+    stop_on_exception: bool,
+    // The number of instructions executed
+    count: u64,
+    tracer: Option<Tracer>,
+    lockstep: Option<Lockstep>,
+    recording: Option<Recording>,
+    semihosting: Option<Semihosting>,
+    // The Linux program whose system calls the emulator does
+    process: Option<Process>,
+    htif: Option<Htif>,
+}
+
+impl Default for EmulatorBuilder {
+    fn default() -> Self {
+        Self {
+            memory_base: MEMORY_BASE,
+            memory_size: MEMORY_SIZE,
+            devices: DeviceMap::default(),
+            harts: 1,
+            isa: DEFAULT_ISA.to_string(),
+            binary: Vec::new(),
+            firmware: None,
+            kernel_addr: None,
+            reset_vector: false,
+            sbi: false,
+            semihosting: None,
+            user: None,
+            htif: None,
+            images: Vec::new(),
+            initrd: None,
+            device_tree: DeviceTree::Generate,
+            bootargs: None,
+            regions: Vec::new(),
+            attached: Vec::new(),
+            disk_image: Vec::new(),
+            virtio_version: 1,
+            time_mode: TimeMode::Deterministic(DEFAULT_INSTRUCTIONS_PER_TICK),
+            stop_on_exception: true,
+            restore: None,
+            tracer: None,
+            lockstep: None,
+            record: None,
+            replay: None,
+        }
+    }
+}
+
+impl EmulatorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The address where the memory starts, MEMORY_BASE by default
+    pub fn memory_base(mut self, base: u64) -> Self {
+        self.memory_base = base;
+        self
+    }
+
+    // The size of the memory, 128MB by default.
+    pub fn memory_size(mut self, size: u64) -> Self {
+        self.memory_size = size;
+        self
+    }
+
+    // The initrd, at `addr` or at the end of the memory. Its range is given in the DTB.
+    pub fn initrd(mut self, initrd: Vec<u8>, addr: Option<u64>) -> Self {
+        self.initrd = Some((initrd, addr));
+        self
+    }
+
+    // The DTB, generated by default
+    pub fn device_tree(mut self, device_tree: DeviceTree) -> Self {
+        self.device_tree = device_tree;
+        self
+    }
+
+    // The command line of the kernel, the bootargs of /chosen in the generated DTB
+    pub fn bootargs(mut self, bootargs: &str) -> Self {
+        self.bootargs = Some(bootargs.to_string());
+        self
+    }
+
+    // Add a memory region of `size` bytes at `base` with the image at the start,
+    // e.g. a boot ROM or more RAM above 4GB.
+    pub fn region(mut self, kind: RegionKind, base: u64, size: u64, image: Vec<u8>) -> Self {
+        self.regions.push((kind, base, size, image));
+        self
+    }
+
+    // Attach a device of the user to the `size` bytes at `base`, see Bus::attach.
+    pub fn device(
+        mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        irq: Option<u64>,
+        device: Box<dyn Device>,
+    ) -> Self {
+        self.attached.push((name.to_string(), base, size, irq, device));
+        self
+    }
+
+    // Where the devices are, all of them at their default addresses by default
+    pub fn devices(mut self, devices: DeviceMap) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    // The number of harts, 1 to MAX_HARTS. The harts take turns to execute an
+    // instruction, starting at the same entry with their hart IDs in a0.
+    pub fn harts(mut self, harts: usize) -> Self {
+        self.harts = harts;
+        self
+    }
+
+    // The ISA string, e.g. "rv64ia_zicsr", which gives misa
+    pub fn isa(mut self, isa: &str) -> Self {
+        self.isa = isa.to_string();
+        self
+    }
+
+    // The program: an ELF file, which is loaded segment by segment and starts at
+    // its entry point, or a flat binary loaded at the start of the memory.
+    pub fn binary(mut self, binary: Vec<u8>) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    // The firmware, e.g. OpenSBI built with FW_DYNAMIC: an ELF file or a flat
+    // binary at the start of the memory. The binary is then the kernel, loaded at
+    // the kernel address if it is not an ELF file, and the machine boots from the
+    // reset ROM, which passes the address of the kernel in the fw_dynamic info.
+    pub fn firmware(mut self, firmware: Vec<u8>) -> Self {
+        self.firmware = Some(firmware);
+        self
+    }
+
+    // Where the kernel is loaded for the firmware, DEFAULT_KERNEL_OFFSET from the
+    // start of the memory by default
+    pub fn kernel_addr(mut self, addr: u64) -> Self {
+        self.kernel_addr = Some(addr);
+        self
+    }
+
+    // Boot from the reset ROM at ROM_BASE also without firmware, like a real board.
+    // Only a0, a1 and a2 are set, not sp.
+    pub fn reset_vector(mut self, reset_vector: bool) -> Self {
+        self.reset_vector = reset_vector;
+        self
+    }
+
+    // Run the program in S-mode and service its SBI calls in the emulator instead
+    // of firmware, see the sbi module.
+    pub fn sbi(mut self, sbi: bool) -> Self {
+        self.sbi = sbi;
+        self
+    }
+
+    // Service the semihosting calls of the program with the files in `root`, and
+    // give it the command line, e.g. "hello.elf arg", see the semihosting module.
+    pub fn semihosting(mut self, root: &Path, cmdline: &str) -> Self {
+        self.semihosting = Some((root.to_path_buf(), cmdline.to_string()));
+        self
+    }
+
+    // Run the binary, a static riscv64 Linux executable, as a process in U-mode
+    // with the arguments, whose first is the program, and the environment, e.g.
+    // "HOME=/root", see the syscall module. The process has its own memory and no
+    // devices.
+    pub fn user(mut self, argv: &[String], envp: &[String]) -> Self {
+        self.user = Some((argv.to_vec(), envp.to_vec()));
+        self
+    }
+
+    // Service the HTIF of the program, a program for Spike with the `tohost` and
+    // `fromhost` symbols, e.g. riscv-pk, and give it the arguments, whose first is
+    // the program, see the htif module.
+    pub fn htif(mut self, argv: &[String]) -> Self {
+        self.htif = Some(argv.to_vec());
+        self
+    }
+
+    // A raw image loaded at `addr`
+    pub fn image(mut self, image: Vec<u8>, addr: u64) -> Self {
+        self.images.push((image, addr));
+        self
+    }
+
+    // The disk of the virtio block device
+    pub fn disk_image(mut self, disk_image: Vec<u8>) -> Self {
+        self.disk_image = disk_image;
+        self
+    }
+
+    // The version of the virtio-mmio interface of the disk: 1, the legacy one and
+    // the default, or 2, e.g. for xv6-riscv since 2022.
+    pub fn virtio_version(mut self, version: u32) -> Self {
+        self.virtio_version = version;
+        self
+    }
+
+    pub fn time_mode(mut self, mode: TimeMode) -> Self {
+        self.time_mode = mode;
+        self
+    }
+
+    // Stop at exceptions (the default), or take the trap and go on, e.g. for tests
+    // which handle traps.
+    pub fn stop_on_exception(mut self, stop: bool) -> Self {
+        self.stop_on_exception = stop;
+        self
+    }
+
+    // Start from the state saved in a snapshot, after loading the program, which
+    // then only gives the symbols.
+    pub fn restore(mut self, path: &str) -> Self {
+        self.restore = Some(path.to_string());
+        self
+    }
+
+    // Write an instruction trace.
+    pub fn tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    // Compare every retired instruction with the commit log of a reference.
+    pub fn lockstep(mut self, reference: Box<dyn BufRead>) -> Self {
+        self.lockstep = Some(reference);
+        self
+    }
+
+    // Record the nondeterministic events to a file.
+    pub fn record(mut self, path: &str) -> Self {
+        self.record = Some(path.to_string());
+        self
+    }
+
+    // Replay the nondeterministic events recorded in a file.
+    pub fn replay(mut self, path: &str) -> Self {
+        self.replay = Some(path.to_string());
+        self
+    }
+
+    pub fn build(mut self) -> io::Result<Emulator> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        // A Linux program has the memory of a process and no devices.
+        if self.user.is_some() {
+            if !is_elf(&self.binary) {
+                return Err(invalid("a Linux program must be an ELF executable".to_string()));
+            }
+            if self.harts != 1 || self.sbi || self.firmware.is_some() || self.reset_vector {
+                return Err(invalid(
+                    "a Linux program runs on 1 hart without firmware nor the SBI".to_string(),
+                ));
+            }
+            self.memory_base = syscall::USER_MEMORY_BASE;
+            self.memory_size = syscall::USER_MEMORY_SIZE;
+            self.devices = DeviceMap::none();
+            self.device_tree = DeviceTree::None;
+        }
+        if self.memory_size == 0 || !self.memory_size.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(invalid(format!(
+                "the memory size must be a multiple of {} bytes",
+                PAGE_SIZE
+            )));
+        }
+        if !self.memory_base.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(invalid(format!(
+                "the memory base must be a multiple of {} bytes",
+                PAGE_SIZE
+            )));
+        }
+        if self.harts == 0 || self.harts > MAX_HARTS {
+            return Err(invalid(format!(
+                "{} harts are not supported, only 1 to {}",
+                self.harts, MAX_HARTS
+            )));
+        }
+        if self.sbi && self.harts != 1 {
+            return Err(invalid("the built-in SBI supports only 1 hart".to_string()));
+        }
+        if self.virtio_version != 1 && self.virtio_version != 2 {
+            return Err(invalid(format!(
+                "the virtio version {} is not supported, only 1 or 2",
+                self.virtio_version
+            )));
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err(invalid("the events cannot be recorded and replayed together".to_string()));
+        }
+        // The reference has one hart.
+        if self.lockstep.is_some() && self.harts != 1 {
+            return Err(invalid("lockstep supports only 1 hart".to_string()));
+        }
+        let misa = parse_isa(&self.isa).map_err(invalid)?;
+        let semihosting = match &self.semihosting {
+            Some((root, cmdline)) => Some(Semihosting::new(root, cmdline)?),
+            None => None,
+        };
+        if self.sbi && (self.firmware.is_some() || self.reset_vector) {
+            return Err(invalid(
+                "the built-in SBI runs the kernel without firmware nor the reset ROM".to_string(),
+            ));
+        }
+
+        // The program at the start of the memory is the firmware if any, and then
+        // the binary is the kernel it starts.
+        let reset_vector = self.reset_vector || self.firmware.is_some();
+        let (program, kernel) = match self.firmware {
+            Some(firmware) => (firmware, Some(self.binary)),
+            None => (self.binary, None),
+        };
+        // A Linux Image is loaded at the offset its header gives.
+        let image_offset = linux_image_offset(&program);
+        let binary = if is_elf(&program) || image_offset.is_some() {
+            Vec::new()
+        } else {
+            program.clone()
+        };
+        if binary.len() as u64 > self.memory_size {
+            return Err(invalid("the binary is larger than the memory".to_string()));
+        }
+        let mut bus = Bus::with_layout(
+            binary,
+            self.disk_image,
+            self.memory_base,
+            self.memory_size,
+            self.devices,
+        )?;
+        bus.virtio.set_version(self.virtio_version);
+        if reset_vector {
+            // The contents are written when the address of the DTB is known.
+            bus.add_region(RegionKind::Rom, ROM_BASE, ROM_SIZE, &[])?;
+        }
+        for (kind, base, size, image) in self.regions.iter() {
+            bus.add_region(*kind, *base, *size, image)?;
+        }
+        for (name, base, size, irq, device) in self.attached {
+            bus.attach(&name, base, size, irq, device)?;
+        }
+        let mut cpu = Cpu::with_bus(bus);
+        // tohost and fromhost of the HTIF, in the ELF file which gives the symbols
+        let mut htif_symbols = None;
+        if is_elf(&program) {
+            let elf = Elf::parse(&program)?;
+            elf.load(&mut cpu.bus)?;
+            cpu.pc = elf.entry;
+            cpu.symbols = Symbols::new(&elf);
+            htif_symbols = htif::locate(&elf);
+        }
+        if let Some(offset) = image_offset {
+            cpu.pc = self.memory_base + offset;
+            cpu.bus.load_image(cpu.pc, &program)?;
+        }
+        let start = cpu.pc;
+        // The kernel for the firmware, whose symbols are the ones of interest
+        let mut next_addr = self
+            .kernel_addr
+            .unwrap_or(self.memory_base + DEFAULT_KERNEL_OFFSET);
+        match kernel {
+            Some(kernel) if is_elf(&kernel) => {
+                let elf = Elf::parse(&kernel)?;
+                elf.load(&mut cpu.bus)?;
+                next_addr = elf.entry;
+                cpu.symbols = Symbols::new(&elf);
+                htif_symbols = htif::locate(&elf);
+            }
+            Some(kernel) => cpu.bus.load_image(next_addr, &kernel)?,
+            None => {}
+        }
+        for (image, addr) in self.images.iter() {
+            cpu.bus.load_image(*addr, image)?;
+        }
+        let htif = match (&self.htif, htif_symbols) {
+            (Some(argv), Some((tohost, fromhost))) => Some(Htif::new(tohost, fromhost, argv)?),
+            (Some(_), None) => {
+                return Err(invalid("HTIF needs the tohost symbol of an ELF file".to_string()))
+            }
+            (None, _) => None,
+        };
+
+        // The initrd and the DTB are at the end of the memory unless given an
+        // address, and the stack is below them.
+        let mut top = self.memory_base + self.memory_size;
+        let mut initrd_range = None;
+        if let Some((initrd, addr)) = &self.initrd {
+            let size = initrd.len() as u64;
+            let addr = match addr {
+                Some(addr) => *addr,
+                None => {
+                    top = top.saturating_sub(size) & !(PAGE_SIZE as u64 - 1);
+                    top
+                }
+            };
+            cpu.bus.load_image(addr, initrd)?;
+            initrd_range = Some((addr, addr + size));
+        }
+        let dtb = match self.device_tree {
+            DeviceTree::Generate => {
+                let info = BootInfo {
+                    harts: self.harts,
+                    isa: self.isa.to_ascii_lowercase(),
+                    bootargs: self.bootargs,
+                    initrd: initrd_range,
+                    htif: htif.is_some(),
+                };
+                Some(generate(&cpu.bus, &info))
+            }
+            DeviceTree::Blob(dtb) if is_dtb(&dtb) => Some(dtb),
+            DeviceTree::Blob(_) => return Err(invalid("the device tree is not a DTB".to_string())),
+            DeviceTree::None => None,
+        };
+        let mut device_tree = None;
+        if let Some(dtb) = dtb {
+            top = top.saturating_sub(dtb.len() as u64) & !(PAGE_SIZE as u64 - 1);
+            cpu.bus.load_image(top, &dtb)?;
+            device_tree = Some((top, dtb.len() as u64));
+        }
+        if reset_vector {
+            let info = FwDynamicInfo {
+                next_addr,
+                next_mode: Mode::Surpervisor,
+                boot_hart: cpu.csrs[MHARTID],
+            };
+            let dtb = device_tree.map_or(0, |(addr, _)| addr);
+            cpu.bus.load_image(ROM_BASE, &reset_rom(start, dtb, &info))?;
+            cpu.pc = ROM_BASE;
+            cpu.regs[2] = 0;
+        } else {
+            // Start as if the reset code ran: a0 is the hart ID and a1 is the
+            // address of the DTB.
+            if let Some((addr, _)) = device_tree {
+                cpu.regs[10] = cpu.csrs[MHARTID];
+                cpu.regs[11] = addr;
+            }
+            cpu.regs[2] = top;
+        }
+        cpu.csrs[MISA] = misa;
+        if self.sbi {
+            sbi::init(&mut cpu);
+        }
+        let process = match self.user {
+            Some((argv, envp)) => {
+                // The program reads the console by read(0), not by the UART.
+                cpu.bus.uart.set_deferred_input(true);
+                Some(Process::new(&mut cpu, &Elf::parse(&program)?, &argv, &envp)?)
+            }
+            None => None,
+        };
+        // The other harts start where hart 0 does. The reset ROM reads the hart ID.
+        for _ in 1..self.harts {
+            let hart = cpu.add_hart();
+            if !reset_vector && device_tree.is_some() {
+                hart.regs[10] = hart.csrs[MHARTID];
+            }
+        }
+        let mut emulator = Emulator {
+            cpu,
+            device_tree,
+            breakpoints: Vec::new(),
+            tohost: None,
+            stop_on_exception: self.stop_on_exception,
+            count: 0,
+            tracer: None,
+            lockstep: None,
+            recording: None,
+            semihosting,
+            process,
+            htif,
+        };
+        emulator.cpu.bus.clint.set_time_mode(self.time_mode);
+
+        // The errors of the files name them.
+        let with_path = |path: &str, e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+        if let Some(path) = &self.restore {
+            snapshot::restore(&mut emulator, path).map_err(|e| with_path(path, e))?;
+        }
+        emulator.tracer = self.tracer;
+        if let Some(reference) = self.lockstep {
+            emulator.lockstep = Some(Lockstep::new(reference, &emulator.cpu));
+        }
+        if let Some(path) = &self.record {
+            emulator.recording = Some(Recording::record(&emulator.cpu, path).map_err(|e| with_path(path, e))?);
+        }
+        if let Some(path) = &self.replay {
+            emulator.recording = Some(Recording::replay(&emulator.cpu, path).map_err(|e| with_path(path, e))?);
+        }
+        Ok(emulator)
+    }
+}
+
+impl Emulator {
+    pub fn builder() -> EmulatorBuilder {
+        EmulatorBuilder::new()
+    }
+
+    // Write an instruction trace.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Compare every retired instruction with a reference.
+    pub fn set_lockstep(&mut self, lockstep: Lockstep) {
+        self.lockstep = Some(lockstep);
+    }
+
+    // Record or replay the nondeterministic events.
+    pub fn set_recording(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+
+    // Take the tracer out, e.g. while the history of the debugger executes again
+    // instructions which were traced the first time.
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn take_lockstep(&mut self) -> Option<Lockstep> {
+        self.lockstep.take()
+    }
+
+    pub fn recording_mut(&mut self) -> Option<&mut Recording> {
+        self.recording.as_mut()
+    }
+
+    // Stop when the program writes a value other than 0 to the 64-bit `tohost`,
+    // as riscv-tests do.
+    pub fn set_tohost(&mut self, tohost: u64) -> io::Result<()> {
+        if self.cpu.bus.load(tohost, 64).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tohost 0x{:x} is not in memory", tohost),
+            ));
+        }
+        self.tohost = Some(tohost);
+        Ok(())
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.retain(|bp| *bp != addr);
+    }
+
+    // The number of instructions executed
+    pub fn instruction_count(&self) -> u64 {
+        self.count
+    }
+
+    // Set the number of instructions executed, when the state of an earlier
+    // instruction is restored.
+    pub fn set_instruction_count(&mut self, count: u64) {
+        self.count = count;
+    }
+
+    // The pc of the current hart, which executes the next instruction. The
+    // registers are the ones of the current hart too.
+    pub fn pc(&self) -> u64 {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.cpu.pc = pc;
+    }
+
+    // An integer register, x0 to x31
+    pub fn reg(&self, reg: usize) -> u64 {
+        match reg {
+            0 => 0,
+            _ => self.cpu.regs[reg],
+        }
+    }
+
+    pub fn set_reg(&mut self, reg: usize, value: u64) {
+        if reg != 0 {
+            self.cpu.regs[reg] = value;
+        }
+    }
+
+    // A floating-point register, f0 to f31, as its 64 bits
+    pub fn freg(&self, reg: usize) -> u64 {
+        self.cpu.fregs[reg]
+    }
+
+    pub fn set_freg(&mut self, reg: usize, value: u64) {
+        self.cpu.fregs[reg] = value;
+    }
+
+    // A CSR as the guest reads it, e.g. sstatus as a view of mstatus
+    pub fn csr(&self, addr: usize) -> u64 {
+        self.cpu.read_csr(addr)
+    }
+
+    // Write a CSR as the guest does, keeping the fields which cannot be written.
+    pub fn set_csr(&mut self, addr: usize, value: u64) {
+        self.cpu.write_csr(addr, value);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.cpu.mode
+    }
+
+    // The address and the size of the DTB in memory
+    pub fn device_tree(&self) -> Option<(u64, u64)> {
+        self.device_tree
+    }
+
+    // The Linux program run by `user`, or the host files of the HTIF syscall proxy,
+    // e.g. to redirect its stdout
+    pub fn process_mut(&mut self) -> Option<&mut Process> {
+        match self.htif.as_mut() {
+            Some(htif) => Some(htif.process_mut()),
+            None => self.process.as_mut(),
+        }
+    }
+
+    // True if the program calls the host by semihosting, Linux system calls or the
+    // HTIF. The effects of the calls on the host cannot be undone.
+    pub fn calls_host(&self) -> bool {
+        self.semihosting.is_some() || self.process.is_some() || self.htif.is_some()
+    }
+
+    // The address of a symbol of the loaded ELF file
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.cpu.symbols.address(name)
+    }
+
+    // Load `size` bits (8, 16, 32 or 64) from the bus.
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        self.cpu.bus.load(addr, size)
+    }
+
+    // Store `size` bits (8, 16, 32 or 64) to the bus.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        self.cpu.bus.store(addr, size, value)
+    }
+
+    // Read and write the bytes from `addr`, wrapping around at the end of the
+    // address space like the accesses of the cpu.
+    pub fn read_memory(&mut self, addr: u64, length: u64) -> Result<Vec<u8>, Exception> {
+        (0..length)
+            .map(|i| self.cpu.bus.load(addr.wrapping_add(i), 8).map(|byte| byte as u8))
+            .collect()
+    }
+
+    pub fn write_memory(&mut self, addr: u64, bytes: &[u8]) -> Result<(), Exception> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.cpu.bus.store(addr.wrapping_add(i as u64), 8, *byte as u64)?;
+        }
+        Ok(())
+    }
+
+    // Execute an instruction and take a trap or an interrupt. Return the reason
+    // to stop, or None if the emulator can go on.
+    pub fn step(&mut self) -> Option<StopReason> {
+        let pc = self.cpu.pc;
+
+        if let Some(recording) = self.recording.as_mut() {
+            if let Err(e) = recording.poll(&mut self.cpu, self.count) {
+                return Some(StopReason::Replay(e.to_string()));
+            }
+        }
+        // In real-time mode mtime follows the host clock, which is read every
+        // HOST_TIME_INTERVAL instructions and recorded.
+        if self.cpu.bus.clint.time_mode() == TimeMode::RealTime
+            && self.count.is_multiple_of(HOST_TIME_INTERVAL)
+        {
+            let clint = &self.cpu.bus.clint;
+            let now = match self.recording.as_mut() {
+                Some(recording) => recording.time(self.count, || clint.host_time()),
+                None => Ok(clint.host_time()),
+            };
+            match now {
+                Ok(now) => self.cpu.bus.clint.set_mtime(now),
+                Err(e) => return Some(StopReason::Replay(e.to_string())),
+            }
+        }
+        self.count += 1;
+
+        // fetch
+        let inst = match self.cpu.fetch() {
+            Ok(inst) => inst,
+            Err(exception) => return self.exception(exception, pc),
+        };
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.disassemble(&self.cpu, inst);
+        }
+        let commit = if self.tracer.is_some() || self.lockstep.is_some() {
+            Some(Commit::begin(&self.cpu, inst))
+        } else {
+            None
+        };
+
+        // Advance the programm counter by the length of the instruction, 2 bytes
+        // for a compressed instruction or 4 bytes.
+        self.cpu.pc += inst_len(inst);
+
+        // decode & execute
+        match self.cpu.execute(inst) {
+            Ok(_) => {
+                if let Some(commit) = commit {
+                    let record = commit.finish(&mut self.cpu);
+                    if let Some(tracer) = self.tracer.as_mut() {
+                        tracer.retire(&record);
+                    }
+                    if let Some(lockstep) = self.lockstep.as_mut() {
+                        if let Err(mismatch) = lockstep.check(&self.cpu, &record) {
+                            return Some(StopReason::Lockstep(mismatch));
+                        }
+                    }
+                }
+            }
+            // The built-in SBI services the call instead of firmware, and the ecall
+            // retires.
+            Err(Exception::EnvironmentCallFromSMode) if self.cpu.sbi => {
+                let call = sbi::call(&mut self.cpu);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.sbi_call(self.cpu.hartid(), &call);
+                }
+                if let Some((reset_type, reason)) = call.reset {
+                    return Some(StopReason::SystemReset(reset_type, reason));
+                }
+            }
+            // A semihosting call is serviced by the emulator, and the ebreak retires
+            // unless the call waits for input.
+            Err(Exception::Breakpoint)
+                if self.semihosting.is_some() && semihosting::is_call(&mut self.cpu, inst, pc) =>
+            {
+                let call = self.semihosting.as_mut().unwrap().call(&mut self.cpu);
+                if call.wait {
+                    self.cpu.pc = pc;
+                } else if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.semihosting_call(self.cpu.hartid(), &call);
+                }
+                if let Some(code) = call.exit {
+                    return Some(StopReason::Exit(code));
+                }
+            }
+            // The system call of a Linux program is done by the emulator, and the
+            // ecall retires.
+            Err(Exception::EnvironmentCallFromUMode) if self.process.is_some() => {
+                let call = self.process.as_mut().unwrap().call(&mut self.cpu);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.syscall(self.cpu.hartid(), &call);
+                }
+                if let Some(code) = call.exit {
+                    return Some(StopReason::Exit(code));
+                }
+            }
+            Err(exception) => {
+                if let Some(reason) = self.exception(exception, pc) {
+                    return Some(reason);
+                }
+            }
+        }
+
+        if let Some(interrupt) = self.cpu.check_pending_interrupt() {
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.interrupt(self.cpu.hartid(), &interrupt, self.cpu.pc);
+            }
+            interrupt.take_trap(&mut self.cpu);
+        }
+
+        // The HTIF takes the command in tohost before it is checked.
+        if let Some(htif) = self.htif.as_mut() {
+            match htif.poll(&mut self.cpu) {
+                Some(Request::Exit(code)) => return Some(StopReason::Exit(code)),
+                Some(Request::Syscall(call)) => {
+                    if let Some(tracer) = self.tracer.as_mut() {
+                        tracer.syscall(self.cpu.hartid(), &call);
+                    }
+                    if let Some(code) = call.exit {
+                        return Some(StopReason::Exit(code));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(tohost) = self.tohost {
+            match self.cpu.bus.load(tohost, 64) {
+                Ok(0) => {}
+                Ok(value) => return Some(StopReason::ToHost(value)),
+                Err(exception) => return Some(StopReason::Exception(exception, tohost)),
+            }
+        }
+
+        // not to infine loop
+        if self.cpu.pc == 0 {
+            return Some(StopReason::ZeroPc);
+        }
+
+        // The harts take turns, an instruction each.
+        if self.cpu.hart_count() > 1 {
+            let next = (self.cpu.hartid() + 1) % self.cpu.hart_count();
+            self.cpu.switch_hart(next);
+        }
+        None
+    }
+
+    // Take the trap of an exception at `pc`.
+    fn exception(&mut self, exception: Exception, pc: u64) -> Option<StopReason> {
+        // The trap returns to the instruction which raised the exception.
+        self.cpu.pc = pc;
+        exception.take_trap(&mut self.cpu);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.exception(&self.cpu, &exception, pc);
+        }
+        if self.stop_on_exception {
+            return Some(StopReason::Exception(exception, pc));
+        }
+        None
+    }
+
+    // Execute at most `limit` instructions.
+    pub fn run(&mut self, limit: u64) -> StopReason {
+        self.run_until(limit, |_| false)
+    }
+
+    // Execute at most `limit` instructions until a breakpoint or `condition`
+    // becomes true. Breakpoints and the condition are not checked before the
+    // first instruction, so that a run resumes from where it stopped.
+    pub fn run_until(&mut self, limit: u64, mut condition: impl FnMut(&Emulator) -> bool) -> StopReason {
+        for i in 0..limit {
+            if i != 0 {
+                if self.breakpoints.contains(&self.cpu.pc) {
+                    return StopReason::Breakpoint(self.cpu.pc);
+                }
+                if condition(self) {
+                    return StopReason::Condition;
+                }
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::InstructionLimit
+    }
+
+    // Finish the run: flush the record of the events, or check that all the
+    // events were replayed.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.recording.as_mut() {
+            Some(recording) => recording.finish(),
+            None => Ok(()),
+        }
+    }
+}
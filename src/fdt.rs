@@ -0,0 +1,314 @@
+// The fdt module generates the flattened device tree (DTB) of the machine, which
+// tells a kernel where the memory and the devices are. The format is described in
+// the Devicetree Specification, chapter 5:
+//
+// - the header
+// - the memory reservation block, empty
+// - the structure block: the nodes with their properties, as tokens
+// - the strings block: the names of the properties
+//
+// All integers are big-endian. A kernel is started with a0 = the hart ID and
+// a1 = the address of the DTB.
+
+use std::collections::HashMap;
+
+use crate::bus::*;
+use crate::clint::*;
+use crate::uart::*;
+use crate::virtio::*;
+
+// The magic number at the start of a DTB
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+// The version of the format, and the oldest version it is compatible with
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+// The size of the header
+const FDT_HEADER_SIZE: usize = 40;
+
+// The tokens of the structure block
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// The phandles which nodes refer to. The interrupt controller of hart i is
+// CPU_INTC_PHANDLE + i.
+const PLIC_PHANDLE: u32 = 1;
+const CPU_INTC_PHANDLE: u32 = 2;
+
+// The number of interrupt sources of the PLIC, as on the QEMU virt machine
+const PLIC_NDEV: u32 = 53;
+// The frequency of the clock of the UART, as on the QEMU virt machine
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// The interrupts of the cpu, which the CLINT and the PLIC raise
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+// Writes a DTB node by node.
+#[derive(Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // The offsets of the names in the strings block
+    offsets: HashMap<String, u32>,
+}
+
+// Pad to a multiple of 4 bytes.
+fn align(data: &mut Vec<u8>) {
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+}
+
+// The cells of a 64-bit address or size
+fn cells(value: u64) -> [u32; 2] {
+    [(value >> 32) as u32, value as u32]
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        align(&mut self.structure);
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let offset = *self.offsets.entry(name.to_string()).or_insert_with(|| {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        });
+        self.token(FDT_PROP);
+        self.structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure.extend_from_slice(&offset.to_be_bytes());
+        self.structure.extend_from_slice(value);
+        align(&mut self.structure);
+    }
+
+    // A property without a value, e.g. interrupt-controller
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property_cells(name, &cells(value));
+    }
+
+    pub fn property_cells(&mut self, name: &str, values: &[u32]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    // A list of strings, e.g. compatible
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    // The reg property of a range with 2 address cells and 2 size cells
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        let [base_high, base_low] = cells(base);
+        let [size_high, size_low] = cells(size);
+        self.property_cells("reg", &[base_high, base_low, size_high, size_low]);
+    }
+
+    // Finish the DTB.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // The memory reservation block is only the terminating entry.
+        let reservations = FDT_HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut dtb: Vec<u8> = header.iter().flat_map(|value| value.to_be_bytes()).collect();
+        dtb.extend_from_slice(&[0; 16]);
+        dtb.extend_from_slice(&self.structure);
+        dtb.extend_from_slice(&self.strings);
+        dtb
+    }
+}
+
+// What the generated DTB tells a kernel besides the machine
+#[derive(Debug, Clone, Default)]
+pub struct BootInfo {
+    // The number of harts
+    pub harts: usize,
+    // The ISA string of the harts
+    pub isa: String,
+    // The command line of the kernel
+    pub bootargs: Option<String>,
+    // The start and the end of the initrd
+    pub initrd: Option<(u64, u64)>,
+    // True if the program talks to the HTIF, e.g. a proxy kernel
+    pub htif: bool,
+}
+
+// Generate the DTB of the machine of `bus`, with nodes like the QEMU virt machine.
+pub fn generate(bus: &Bus, info: &BootInfo) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscv-virtio,riscvemu");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &info.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some(uart) = bus.devices.uart {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart));
+    }
+    if let Some((start, end)) = info.initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    // The node of Spike, by which a proxy kernel finds the HTIF
+    if info.htif {
+        fdt.begin_node("htif");
+        fdt.property_string("compatible", "ucb,htif0");
+        fdt.end_node();
+    }
+
+    // The main memory and the RAM regions
+    let mut memories = vec![(bus.memory.base(), bus.memory.size())];
+    for region in bus.regions.iter().filter(|region| region.kind == RegionKind::Ram) {
+        memories.push((region.memory.base(), region.memory.size()));
+    }
+    for (base, size) in memories {
+        fdt.begin_node(&format!("memory@{:x}", base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg(base, size);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+    for hart in 0..info.harts as u32 {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &info.isa);
+        fdt.property_string("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE + hart);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+    // The interrupts of the controllers go to the interrupt controller of each hart.
+    let interrupts = |irqs: &[u32]| -> Vec<u32> {
+        (0..info.harts as u32)
+            .flat_map(|hart| irqs.iter().flat_map(move |irq| [CPU_INTC_PHANDLE + hart, *irq]))
+            .collect()
+    };
+    if let Some(clint) = bus.devices.clint {
+        fdt.begin_node(&format!("clint@{:x}", clint));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(clint, CLINT_SIZE);
+        fdt.property_cells("interrupts-extended", &interrupts(&[IRQ_M_SOFT, IRQ_M_TIMER]));
+        fdt.end_node();
+    }
+    if let Some(plic) = bus.devices.plic {
+        fdt.begin_node(&format!("plic@{:x}", plic));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(plic, PLIC_SIZE);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_NDEV);
+        fdt.property_cells("interrupts-extended", &interrupts(&[IRQ_M_EXT, IRQ_S_EXT]));
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+        fdt.end_node();
+    }
+    if let Some(uart) = bus.devices.uart {
+        fdt.begin_node(&format!("serial@{:x}", uart));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(uart, UART_SIZE);
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        if bus.devices.plic.is_some() {
+            fdt.property_u32("interrupts", UART_IRQ as u32);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        }
+        fdt.end_node();
+    }
+    // The disk is only described if there is one, so that a kernel does not probe
+    // an empty disk.
+    if let (Some(virtio), false) = (bus.devices.virtio, bus.virtio.disk().is_empty()) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", virtio));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(virtio, VIRTIO_SIZE);
+        if bus.devices.plic.is_some() {
+            fdt.property_u32("interrupts", VIRTIO_IRQ as u32);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+// Return true if `dtb` starts with the magic number of a DTB.
+pub fn is_dtb(dtb: &[u8]) -> bool {
+    dtb.len() >= FDT_HEADER_SIZE && dtb[..4] == FDT_MAGIC.to_be_bytes()
+}
@@ -0,0 +1,597 @@
+// The fpu module does the floating-point arithmetic of the F and D extensions in
+// software, with integers only, so that the results and the exception flags are
+// the ones of the IEEE 754 standard as the ISA manual specifies them, the same on
+// every host. The values are the raw bits of a single-precision or a
+// double-precision number; the NaN-boxing of single-precision values in the
+// 64-bit registers is done by the cpu.
+//
+// An operation rounds its exact result once: the significand is computed with
+// enough extra bits and a sticky bit, which is set if any bit below them is not 0,
+// and round_pack rounds it to the format. Tininess is detected after rounding,
+// as RISC-V does.
+
+use std::cmp::Ordering;
+
+// The exception flags of fflags
+// Inexact
+pub const FLAG_NX: u64 = 1;
+// Underflow
+pub const FLAG_UF: u64 = 1 << 1;
+// Overflow
+pub const FLAG_OF: u64 = 1 << 2;
+// Divide by zero
+pub const FLAG_DZ: u64 = 1 << 3;
+// Invalid operation
+pub const FLAG_NV: u64 = 1 << 4;
+
+// The rounding modes, encoded as in the rm field of an instruction and in frm
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Rounding {
+    // Round to nearest, ties to even
+    NearestEven = 0,
+    // Round towards zero
+    TowardZero = 1,
+    // Round down, towards -infinity
+    Down = 2,
+    // Round up, towards +infinity
+    Up = 3,
+    // Round to nearest, ties to max magnitude
+    NearestMaxMagnitude = 4,
+}
+
+impl Rounding {
+    // The rounding mode of an encoding, None if it is reserved.
+    pub fn from_bits(rm: u64) -> Option<Self> {
+        match rm {
+            0 => Some(Rounding::NearestEven),
+            1 => Some(Rounding::TowardZero),
+            2 => Some(Rounding::Down),
+            3 => Some(Rounding::Up),
+            4 => Some(Rounding::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+// The floating-point formats
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Format {
+    // binary32 of the F extension
+    Single,
+    // binary64 of the D extension
+    Double,
+}
+
+impl Format {
+    fn exponent_bits(self) -> u32 {
+        match self {
+            Format::Single => 8,
+            Format::Double => 11,
+        }
+    }
+
+    fn fraction_bits(self) -> u32 {
+        match self {
+            Format::Single => 23,
+            Format::Double => 52,
+        }
+    }
+
+    fn bias(self) -> i32 {
+        (1 << (self.exponent_bits() - 1)) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exponent_bits() + self.fraction_bits())
+    }
+
+    // The biased exponent of infinities and NaNs
+    fn max_exponent(self) -> u64 {
+        (1 << self.exponent_bits()) - 1
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.zero(sign) | self.max_exponent() << self.fraction_bits()
+    }
+
+    // The largest finite number
+    fn max_finite(self, sign: bool) -> u64 {
+        self.infinity(sign) - 1
+    }
+
+    // The NaN which the operations return, since the payloads of NaNs are not
+    // propagated.
+    pub fn canonical_nan(self) -> u64 {
+        self.max_exponent() << self.fraction_bits() | 1 << (self.fraction_bits() - 1)
+    }
+
+    // Split a number into its sign and its value.
+    fn unpack(self, bits: u64) -> (bool, Value) {
+        let fraction_bits = self.fraction_bits();
+        let sign = bits & self.sign_bit() != 0;
+        let exponent = (bits >> fraction_bits) & self.max_exponent();
+        let fraction = bits & ((1 << fraction_bits) - 1);
+        let value = if exponent == self.max_exponent() {
+            if fraction == 0 {
+                Value::Infinity
+            } else {
+                // A NaN is quiet if the top bit of the fraction is set.
+                Value::Nan(fraction >> (fraction_bits - 1) == 0)
+            }
+        } else if exponent == 0 {
+            if fraction == 0 {
+                Value::Zero
+            } else {
+                // A subnormal number has the exponent of the smallest normal one.
+                Value::Finite(fraction as u128, 1 - self.bias() - fraction_bits as i32)
+            }
+        } else {
+            let significand = fraction | 1 << fraction_bits;
+            Value::Finite(significand as u128, exponent as i32 - self.bias() - fraction_bits as i32)
+        };
+        (sign, value)
+    }
+
+    // The class of a number as the fclass instructions return it: a mask with one
+    // bit set.
+    pub fn classify(self, bits: u64) -> u64 {
+        let (sign, value) = self.unpack(bits);
+        let normal = bits & (self.max_exponent() << self.fraction_bits()) != 0;
+        let bit = match (value, sign) {
+            (Value::Infinity, true) => 0,
+            (Value::Finite(..), true) if normal => 1,
+            (Value::Finite(..), true) => 2,
+            (Value::Zero, true) => 3,
+            (Value::Zero, false) => 4,
+            (Value::Finite(..), false) if !normal => 5,
+            (Value::Finite(..), false) => 6,
+            (Value::Infinity, false) => 7,
+            (Value::Nan(true), _) => 8,
+            (Value::Nan(false), _) => 9,
+        };
+        1 << bit
+    }
+
+    // The order of the numbers other than NaNs, in which -0 is less than +0 if
+    // `signed_zeros`, otherwise equal to it.
+    fn key(self, bits: u64, signed_zeros: bool) -> i64 {
+        let magnitude = (bits & !self.sign_bit()) as i64;
+        match (bits & self.sign_bit() != 0, signed_zeros) {
+            (false, _) => magnitude,
+            (true, false) => -magnitude,
+            (true, true) => -magnitude - 1,
+        }
+    }
+}
+
+// The value of a number
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Value {
+    Zero,
+    // A finite number other than 0: significand * 2^exponent
+    Finite(u128, i32),
+    Infinity,
+    // A NaN, true if it is signaling
+    Nan(bool),
+}
+
+// The number of bits of a significand
+fn bit_length(significand: u128) -> i32 {
+    128 - significand.leading_zeros() as i32
+}
+
+// Shift a significand right by `shift` bits. The lowest bit of the result is set
+// if any bit shifted out is not 0.
+fn shift_right_jam(significand: u128, shift: i32) -> u128 {
+    if shift <= 0 {
+        significand
+    } else if shift >= 128 {
+        (significand != 0) as u128
+    } else {
+        significand >> shift | (significand & ((1 << shift) - 1) != 0) as u128
+    }
+}
+
+// The integer square root, rounded down
+fn isqrt(value: u128) -> u128 {
+    let mut remainder = value;
+    let mut root = 0;
+    let mut bit = 1 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+// An operation in a rounding mode, which accrues the exception flags that it
+// raises in `flags`.
+pub struct Fpu {
+    pub rounding: Rounding,
+    pub flags: u64,
+}
+
+impl Fpu {
+    pub fn new(rounding: Rounding) -> Self {
+        Self { rounding, flags: 0 }
+    }
+
+    // Shift a significand right by `shift` bits and round it to an integer. Return
+    // the result and true if it is inexact.
+    fn round(&self, significand: u128, shift: i32, sign: bool) -> (u128, bool) {
+        if shift <= 0 {
+            return (significand << -shift, false);
+        }
+        let (kept, half) = match shift {
+            // The rest is less than a half.
+            129.. => (0, Ordering::Less),
+            128 => (0, significand.cmp(&(1 << 127))),
+            _ => (significand >> shift, (significand & ((1 << shift) - 1)).cmp(&(1 << (shift - 1)))),
+        };
+        let inexact = match shift {
+            128.. => significand != 0,
+            _ => significand & ((1 << shift) - 1) != 0,
+        };
+        let up = match self.rounding {
+            Rounding::NearestEven => half == Ordering::Greater || (half == Ordering::Equal && kept & 1 == 1),
+            Rounding::NearestMaxMagnitude => half != Ordering::Less,
+            Rounding::TowardZero => false,
+            Rounding::Down => inexact && sign,
+            Rounding::Up => inexact && !sign,
+        };
+        (kept + up as u128, inexact)
+    }
+
+    // Round (-1)^sign * significand * 2^exponent to the format.
+    fn round_pack(&mut self, format: Format, sign: bool, exponent: i32, significand: u128) -> u64 {
+        if significand == 0 {
+            return format.zero(sign);
+        }
+        let precision = format.fraction_bits() as i32 + 1;
+        let min_exponent = 1 - format.bias();
+        // The exponent of the lowest bit of the significand rounded to the precision,
+        // and its minimum, the one of the subnormal numbers.
+        let lowest = exponent + bit_length(significand) - precision;
+        let min_lowest = min_exponent - (precision - 1);
+        // The result is tiny if it is below the smallest normal number when it is
+        // rounded with an unbounded exponent.
+        let tiny = lowest < min_lowest && {
+            let (rounded, _) = self.round(significand, lowest - exponent, sign);
+            lowest + bit_length(rounded) - 1 < min_exponent
+        };
+        let mut lowest = lowest.max(min_lowest);
+        let (mut rounded, inexact) = self.round(significand, lowest - exponent, sign);
+        // Rounding up may carry into a new bit.
+        if bit_length(rounded) > precision {
+            rounded >>= 1;
+            lowest += 1;
+        }
+        if inexact {
+            self.flags |= FLAG_NX;
+            if tiny {
+                self.flags |= FLAG_UF;
+            }
+        }
+        if lowest + precision - 1 > format.bias() {
+            self.flags |= FLAG_OF | FLAG_NX;
+            let infinity = match self.rounding {
+                Rounding::NearestEven | Rounding::NearestMaxMagnitude => true,
+                Rounding::TowardZero => false,
+                Rounding::Down => sign,
+                Rounding::Up => !sign,
+            };
+            return if infinity {
+                format.infinity(sign)
+            } else {
+                format.max_finite(sign)
+            };
+        }
+        let fraction = rounded as u64 & ((1 << format.fraction_bits()) - 1);
+        let exponent = if bit_length(rounded) == precision {
+            (lowest + precision - 1 + format.bias()) as u64
+        } else {
+            // A subnormal number
+            0
+        };
+        format.zero(sign) | exponent << format.fraction_bits() | fraction
+    }
+
+    // The canonical NaN, the result of an operation on a NaN. A signaling NaN raises
+    // the invalid operation exception.
+    fn nan(&mut self, format: Format, values: &[Value]) -> u64 {
+        if values.contains(&Value::Nan(true)) {
+            self.flags |= FLAG_NV;
+        }
+        format.canonical_nan()
+    }
+
+    // The canonical NaN of an invalid operation
+    fn invalid(&mut self, format: Format) -> u64 {
+        self.flags |= FLAG_NV;
+        format.canonical_nan()
+    }
+
+    // The sum of two numbers other than 0: (-1)^sign * significand * 2^exponent
+    fn add_finite(&mut self, format: Format, a: (bool, u128, i32), b: (bool, u128, i32)) -> u64 {
+        // Align the top bits of the significands at bit 125, which leaves room for a
+        // carry, and shift the smaller one right by the difference of exponents.
+        let align = |(sign, significand, exponent): (bool, u128, i32)| {
+            let shift = 126 - bit_length(significand);
+            (sign, significand << shift, exponent - shift)
+        };
+        let (a, b) = (align(a), align(b));
+        let (big, small) = if (a.2, a.1) >= (b.2, b.1) { (a, b) } else { (b, a) };
+        let small_significand = shift_right_jam(small.1, big.2 - small.2);
+        if big.0 == small.0 {
+            return self.round_pack(format, big.0, big.2, big.1 + small_significand);
+        }
+        let difference = big.1 - small_significand;
+        if difference == 0 {
+            // An exact 0 is -0 only when rounding down.
+            return format.zero(self.rounding == Rounding::Down);
+        }
+        self.round_pack(format, big.0, big.2, difference)
+    }
+
+    pub fn add(&mut self, format: Format, a: u64, b: u64) -> u64 {
+        let ((sign_a, a_value), (sign_b, b_value)) = (format.unpack(a), format.unpack(b));
+        match (a_value, b_value) {
+            (Value::Nan(_), _) | (_, Value::Nan(_)) => self.nan(format, &[a_value, b_value]),
+            (Value::Infinity, Value::Infinity) if sign_a != sign_b => self.invalid(format),
+            (Value::Infinity, _) => a,
+            (_, Value::Infinity) => b,
+            (Value::Zero, Value::Zero) if sign_a != sign_b => format.zero(self.rounding == Rounding::Down),
+            (Value::Zero, _) => b,
+            (_, Value::Zero) => a,
+            (Value::Finite(a_significand, a_exponent), Value::Finite(b_significand, b_exponent)) => {
+                self.add_finite(format, (sign_a, a_significand, a_exponent), (sign_b, b_significand, b_exponent))
+            }
+        }
+    }
+
+    pub fn sub(&mut self, format: Format, a: u64, b: u64) -> u64 {
+        // A NaN stays a NaN with its sign flipped.
+        self.add(format, a, b ^ format.sign_bit())
+    }
+
+    pub fn mul(&mut self, format: Format, a: u64, b: u64) -> u64 {
+        let ((sign_a, a_value), (sign_b, b_value)) = (format.unpack(a), format.unpack(b));
+        let sign = sign_a != sign_b;
+        match (a_value, b_value) {
+            (Value::Nan(_), _) | (_, Value::Nan(_)) => self.nan(format, &[a_value, b_value]),
+            (Value::Infinity, Value::Zero) | (Value::Zero, Value::Infinity) => self.invalid(format),
+            (Value::Infinity, _) | (_, Value::Infinity) => format.infinity(sign),
+            (Value::Zero, _) | (_, Value::Zero) => format.zero(sign),
+            (Value::Finite(a_significand, a_exponent), Value::Finite(b_significand, b_exponent)) => {
+                self.round_pack(format, sign, a_exponent + b_exponent, a_significand * b_significand)
+            }
+        }
+    }
+
+    pub fn div(&mut self, format: Format, a: u64, b: u64) -> u64 {
+        let ((sign_a, a_value), (sign_b, b_value)) = (format.unpack(a), format.unpack(b));
+        let sign = sign_a != sign_b;
+        match (a_value, b_value) {
+            (Value::Nan(_), _) | (_, Value::Nan(_)) => self.nan(format, &[a_value, b_value]),
+            (Value::Infinity, Value::Infinity) | (Value::Zero, Value::Zero) => self.invalid(format),
+            (Value::Infinity, _) => format.infinity(sign),
+            (_, Value::Infinity) | (Value::Zero, _) => format.zero(sign),
+            (_, Value::Zero) => {
+                self.flags |= FLAG_DZ;
+                format.infinity(sign)
+            }
+            (Value::Finite(a_significand, a_exponent), Value::Finite(b_significand, b_exponent)) => {
+                // With both significands of 64 bits, the quotient of a shifted left
+                // by 64 bits has 64 or 65 bits, and the remainder is the sticky bit.
+                let a_shift = 64 - bit_length(a_significand);
+                let b_shift = 64 - bit_length(b_significand);
+                let dividend = a_significand << (a_shift + 64);
+                let divisor = b_significand << b_shift;
+                let quotient = (dividend / divisor) | !dividend.is_multiple_of(divisor) as u128;
+                let exponent = a_exponent - a_shift - 64 - (b_exponent - b_shift);
+                self.round_pack(format, sign, exponent, quotient)
+            }
+        }
+    }
+
+    pub fn sqrt(&mut self, format: Format, a: u64) -> u64 {
+        let (sign, value) = format.unpack(a);
+        match value {
+            Value::Nan(_) => self.nan(format, &[value]),
+            Value::Zero => a,
+            _ if sign => self.invalid(format),
+            Value::Infinity => a,
+            Value::Finite(significand, exponent) => {
+                // Shift the significand to 125 or 126 bits so that the exponent is
+                // even, and its root has 63 bits. The remainder is the sticky bit.
+                let mut shift = 125 - bit_length(significand);
+                if (exponent - shift) % 2 != 0 {
+                    shift += 1;
+                }
+                let significand = significand << shift;
+                let root = isqrt(significand);
+                let root = root | (root * root != significand) as u128;
+                self.round_pack(format, false, (exponent - shift) / 2, root)
+            }
+        }
+    }
+
+    // (-1)^negate_product * a * b + (-1)^negate_addend * c, rounded once. This is
+    // fmadd, fmsub, fnmsub and fnmadd.
+    pub fn fused_multiply_add(
+        &mut self,
+        format: Format,
+        a: u64,
+        b: u64,
+        c: u64,
+        negate_product: bool,
+        negate_addend: bool,
+    ) -> u64 {
+        let ((sign_a, a_value), (sign_b, b_value)) = (format.unpack(a), format.unpack(b));
+        let (sign_c, c_value) = format.unpack(c);
+        let product_sign = sign_a != sign_b && !negate_product || sign_a == sign_b && negate_product;
+        let sign_c = sign_c != negate_addend;
+        let invalid_product = matches!(
+            (a_value, b_value),
+            (Value::Infinity, Value::Zero) | (Value::Zero, Value::Infinity)
+        );
+        // The product of infinity and 0 is invalid even when c is a quiet NaN.
+        if invalid_product {
+            return self.invalid(format);
+        }
+        if matches!(a_value, Value::Nan(_)) || matches!(b_value, Value::Nan(_)) || matches!(c_value, Value::Nan(_)) {
+            return self.nan(format, &[a_value, b_value, c_value]);
+        }
+        let product_infinite = a_value == Value::Infinity || b_value == Value::Infinity;
+        let product_zero = a_value == Value::Zero || b_value == Value::Zero;
+        match (product_infinite, product_zero, c_value) {
+            (true, _, Value::Infinity) if product_sign != sign_c => self.invalid(format),
+            (true, _, _) => format.infinity(product_sign),
+            (_, _, Value::Infinity) => format.infinity(sign_c),
+            (_, true, Value::Zero) if product_sign != sign_c => format.zero(self.rounding == Rounding::Down),
+            (_, true, _) => c & !format.sign_bit() | format.zero(sign_c),
+            _ => {
+                let (a_significand, a_exponent, b_significand, b_exponent) = match (a_value, b_value) {
+                    (Value::Finite(a_significand, a_exponent), Value::Finite(b_significand, b_exponent)) => {
+                        (a_significand, a_exponent, b_significand, b_exponent)
+                    }
+                    _ => unreachable!(),
+                };
+                let product = (product_sign, a_significand * b_significand, a_exponent + b_exponent);
+                match c_value {
+                    Value::Finite(c_significand, c_exponent) => {
+                        self.add_finite(format, product, (sign_c, c_significand, c_exponent))
+                    }
+                    _ => self.round_pack(format, product.0, product.2, product.1),
+                }
+            }
+        }
+    }
+
+    // Convert a number to another format.
+    pub fn convert(&mut self, from: Format, to: Format, a: u64) -> u64 {
+        let (sign, value) = from.unpack(a);
+        match value {
+            Value::Nan(_) => self.nan(to, &[value]),
+            Value::Zero => to.zero(sign),
+            Value::Infinity => to.infinity(sign),
+            Value::Finite(significand, exponent) => self.round_pack(to, sign, exponent, significand),
+        }
+    }
+
+    // Convert a number to an integer of `width` bits, signed or unsigned. A NaN or a
+    // number out of range is invalid and gives the nearest integer in the range, or
+    // the largest one for a NaN. A 32-bit result is sign-extended to 64 bits.
+    pub fn to_int(&mut self, format: Format, a: u64, signed: bool, width: u32) -> u64 {
+        let (min, max): (i128, i128) = if signed {
+            (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+        } else {
+            (0, (1 << width) - 1)
+        };
+        let (sign, value) = format.unpack(a);
+        let result = match value {
+            Value::Nan(_) => None,
+            Value::Infinity => None,
+            Value::Zero => Some(0),
+            // The number is at least 2^64.
+            Value::Finite(_, exponent) if exponent > 64 => None,
+            Value::Finite(significand, exponent) => {
+                let (magnitude, inexact) = self.round(significand, -exponent, sign);
+                let integer = if sign { -(magnitude as i128) } else { magnitude as i128 };
+                if integer < min || integer > max {
+                    None
+                } else {
+                    if inexact {
+                        self.flags |= FLAG_NX;
+                    }
+                    Some(integer)
+                }
+            }
+        };
+        let integer = result.unwrap_or_else(|| {
+            self.flags |= FLAG_NV;
+            match value {
+                Value::Nan(_) => max,
+                _ if sign => min,
+                _ => max,
+            }
+        });
+        match width {
+            32 => integer as u32 as i32 as u64,
+            _ => integer as u64,
+        }
+    }
+
+    // Convert the lowest `width` bits of `value`, a signed or an unsigned integer, to
+    // a number.
+    pub fn from_int(&mut self, format: Format, value: u64, signed: bool, width: u32) -> u64 {
+        let integer = match (signed, width) {
+            (true, 32) => value as i32 as i128,
+            (false, 32) => value as u32 as i128,
+            (true, _) => value as i64 as i128,
+            (false, _) => value as i128,
+        };
+        self.round_pack(format, integer < 0, 0, integer.unsigned_abs())
+    }
+
+    // feq: a quiet comparison, which is invalid only for a signaling NaN
+    pub fn eq(&mut self, format: Format, a: u64, b: u64) -> bool {
+        let ((_, a_value), (_, b_value)) = (format.unpack(a), format.unpack(b));
+        if matches!(a_value, Value::Nan(_)) || matches!(b_value, Value::Nan(_)) {
+            self.nan(format, &[a_value, b_value]);
+            return false;
+        }
+        format.key(a, false) == format.key(b, false)
+    }
+
+    // flt and fle: signaling comparisons, which are invalid for any NaN
+    pub fn compare(&mut self, format: Format, a: u64, b: u64) -> Option<Ordering> {
+        let ((_, a_value), (_, b_value)) = (format.unpack(a), format.unpack(b));
+        if matches!(a_value, Value::Nan(_)) || matches!(b_value, Value::Nan(_)) {
+            self.flags |= FLAG_NV;
+            return None;
+        }
+        Some(format.key(a, false).cmp(&format.key(b, false)))
+    }
+
+    // fmin and fmax: the minimum or the maximum of two numbers, where -0 is less
+    // than +0. If one is a NaN the result is the other one.
+    pub fn min_max(&mut self, format: Format, a: u64, b: u64, max: bool) -> u64 {
+        let ((_, a_value), (_, b_value)) = (format.unpack(a), format.unpack(b));
+        match (a_value, b_value) {
+            (Value::Nan(_), Value::Nan(_)) => self.nan(format, &[a_value, b_value]),
+            (Value::Nan(_), _) => {
+                self.nan(format, &[a_value]);
+                b
+            }
+            (_, Value::Nan(_)) => {
+                self.nan(format, &[b_value]);
+                a
+            }
+            _ => {
+                let less = format.key(a, true) < format.key(b, true);
+                if less != max {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
+}
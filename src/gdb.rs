@@ -0,0 +1,826 @@
+// The gdb module is a stub of the GDB remote serial protocol (RSP), so that
+// riscv64-unknown-elf-gdb can debug a program running in the emulator:
+//
+//   riscvemu --gdb=1234 program.elf
+//   riscv64-unknown-elf-gdb program.elf -ex "target remote :1234"
+//
+// The stub listens on a TCP port or a Unix socket and serves one connection.
+// It supports reading and writing registers, CSRs and memory, breakpoints,
+// watchpoints, single-step, continue and Ctrl-C. Breakpoints and watchpoints
+// are kept in the stub instead of being written to memory, so software and
+// hardware breakpoints work the same way.
+//
+// The program runs with Emulator::step, so semihosting, the system calls of
+// --user, the HTIF, the tracer and --lockstep work as without GDB. GDB gets an
+// exit reply when the program exits.
+//
+// reverse-stepi and reverse-continue run the program backward with the history
+// of the reverse module. A watchpoint and reverse-continue find the instruction
+// which last changed a memory location. A program which calls the host cannot
+// run backward, since the calls would be made again.
+//
+// The machine must have one hart, and the events of the program cannot be
+// recorded or replayed, since the history records them.
+//
+// See the spec: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::emulator::*;
+use crate::replay::*;
+use crate::reverse::*;
+use crate::syscall;
+use crate::trace::*;
+
+// GDB's register numbers for RISC-V.
+const PC_REGNUM: usize = 32;
+const FIRST_FPR_REGNUM: usize = 33;
+const FIRST_CSR_REGNUM: usize = 65;
+// The virtual register of the privilege mode, after the CSRs.
+const PRIV_REGNUM: usize = FIRST_CSR_REGNUM + CSRS_NUMBER;
+
+// Check for Ctrl-C from GDB every this number of instructions.
+const INTERRUPT_CHECK_INTERVAL: u64 = 0x10000;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// A connection to GDB.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u64,
+    len: u64,
+}
+
+// Why the cpu stopped.
+enum Stop {
+    // A single step finished or a breakpoint was hit.
+    Trap,
+    // A watchpoint was hit before the access at the address.
+    Watch(WatchKind, u64),
+    // Ctrl-C from GDB
+    Interrupt,
+    // The program went back to the start of the history.
+    Begin,
+    // The connection was closed while running.
+    Closed,
+    // The program raised an exception which stops the emulator, with the signal
+    // Linux would send for it.
+    Signal(u8),
+    // The program exited with the exit code.
+    Exit(u64),
+    // A Linux program or a program for Spike was killed by the signal of an
+    // exception.
+    Killed(u8),
+}
+
+// A packet from GDB.
+enum Packet {
+    Command(String),
+    // Ctrl-C, which is sent as a raw 0x03 byte.
+    Interrupt,
+}
+
+pub struct GdbStub {
+    connection: Box<dyn Connection>,
+    // Bytes received but not handled yet
+    buffer: Vec<u8>,
+    breakpoints: Vec<u64>,
+    watchpoints: Vec<Watchpoint>,
+    // The checkpoints to run the program backward
+    history: History,
+    // False if the program cannot run backward
+    reverse: bool,
+}
+
+// Wait for GDB to connect to `addr`, which is a TCP port, `host:port` or
+// `unix:<path>`.
+pub fn listen(addr: &str) -> io::Result<Box<dyn Connection>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // Remove the socket left by a previous run.
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        println!("gdb: waiting for a connection on {}", path);
+        let (stream, _) = listener.accept()?;
+        return Ok(Box::new(stream));
+    }
+    let addr = if addr.contains(':') {
+        addr.to_string()
+    } else {
+        format!("127.0.0.1:{}", addr)
+    };
+    let listener = TcpListener::bind(&addr)?;
+    println!("gdb: waiting for a connection on {}", addr);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(Box::new(stream))
+}
+
+// Serve GDB on `addr` until it detaches, kills the program or disconnects, or the
+// program exits. Return the exit code of the program if it exited.
+pub fn serve(emulator: &mut Emulator, addr: &str) -> io::Result<Option<u64>> {
+    check(emulator)?;
+    let mut stub = GdbStub::new(listen(addr)?);
+    stub.run(emulator)
+}
+
+// Check that GDB can debug the machine.
+fn check(emulator: &mut Emulator) -> io::Result<()> {
+    // The harts take turns to execute an instruction each, and GDB would see
+    // them as one thread whose registers change at every step.
+    if emulator.cpu.hart_count() != 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "GDB supports only 1 hart"));
+    }
+    // The history records the events itself.
+    if emulator.recording_mut().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a program debugged by GDB cannot be recorded or replayed",
+        ));
+    }
+    Ok(())
+}
+
+fn hex_u64(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+// Encode bytes as hex digits.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Decode hex digits to bytes.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Encode a register in the target byte order, i.e. little-endian.
+fn encode_reg(value: u64) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn decode_reg(s: &str) -> Option<u64> {
+    let bytes = decode_hex(s)?;
+    if bytes.len() != 8 {
+        return None;
+    }
+    Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+// The target description, which tells GDB the registers of the target. The
+// floating-point registers are there if the cpu has the F extension.
+fn target_xml(float: bool) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv64</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (i, name) in XREG_NAMES.iter().enumerate() {
+        let kind = match i {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, kind, i
+        ));
+    }
+    xml.push_str(&format!(
+        "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\n",
+        PC_REGNUM
+    ));
+    if float {
+        xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+        for (i, name) in FREG_NAMES.iter().enumerate() {
+            xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>\n",
+                name,
+                FIRST_FPR_REGNUM + i
+            ));
+        }
+    }
+    xml.push_str("</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for addr in 0..CSRS_NUMBER {
+        if let Some(name) = csr_name(addr) {
+            xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\" group=\"csr\"/>\n",
+                name,
+                FIRST_CSR_REGNUM + addr
+            ));
+        }
+    }
+    xml.push_str(&format!(
+        "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n\
+         <reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{}\" group=\"general\"/>\n\
+         </feature>\n</target>\n",
+        PRIV_REGNUM
+    ));
+    xml
+}
+
+impl GdbStub {
+    pub fn new(connection: Box<dyn Connection>) -> Self {
+        Self {
+            connection,
+            buffer: Vec::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            history: History::new(),
+            reverse: true,
+        }
+    }
+
+    // Handle packets until GDB detaches, kills the program or disconnects, or the
+    // program exits. Return the exit code of the program if it exited.
+    pub fn run(&mut self, emulator: &mut Emulator) -> io::Result<Option<u64>> {
+        check(emulator)?;
+        self.reverse = !emulator.calls_host();
+        if self.reverse {
+            // The guest receives the input at instruction boundaries, so that the
+            // program runs the same way when it runs again in the history.
+            let recording = Recording::history(&emulator.cpu);
+            emulator.set_recording(recording);
+        }
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                // Ctrl-C while stopped
+                Some(Packet::Interrupt) => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+
+            let cpu = &mut emulator.cpu;
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => format!("S{:02x}", SIGTRAP),
+                Some(b'g') => self.read_registers(cpu),
+                Some(b'G') => self.write_registers(cpu, &packet[1..]),
+                Some(b'p') => self.read_register(cpu, &packet[1..]),
+                Some(b'P') => self.write_register(cpu, &packet[1..]),
+                Some(b'm') => self.read_memory(cpu, &packet[1..]),
+                Some(b'M') => self.write_memory(cpu, &packet[1..]),
+                Some(b'c') | Some(b's') => {
+                    // An optional address to resume at
+                    if let Some(addr) = hex_u64(&packet[1..]) {
+                        cpu.pc = addr;
+                        self.history.discard_future(emulator);
+                    }
+                    let stop = self.resume(emulator, packet.starts_with('s'))?;
+                    let exit = match stop {
+                        Stop::Exit(code) => Some(code),
+                        Stop::Killed(signal) => Some(128 + signal as u64),
+                        _ => None,
+                    };
+                    match self.stop_reply(stop) {
+                        // The program is gone, so the session ends with the reply.
+                        Some(reply) if exit.is_some() => {
+                            self.send(&reply)?;
+                            return Ok(exit);
+                        }
+                        Some(reply) => reply,
+                        None => return Ok(None),
+                    }
+                }
+                Some(b'b') => {
+                    let stop = match packet.as_str() {
+                        "bs" if self.reverse => self.reverse_step(emulator),
+                        "bc" if self.reverse => self.reverse_continue(emulator)?,
+                        _ => {
+                            self.send("")?;
+                            continue;
+                        }
+                    };
+                    match self.stop_reply(stop) {
+                        Some(reply) => reply,
+                        None => return Ok(None),
+                    }
+                }
+                Some(b'Z') => self.set_point(&packet[1..], true),
+                Some(b'z') => self.set_point(&packet[1..], false),
+                Some(b'q') => self.query(cpu, &packet),
+                Some(b'H') | Some(b'T') => "OK".to_string(),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(None);
+                }
+                // Kill: there is no reply.
+                Some(b'k') => return Ok(None),
+                // Unsupported packets get an empty reply.
+                _ => String::new(),
+            };
+            // Changing registers or memory changes how the program runs from here.
+            if matches!(packet.as_bytes().first(), Some(b'G' | b'P' | b'M')) && reply == "OK" {
+                self.history.discard_future(emulator);
+            }
+            self.send(&reply)?;
+        }
+    }
+
+    // The stop reply packet, or None if the connection was closed.
+    fn stop_reply(&self, stop: Stop) -> Option<String> {
+        let reply = match stop {
+            Stop::Trap => format!("S{:02x}", SIGTRAP),
+            Stop::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            Stop::Interrupt => format!("S{:02x}", SIGINT),
+            Stop::Begin => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Stop::Closed => return None,
+            Stop::Signal(signal) => format!("S{:02x}", signal),
+            Stop::Exit(code) => format!("W{:02x}", code as u8),
+            Stop::Killed(signal) => format!("X{:02x}", signal),
+        };
+        Some(reply)
+    }
+
+    // The stop for a reason to stop of Emulator::step. The reasons which GDB does
+    // not know are printed and the program stops as at a breakpoint.
+    fn stop(&self, emulator: &mut Emulator, reason: StopReason) -> Stop {
+        match reason {
+            StopReason::Exit(code) => Stop::Exit(code),
+            // The exit code is in the payload above bit 0, as for the HTIF.
+            StopReason::ToHost(value) => Stop::Exit(value >> 1),
+            StopReason::SystemReset(reset_type, reason) => {
+                println!("system reset: {:?} (reason {})", reset_type, reason);
+                Stop::Exit(0)
+            }
+            // A Linux program or a program for Spike is killed by the signal, as
+            // it is without GDB.
+            StopReason::Exception(exception, pc) => {
+                println!("exception: {:?} at {}", exception, emulator.cpu.symbols.format(pc));
+                let signal = syscall::signal(&exception) as u8;
+                if emulator.process_mut().is_some() {
+                    Stop::Killed(signal)
+                } else {
+                    Stop::Signal(signal)
+                }
+            }
+            StopReason::Lockstep(mismatch) => {
+                print!("{}", mismatch);
+                Stop::Trap
+            }
+            StopReason::Replay(e) => {
+                println!("replay: {}", e);
+                Stop::Trap
+            }
+            _ => Stop::Trap,
+        }
+    }
+
+    // Execute an instruction, in the history if the program can run backward.
+    fn step(&mut self, emulator: &mut Emulator) -> Option<StopReason> {
+        if self.reverse {
+            self.history.step(emulator)
+        } else {
+            emulator.step()
+        }
+    }
+
+    fn query(&self, cpu: &Cpu, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            let mut features = "PacketSize=4000;qXfer:features:read+".to_string();
+            if self.reverse {
+                features.push_str(";ReverseStep+;ReverseContinue+");
+            }
+            return features;
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = match args.split_once(',') {
+                Some((offset, length)) => (hex_u64(offset), hex_u64(length)),
+                None => (None, None),
+            };
+            let (offset, length) = match (offset, length) {
+                (Some(offset), Some(length)) => (offset as usize, length as usize),
+                _ => return "E01".to_string(),
+            };
+            let end = match offset.checked_add(length) {
+                Some(end) => end,
+                None => return "E01".to_string(),
+            };
+            let xml = target_xml(cpu.has_extension(b'f'));
+            let start = offset.min(xml.len());
+            let end = end.min(xml.len());
+            // "m" if there is more data, "l" if this is the last part.
+            let prefix = if end < xml.len() { "m" } else { "l" };
+            return format!("{}{}", prefix, &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_registers(&self, cpu: &Cpu) -> String {
+        // x0-x31 and pc. GDB reads the other registers with `p`.
+        let mut reply = encode_reg(0);
+        reply.extend(cpu.regs[1..].iter().map(|reg| encode_reg(*reg)));
+        reply.push_str(&encode_reg(cpu.pc));
+        reply
+    }
+
+    fn write_registers(&self, cpu: &mut Cpu, data: &str) -> String {
+        for i in 0..=PC_REGNUM {
+            let value = match data.get(i * 16..i * 16 + 16).and_then(decode_reg) {
+                Some(value) => value,
+                None => break,
+            };
+            match i {
+                0 => {}
+                PC_REGNUM => cpu.pc = value,
+                _ => cpu.regs[i] = value,
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, cpu: &Cpu, data: &str) -> String {
+        let value = match hex_u64(data).map(|n| n as usize) {
+            Some(0) => 0,
+            Some(n) if n < REGISTER_NUMBER => cpu.regs[n],
+            Some(PC_REGNUM) => cpu.pc,
+            Some(PRIV_REGNUM) => cpu.mode as u64,
+            Some(n) if (FIRST_FPR_REGNUM..FIRST_CSR_REGNUM).contains(&n) => {
+                cpu.fregs[n - FIRST_FPR_REGNUM]
+            }
+            Some(n) if (FIRST_CSR_REGNUM..PRIV_REGNUM).contains(&n) => {
+                cpu.read_csr(n - FIRST_CSR_REGNUM)
+            }
+            _ => return "E01".to_string(),
+        };
+        encode_reg(value)
+    }
+
+    fn write_register(&self, cpu: &mut Cpu, data: &str) -> String {
+        let (n, value) = match data.split_once('=') {
+            Some((n, value)) => (hex_u64(n).map(|n| n as usize), decode_reg(value)),
+            None => (None, None),
+        };
+        let (n, value) = match (n, value) {
+            (Some(n), Some(value)) => (n, value),
+            _ => return "E01".to_string(),
+        };
+        match n {
+            0 => {}
+            _ if n < REGISTER_NUMBER => cpu.regs[n] = value,
+            PC_REGNUM => cpu.pc = value,
+            PRIV_REGNUM => {
+                cpu.mode = match value {
+                    0 => Mode::User,
+                    1 => Mode::Surpervisor,
+                    3 => Mode::Machine,
+                    _ => return "E01".to_string(),
+                }
+            }
+            _ if (FIRST_FPR_REGNUM..FIRST_CSR_REGNUM).contains(&n) => {
+                cpu.fregs[n - FIRST_FPR_REGNUM] = value
+            }
+            _ if (FIRST_CSR_REGNUM..PRIV_REGNUM).contains(&n) => {
+                cpu.write_csr(n - FIRST_CSR_REGNUM, value)
+            }
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    // `m addr,length`
+    fn read_memory(&self, cpu: &mut Cpu, data: &str) -> String {
+        let (addr, length) = match data.split_once(',') {
+            Some((addr, length)) => (hex_u64(addr), hex_u64(length)),
+            None => (None, None),
+        };
+        let (addr, length) = match (addr, length) {
+            (Some(addr), Some(length)) => (addr, length),
+            _ => return "E01".to_string(),
+        };
+        let mut bytes = Vec::new();
+        for i in 0..length {
+            match cpu.bus.load(addr.wrapping_add(i), 8) {
+                Ok(byte) => bytes.push(byte as u8),
+                Err(_) => break,
+            }
+        }
+        // A partial read returns the bytes which could be read.
+        if bytes.is_empty() && length != 0 {
+            return "E14".to_string();
+        }
+        encode_hex(&bytes)
+    }
+
+    // `M addr,length:XX...`
+    fn write_memory(&self, cpu: &mut Cpu, data: &str) -> String {
+        let (header, bytes) = match data.split_once(':') {
+            Some((header, bytes)) => (header, decode_hex(bytes)),
+            None => return "E01".to_string(),
+        };
+        let addr = match header.split_once(',').and_then(|(addr, _)| hex_u64(addr)) {
+            Some(addr) => addr,
+            None => return "E01".to_string(),
+        };
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+        for (i, byte) in bytes.iter().enumerate() {
+            if cpu.bus.store(addr.wrapping_add(i as u64), 8, *byte as u64).is_err() {
+                return "E14".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // `Z type,addr,kind` and `z type,addr,kind`
+    fn set_point(&mut self, data: &str, insert: bool) -> String {
+        let fields: Vec<&str> = data.split(',').collect();
+        if fields.len() < 3 {
+            return "E01".to_string();
+        }
+        let (addr, len) = match (hex_u64(fields[1]), hex_u64(fields[2])) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        let kind = match fields[0] {
+            // Software and hardware breakpoints
+            "0" | "1" => {
+                if insert {
+                    if !self.breakpoints.contains(&addr) {
+                        self.breakpoints.push(addr);
+                    }
+                } else {
+                    self.breakpoints.retain(|bp| *bp != addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        // The watched range must not wrap around the address space.
+        if addr.checked_add(len).is_none() {
+            return "E01".to_string();
+        }
+        if insert {
+            self.watchpoints.push(Watchpoint { kind, addr, len });
+        } else {
+            self.watchpoints
+                .retain(|wp| !(wp.kind == kind && wp.addr == addr && wp.len == len));
+        }
+        "OK".to_string()
+    }
+
+    // Return the watchpoint hit by the memory accesses of the next instruction.
+    fn check_watchpoints(&self, cpu: &mut Cpu) -> Option<Stop> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let inst = cpu.fetch().ok()?;
+        let commit = Commit::begin(cpu, inst);
+        for (addr, size, store) in commit.accesses() {
+            for wp in self.watchpoints.iter() {
+                let hit = match wp.kind {
+                    WatchKind::Write => store,
+                    WatchKind::Read => !store,
+                    WatchKind::Access => true,
+                };
+                if hit && addr < wp.addr + wp.len && wp.addr < addr.saturating_add(size) {
+                    return Some(Stop::Watch(wp.kind, addr.max(wp.addr)));
+                }
+            }
+        }
+        None
+    }
+
+    // Run one instruction, or until a breakpoint, a watchpoint, Ctrl-C or a reason
+    // to stop of the emulator. Traps are taken as usual and do not stop the cpu
+    // unless the emulator stops on exceptions.
+    fn resume(&mut self, emulator: &mut Emulator, step: bool) -> io::Result<Stop> {
+        let mut count: u64 = 0;
+        loop {
+            // Do not stop at the breakpoint the cpu resumes from.
+            if count != 0 && self.breakpoints.contains(&emulator.cpu.pc) {
+                return Ok(Stop::Trap);
+            }
+
+            // A watchpoint stops the cpu before the access, like a trigger of the
+            // debug spec.
+            if let Some(stop) = self.check_watchpoints(&mut emulator.cpu) {
+                return Ok(stop);
+            }
+            if let Some(reason) = self.step(emulator) {
+                return Ok(self.stop(emulator, reason));
+            }
+
+            if step {
+                return Ok(Stop::Trap);
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+                if let Some(stop) = self.poll_interrupt()? {
+                    return Ok(stop);
+                }
+            }
+        }
+    }
+
+    // Go back one instruction.
+    fn reverse_step(&mut self, emulator: &mut Emulator) -> Stop {
+        match self.history.origin() {
+            Some(origin) if emulator.instruction_count() > origin => {
+                let target = emulator.instruction_count() - 1;
+                self.history.go_to(emulator, target);
+                Stop::Trap
+            }
+            _ => Stop::Begin,
+        }
+    }
+
+    // Go back to the last instruction before the current one at a breakpoint or
+    // which hits a watchpoint. The history is searched from the latest checkpoint
+    // back, by running the program from each checkpoint.
+    fn reverse_continue(&mut self, emulator: &mut Emulator) -> io::Result<Stop> {
+        let mut end = emulator.instruction_count();
+        for start in self.history.checkpoints_before(end) {
+            self.history.go_to(emulator, start);
+            // The last stop between the checkpoint and `end`
+            let mut found = None;
+            while emulator.instruction_count() < end {
+                let stop = match self.check_watchpoints(&mut emulator.cpu) {
+                    Some(stop) => Some(stop),
+                    None if self.breakpoints.contains(&emulator.cpu.pc) => Some(Stop::Trap),
+                    None => None,
+                };
+                if let Some(stop) = stop {
+                    found = Some((emulator.instruction_count(), stop));
+                }
+                self.history.step(emulator);
+            }
+            if let Some((count, stop)) = found {
+                self.history.go_to(emulator, count);
+                return Ok(stop);
+            }
+            // Stop at the checkpoint on Ctrl-C.
+            self.history.go_to(emulator, start);
+            if let Some(stop) = self.poll_interrupt()? {
+                return Ok(stop);
+            }
+            end = start;
+        }
+        Ok(Stop::Begin)
+    }
+
+    // Check if GDB sent Ctrl-C without blocking.
+    fn poll_interrupt(&mut self) -> io::Result<Option<Stop>> {
+        let mut bytes = [0; 256];
+        self.connection.set_nonblocking(true)?;
+        let result = self.connection.read(&mut bytes);
+        self.connection.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(Some(Stop::Closed)),
+            Ok(n) => {
+                if let Some(i) = bytes[..n].iter().position(|byte| *byte == 0x03) {
+                    // Keep the bytes after Ctrl-C for the next packet.
+                    self.buffer.extend_from_slice(&bytes[i + 1..n]);
+                    return Ok(Some(Stop::Interrupt));
+                }
+                self.buffer.extend_from_slice(&bytes[..n]);
+                Ok(None)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Read a byte, or None if the connection was closed.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.buffer.is_empty() {
+            return Ok(Some(self.buffer.remove(0)));
+        }
+        let mut byte = [0; 1];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Read a packet `$data#checksum` and acknowledge it. Return None if the
+    // connection was closed.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            // Skip acknowledgements and anything before the start of a packet.
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            // The checksum is the sum of the bytes as sent, before unescaping.
+            let mut data = Vec::new();
+            let mut actual = 0u8;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    // An escaped byte is the next byte xor 0x20.
+                    Some(b'}') => match self.read_byte()? {
+                        None => return Ok(None),
+                        Some(byte) => {
+                            actual = actual.wrapping_add(b'}').wrapping_add(byte);
+                            data.push(byte ^ 0x20);
+                        }
+                    },
+                    Some(byte) => {
+                        actual = actual.wrapping_add(byte);
+                        data.push(byte);
+                    }
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected != Some(actual) {
+                // Ask GDB to send the packet again.
+                self.connection.write_all(b"-")?;
+                continue;
+            }
+            self.connection.write_all(b"+")?;
+            return Ok(Some(Packet::Command(
+                String::from_utf8_lossy(&data).into_owned(),
+            )));
+        }
+    }
+
+    // Send a packet and wait for GDB to acknowledge it.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            self.connection.write_all(packet.as_bytes())?;
+            self.connection.flush()?;
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                // Keep the start of the next packet, e.g. when GDB does not wait.
+                Some(byte) if byte != b'+' => {
+                    self.buffer.insert(0, byte);
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// The htif module is the host-target interface of Spike, by which the programs
+// for Spike, e.g. riscv-pk and the riscv-tests, talk to the host through two
+// 64-bit words in memory, `tohost` and `fromhost`, whose addresses are given by
+// the symbols of the ELF file.
+//
+// The program writes a command to tohost: the device in bits 63:56, the command
+// in bits 55:48 and the payload in bits 47:0. The emulator takes it, writes 0 to
+// tohost, and writes the response, which has the same device and command, to
+// fromhost once the program has read the previous one and written 0 to it.
+//
+// - Device 0 is the syscall proxy. A payload with bit 0 set is the exit of the
+//   program, with the exit code in the other bits. Any other payload is the
+//   address of 8 words: the number of a system call and its arguments, which the
+//   emulator does on the host files as the front-end server of Spike does. The
+//   result is written to the first word, and the response is 1. The arguments
+//   are the ones of Linux, except that a path is followed by its length, e.g.
+//   openat(dirfd, path, length, flags, mode), and struct stat has no nanoseconds.
+// - Device 1 is the console. The command 1 writes the byte of the payload to the
+//   UART, and the command 0 asks for a byte of input, which is the response when
+//   one is typed.
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::cpu::*;
+use crate::elf::*;
+use crate::sbi;
+use crate::syscall::{self, Call, Process};
+
+// The devices and their commands
+pub const HTIF_DEVICE_SYSCALL: u64 = 0;
+pub const HTIF_DEVICE_CONSOLE: u64 = 1;
+pub const HTIF_CONSOLE_GETCHAR: u64 = 0;
+pub const HTIF_CONSOLE_PUTCHAR: u64 = 1;
+
+// The system call of the front-end server which gives argc and argv
+pub const SYS_GETMAINVARS: u64 = 2011;
+
+// The value of tohost or fromhost
+pub fn command(device: u64, command: u64, payload: u64) -> u64 {
+    device << 56 | command << 48 | payload & 0xffff_ffff_ffff
+}
+
+// The addresses of tohost and fromhost in the symbols of the ELF file. They are
+// data, e.g. in riscv-pk, or labels, e.g. in the riscv-tests.
+pub fn locate(elf: &Elf) -> Option<(u64, Option<u64>)> {
+    let address = |name: &str| {
+        elf.symbols
+            .iter()
+            .find(|symbol| symbol.name == name && symbol.value != 0)
+            .map(|symbol| symbol.value)
+    };
+    Some((address("tohost")?, address("fromhost")))
+}
+
+// A command which the emulator took from tohost
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    // The program exited with the exit code.
+    Exit(u64),
+    // The program forwarded the system call.
+    Syscall(Call),
+    // The console or an unknown device
+    Other(u64),
+}
+
+pub struct Htif {
+    tohost: u64,
+    // Without fromhost, the commands get no response, e.g. in the riscv-tests.
+    fromhost: Option<u64>,
+    // The arguments of the program, whose first is the program
+    argv: Vec<String>,
+    // The host files of the syscall proxy
+    process: Process,
+    // The responses to write to fromhost in order
+    responses: VecDeque<u64>,
+    // The number of console reads which wait for input
+    reads: usize,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>, argv: &[String]) -> io::Result<Self> {
+        Ok(Self {
+            tohost,
+            fromhost,
+            argv: argv.to_vec(),
+            process: Process::proxy()?,
+            responses: VecDeque::new(),
+            reads: 0,
+        })
+    }
+
+    pub fn tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    pub fn fromhost(&self) -> Option<u64> {
+        self.fromhost
+    }
+
+    // The host files of the syscall proxy, e.g. to redirect its stdout
+    pub fn process_mut(&mut self) -> &mut Process {
+        &mut self.process
+    }
+
+    // Take the command in tohost if any, and write a response to fromhost if the
+    // program read the previous one. This is called after every instruction.
+    pub fn poll(&mut self, cpu: &mut Cpu) -> Option<Request> {
+        // A byte typed for a console read
+        if self.reads > 0 {
+            if let Some(byte) = sbi::getchar(cpu) {
+                self.reads -= 1;
+                let response = command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR, 0x100 | byte as u64);
+                self.responses.push_back(response);
+            }
+        }
+        let request = match cpu.bus.load(self.tohost, 64) {
+            Ok(0) | Err(_) => None,
+            Ok(value) => self.take(cpu, value),
+        };
+        if let (Some(fromhost), Some(response)) = (self.fromhost, self.responses.front()) {
+            if cpu.bus.load(fromhost, 64) == Ok(0) {
+                let _ = cpu.bus.store(fromhost, 64, *response);
+                self.responses.pop_front();
+            }
+        }
+        request
+    }
+
+    // Do the command `value` of tohost. A system call which reads the console waits
+    // for input by leaving the command in tohost.
+    fn take(&mut self, cpu: &mut Cpu, value: u64) -> Option<Request> {
+        let device = value >> 56;
+        let cmd = value >> 48 & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+        let request = match (device, cmd) {
+            (HTIF_DEVICE_SYSCALL, 0) if payload & 1 == 1 => Request::Exit(payload >> 1),
+            (HTIF_DEVICE_SYSCALL, 0) => {
+                let call = self.syscall(cpu, payload)?;
+                self.responses.push_back(command(device, cmd, 1));
+                Request::Syscall(call)
+            }
+            (HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR) => {
+                sbi::putchar(cpu, payload as u8);
+                self.responses.push_back(command(device, cmd, 0x100 | payload & 0xff));
+                Request::Other(value)
+            }
+            (HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR) => {
+                self.reads += 1;
+                Request::Other(value)
+            }
+            _ => Request::Other(value),
+        };
+        let _ = cpu.bus.store(self.tohost, 64, 0);
+        Some(request)
+    }
+
+    // Do the system call whose number and arguments are at `addr`, and write the
+    // result there. Return None if it waits for input.
+    fn syscall(&mut self, cpu: &mut Cpu, addr: u64) -> Option<Call> {
+        let mut words = [0; 8];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = cpu.bus.load(addr + 8 * i as u64, 64).unwrap_or(0);
+        }
+        let number = words[0];
+        // Drop the lengths of the paths to get the arguments of Linux.
+        let args = match number {
+            syscall::NR_OPENAT
+            | syscall::NR_NEWFSTATAT
+            | syscall::NR_FACCESSAT
+            | syscall::NR_UNLINKAT
+            | syscall::NR_MKDIRAT => [words[1], words[2], words[4], words[5], words[6], words[7]],
+            syscall::NR_LINKAT | syscall::NR_RENAMEAT => {
+                [words[1], words[2], words[4], words[5], words[7], 0]
+            }
+            _ => [words[1], words[2], words[3], words[4], words[5], words[6]],
+        };
+        let call = |result| Call {
+            number,
+            args,
+            result,
+            exit: None,
+        };
+        let call = match number {
+            syscall::NR_READ if self.process.is_console_input(args[0]) => {
+                call(self.read_console(cpu, args[1], args[2])?)
+            }
+            SYS_GETMAINVARS => call(self.getmainvars(cpu, args[0], args[1])),
+            // The memory is the one of the proxy kernel.
+            syscall::NR_BRK | syscall::NR_MMAP | syscall::NR_MUNMAP | syscall::NR_MREMAP => {
+                call(-syscall::ENOSYS)
+            }
+            _ => self.process.syscall(cpu, number, args),
+        };
+        let _ = cpu.bus.store(addr, 64, call.result as u64);
+        Some(call)
+    }
+
+    // Read the bytes typed so far, or return None if there is none.
+    fn read_console(&mut self, cpu: &mut Cpu, buffer: u64, length: u64) -> Option<i64> {
+        let mut bytes = Vec::new();
+        while (bytes.len() as u64) < length {
+            match sbi::getchar(cpu) {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        if bytes.is_empty() && length != 0 {
+            return None;
+        }
+        Some(match syscall::write_bytes(cpu, buffer, &bytes) {
+            Ok(_) => bytes.len() as i64,
+            Err(errno) => -errno,
+        })
+    }
+
+    // Write argc, argv, a NULL envp and the strings to the buffer, as the
+    // front-end server of Spike does.
+    fn getmainvars(&mut self, cpu: &mut Cpu, buffer: u64, limit: u64) -> i64 {
+        let mut words = vec![self.argv.len() as u64];
+        let mut strings = Vec::new();
+        let mut addr = buffer + 8 * (self.argv.len() as u64 + 3);
+        for arg in self.argv.iter() {
+            words.push(addr);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0);
+            addr += arg.len() as u64 + 1;
+        }
+        words.extend_from_slice(&[0, 0]);
+        if addr - buffer > limit {
+            return -syscall::ENOMEM;
+        }
+        let written = syscall::write_words(cpu, buffer, &words)
+            .and_then(|_| syscall::write_bytes(cpu, buffer + 8 * words.len() as u64, &strings));
+        match written {
+            Ok(_) => 0,
+            Err(errno) => -errno,
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// The riscvemu library: a RISC-V emulator which can be embedded, e.g. in test
+// harnesses, with the Emulator API of the emulator module. The riscvemu binary
+// (main.rs) is the command line interface built on it.
+
+pub mod cpu;
+pub mod mmu;
+pub mod rvc;
+pub mod fpu;
+pub mod bus;
+pub mod memory;
+pub mod trap;
+pub mod clint;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+pub mod disasm;
+pub mod trace;
+pub mod lockstep;
+pub mod elf;
+pub mod riscv_tests;
+pub mod signature;
+pub mod symbols;
+pub mod gdb;
+pub mod monitor;
+pub mod snapshot;
+pub mod replay;
+pub mod reverse;
+pub mod emulator;
+pub mod config;
+pub mod fdt;
+pub mod boot;
+pub mod sbi;
+pub mod semihosting;
+pub mod syscall;
+pub mod htif;
@@ -0,0 +1,246 @@
+// The lockstep module compares the emulator against a reference commit log,
+// e.g. one produced by `spike --log-commits` or by an RTL simulation that
+// writes the same format. After every retired instruction the state of the
+// emulator is compared with the reference, and the run stops at the first
+// instruction where they differ.
+
+use std::fmt;
+use std::io::BufRead;
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::rvc::*;
+use crate::trace::*;
+
+// The first instruction at which the emulator and the reference differ.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    // The number of instructions which retired before the mismatch.
+    pub count: u64,
+    // The line in the reference log.
+    pub line: usize,
+    // The record of the emulator
+    pub actual: Record,
+    // The record of the reference, None if the reference log ended.
+    pub expected: Option<Record>,
+    // The differences found, one per line.
+    pub differences: Vec<String>,
+}
+
+pub struct Lockstep {
+    reference: Box<dyn BufRead>,
+    // The line number of the last line read from the reference.
+    line: usize,
+    // The integer registers of the reference. They start as a copy of the
+    // registers of the emulator and are updated by the writes in the reference.
+    regs: [u64; REGISTER_NUMBER],
+    // The floating-point registers of the reference, updated the same way
+    fregs: [u64; REGISTER_NUMBER],
+    // False until the first reference record at the pc of the emulator is found.
+    // Records before it (e.g. Spike's boot ROM at 0x1000) are skipped.
+    synced: bool,
+    // The number of instructions compared so far.
+    count: u64,
+}
+
+impl Lockstep {
+    pub fn new(reference: Box<dyn BufRead>, cpu: &Cpu) -> Self {
+        Self {
+            reference,
+            line: 0,
+            regs: cpu.regs,
+            fregs: cpu.fregs,
+            synced: false,
+            count: 0,
+        }
+    }
+
+    // Read the next commit line from the reference. Other lines are skipped.
+    fn next_record(&mut self) -> Option<Record> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reference.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {
+                    self.line += 1;
+                    if let Some(record) = Record::parse(&line) {
+                        return Some(record);
+                    }
+                }
+            }
+        }
+    }
+
+    // Compare the state of `cpu` after the instruction of `actual` retired with the
+    // next record of the reference.
+    pub fn check(&mut self, cpu: &Cpu, actual: &Record) -> Result<(), Box<Mismatch>> {
+        let mut expected = self.next_record();
+        if !self.synced {
+            while let Some(record) = &expected {
+                if record.pc == actual.pc {
+                    break;
+                }
+                expected = self.next_record();
+            }
+            self.synced = true;
+        }
+        let expected = match expected {
+            Some(expected) => expected,
+            None => {
+                return Err(self.mismatch(
+                    actual.clone(),
+                    None,
+                    vec!["the reference log ended".to_string()],
+                ))
+            }
+        };
+
+        let mut differences = Vec::new();
+        if expected.pc != actual.pc {
+            differences.push(format!(
+                "pc: expected 0x{:016x}, actual 0x{:016x}",
+                expected.pc, actual.pc
+            ));
+        }
+        if expected.inst != actual.inst {
+            differences.push(format!(
+                "instruction: expected 0x{:08x} ({}), actual 0x{:08x} ({})",
+                expected.inst,
+                disassemble(decompress(expected.inst)),
+                actual.inst,
+                disassemble(decompress(actual.inst))
+            ));
+        }
+        if expected.mode != actual.mode {
+            differences.push(format!(
+                "privilege mode: expected {}, actual {}",
+                expected.mode, actual.mode
+            ));
+        }
+
+        // Registers: compare the whole register file, so that a write done only by
+        // the emulator is found as well.
+        for effect in expected.effects.iter() {
+            match *effect {
+                Effect::Reg(reg, value) if reg < REGISTER_NUMBER => self.regs[reg] = value,
+                Effect::FReg(reg, value) if reg < REGISTER_NUMBER => self.fregs[reg] = value,
+                _ => {}
+            }
+        }
+        for (reg, name) in XREG_NAMES.iter().enumerate().skip(1) {
+            if self.regs[reg] != cpu.regs[reg] {
+                differences.push(format!(
+                    "x{}({}): expected 0x{:016x}, actual 0x{:016x}",
+                    reg, name, self.regs[reg], cpu.regs[reg]
+                ));
+                // Report the difference only once.
+                self.regs[reg] = cpu.regs[reg];
+            }
+        }
+        for (reg, name) in FREG_NAMES.iter().enumerate() {
+            if self.fregs[reg] != cpu.fregs[reg] {
+                differences.push(format!(
+                    "f{}({}): expected 0x{:016x}, actual 0x{:016x}",
+                    reg, name, self.fregs[reg], cpu.fregs[reg]
+                ));
+                self.fregs[reg] = cpu.fregs[reg];
+            }
+        }
+
+        // CSRs: compare the CSRs written by either side.
+        let csr_writes = |record: &Record| {
+            record
+                .effects
+                .iter()
+                .filter_map(|effect| match *effect {
+                    Effect::Csr(addr, value) => Some((addr, value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        for (addr, value) in csr_writes(&expected) {
+            if addr < CSRS_NUMBER && cpu.read_csr(addr) != value {
+                differences.push(format!(
+                    "{}: expected 0x{:016x}, actual 0x{:016x}",
+                    csr_name(addr).unwrap_or("unknown csr"),
+                    value,
+                    cpu.read_csr(addr)
+                ));
+            }
+        }
+        let expected_csrs = csr_writes(&expected);
+        for (addr, value) in csr_writes(actual) {
+            if !expected_csrs.iter().any(|(a, _)| *a == addr) {
+                differences.push(format!(
+                    "{}: written with 0x{:016x}, but not written by the reference",
+                    csr_name(addr).unwrap_or("unknown csr"),
+                    value
+                ));
+            }
+        }
+
+        // Memory: compare the stores.
+        let stores = |record: &Record| {
+            record
+                .effects
+                .iter()
+                .filter(|effect| matches!(effect, Effect::Store(..)))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        let (expected_stores, actual_stores) = (stores(&expected), stores(actual));
+        if expected_stores != actual_stores {
+            differences.push(format!(
+                "stores: expected {:x?}, actual {:x?}",
+                expected_stores, actual_stores
+            ));
+        }
+
+        if !differences.is_empty() {
+            return Err(self.mismatch(actual.clone(), Some(expected), differences));
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn mismatch(
+        &self,
+        actual: Record,
+        expected: Option<Record>,
+        differences: Vec<String>,
+    ) -> Box<Mismatch> {
+        Box::new(Mismatch {
+            count: self.count,
+            line: self.line,
+            actual,
+            expected,
+            differences,
+        })
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "lockstep: mismatch after {} matching instructions (reference line {})",
+            self.count, self.line
+        )?;
+        writeln!(
+            f,
+            "  pc 0x{:016x} (0x{:08x}) {}",
+            self.actual.pc,
+            self.actual.inst,
+            disassemble(decompress(self.actual.inst))
+        )?;
+        writeln!(f, "  actual:   {}", self.actual)?;
+        if let Some(expected) = &self.expected {
+            writeln!(f, "  expected: {}", expected)?;
+        }
+        for difference in self.differences.iter() {
+            writeln!(f, "  {}", difference)?;
+        }
+        Ok(())
+    }
+}
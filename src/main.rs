@@ -1,79 +1,433 @@
-pub mod cpu;
-pub mod bus;
-pub mod memory;
-pub mod trap;
-pub mod clint;
-pub mod plic;
-pub mod uart;
-
 use std::env;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use cpu::*;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process;
+
+use riscvemu::clint::*;
+use riscvemu::config::*;
+use riscvemu::emulator::*;
+use riscvemu::monitor::*;
+use riscvemu::trace::*;
+use riscvemu::{gdb, riscv_tests, signature, syscall};
+
+const USAGE: &str = "Usage: riscvemu [<machine options>] [-l] [--log-commits] [--log-symbols] [--log-sbi] [--log=<trace file>] [--lockstep=<reference log>] [--monitor] [--handle-traps] [--restore=<snapshot>] [--record=<file> | --replay=<file>] [--time=deterministic[:<instructions per tick>] | --time=realtime] [<file name>]
+       riscvemu [<machine options>] --gdb=<port | host:port | unix:path> [<file name>]
+       riscvemu --signature=<signature file> [--signature-granularity=<bytes>] <ELF file>
+       riscvemu --riscv-tests <test or directory>...
+       riscvemu [-l] [--log-commits] [--log-sbi] [--log=<trace file>] [--time=...] --user <ELF file> [<argument>...]
+       riscvemu [<machine options>] [-l] [--log-commits] [--log-sbi] [--log=<trace file>] --htif <ELF file> [<argument>...]
+
+Machine options, which override the machine description given by --config:
+  --config=<file>          read the machine description (TOML)
+  --memory-base=<address>  the address of the RAM
+  --memory-size=<size>     the size of the RAM, e.g. 256M
+  --firmware=<file>        the firmware, which runs the kernel
+  --kernel=<file>          the kernel, the same as <file name>
+  --kernel-addr=<address>  where the kernel is loaded for the firmware
+  --initrd=<file>          the initial ramdisk
+  --initrd-addr=<address>  where the initial ramdisk is loaded
+  --disk=<file>            the disk image of the virtio block device
+  --virtio-version=<1 | 2> the virtio-mmio interface of the disk, 1 (legacy) by default
+  --harts=<number>         the number of harts
+  --isa=<ISA string>       the ISA, e.g. rv64ia_zicsr
+  --bootargs=<string>      the command line of the kernel in the device tree
+  --dtb=<file>             the device tree blob instead of the generated one
+  --no-dtb                 start without a device tree
+  --reset-vector           start at the reset ROM at 0x1000, also without firmware
+  --sbi                    run the kernel in S-mode with the built-in SBI instead of firmware
+  --semihosting=<dir>      service the semihosting calls with the files in the directory
+  --dump-dtb=<file>        write the device tree blob to the file and exit";
+
+// Print the error and the usage, and exit.
+fn usage(message: &str) -> ! {
+    eprintln!("riscvemu: {}", message);
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
-use crate::trap::*;
+// Parse the value of an option with `parse`, or exit with the usage.
+fn value<T>(arg: &str, parse: impl Fn(&str) -> Option<T>) -> T {
+    let (option, value) = arg.split_once('=').unwrap_or((arg, ""));
+    match parse(value) {
+        Some(value) => value,
+        None => usage(&format!("invalid value of {}: {}", option, value)),
+    }
+}
 
 fn main() -> io::Result<()> {
-    println!("Hello,RISC-V Emulator!");
     // get data from command line & get cammand length 
     let args: Vec<String> = env::args().collect();
+    // --user runs a static Linux program and --htif a program for Spike, e.g. riscv-pk,
+    // with the arguments after the program, and the output of the program is the
+    // only output
+    let program = args.iter().position(|arg| arg == "--user" || arg == "--htif");
+    let user = program.filter(|i| args[*i] == "--user");
+    if program.is_none() {
+        println!("Hello,RISC-V Emulator!");
+    }
 
-    // check command line length of argments
-    // if not length is 2, cammnd is wrong
-    if args.len() != 2 {
-        panic!("Usage: riscvemu <file name>");
+    // --riscv-tests runs the riscv-tests ISA tests given as files or directories
+    // and exits with 1 if any of them failed
+    if args.len() > 2 && args[1] == "--riscv-tests" {
+        if !riscv_tests::run_suite(&args[2..])? {
+            process::exit(1);
+        }
+        return Ok(());
     }
 
-    // read file
-    let mut file = File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary)?;
-    // set up Cpu & set binary read from file to cpu memory
-    let mut cpu = Cpu::new(binary);
+    // check command line argments
+    // -l and --log-commits print an instruction trace in the format of Spike,
+    // to stderr or to the file given by --log=<trace file>
+    // --log-symbols appends function+offset of the pc to the trace
+    // --log-sbi traces only the calls to the built-in SBI, the semihosting calls, the
+    // system calls of a Linux program or of the HTIF syscall proxy and the traps
+    // --lockstep compares every retired instruction with a reference commit log
+    // --monitor enters the monitor console before the first instruction, which is
+    // also entered by typing Ctrl-A c
+    // --restore starts from the state saved in a snapshot instead of the state after
+    // loading the file, which then only gives the symbols and can be omitted
+    // --record writes the console input and the other nondeterministic events with the
+    // instruction they happened at to a file, and --replay reproduces the run from it
+    // --time=deterministic advances mtime by one every <instructions per tick> instructions
+    // (the default), --time=realtime advances it by the host clock
+    // --gdb waits for GDB to connect and runs the program under its control
+    // --config reads the machine description, and the machine options such as
+    // --memory-size override it (see USAGE)
+    // --signature runs a riscv-arch-test test until it writes to tohost and writes
+    // the memory between begin_signature and end_signature to the signature file
+    let mut disassembly = false;
+    let mut commits = false;
+    let mut log_symbols = false;
+    let mut log_sbi = false;
+    let mut log = None;
+    let mut reference = None;
+    let mut signature = None;
+    let mut gdb = None;
+    let mut start_monitor = false;
+    let mut restore = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut time_mode = TimeMode::Deterministic(DEFAULT_INSTRUCTIONS_PER_TICK);
+    let mut granularity = signature::DEFAULT_GRANULARITY;
+    let mut filename = None;
+    let mut config_file = None;
+    let mut memory_base = None;
+    let mut memory_size = None;
+    let mut firmware = None;
+    let mut kernel_addr = None;
+    let mut initrd = None;
+    let mut initrd_addr = None;
+    let mut disk = None;
+    let mut virtio_version = None;
+    let mut harts = None;
+    let mut isa = None;
+    let mut bootargs = None;
+    let mut dtb = None;
+    let mut no_dtb = false;
+    let mut reset_vector = false;
+    let mut sbi = false;
+    let mut semihosting = None;
+    let mut handle_traps = false;
+    let mut dump_dtb = None;
+    for arg in &args[1..program.unwrap_or(args.len())] {
+        match arg.as_str() {
+            "-l" => disassembly = true,
+            "--log-commits" => commits = true,
+            "--log-symbols" => log_symbols = true,
+            "--log-sbi" => log_sbi = true,
+            "--monitor" => start_monitor = true,
+            _ if arg.starts_with("--log=") => log = Some(arg["--log=".len()..].to_string()),
+            _ if arg.starts_with("--lockstep=") => {
+                reference = Some(arg["--lockstep=".len()..].to_string())
+            }
+            _ if arg.starts_with("--restore=") => {
+                restore = Some(arg["--restore=".len()..].to_string())
+            }
+            _ if arg.starts_with("--record=") => {
+                record = Some(arg["--record=".len()..].to_string())
+            }
+            _ if arg.starts_with("--replay=") => {
+                replay = Some(arg["--replay=".len()..].to_string())
+            }
+            _ if arg.starts_with("--time=") => time_mode = value(arg, TimeMode::parse),
+            _ if arg.starts_with("--gdb=") => gdb = Some(arg["--gdb=".len()..].to_string()),
+            _ if arg.starts_with("--signature=") => {
+                signature = Some(arg["--signature=".len()..].to_string())
+            }
+            _ if arg.starts_with("--signature-granularity=") => {
+                granularity = value(arg, |v| v.parse().ok())
+            }
+            _ if arg.starts_with("--config=") => {
+                config_file = Some(arg["--config=".len()..].to_string())
+            }
+            _ if arg.starts_with("--memory-base=") => memory_base = Some(value(arg, parse_integer)),
+            _ if arg.starts_with("--memory-size=") => memory_size = Some(value(arg, parse_size)),
+            _ if arg.starts_with("--firmware=") => {
+                firmware = Some(PathBuf::from(&arg["--firmware=".len()..]))
+            }
+            _ if arg.starts_with("--kernel=") => {
+                filename = Some(arg["--kernel=".len()..].to_string())
+            }
+            _ if arg.starts_with("--kernel-addr=") => kernel_addr = Some(value(arg, parse_integer)),
+            _ if arg.starts_with("--initrd=") => {
+                initrd = Some(PathBuf::from(&arg["--initrd=".len()..]))
+            }
+            _ if arg.starts_with("--initrd-addr=") => initrd_addr = Some(value(arg, parse_integer)),
+            _ if arg.starts_with("--disk=") => disk = Some(PathBuf::from(&arg["--disk=".len()..])),
+            _ if arg.starts_with("--virtio-version=") => {
+                virtio_version = Some(value(arg, |v| v.parse().ok()))
+            }
+            _ if arg.starts_with("--harts=") => harts = Some(value(arg, |v| v.parse().ok())),
+            _ if arg.starts_with("--isa=") => isa = Some(arg["--isa=".len()..].to_string()),
+            _ if arg.starts_with("--bootargs=") => {
+                bootargs = Some(arg["--bootargs=".len()..].to_string())
+            }
+            _ if arg.starts_with("--dtb=") => dtb = Some(PathBuf::from(&arg["--dtb=".len()..])),
+            "--no-dtb" => no_dtb = true,
+            "--reset-vector" => reset_vector = true,
+            "--sbi" => sbi = true,
+            _ if arg.starts_with("--semihosting=") => {
+                semihosting = Some(PathBuf::from(&arg["--semihosting=".len()..]))
+            }
+            "--handle-traps" => handle_traps = true,
+            _ if arg.starts_with("--dump-dtb=") => {
+                dump_dtb = Some(arg["--dump-dtb=".len()..].to_string())
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if filename.is_none() && !arg.starts_with('-') => filename = Some(arg.clone()),
+            _ => usage(&format!("unknown option: {}", arg)),
+        }
+    }
+    // The machine: the description of --config, overridden by the options
+    let mut config = match config_file {
+        Some(path) => match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+    // The program of --user or --htif and its arguments, whose first is the program
+    let program_args = program.map(|i| args[i + 1..].to_vec());
+    if let (Some(i), Some(program_args)) = (program, &program_args) {
+        match program_args.first() {
+            Some(program) if filename.is_none() => filename = Some(program.clone()),
+            Some(_) => usage(&format!("{} is given a file name", args[i])),
+            None => usage(&format!("{} needs a program", args[i])),
+        }
+    }
+    if let Some(filename) = &filename {
+        config.kernel = Some(PathBuf::from(filename));
+    }
+    config.memory_base = memory_base.unwrap_or(config.memory_base);
+    config.memory_size = memory_size.unwrap_or(config.memory_size);
+    config.firmware = firmware.or(config.firmware);
+    config.kernel_addr = kernel_addr.or(config.kernel_addr);
+    config.initrd = initrd.or(config.initrd);
+    config.initrd_addr = initrd_addr.or(config.initrd_addr);
+    config.disk = disk.or(config.disk);
+    config.virtio_version = virtio_version.unwrap_or(config.virtio_version);
+    config.harts = harts.unwrap_or(config.harts);
+    config.isa = isa.unwrap_or(config.isa);
+    config.bootargs = bootargs.or(config.bootargs);
+    config.dtb = dtb.or(config.dtb);
+    config.reset_vector |= reset_vector;
+    config.sbi |= sbi;
+    config.semihosting = semihosting.or(config.semihosting);
+    if no_dtb {
+        config.dtb = None;
+        config.generate_dtb = false;
+    }
+    if config.firmware.is_none() && config.kernel.is_none() && restore.is_none() && dump_dtb.is_none() {
+        usage("no program to run");
+    }
 
-    loop {
-        // fetch
-        // break when error occur
-        let inst = match cpu.fetch(){
-            Ok(inst) => inst,
-            Err(exception) => {
-                exception.take_trap(&mut cpu);
-                println!("exception: {:?}", exception);
-                break;
-            },
+    let tracer = if disassembly || commits || log_sbi {
+        let output: Box<dyn Write> = match log {
+            Some(log) => Box::new(BufWriter::new(File::create(log)?)),
+            None => Box::new(io::stderr()),
         };
+        Some(Tracer::new(output, disassembly, commits, log_symbols))
+    } else {
+        None
+    };
 
-        // add 4 to the programm counter
-        cpu.pc += 4;
+    if let Some(signature) = signature {
+        // read file
+        let mut binary = Vec::new();
+        if let Some(filename) = &filename {
+            File::open(filename)?.read_to_end(&mut binary)?;
+        }
+        let outcome = signature::run_signature(&binary, &signature, granularity)?;
+        println!("{:?}", outcome);
+        if outcome != riscv_tests::Outcome::Pass {
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
-        // decode & execute
-        // break when error occur
-        match cpu.execute(inst){
-            Ok(_) => {},
-            Err(exception) => {
-                exception.take_trap(&mut cpu);
-                println!("exception: {:?}", exception);
-                break;
-            },
+    // set up the emulator & load the images read from files
+    // an ELF file is loaded segment by segment and starts at its entry point,
+    // other files are flat binaries loaded at the start of the memory
+    // A kernel started by firmware or the built-in SBI, or a program for Spike,
+    // handles its own exceptions, e.g. page faults, so they do not stop the run.
+    let kernel_handles_traps = handle_traps
+        || config.sbi
+        || config.firmware.is_some()
+        || (program.is_some() && user.is_none());
+    // A Linux program gets the environment of the emulator.
+    let envp: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+    let emulator = config.builder().and_then(|mut builder| {
+        builder = match &program_args {
+            Some(argv) if user.is_some() => builder.user(argv, &envp),
+            Some(argv) => builder.htif(argv),
+            None => builder,
         };
+        if let Some(path) = &restore {
+            builder = builder.restore(path);
+        }
+        if let Some(tracer) = tracer {
+            builder = builder.tracer(tracer);
+        }
+        if let Some(reference) = reference {
+            let file = File::open(&reference)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", reference, e)))?;
+            builder = builder.lockstep(Box::new(BufReader::new(file)));
+        }
+        if let Some(path) = &record {
+            builder = builder.record(path);
+        }
+        if let Some(path) = &replay {
+            builder = builder.replay(path);
+        }
+        builder
+            .time_mode(time_mode)
+            .stop_on_exception(!kernel_handles_traps)
+            .build()
+    });
+    let mut emulator = match emulator {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            eprintln!("riscvemu: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(path) = dump_dtb {
+        match emulator.device_tree() {
+            Some((addr, size)) => {
+                let dtb = emulator.read_memory(addr, size).expect("the DTB is not in memory");
+                File::create(path)?.write_all(&dtb)?;
+            }
+            None => usage("--dump-dtb needs a device tree"),
+        }
+        return Ok(());
+    }
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => interrupt.take_trap(&mut cpu),
-            None => {}
+    // Send the input to the monitor from the start, not to the guest.
+    if start_monitor {
+        emulator.cpu.bus.uart.set_monitor_active(true);
+    }
+
+    // The exit code of the program is the one of the emulator.
+    if let Some(gdb) = gdb {
+        match gdb::serve(&mut emulator, &gdb) {
+            Ok(Some(code)) => process::exit(code as i32),
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                eprintln!("riscvemu: {}", e);
+                process::exit(1);
+            }
         }
+    }
 
-        // not to infine loop
-        if cpu.pc == 0 {
-            break;
+    // Run until a stop which is not for the monitor: the monitor is entered when
+    // a `step` finished, at a breakpoint or by Ctrl-A c.
+    let mut monitor = Monitor::new();
+    let mut exit_code = None;
+    let mut action = if start_monitor {
+        monitor.enter(&mut emulator)
+    } else {
+        Action::Continue
+    };
+    loop {
+        let limit = match action {
+            Action::Step(n) => n,
+            Action::Continue => u64::MAX,
+            Action::Quit => break,
+        };
+        let reason = emulator.run_until(limit, |emulator| {
+            emulator.cpu.bus.uart.take_monitor_request() || monitor.is_breakpoint(emulator.cpu.pc)
+        });
+        // A Linux program exits with its exit code, or with 128 + the signal which
+        // Linux would have sent for the exception, as a shell reports it. So does a
+        // program for Spike.
+        if program.is_some() && reason != StopReason::InstructionLimit && reason != StopReason::Condition {
+            if let Err(e) = emulator.finish() {
+                eprintln!("replay: {}", e);
+            }
+            let code = match reason {
+                StopReason::Exit(code) => code,
+                StopReason::Exception(exception, pc) => {
+                    eprintln!("riscvemu: {:?} at {}", exception, emulator.cpu.symbols.format(pc));
+                    128 + syscall::signal(&exception)
+                }
+                reason => {
+                    eprintln!("riscvemu: {:?}", reason);
+                    1
+                }
+            };
+            process::exit(code as i32);
+        }
+        match reason {
+            StopReason::InstructionLimit | StopReason::Condition => {
+                action = monitor.enter(&mut emulator)
+            }
+            StopReason::Exception(exception, pc) => {
+                println!("exception: {:?} at {}", exception, emulator.cpu.symbols.format(pc));
+                break;
+            }
+            StopReason::Lockstep(mismatch) => {
+                print!("{}", mismatch);
+                break;
+            }
+            StopReason::Replay(e) => {
+                println!("replay: {}", e);
+                break;
+            }
+            StopReason::SystemReset(reset_type, reason) => {
+                println!("system reset: {:?} (reason {})", reset_type, reason);
+                break;
+            }
+            // The exit code of the program is the one of the emulator.
+            StopReason::Exit(code) => {
+                println!("exit: {}", code);
+                exit_code = Some(code);
+                break;
+            }
+            _ => break,
         }
     }
 
+    if let Err(e) = emulator.finish() {
+        println!("replay: {}", e);
+    }
+
     // print reg (x0 to x31)
-    cpu.dump_registers();
+    emulator.cpu.dump_registers();
 
     println!("----------------------------------------------------------------------------------------------------------");
-    cpu.dump_csrs();
-    println!("");
+    emulator.cpu.dump_csrs();
+    println!();
+    if let Some(code) = exit_code {
+        process::exit(code as i32);
+    }
     Ok(())
 }
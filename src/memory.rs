@@ -1,12 +1,34 @@
+use std::io;
+
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// Default memory size (128MB).
 pub const MEMORY_SIZE: u64 = 1024 * 1024 * 128;
+// The unit of memory in snapshots and checkpoints. Pages which are all zero are
+// not saved in snapshots.
+pub const PAGE_SIZE: usize = 4096;
+
+// A page of memory, allocated when it is first written
+type Page = Box<[u8; PAGE_SIZE]>;
+
+// The content of the pages which were never written
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
 
+// The RAM is a table of pages which are allocated when they are first written, so
+// that a large memory costs nothing until the guest uses it. Reading a page which
+// was never written gives zero.
 #[derive(Debug)]
 pub struct Memory {
-    pub memory : Vec<u8>,
+    pages: Vec<Option<Page>>,
+    // The address where the memory starts
+    base: u64,
+    // True for the pages written since the last `take_dirty_pages`, which are
+    // saved by the checkpoints of reverse execution
+    dirty: Vec<bool>,
+    // The indexes of the dirty pages
+    dirty_pages: Vec<usize>,
 }
 
 impl Device for Memory {
@@ -22,91 +44,265 @@ impl Device for Memory {
 
     fn store(&mut self,addr: u64, size:u64, value: u64) -> Result<(),Exception> {
         match size {
-            8 => Ok(self.store8(addr,value)),
-            16 => Ok(self.store16(addr,value)),
-            32 => Ok(self.store32(addr,value)),
-            64 => Ok(self.store64(addr,value)),
+            8 => {
+                self.store8(addr,value);
+                Ok(())
+            }
+            16 => {
+                self.store16(addr,value);
+                Ok(())
+            }
+            32 => {
+                self.store32(addr,value);
+                Ok(())
+            }
+            64 => {
+                self.store64(addr,value);
+                Ok(())
+            }
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    // Save the pages which are not all zero.
+    fn save(&self, writer: &mut Writer) {
+        writer.section("memory");
+        writer.u64(self.size());
+        for index in self.allocated_pages() {
+            let page = self.page(index);
+            if page.iter().any(|byte| *byte != 0) {
+                writer.u64(index as u64);
+                writer.bytes(page);
+            }
+        }
+        // The end of the pages
+        writer.u64(u64::MAX);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("memory")?;
+        let size = reader.u64()?;
+        if size != self.size() {
+            return Err(invalid(format!(
+                "the memory size is {} bytes, expected {}",
+                size,
+                self.size()
+            )));
+        }
+        self.pages.iter_mut().for_each(|page| *page = None);
+        loop {
+            let index = reader.u64()?;
+            if index == u64::MAX {
+                return Ok(());
+            }
+            let page = reader.bytes()?;
+            if index as usize >= self.pages.len() || page.len() > PAGE_SIZE {
+                return Err(invalid(format!("page {} is out of memory", index)));
+            }
+            self.set_page(index as usize, page);
+        }
+    }
 }
 
 impl Memory {
-    pub fn new(binary: Vec<u8>) -> Memory {
-        let mut memory = vec![0; MEMORY_SIZE as usize];
-        memory.splice(..binary.len(), binary.iter().cloned());
+    // Create a memory of `size` bytes, a multiple of PAGE_SIZE, at `base` with the
+    // binary at the start.
+    pub fn new(binary: Vec<u8>, base: u64, size: u64) -> Memory {
+        let count = size as usize / PAGE_SIZE;
+        let mut memory = Self {
+            pages: vec![None; count],
+            base,
+            dirty: vec![false; count],
+            dirty_pages: Vec::new(),
+        };
+        memory.write_bytes(base, &binary);
+        memory
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    // The size in bytes
+    pub fn size(&self) -> u64 {
+        (self.pages.len() * PAGE_SIZE) as u64
+    }
+
+    // The indexes of the pages which were written, the others are zero.
+    pub fn allocated_pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.is_some())
+            .map(|(index, _)| index)
+    }
+
+    // Return the pages written since the last call, and forget them.
+    pub fn take_dirty_pages(&mut self) -> Vec<usize> {
+        for page in self.dirty_pages.iter() {
+            self.dirty[*page] = false;
+        }
+        std::mem::take(&mut self.dirty_pages)
+    }
+
+    pub fn page(&self, index: usize) -> &[u8] {
+        match &self.pages[index] {
+            Some(page) => &page[..],
+            None => &ZERO_PAGE,
+        }
+    }
+
+    // Overwrite a page, e.g. to go back to a checkpoint. The page does not become
+    // dirty. A page of zero is freed.
+    pub fn set_page(&mut self, index: usize, data: &[u8]) {
+        if data.iter().all(|byte| *byte == 0) {
+            self.pages[index] = None;
+        } else {
+            self.page_mut(index)[..data.len()].copy_from_slice(data);
+        }
+    }
+
+    // The page at `index`, allocated if it was never written
+    fn page_mut(&mut self, index: usize) -> &mut [u8; PAGE_SIZE] {
+        self.pages[index].get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    // Copy the bytes at `addr` to `data`.
+    pub fn read_bytes(&self, addr: u64, data: &mut [u8]) {
+        let mut index = (addr - self.base) as usize;
+        for chunk in data.chunks_mut(PAGE_SIZE) {
+            // The part of the chunk in the page of `index`
+            let offset = index % PAGE_SIZE;
+            let length = chunk.len().min(PAGE_SIZE - offset);
+            let page = self.page(index / PAGE_SIZE);
+            chunk[..length].copy_from_slice(&page[offset..offset + length]);
+            let rest = &mut chunk[length..];
+            if !rest.is_empty() {
+                let page = self.page(index / PAGE_SIZE + 1);
+                rest.copy_from_slice(&page[..rest.len()]);
+            }
+            index += chunk.len();
+        }
+    }
+
+    // Copy `data` to `addr`, e.g. to load an image. The pages do not become dirty.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) {
+        let mut index = (addr - self.base) as usize;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = index % PAGE_SIZE;
+            let length = data.len().min(PAGE_SIZE - offset);
+            // Pages of zero stay unallocated.
+            if data[..length].iter().any(|byte| *byte != 0) {
+                self.page_mut(index / PAGE_SIZE)[offset..offset + length]
+                    .copy_from_slice(&data[..length]);
+            }
+            index += length;
+            data = &data[length..];
+        }
+    }
+
+    // Fill the `length` bytes at `addr` with zero, e.g. for a new mapping of a
+    // Linux program. The pages which are entirely zeroed are freed.
+    pub fn clear(&mut self, addr: u64, length: u64) {
+        let start = (addr - self.base) as usize;
+        let end = start + length as usize;
+        let mut index = start;
+        while index < end {
+            let page = index / PAGE_SIZE;
+            let offset = index % PAGE_SIZE;
+            let length = (end - index).min(PAGE_SIZE - offset);
+            if self.pages[page].is_some() {
+                self.mark_dirty(index, length);
+                if length == PAGE_SIZE {
+                    self.pages[page] = None;
+                } else {
+                    self.page_mut(page)[offset..offset + length].fill(0);
+                }
+            }
+            index += length;
+        }
+    }
+
+    // Mark the pages of `size` bytes at `index` as written.
+    fn mark_dirty(&mut self, index: usize, size: usize) {
+        for page in index / PAGE_SIZE..=(index + size - 1) / PAGE_SIZE {
+            if !self.dirty[page] {
+                self.dirty[page] = true;
+                self.dirty_pages.push(page);
+            }
+        }
+    }
+
+    // Load `bytes` bytes at `addr` from the little-endian memory. An access within a
+    // page, the common case, reads the page directly.
+    fn load_bytes(&self, addr: u64, bytes: usize) -> u64 {
+        let index = (addr - self.base) as usize;
+        let offset = index % PAGE_SIZE;
+        let mut value = [0; 8];
+        if offset + bytes <= PAGE_SIZE {
+            if let Some(page) = &self.pages[index / PAGE_SIZE] {
+                value[..bytes].copy_from_slice(&page[offset..offset + bytes]);
+            }
+        } else {
+            self.read_bytes(addr, &mut value[..bytes]);
+        }
+        u64::from_le_bytes(value)
+    }
 
-        Self { memory }
+    // Store the low `bytes` bytes of `val` at `addr` to the little-endian memory.
+    fn store_bytes(&mut self, addr: u64, bytes: usize, val: u64) {
+        let index = (addr - self.base) as usize;
+        let offset = index % PAGE_SIZE;
+        self.mark_dirty(index, bytes);
+        let value = val.to_le_bytes();
+        if offset + bytes <= PAGE_SIZE {
+            self.page_mut(index / PAGE_SIZE)[offset..offset + bytes].copy_from_slice(&value[..bytes]);
+        } else {
+            for (i, byte) in value[..bytes].iter().enumerate() {
+                let index = index + i;
+                self.page_mut(index / PAGE_SIZE)[index % PAGE_SIZE] = *byte;
+            }
+        }
     }
 
     // load 1 bite from the littele-endian memory
     pub fn load8(&self, addr:u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] as u64
+        self.load_bytes(addr, 1)
     }
 
     // load 2 bites from the littele-endian memory
     pub fn load16(&self, addr:u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64) 
-            | ((self.memory[index + 1] as u64) << 8);
+        self.load_bytes(addr, 2)
     }
 
     //load 4 bites from the littele-endian memory
     pub fn load32(&self, addr:u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64)
-            | ((self.memory[index + 1] as u64) << 8)
-            | ((self.memory[index + 2] as u64) << 16)
-            | ((self.memory[index + 3] as u64) << 24);
+        self.load_bytes(addr, 4)
     }
 
     // load 8 bites from the little-endian memory
     pub fn load64(&self, addr: u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64) 
-            | ((self.memory[index + 1] as u64) << 8)
-            | ((self.memory[index + 2] as u64) << 16)
-            | ((self.memory[index + 3] as u64) << 24)
-            | ((self.memory[index + 4] as u64) << 32)
-            | ((self.memory[index + 5] as u64) << 40)
-            | ((self.memory[index + 6] as u64) << 48)
-            | ((self.memory[index + 7] as u64) << 56);
-    }
-
-    // 🍫 val & 0xff ?
+        self.load_bytes(addr, 8)
+    }
+
     // store 1 bite to the little-endian memory
     pub fn store8(&mut self, addr:u64, val:u64){
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = val as u8;
+        self.store_bytes(addr, 1, val);
     }
 
     // store 2 bites to the little-endian memory
     pub fn store16(&mut self, addr:u64, val:u64){
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (val & 0xff) as u8;
-        self.memory[index + 1] = ((val >> 8) & 0xff) as u8;
+        self.store_bytes(addr, 2, val);
     }
 
     // store 4 bites to the little-endian memory
     pub fn store32(&mut self, addr:u64, val:u64){
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (val & 0xff) as u8;
-        self.memory[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.memory[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.memory[index + 3] = ((val >> 24) & 0xff) as u8;
+        self.store_bytes(addr, 4, val);
     }
 
     // store 8 bites to the little-endian memory
     pub fn store64(&mut self,addr:u64, val:u64){
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (val & 0xff) as u8;
-        self.memory[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.memory[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.memory[index + 3] = ((val >> 24) & 0xff) as u8;
-        self.memory[index + 4] = ((val >> 32) & 0xff) as u8;
-        self.memory[index + 5] = ((val >> 40) & 0xff) as u8;
-        self.memory[index + 6] = ((val >> 48) & 0xff) as u8;
-        self.memory[index + 7] = ((val >> 56) & 0xff) as u8;
-    }
-}
\ No newline at end of file
+        self.store_bytes(addr, 8, val);
+    }
+}
@@ -0,0 +1,217 @@
+// The mmu module translates virtual addresses to physical addresses with the
+// Sv39 page-based virtual memory system of the privileged ISA manual, chapter
+// "Supervisor-Level ISA", so that a kernel such as Linux can run with paging:
+//
+// - satp gives the mode (Bare or Sv39) and the physical page number of the root
+//   page table
+// - a virtual address has three 9-bit virtual page numbers (VPN) and a 12-bit
+//   offset, and the bits above bit 38 must be copies of bit 38
+// - a page table entry (PTE) is 8 bytes: V, R, W, X, U, G, A and D in the lowest
+//   bits, and the physical page number from bit 10
+//
+// The A and D bits are set by the hardware when a page is accessed and written.
+// The translations are cached in a TLB, which is flushed by sfence.vma and by a
+// write to satp.
+
+use crate::cpu::*;
+use crate::trap::*;
+
+// The size of a page
+pub const PAGE_SHIFT: u64 = 12;
+// The number of levels of the page table of Sv39
+const LEVELS: u64 = 3;
+// The size of a PTE
+const PTE_SIZE: u64 = 8;
+
+// The fields of satp
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+// The bits of a PTE
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_G: u64 = 1 << 5;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+
+// The number of entries of the TLB
+const TLB_SIZE: usize = 256;
+
+// The kind of a memory access, which decides the permission it needs and the
+// exception it raises
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault,
+            AccessType::Load => Exception::LoadPageFault,
+            AccessType::Store => Exception::StoreAMOPageFault,
+        }
+    }
+
+    pub fn access_fault(self) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault,
+            AccessType::Load => Exception::LoadAccessFault,
+            AccessType::Store => Exception::StoreAMOAccessFault,
+        }
+    }
+}
+
+// A cached translation of a 4KB page. A superpage is cached page by page.
+#[derive(Debug, Clone, Copy, Default)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u64,
+    ppn: u64,
+    // The bits of the PTE
+    flags: u64,
+}
+
+// A direct-mapped cache of translations
+pub struct Tlb {
+    entries: Vec<TlbEntry>,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![TlbEntry::default(); TLB_SIZE],
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.valid = false;
+        }
+    }
+
+    fn entry(&mut self, vpn: u64) -> &mut TlbEntry {
+        &mut self.entries[vpn as usize % TLB_SIZE]
+    }
+}
+
+// Return true if a PTE with `flags` allows the access in `mode`.
+fn permitted(cpu: &Cpu, flags: u64, access: AccessType, mode: Mode) -> bool {
+    let status = cpu.csrs[MSTATUS];
+    let allowed = match access {
+        AccessType::Instruction => flags & PTE_X != 0,
+        // With MXR, pages which are only executable can be read.
+        AccessType::Load => flags & PTE_R != 0 || (status & MSTATUS_MXR != 0 && flags & PTE_X != 0),
+        AccessType::Store => flags & PTE_W != 0,
+    };
+    let user = flags & PTE_U != 0;
+    let mode_allowed = match mode {
+        Mode::User => user,
+        // S-mode can access the pages of U-mode with SUM, but never execute them.
+        Mode::Surpervisor => !user || (access != AccessType::Instruction && status & MSTATUS_SUM != 0),
+        Mode::Machine => true,
+    };
+    allowed && mode_allowed
+}
+
+// The privilege mode which the access is done in: a load or a store in M-mode
+// uses MPP when MPRV is set.
+fn effective_mode(cpu: &Cpu, access: AccessType) -> Mode {
+    let status = cpu.csrs[MSTATUS];
+    if access != AccessType::Instruction && cpu.mode == Mode::Machine && status & MSTATUS_MPRV != 0 {
+        return match (status >> 11) & 0b11 {
+            0b00 => Mode::User,
+            0b01 => Mode::Surpervisor,
+            _ => Mode::Machine,
+        };
+    }
+    cpu.mode
+}
+
+// Translate the virtual address `addr` to a physical address.
+pub fn translate(cpu: &mut Cpu, addr: u64, access: AccessType) -> Result<u64, Exception> {
+    let mode = effective_mode(cpu, access);
+    if mode == Mode::Machine || cpu.csrs[SATP] >> SATP_MODE_SHIFT != SATP_MODE_SV39 {
+        return Ok(addr);
+    }
+    // The bits 63 to 39 must be the same as the bit 38.
+    if ((addr as i64) << 25 >> 25) as u64 != addr {
+        return Err(access.page_fault());
+    }
+
+    let vpn = (addr >> PAGE_SHIFT) & ((1 << 27) - 1);
+    let offset = addr & ((1 << PAGE_SHIFT) - 1);
+    let entry = *cpu.tlb.entry(vpn);
+    // A write to a page which is not dirty yet walks the page table to set D.
+    if entry.valid
+        && entry.vpn == vpn
+        && (access != AccessType::Store || entry.flags & PTE_D != 0)
+    {
+        if !permitted(cpu, entry.flags, access, mode) {
+            return Err(access.page_fault());
+        }
+        return Ok(entry.ppn << PAGE_SHIFT | offset);
+    }
+
+    let mut table = (cpu.csrs[SATP] & SATP_PPN_MASK) << PAGE_SHIFT;
+    let mut level = LEVELS;
+    let (pte, pte_addr) = loop {
+        if level == 0 {
+            return Err(access.page_fault());
+        }
+        level -= 1;
+        let index = (vpn >> (9 * level)) & 0x1ff;
+        let pte_addr = table + index * PTE_SIZE;
+        let pte = cpu.bus.load(pte_addr, 64).map_err(|_| access.access_fault())?;
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(access.page_fault());
+        }
+        if pte & (PTE_R | PTE_X) != 0 {
+            break (pte, pte_addr);
+        }
+        // A pointer to the next level of the page table
+        table = ((pte >> 10) & SATP_PPN_MASK) << PAGE_SHIFT;
+    };
+
+    if !permitted(cpu, pte, access, mode) {
+        return Err(access.page_fault());
+    }
+    // A superpage must be aligned: the lower PPNs must be 0.
+    let ppn = (pte >> 10) & SATP_PPN_MASK;
+    let low_ppn_mask = (1 << (9 * level)) - 1;
+    if ppn & low_ppn_mask != 0 {
+        return Err(access.page_fault());
+    }
+
+    // Set the A bit, and the D bit for a write.
+    let mut flags = pte | PTE_A;
+    if access == AccessType::Store {
+        flags |= PTE_D;
+    }
+    if flags != pte {
+        cpu.bus.store(pte_addr, 64, flags).map_err(|_| access.access_fault())?;
+    }
+
+    // The page of a superpage has the lower VPNs of the virtual address.
+    let ppn = ppn | (vpn & low_ppn_mask);
+    *cpu.tlb.entry(vpn) = TlbEntry {
+        valid: true,
+        vpn,
+        ppn,
+        flags: flags & 0xff,
+    };
+    Ok(ppn << PAGE_SHIFT | offset)
+}
@@ -0,0 +1,336 @@
+// The monitor module is an interactive console to inspect and control the
+// emulator while it runs. It is entered at startup with --monitor, by typing
+// Ctrl-A c on the console, at a breakpoint, or when a `step` finished.
+//
+//   (monitor) break main
+//   (monitor) continue
+//   (monitor) regs
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::emulator::Emulator;
+use crate::snapshot;
+
+const HELP: &str = "\
+step [n]                  execute n instructions (default 1)
+continue                  continue until a breakpoint or Ctrl-A c
+break [addr|symbol]       set a breakpoint, or list the breakpoints
+delete <addr|symbol>      delete a breakpoint
+regs                      print the integer registers
+csrs                      print the CSRs
+x <addr|symbol> [bytes]   examine memory (default 64 bytes)
+write <addr> <value> [size]
+                          write a value of 1, 2, 4 or 8 bytes (default 8) to memory
+set <reg|pc|csr> <value>  set a register or a CSR
+save <file>               save a snapshot of the machine
+load <file>               restore a snapshot of the machine
+mode                      show the privilege mode
+interrupts                show the pending and enabled interrupts
+quit                      stop the emulator";
+
+// The names of the interrupts by bit of mip/mie.
+const INTERRUPT_NAMES: [(u64, &str); 6] = [
+    (MIP_SSIP, "supervisor software"),
+    (MIP_MSIP, "machine software"),
+    (MIP_STIP, "supervisor timer"),
+    (MIP_MTIP, "machine timer"),
+    (MIP_SEIP, "supervisor external"),
+    (MIP_MEIP, "machine external"),
+];
+
+// What the emulator does after the monitor returns.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    // Execute this number of instructions and enter the monitor again.
+    Step(u64),
+    // Run until a breakpoint or Ctrl-A c.
+    Continue,
+    // Stop the emulator.
+    Quit,
+}
+
+// A command of the monitor. The addresses are numbers or symbols, which are
+// looked up when the command runs.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Step(u64),
+    Continue,
+    Quit,
+    // List the breakpoints.
+    Breakpoints,
+    Break(String),
+    Delete(String),
+    Regs,
+    Csrs,
+    // Examine this number of bytes at the address.
+    Examine(String, u64),
+    // Write the value of this number of bytes to the address.
+    Write(String, u64, u64),
+    Set(String, u64),
+    Save(String),
+    Load(String),
+    Mode,
+    Interrupts,
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    breakpoints: Vec<u64>,
+}
+
+// Parse a number: hex with 0x, otherwise decimal.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Parse an address: a number or a symbol of the loaded ELF file.
+fn parse_addr(cpu: &Cpu, s: &str) -> Option<u64> {
+    parse_number(s).or_else(|| cpu.symbols.address(s))
+}
+
+impl Command {
+    // Parse a line of the monitor. Return None for an empty line, or the message
+    // to print if the line is not a valid command.
+    pub fn parse(line: &str) -> Result<Option<Command>, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let command = match args.first() {
+            Some(command) => *command,
+            None => return Ok(None),
+        };
+        let command = match (command, &args[1..]) {
+            ("help" | "h", _) => Command::Help,
+            ("step" | "s", []) => Command::Step(1),
+            ("step" | "s", [n]) => match parse_number(n) {
+                Some(n) if n > 0 => Command::Step(n),
+                _ => return Err(format!("invalid number of instructions: {}", n)),
+            },
+            ("continue" | "c", []) => Command::Continue,
+            ("quit" | "q", []) => Command::Quit,
+            ("break" | "b", []) => Command::Breakpoints,
+            ("break" | "b", [addr]) => Command::Break(addr.to_string()),
+            ("delete" | "d", [addr]) => Command::Delete(addr.to_string()),
+            ("regs" | "r", []) => Command::Regs,
+            ("csrs", []) => Command::Csrs,
+            ("x", [addr]) => Command::Examine(addr.to_string(), 64),
+            ("x", [addr, length]) => match parse_number(length) {
+                Some(length) => Command::Examine(addr.to_string(), length),
+                None => return Err("invalid address or length".to_string()),
+            },
+            ("write" | "w", [addr, value]) => {
+                Command::Write(addr.to_string(), parse_value(value)?, 8)
+            }
+            ("write" | "w", [addr, value, size]) => match parse_number(size) {
+                Some(size @ (1 | 2 | 4 | 8)) => {
+                    Command::Write(addr.to_string(), parse_value(value)?, size)
+                }
+                _ => return Err("invalid address, value or size".to_string()),
+            },
+            ("set", [name, value]) => match parse_number(value) {
+                Some(value) => Command::Set(name.to_string(), value),
+                None => return Err(format!("invalid value: {}", value)),
+            },
+            ("save", [path]) => Command::Save(path.to_string()),
+            ("load", [path]) => Command::Load(path.to_string()),
+            ("mode", []) => Command::Mode,
+            ("interrupts" | "i", []) => Command::Interrupts,
+            _ => {
+                return Err(format!(
+                    "unknown command: {}, type `help` for the commands",
+                    line.trim()
+                ))
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+fn parse_value(value: &str) -> Result<u64, String> {
+    parse_number(value).ok_or_else(|| "invalid address, value or size".to_string())
+}
+
+// Dump `length` bytes of memory at `addr` like hexdump, 16 bytes per line. The dump
+// ends with an error line at the first byte which cannot be accessed.
+pub fn dump_memory(cpu: &mut Cpu, addr: u64, length: u64) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in (0..length).step_by(16) {
+        let mut output = format!("{:016x}:", addr + line);
+        for i in line..(line + 16).min(length) {
+            match cpu.bus.load(addr + i, 8) {
+                Ok(byte) => output.push_str(&format!(" {:02x}", byte)),
+                Err(_) => {
+                    lines.push(output);
+                    lines.push(format!("cannot access memory at {:#x}", addr + i));
+                    return lines;
+                }
+            }
+        }
+        lines.push(output);
+    }
+    lines
+}
+
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::User => "user",
+        Mode::Surpervisor => "supervisor",
+        Mode::Machine => "machine",
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_breakpoint(&self, pc: u64) -> bool {
+        self.breakpoints.contains(&pc)
+    }
+
+    // Read and run commands until one of them resumes or stops the emulator.
+    pub fn enter(&mut self, emulator: &mut Emulator) -> Action {
+        let cpu = &mut emulator.cpu;
+        cpu.bus.uart.set_monitor_active(true);
+        println!();
+        println!(
+            "monitor: stopped at {} ({}), type `help` for the commands",
+            cpu.symbols.format(cpu.pc),
+            mode_name(cpu.mode)
+        );
+        let action = loop {
+            print!("(monitor) ");
+            io::stdout().flush().expect("failed to flush stdout");
+            let line = match emulator.cpu.bus.uart.read_monitor_line() {
+                Some(line) => line,
+                // The end of the input
+                None => break Action::Quit,
+            };
+            if let Some(action) = self.command(emulator, &line) {
+                break action;
+            }
+        };
+        emulator.cpu.bus.uart.set_monitor_active(false);
+        action
+    }
+
+    // Run a command. Return the action if the command leaves the monitor.
+    fn command(&mut self, emulator: &mut Emulator, line: &str) -> Option<Action> {
+        let cpu = &mut emulator.cpu;
+        let command = match Command::parse(line) {
+            Ok(Some(command)) => command,
+            Ok(None) => return None,
+            Err(message) => {
+                println!("{}", message);
+                return None;
+            }
+        };
+        match command {
+            Command::Help => println!("{}", HELP),
+            Command::Step(n) => return Some(Action::Step(n)),
+            Command::Continue => return Some(Action::Continue),
+            Command::Quit => return Some(Action::Quit),
+            Command::Breakpoints => {
+                for bp in self.breakpoints.iter() {
+                    println!("{}", cpu.symbols.format(*bp));
+                }
+            }
+            Command::Break(addr) => match parse_addr(cpu, &addr) {
+                Some(addr) => {
+                    if !self.breakpoints.contains(&addr) {
+                        self.breakpoints.push(addr);
+                    }
+                    println!("breakpoint at {}", cpu.symbols.format(addr));
+                }
+                None => println!("unknown address or symbol: {}", addr),
+            },
+            Command::Delete(addr) => match parse_addr(cpu, &addr) {
+                Some(addr) if self.breakpoints.contains(&addr) => {
+                    self.breakpoints.retain(|bp| *bp != addr)
+                }
+                _ => println!("no breakpoint at {}", addr),
+            },
+            Command::Regs => {
+                cpu.dump_registers();
+            }
+            Command::Csrs => {
+                cpu.dump_csrs();
+            }
+            Command::Examine(addr, length) => match parse_addr(cpu, &addr) {
+                Some(addr) => {
+                    for line in dump_memory(cpu, addr, length) {
+                        println!("{}", line);
+                    }
+                }
+                None => println!("invalid address or length"),
+            },
+            Command::Write(addr, value, size) => match parse_addr(cpu, &addr) {
+                Some(addr) => {
+                    if cpu.bus.store(addr, size * 8, value).is_err() {
+                        println!("cannot access memory at {:#x}", addr);
+                    }
+                }
+                None => println!("invalid address, value or size"),
+            },
+            Command::Set(name, value) => self.set(cpu, &name, value),
+            Command::Save(path) => match snapshot::save(emulator, &path) {
+                Ok(()) => println!("saved a snapshot to {}", path),
+                Err(e) => println!("{}: {}", path, e),
+            },
+            Command::Load(path) => match snapshot::restore(emulator, &path) {
+                Ok(()) => {
+                    let cpu = &emulator.cpu;
+                    println!("restored {}, now at {}", path, cpu.symbols.format(cpu.pc))
+                }
+                Err(e) => println!("{}: {}", path, e),
+            },
+            Command::Mode => println!("{}", mode_name(cpu.mode)),
+            Command::Interrupts => {
+                println!(
+                    "mip={:#x} mie={:#x} mideleg={:#x} mstatus.MIE={} sstatus.SIE={}",
+                    cpu.csrs[MIP],
+                    cpu.csrs[MIE],
+                    cpu.csrs[MIDELEG],
+                    (cpu.csrs[MSTATUS] & MSTATUS_MIE != 0) as u8,
+                    (cpu.csrs[MSTATUS] & MSTATUS_SIE != 0) as u8
+                );
+                for (bit, name) in INTERRUPT_NAMES.iter() {
+                    if cpu.csrs[MIP] & bit != 0 {
+                        println!(
+                            "  {} interrupt: pending, {}{}",
+                            name,
+                            if cpu.csrs[MIE] & bit != 0 { "enabled" } else { "disabled" },
+                            if cpu.csrs[MIDELEG] & bit != 0 { ", delegated" } else { "" }
+                        );
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn set(&self, cpu: &mut Cpu, name: &str, value: u64) {
+        if name == "pc" {
+            cpu.pc = value;
+            return;
+        }
+        // x1, ra, ...
+        let reg = XREG_NAMES
+            .iter()
+            .position(|reg| *reg == name)
+            .or_else(|| name.strip_prefix('x').and_then(|n| n.parse().ok()));
+        match reg {
+            Some(0) => println!("x0 is always zero"),
+            Some(reg) if reg < REGISTER_NUMBER => cpu.regs[reg] = value,
+            _ => match (0..CSRS_NUMBER).find(|addr| csr_name(*addr) == Some(name)) {
+                Some(addr) => cpu.write_csr(addr, value),
+                None => println!("unknown register: {}", name),
+            },
+        }
+    }
+}
@@ -2,25 +2,67 @@
 // The plic connects all external interrupts in the system to all hart
 // contexts in the system, via the external interrupt source in each hart.
 // It's the global interrupt controller in a RISC-V system.
+//
+// The registers are the ones of the SiFive PLIC, which Linux and xv6 drive:
+//
+// - a priority per interrupt source, 0 meaning never interrupt
+// - the pending bits of the sources
+// - per context, the enable bits of the sources, a priority threshold, and the
+//   claim/complete register
+//
+// A context is a privilege mode of a hart: context 2 * i is M-mode and context
+// 2 * i + 1 is S-mode of hart i, as in the QEMU virt machine. A context is interrupted while an enabled source with a
+// priority above its threshold is pending. Claiming takes the source with the
+// highest priority, which is not pending again until the claim is completed.
+
+use std::io;
 
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
+// The number of interrupt sources. Source 0 does not exist.
+pub const PLIC_SOURCES: usize = 64;
+// The number of contexts
+pub const PLIC_CONTEXTS: usize = 2 * MAX_HARTS;
+// The contexts of M-mode and S-mode of hart 0
+pub const PLIC_CONTEXT_M: usize = 0;
+pub const PLIC_CONTEXT_S: usize = 1;
+
+// The context of M-mode (PLIC_CONTEXT_M) or S-mode (PLIC_CONTEXT_S) of `hart`
+pub fn plic_context(hart: usize, mode: usize) -> usize {
+    2 * hart + mode
+}
+
+// The address of the priority of source 1. The priority of source i is at
+// PLIC_PRIORITY + 4 * i.
+pub const PLIC_PRIORITY: u64 = PLIC_BASE;
+const PLIC_PRIORITY_END: u64 = PLIC_PENDING - 4;
 // The address of interrupt pending bits
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-// The address of the registers to enable interrupts for S-mode
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
-// The address of the registers to a priority for S-mode
-pub const PLIC_SPIORITY: u64 = PLIC_BASE + 0x201000;
-// The address of the claim/complete registers for S-mode
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+// The address of the enable bits of context 0, and the stride of the contexts
+pub const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+// The address of the threshold of context 0, and the stride of the contexts. The
+// claim/complete register follows the threshold.
+pub const PLIC_THRESHOLD: u64 = PLIC_BASE + 0x20_0000;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+// The address of the registers to enable interrupts for S-mode of hart 0
+pub const PLIC_SENABLE: u64 = PLIC_ENABLE + PLIC_ENABLE_STRIDE;
+// The address of the registers to a priority for S-mode of hart 0
+pub const PLIC_SPIORITY: u64 = PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE;
+// The address of the claim/complete registers for S-mode of hart 0
+pub const PLIC_SCLAIM: u64 = PLIC_SPIORITY + 4;
 
 // The platfrom-level-interrupt controller(PLIC)
 pub struct Plic {
+    priority: [u32; PLIC_SOURCES],
+    // A bit per source
     pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    // The sources claimed and not completed yet
+    claimed: u64,
+    enable: [u64; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
 }
 
 impl Device for Plic {
@@ -33,40 +75,161 @@ impl Device for Plic {
 
     fn store(&mut self,addr: u64, size: u64, value: u64) -> Result<(),Exception> {
         match size {
-            32 => Ok(self.store32(addr,value)),
+            32 => {
+                self.store32(addr,value as u32);
+                Ok(())
+            },
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    fn save(&self, writer: &mut Writer) {
+        writer.section("plic");
+        for priority in self.priority.iter() {
+            writer.u32(*priority);
+        }
+        writer.u64(self.pending);
+        writer.u64(self.claimed);
+        for context in 0..PLIC_CONTEXTS {
+            writer.u64(self.enable[context]);
+            writer.u32(self.threshold[context]);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("plic")?;
+        for priority in self.priority.iter_mut() {
+            *priority = reader.u32()?;
+        }
+        self.pending = reader.u64()?;
+        self.claimed = reader.u64()?;
+        for context in 0..PLIC_CONTEXTS {
+            self.enable[context] = reader.u64()?;
+            self.threshold[context] = reader.u32()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Plic {
     // create a new plic object
     pub fn new() -> Self {
         Self {
+            priority: [0; PLIC_SOURCES],
             pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            claimed: 0,
+            enable: [0; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    // Set the level of the interrupt source `irq`. A raised source becomes pending
+    // unless it is claimed, and stays pending until it is claimed.
+    pub fn set_level(&mut self, irq: u64, level: bool) {
+        if !level || irq == 0 || irq as usize >= PLIC_SOURCES {
+            return;
+        }
+        if self.claimed & (1 << irq) == 0 {
+            self.pending |= 1 << irq;
+        }
+    }
+
+    // The pending source with the highest priority which interrupts `context`, the
+    // lowest one of the same priority, or 0.
+    fn best(&self, context: usize) -> u64 {
+        let candidates = self.pending & self.enable[context];
+        let mut best = 0;
+        let mut best_priority = self.threshold[context];
+        for irq in 1..PLIC_SOURCES {
+            if candidates & (1 << irq) != 0 && self.priority[irq] > best_priority {
+                best = irq as u64;
+                best_priority = self.priority[irq];
+            }
+        }
+        best
+    }
+
+    // Return true if the PLIC interrupts `context`.
+    pub fn has_interrupt(&self, context: usize) -> bool {
+        self.pending & self.enable[context] != 0 && self.best(context) != 0
+    }
+
+    // Claim the interrupt of `context`. Return the source, or 0 if there is none.
+    fn claim(&mut self, context: usize) -> u64 {
+        let irq = self.best(context);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+        }
+        irq
+    }
+
+    // The context of the registers at `addr` from `base`, whose stride is `stride`,
+    // and the offset in them.
+    fn context(addr: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let context = ((addr - base) / stride) as usize;
+        if context < PLIC_CONTEXTS {
+            Some((context, (addr - base) % stride))
+        } else {
+            None
         }
     }
 
-    fn load32(&self, addr: u64) -> u64 {
+    fn load32(&mut self, addr: u64) -> u64 {
         match addr {
-            PLIC_PENDING => self.pending,
-            PLIC_SENABLE => self.senable,
-            PLIC_SPIORITY => self.spriority,
-            PLIC_SCLAIM => self.sclaim,
+            PLIC_PRIORITY..=PLIC_PRIORITY_END => {
+                let irq = ((addr - PLIC_PRIORITY) / 4) as usize;
+                self.priority.get(irq).copied().unwrap_or(0) as u64
+            }
+            PLIC_PENDING => self.pending as u32 as u64,
+            _ if addr == PLIC_PENDING + 4 => self.pending >> 32,
+            _ if addr >= PLIC_THRESHOLD => match Self::context(addr, PLIC_THRESHOLD, PLIC_CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] as u64,
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+            _ if addr >= PLIC_ENABLE => match Self::context(addr, PLIC_ENABLE, PLIC_ENABLE_STRIDE) {
+                Some((context, 0)) => self.enable[context] as u32 as u64,
+                Some((context, 4)) => self.enable[context] >> 32,
+                _ => 0,
+            },
             _ => 0,
         }
     }
 
-    fn store32(&mut self, addr: u64, value: u64) {
+    fn store32(&mut self, addr: u64, value: u32) {
         match addr {
-            PLIC_PENDING => self.pending = value,
-            PLIC_SENABLE => self.senable = value,
-            PLIC_SPIORITY => self.spriority = value,
-            PLIC_SCLAIM => self.sclaim = value,
-            _ => {},
+            PLIC_PRIORITY..=PLIC_PRIORITY_END => {
+                let irq = ((addr - PLIC_PRIORITY) / 4) as usize;
+                // Source 0 does not exist.
+                if irq != 0 && irq < PLIC_SOURCES {
+                    self.priority[irq] = value & 0x7;
+                }
+            }
+            // The pending bits are read-only.
+            _ if addr >= PLIC_THRESHOLD => match Self::context(addr, PLIC_THRESHOLD, PLIC_CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] = value & 0x7,
+                // Complete the interrupt, so that the source can be pending again.
+                Some((_, 4)) => self.claimed &= !(1u64.checked_shl(value).unwrap_or(0)),
+                _ => {}
+            },
+            _ if addr >= PLIC_ENABLE => match Self::context(addr, PLIC_ENABLE, PLIC_ENABLE_STRIDE) {
+                // Source 0 cannot be enabled.
+                Some((context, 0)) => {
+                    self.enable[context] = (self.enable[context] & !0xffff_ffff) | (value as u64 & !1)
+                }
+                Some((context, 4)) => {
+                    self.enable[context] = (self.enable[context] & 0xffff_ffff) | (value as u64) << 32
+                }
+                _ => {}
+            },
+            _ => {}
         }
-    } 
-}
\ No newline at end of file
+    }
+}
@@ -0,0 +1,282 @@
+// The replay module records the nondeterministic inputs of a run and replays
+// them, so that a run, e.g. an intermittent bug of a guest kernel, can be
+// reproduced exactly.
+//
+// Every event is stamped with the number of instructions executed before it:
+//
+// - the bytes typed on the console, which the UART receives at that instruction
+//   instead of whenever the reader thread gets them
+// - the host time, for clocks which read it
+// - the contents of the disk and the date of the host at the start
+//
+// A record file is the magic number, the version of the format and then the
+// events, written as they happen. All integers are little-endian.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use crate::cpu::*;
+
+// The magic number at the start of a record file.
+const RECORD_MAGIC: &[u8; 8] = b"RVEMUREC";
+// The version of the format. Increment it when an event changes.
+pub const RECORD_VERSION: u32 = 2;
+
+const EVENT_INPUT: u8 = 0;
+const EVENT_TIME: u8 = 1;
+const EVENT_DISK: u8 = 2;
+const EVENT_START_DATE: u8 = 3;
+
+fn invalid_record(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid record: {}", message))
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    // A byte typed on the console
+    Input(u8),
+    // A value of the host time
+    Time(u64),
+    // The contents of the disk
+    Disk(Vec<u8>),
+    // The date of the host at the start, which the dates read by the program
+    // count from, see Clint::date
+    StartDate(u64),
+}
+
+pub enum Recording {
+    // Write the events to a file as they happen.
+    Record(BufWriter<File>),
+    // Apply the recorded events at the same instructions.
+    Replay(VecDeque<(u64, Event)>),
+    // Keep the events in memory for the history of the reverse module, which
+    // runs the program again after going back: the events of the instructions
+    // before `head` are replayed, the later ones are recorded.
+    History { events: Vec<(u64, Event)>, head: u64 },
+}
+
+impl Recording {
+    // Start recording the events of the run of `cpu` to the file `path`.
+    pub fn record(cpu: &Cpu, path: &str) -> io::Result<Self> {
+        let mut output = BufWriter::new(File::create(path)?);
+        output.write_all(RECORD_MAGIC)?;
+        output.write_all(&RECORD_VERSION.to_le_bytes())?;
+        cpu.bus.uart.set_deferred_input(true);
+        let mut replay = Recording::Record(output);
+        replay.write(0, &Event::Disk(cpu.bus.virtio.disk().to_vec()))?;
+        replay.write(0, &Event::StartDate(cpu.bus.clint.start_date()))?;
+        Ok(replay)
+    }
+
+    // Read the events recorded in the file `path` to replay them on `cpu`.
+    pub fn replay(cpu: &Cpu, path: &str) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != RECORD_MAGIC {
+            return Err(invalid_record("no record magic number".to_string()));
+        }
+        let version = read_u32(&mut input)?;
+        if version != RECORD_VERSION {
+            return Err(invalid_record(format!(
+                "version {} is not supported, expected {}",
+                version, RECORD_VERSION
+            )));
+        }
+        let mut events = VecDeque::new();
+        // The events end at the end of the file.
+        while !input.fill_buf()?.is_empty() {
+            let count = read_u64(&mut input)?;
+            let event = match read_u8(&mut input)? {
+                EVENT_INPUT => Event::Input(read_u8(&mut input)?),
+                EVENT_TIME => Event::Time(read_u64(&mut input)?),
+                EVENT_DISK => {
+                    // Read the bytes which are there rather than allocating the
+                    // size first, which may be corrupted.
+                    let size = read_u64(&mut input)?;
+                    let mut disk = Vec::new();
+                    (&mut input).take(size).read_to_end(&mut disk)?;
+                    if disk.len() as u64 != size {
+                        return Err(invalid_record(format!(
+                            "the disk of {} bytes ends after {} bytes",
+                            size,
+                            disk.len()
+                        )));
+                    }
+                    Event::Disk(disk)
+                }
+                EVENT_START_DATE => Event::StartDate(read_u64(&mut input)?),
+                kind => return Err(invalid_record(format!("unknown event {}", kind))),
+            };
+            events.push_back((count, event));
+        }
+        // The typed input is ignored, the guest only receives the recorded one.
+        cpu.bus.uart.set_deferred_input(true);
+        Ok(Recording::Replay(events))
+    }
+
+    // Record the events of the run of `cpu` in memory, to run it again from a
+    // checkpoint of the history.
+    pub fn history(cpu: &Cpu) -> Self {
+        cpu.bus.uart.set_deferred_input(true);
+        Recording::History {
+            events: Vec::new(),
+            head: 0,
+        }
+    }
+
+    // Forget the events from the instruction `count` on, which will not be
+    // replayed since the run changed there.
+    pub fn truncate(&mut self, count: u64) {
+        if let Recording::History { events, head } = self {
+            events.retain(|(at, _)| *at < count);
+            *head = count;
+        }
+    }
+
+    fn write(&mut self, count: u64, event: &Event) -> io::Result<()> {
+        let output = match self {
+            Recording::Record(output) => output,
+            _ => return Ok(()),
+        };
+        output.write_all(&count.to_le_bytes())?;
+        match event {
+            Event::Input(byte) => output.write_all(&[EVENT_INPUT, *byte]),
+            Event::Time(time) => {
+                output.write_all(&[EVENT_TIME])?;
+                output.write_all(&time.to_le_bytes())
+            }
+            Event::Disk(disk) => {
+                output.write_all(&[EVENT_DISK])?;
+                output.write_all(&(disk.len() as u64).to_le_bytes())?;
+                output.write_all(disk)
+            }
+            Event::StartDate(date) => {
+                output.write_all(&[EVENT_START_DATE])?;
+                output.write_all(&date.to_le_bytes())
+            }
+        }
+    }
+
+    // Apply the events of the instruction `count`, before it is executed: receive
+    // the typed input when recording, the recorded events when replaying.
+    pub fn poll(&mut self, cpu: &mut Cpu, count: u64) -> io::Result<()> {
+        match self {
+            Recording::Record(_) => {
+                while let Some(byte) = cpu.bus.uart.take_input() {
+                    self.write(count, &Event::Input(byte))?;
+                    cpu.bus.uart.receive(byte);
+                }
+            }
+            Recording::Replay(events) => {
+                while cpu.bus.uart.take_input().is_some() {}
+                while let Some((at, event)) = events.front() {
+                    match event {
+                        // The time is taken by `time` while the instruction executes.
+                        Event::Time(_) if *at < count => {
+                            return Err(invalid_record(format!(
+                                "the host time was not read at instruction {}",
+                                at
+                            )))
+                        }
+                        _ if *at > count => break,
+                        Event::Time(_) => break,
+                        _ => {}
+                    }
+                    match events.pop_front() {
+                        Some((_, Event::Input(byte))) => cpu.bus.uart.receive(byte),
+                        Some((_, Event::Disk(disk))) => cpu.bus.virtio.set_disk(disk),
+                        Some((_, Event::StartDate(date))) => cpu.bus.clint.set_start_date(date),
+                        _ => {}
+                    }
+                }
+            }
+            Recording::History { events, head } if count < *head => {
+                let start = events.partition_point(|(at, _)| *at < count);
+                for (_, event) in events[start..].iter().take_while(|(at, _)| *at == count) {
+                    if let Event::Input(byte) = event {
+                        cpu.bus.uart.receive(*byte);
+                    }
+                }
+            }
+            Recording::History { events, head } => {
+                while let Some(byte) = cpu.bus.uart.take_input() {
+                    events.push((count, Event::Input(byte)));
+                    cpu.bus.uart.receive(byte);
+                }
+                *head = count + 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Read the host time at the instruction `count` with `now` and record it, or
+    // return the recorded time when replaying.
+    pub fn time(&mut self, count: u64, now: impl FnOnce() -> u64) -> io::Result<u64> {
+        match self {
+            Recording::Replay(events) => {
+                return match events.pop_front() {
+                    Some((at, Event::Time(time))) if at == count => Ok(time),
+                    _ => Err(invalid_record(format!(
+                        "no recorded host time at instruction {}",
+                        count
+                    ))),
+                };
+            }
+            Recording::History { events, .. } => {
+                let start = events.partition_point(|(at, _)| *at < count);
+                let recorded = events[start..]
+                    .iter()
+                    .take_while(|(at, _)| *at == count)
+                    .find_map(|(_, event)| match event {
+                        Event::Time(time) => Some(*time),
+                        _ => None,
+                    });
+                if let Some(time) = recorded {
+                    return Ok(time);
+                }
+                let time = now();
+                events.push((count, Event::Time(time)));
+                return Ok(time);
+            }
+            Recording::Record(_) => {}
+        }
+        let time = now();
+        self.write(count, &Event::Time(time))?;
+        Ok(time)
+    }
+
+    // Finish the run: flush the record, or report the events which were not
+    // replayed, which means the replay diverged from the recorded run.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Recording::Record(output) => output.flush(),
+            Recording::Replay(events) if !events.is_empty() => Err(invalid_record(format!(
+                "the run ended with {} events not replayed",
+                events.len()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
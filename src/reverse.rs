@@ -0,0 +1,222 @@
+// The reverse module runs a program backward for the debugger. The program runs
+// forward only: the history keeps a checkpoint every CHECKPOINT_INTERVAL
+// instructions, and going back to an instruction restores the last checkpoint
+// before it and executes the program again up to that instruction.
+//
+// A checkpoint has the registers, the CSRs and the devices, but only the pages
+// of memory written since the previous checkpoint, so that checkpoints are
+// cheap. The first checkpoint has all the pages which are not zero.
+//
+// The instructions are executed by Emulator::step, like a run without the
+// debugger. The program must run the same way again, so the emulator records
+// the console input and the host time in memory with Recording::history and
+// replays them before the end of the history. When a register or memory is
+// changed while the program is back in the history, the history after that
+// point is discarded.
+
+use crate::cpu::*;
+use crate::emulator::*;
+use crate::memory::*;
+use crate::snapshot::*;
+
+// The number of instructions between checkpoints. Going back executes at most
+// this number of instructions again.
+pub const CHECKPOINT_INTERVAL: u64 = 100_000;
+
+struct Checkpoint {
+    // The number of instructions executed before the checkpoint
+    count: u64,
+    regs: [u64; REGISTER_NUMBER],
+    fregs: [u64; REGISTER_NUMBER],
+    pc: u64,
+    // The values of the implemented CSRs, in the order of Cpu::implemented_csrs
+    csrs: Vec<u64>,
+    mode: Mode,
+    // The state of the devices other than the memory, saved by Bus::save_devices
+    devices: Vec<u8>,
+    // The pages written since the previous checkpoint by the number of the memory
+    // in Bus::memories and the index of the page, sorted
+    pages: Vec<((usize, usize), Vec<u8>)>,
+}
+
+#[derive(Default)]
+pub struct History {
+    checkpoints: Vec<Checkpoint>,
+    // The number of instructions executed at the end of the history. The
+    // instruction count of the emulator is smaller than this while the program
+    // runs again after going back.
+    head: u64,
+}
+
+impl Checkpoint {
+    fn page(&self, index: (usize, usize)) -> Option<&[u8]> {
+        self.pages
+            .binary_search_by_key(&index, |(page, _)| *page)
+            .ok()
+            .map(|i| &self.pages[i].1[..])
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The first instruction in the history, where the program cannot go back
+    // further. None before the program ran.
+    pub fn origin(&self) -> Option<u64> {
+        self.checkpoints.first().map(|checkpoint| checkpoint.count)
+    }
+
+    // The instructions of the checkpoints before the instruction `count`, the
+    // latest first.
+    pub fn checkpoints_before(&self, count: u64) -> Vec<u64> {
+        let mut counts: Vec<u64> = self
+            .checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.count)
+            .filter(|at| *at < count)
+            .collect();
+        counts.dedup();
+        counts.reverse();
+        counts
+    }
+
+    // Save the state at the instruction `count`.
+    fn checkpoint(&mut self, cpu: &mut Cpu, count: u64) {
+        let mut pages = Vec::new();
+        for (number, memory) in cpu.bus.memories_mut().enumerate() {
+            let mut indexes = memory.take_dirty_pages();
+            if self.checkpoints.is_empty() {
+                indexes = memory
+                    .allocated_pages()
+                    .filter(|index| memory.page(*index).iter().any(|byte| *byte != 0))
+                    .collect();
+            }
+            indexes.sort_unstable();
+            pages.extend(
+                indexes
+                    .into_iter()
+                    .map(|index| ((number, index), memory.page(index).to_vec())),
+            );
+        }
+        let mut devices = Writer::new();
+        cpu.bus.save_devices(&mut devices);
+        self.checkpoints.push(Checkpoint {
+            count,
+            regs: cpu.regs,
+            fregs: cpu.fregs,
+            pc: cpu.pc,
+            csrs: Cpu::implemented_csrs().map(|addr| cpu.csrs[addr]).collect(),
+            mode: cpu.mode,
+            devices: devices.into_inner(),
+            pages,
+        });
+    }
+
+    // Execute an instruction with Emulator::step and return its reason to stop.
+    // The tracer and the lockstep checks are left out while the program runs
+    // again, since they saw the instructions the first time.
+    pub fn step(&mut self, emulator: &mut Emulator) -> Option<StopReason> {
+        let count = emulator.instruction_count();
+        if count < self.head {
+            let tracer = emulator.take_tracer();
+            let lockstep = emulator.take_lockstep();
+            let reason = emulator.step();
+            if let Some(tracer) = tracer {
+                emulator.set_tracer(tracer);
+            }
+            if let Some(lockstep) = lockstep {
+                emulator.set_lockstep(lockstep);
+            }
+            return reason;
+        }
+
+        if self
+            .checkpoints
+            .last()
+            .is_none_or(|checkpoint| count - checkpoint.count >= CHECKPOINT_INTERVAL)
+        {
+            self.checkpoint(&mut emulator.cpu, count);
+        }
+        let reason = emulator.step();
+        self.head = self.head.max(emulator.instruction_count());
+        reason
+    }
+
+    // Go back (or forward, up to the end of the history) to the state before the
+    // instruction `target` was executed.
+    pub fn go_to(&mut self, emulator: &mut Emulator, target: u64) {
+        let k = match self.checkpoints.iter().rposition(|checkpoint| checkpoint.count <= target) {
+            Some(k) => k,
+            None => return,
+        };
+
+        // The pages which may have changed since the checkpoint: the pages written
+        // after it and the pages saved by the later checkpoints.
+        let cpu = &mut emulator.cpu;
+        let zero = [0; PAGE_SIZE];
+        for (number, memory) in cpu.bus.memories_mut().enumerate() {
+            let mut indexes = memory.take_dirty_pages();
+            for checkpoint in self.checkpoints[k + 1..].iter() {
+                indexes.extend(
+                    checkpoint
+                        .pages
+                        .iter()
+                        .filter(|((page_number, _), _)| *page_number == number)
+                        .map(|((_, index), _)| *index),
+                );
+            }
+            indexes.sort_unstable();
+            indexes.dedup();
+            for index in indexes {
+                // The latest content of the page at the checkpoint, zero if the page
+                // was never saved.
+                let page = self.checkpoints[..=k]
+                    .iter()
+                    .rev()
+                    .find_map(|checkpoint| checkpoint.page((number, index)))
+                    .unwrap_or(&zero);
+                memory.set_page(index, page);
+            }
+        }
+
+        let checkpoint = &self.checkpoints[k];
+        cpu.regs = checkpoint.regs;
+        cpu.fregs = checkpoint.fregs;
+        cpu.pc = checkpoint.pc;
+        for (addr, value) in Cpu::implemented_csrs().zip(checkpoint.csrs.iter()) {
+            cpu.csrs[addr] = *value;
+        }
+        cpu.mode = checkpoint.mode;
+        // The cached translations and the reservation may be of a later state.
+        cpu.tlb.flush();
+        cpu.reservation = None;
+        cpu.bus
+            .restore_devices(&mut Reader::new(&checkpoint.devices))
+            .expect("failed to restore a checkpoint");
+        emulator.set_instruction_count(checkpoint.count);
+
+        // The stops were reported when the instructions were executed the first time.
+        while emulator.instruction_count() < target.min(self.head) {
+            self.step(emulator);
+        }
+    }
+
+    // Forget the history after the current instruction, since a register or memory
+    // was changed and the program does not run the same way anymore.
+    pub fn discard_future(&mut self, emulator: &mut Emulator) {
+        if self.checkpoints.is_empty() {
+            return;
+        }
+        let count = emulator.instruction_count();
+        self.checkpoints.retain(|checkpoint| checkpoint.count <= count);
+        if let Some(recording) = emulator.recording_mut() {
+            recording.truncate(count);
+        }
+        self.head = count;
+        // A checkpoint at the same instruction as an earlier one replaces it,
+        // since the latest checkpoint is used to go back.
+        self.checkpoint(&mut emulator.cpu, count);
+    }
+}
@@ -0,0 +1,122 @@
+// The riscv_tests module runs the ISA tests of riscv-tests
+// (https://github.com/riscv-software-src/riscv-tests), e.g. rv64ui-p-add.
+//
+// A test reports its result by writing to the `tohost` symbol: 1 means that the
+// test passed and (test number << 1) | 1 means that the test case with that
+// number failed. Tests can be ELF files, which give the address of `tohost`, or
+// flat binaries loaded at 0x80000000, which have `tohost` at 0x80001000 as in
+// riscv-tests' env/p/link.ld.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::bus::*;
+use crate::emulator::*;
+
+// The address of `tohost` in flat binaries.
+pub const DEFAULT_TOHOST: u64 = MEMORY_BASE + 0x1000;
+// A test which does not write to `tohost` within this number of instructions
+// is reported as a timeout.
+pub const INSTRUCTION_LIMIT: u64 = 10_000_000;
+
+// The suites of riscv-tests which run in this emulator.
+const SUITES: [&str; 8] = [
+    "rv64ui", "rv64um", "rv64ua", "rv64uf", "rv64ud", "rv64uc", "rv64mi", "rv64si",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    // The number of the failed test case
+    Fail(u64),
+    // No result within INSTRUCTION_LIMIT instructions
+    Timeout,
+    // The test could not be loaded
+    Error(String),
+}
+
+// Load a test and run it until it writes to `tohost`.
+pub fn run_test(path: &Path) -> Outcome {
+    let mut binary = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_end(&mut binary)) {
+        return Outcome::Error(e.to_string());
+    }
+
+    // Traps are part of the tests, so they are taken and the run goes on.
+    let mut emulator = match Emulator::builder().binary(binary).stop_on_exception(false).build() {
+        Ok(emulator) => emulator,
+        Err(e) => return Outcome::Error(e.to_string()),
+    };
+    let tohost = emulator.symbol("tohost").unwrap_or(DEFAULT_TOHOST);
+    run_until_tohost(&mut emulator, tohost)
+}
+
+// Run the emulator until the program writes to `tohost`.
+pub fn run_until_tohost(emulator: &mut Emulator, tohost: u64) -> Outcome {
+    if let Err(e) = emulator.set_tohost(tohost) {
+        return Outcome::Error(e.to_string());
+    }
+    match emulator.run(INSTRUCTION_LIMIT) {
+        StopReason::ToHost(1) => Outcome::Pass,
+        StopReason::ToHost(value) => Outcome::Fail(value >> 1),
+        StopReason::InstructionLimit => Outcome::Timeout,
+        reason => Outcome::Error(format!("{:?}", reason)),
+    }
+}
+
+// Collect the tests in `paths`. A directory is searched for the binaries of the
+// suites in SUITES, e.g. rv64ui-p-add, skipping objdump outputs.
+pub fn collect_tests(paths: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if !path.is_dir() {
+            tests.push(path);
+            continue;
+        }
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?.path();
+            let name = match entry.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if SUITES.iter().any(|suite| name.starts_with(suite)) && !name.ends_with(".dump") {
+                entries.push(entry);
+            }
+        }
+        entries.sort();
+        tests.extend(entries);
+    }
+    Ok(tests)
+}
+
+// Run the tests in `paths` and print the results as a table. Return true if all
+// the tests passed.
+pub fn run_suite(paths: &[String]) -> io::Result<bool> {
+    let tests = collect_tests(paths)?;
+    let width = tests
+        .iter()
+        .map(|test| test.display().to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    let mut passed = 0;
+    for test in tests.iter() {
+        let result = match run_test(test) {
+            Outcome::Pass => {
+                passed += 1;
+                "PASS".to_string()
+            }
+            Outcome::Fail(testnum) => format!("FAIL (test {})", testnum),
+            Outcome::Timeout => format!("TIMEOUT ({} instructions)", INSTRUCTION_LIMIT),
+            Outcome::Error(e) => format!("ERROR ({})", e),
+        };
+        println!("{:width$}  {}", test.display(), result, width = width);
+    }
+    println!("{} / {} passed", passed, tests.len());
+    Ok(!tests.is_empty() && passed == tests.len())
+}
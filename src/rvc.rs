@@ -0,0 +1,261 @@
+// The rvc module expands the 16-bit instructions of the "C" standard extension
+// for compressed instructions to the 32-bit instructions they are short for, so
+// that the cpu executes a compressed instruction as its expansion. The expansions
+// are listed in the chapter "C" Standard Extension for Compressed Instructions of
+// the unprivileged ISA manual.
+//
+// An instruction is compressed if its lowest two bits are not 0b11.
+
+// The length of an instruction in bytes: 2 if it is compressed, otherwise 4.
+pub fn inst_len(inst: u64) -> u64 {
+    if inst & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+// The 32-bit instruction which `inst` stands for: the expansion of a compressed
+// instruction, or the instruction itself. An illegal compressed instruction is
+// returned as it is, so that it decodes as an unknown instruction.
+pub fn decompress(inst: u64) -> u64 {
+    if inst_len(inst) == 4 {
+        return inst;
+    }
+    expand(inst as u16).map_or(inst, |expanded| expanded as u64)
+}
+
+// The bits [hi:lo] of an instruction
+fn bits(inst: u32, hi: u32, lo: u32) -> u32 {
+    (inst >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+// Sign-extend the lowest `width` bits.
+fn sign_extend(value: u32, width: u32) -> u32 {
+    ((value << (32 - width)) as i32 >> (32 - width)) as u32
+}
+
+// The registers x8 to x15 of the 3-bit register fields rd', rs1' and rs2'
+fn reg_prime(field: u32) -> u32 {
+    field + 8
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    bits(imm, 11, 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | bits(imm, 4, 0) << 7 | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    bits(imm, 12, 12) << 31
+        | bits(imm, 10, 5) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | bits(imm, 4, 1) << 8
+        | bits(imm, 11, 11) << 7
+        | 0x63
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    bits(imm, 20, 20) << 31
+        | bits(imm, 10, 1) << 21
+        | bits(imm, 11, 11) << 20
+        | bits(imm, 19, 12) << 12
+        | rd << 7
+        | 0x6f
+}
+
+// Expand a compressed instruction. Return None if it is illegal. The
+// floating-point loads and stores expand even without the D extension, whose
+// instructions are then illegal.
+pub fn expand(inst: u16) -> Option<u32> {
+    let inst = inst as u32;
+    let funct3 = bits(inst, 15, 13);
+    // rd and rs1 of the CI, CR and CSS formats
+    let rd = bits(inst, 11, 7);
+    let rs2 = bits(inst, 6, 2);
+    // rd', rs1' and rs2' of the CIW, CL, CS, CA and CB formats
+    let rd_prime = reg_prime(bits(inst, 4, 2));
+    let rs1_prime = reg_prime(bits(inst, 9, 7));
+    // The 6-bit immediate of the CI format, imm[5] = inst[12] and imm[4:0] = inst[6:2]
+    let imm6 = sign_extend(bits(inst, 12, 12) << 5 | bits(inst, 6, 2), 6);
+    let shamt = bits(inst, 12, 12) << 5 | bits(inst, 6, 2);
+
+    let expanded = match (inst & 0b11, funct3) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            // c.addi4spn: addi rd', x2, nzuimm
+            let imm = bits(inst, 12, 11) << 4
+                | bits(inst, 10, 7) << 6
+                | bits(inst, 6, 6) << 2
+                | bits(inst, 5, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0x0, rd_prime, 0x13)
+        }
+        (0b00, 0b001) => {
+            // c.fld: fld rd', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            i_type(imm, rs1_prime, 0x3, rd_prime, 0x07)
+        }
+        (0b00, 0b010) => {
+            // c.lw: lw rd', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 6) << 2 | bits(inst, 5, 5) << 6;
+            i_type(imm, rs1_prime, 0x2, rd_prime, 0x03)
+        }
+        (0b00, 0b011) => {
+            // c.ld: ld rd', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            i_type(imm, rs1_prime, 0x3, rd_prime, 0x03)
+        }
+        (0b00, 0b101) => {
+            // c.fsd: fsd rs2', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            s_type(imm, rd_prime, rs1_prime, 0x3, 0x27)
+        }
+        (0b00, 0b110) => {
+            // c.sw: sw rs2', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 6) << 2 | bits(inst, 5, 5) << 6;
+            s_type(imm, rd_prime, rs1_prime, 0x2, 0x23)
+        }
+        (0b00, 0b111) => {
+            // c.sd: sd rs2', uimm(rs1')
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            s_type(imm, rd_prime, rs1_prime, 0x3, 0x23)
+        }
+
+        // Quadrant 1
+        // c.addi (c.nop if rd is x0): addi rd, rd, imm
+        (0b01, 0b000) => i_type(imm6, rd, 0x0, rd, 0x13),
+        // c.addiw: addiw rd, rd, imm
+        (0b01, 0b001) if rd != 0 => i_type(imm6, rd, 0x0, rd, 0x1b),
+        // c.li: addi rd, x0, imm
+        (0b01, 0b010) => i_type(imm6, 0, 0x0, rd, 0x13),
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp: addi x2, x2, nzimm
+            let imm = sign_extend(
+                bits(inst, 12, 12) << 9
+                    | bits(inst, 6, 6) << 4
+                    | bits(inst, 5, 5) << 6
+                    | bits(inst, 4, 3) << 7
+                    | bits(inst, 2, 2) << 5,
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0x0, 2, 0x13)
+        }
+        (0b01, 0b011) => {
+            // c.lui: lui rd, nzimm
+            let imm = sign_extend(bits(inst, 12, 12) << 17 | bits(inst, 6, 2) << 12, 18);
+            if imm == 0 {
+                return None;
+            }
+            imm & 0xfffff000 | rd << 7 | 0x37
+        }
+        (0b01, 0b100) => match (bits(inst, 11, 10), bits(inst, 12, 12), bits(inst, 6, 5)) {
+            // c.srli: srli rd', rd', shamt
+            (0b00, _, _) => i_type(shamt, rs1_prime, 0x5, rs1_prime, 0x13),
+            // c.srai: srai rd', rd', shamt
+            (0b01, _, _) => i_type(0x400 | shamt, rs1_prime, 0x5, rs1_prime, 0x13),
+            // c.andi: andi rd', rd', imm
+            (0b10, _, _) => i_type(imm6, rs1_prime, 0x7, rs1_prime, 0x13),
+            // c.sub, c.xor, c.or and c.and
+            (0b11, 0, 0b00) => r_type(0x20, rd_prime, rs1_prime, 0x0, rs1_prime, 0x33),
+            (0b11, 0, 0b01) => r_type(0x00, rd_prime, rs1_prime, 0x4, rs1_prime, 0x33),
+            (0b11, 0, 0b10) => r_type(0x00, rd_prime, rs1_prime, 0x6, rs1_prime, 0x33),
+            (0b11, 0, 0b11) => r_type(0x00, rd_prime, rs1_prime, 0x7, rs1_prime, 0x33),
+            // c.subw and c.addw
+            (0b11, 1, 0b00) => r_type(0x20, rd_prime, rs1_prime, 0x0, rs1_prime, 0x3b),
+            (0b11, 1, 0b01) => r_type(0x00, rd_prime, rs1_prime, 0x0, rs1_prime, 0x3b),
+            _ => return None,
+        },
+        (0b01, 0b101) => {
+            // c.j: jal x0, offset
+            let imm = sign_extend(
+                bits(inst, 12, 12) << 11
+                    | bits(inst, 11, 11) << 4
+                    | bits(inst, 10, 9) << 8
+                    | bits(inst, 8, 8) << 10
+                    | bits(inst, 7, 7) << 6
+                    | bits(inst, 6, 6) << 7
+                    | bits(inst, 5, 3) << 1
+                    | bits(inst, 2, 2) << 5,
+                12,
+            );
+            j_type(imm, 0)
+        }
+        (0b01, 0b110) | (0b01, 0b111) => {
+            // c.beqz and c.bnez: beq rs1', x0, offset and bne rs1', x0, offset
+            let imm = sign_extend(
+                bits(inst, 12, 12) << 8
+                    | bits(inst, 11, 10) << 3
+                    | bits(inst, 6, 5) << 6
+                    | bits(inst, 4, 3) << 1
+                    | bits(inst, 2, 2) << 5,
+                9,
+            );
+            b_type(imm, 0, rs1_prime, funct3 & 1)
+        }
+
+        // Quadrant 2
+        // c.slli: slli rd, rd, shamt
+        (0b10, 0b000) => i_type(shamt, rd, 0x1, rd, 0x13),
+        (0b10, 0b001) => {
+            // c.fldsp: fld rd, uimm(x2)
+            let imm = bits(inst, 12, 12) << 5 | bits(inst, 6, 5) << 3 | bits(inst, 4, 2) << 6;
+            i_type(imm, 2, 0x3, rd, 0x07)
+        }
+        (0b10, 0b010) if rd != 0 => {
+            // c.lwsp: lw rd, uimm(x2)
+            let imm = bits(inst, 12, 12) << 5 | bits(inst, 6, 4) << 2 | bits(inst, 3, 2) << 6;
+            i_type(imm, 2, 0x2, rd, 0x03)
+        }
+        (0b10, 0b011) if rd != 0 => {
+            // c.ldsp: ld rd, uimm(x2)
+            let imm = bits(inst, 12, 12) << 5 | bits(inst, 6, 5) << 3 | bits(inst, 4, 2) << 6;
+            i_type(imm, 2, 0x3, rd, 0x03)
+        }
+        (0b10, 0b100) => match (bits(inst, 12, 12), rd, rs2) {
+            // c.jr: jalr x0, 0(rs1)
+            (0, 0, 0) => return None,
+            (0, _, 0) => i_type(0, rd, 0x0, 0, 0x67),
+            // c.mv: add rd, x0, rs2
+            (0, _, _) => r_type(0x00, rs2, 0, 0x0, rd, 0x33),
+            // c.ebreak
+            (1, 0, 0) => 0x0010_0073,
+            // c.jalr: jalr x1, 0(rs1)
+            (1, _, 0) => i_type(0, rd, 0x0, 1, 0x67),
+            // c.add: add rd, rd, rs2
+            _ => r_type(0x00, rs2, rd, 0x0, rd, 0x33),
+        },
+        (0b10, 0b101) => {
+            // c.fsdsp: fsd rs2, uimm(x2)
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 9, 7) << 6;
+            s_type(imm, rs2, 2, 0x3, 0x27)
+        }
+        (0b10, 0b110) => {
+            // c.swsp: sw rs2, uimm(x2)
+            let imm = bits(inst, 12, 9) << 2 | bits(inst, 8, 7) << 6;
+            s_type(imm, rs2, 2, 0x2, 0x23)
+        }
+        (0b10, 0b111) => {
+            // c.sdsp: sd rs2, uimm(x2)
+            let imm = bits(inst, 12, 10) << 3 | bits(inst, 9, 7) << 6;
+            s_type(imm, rs2, 2, 0x3, 0x23)
+        }
+
+        // The reserved encodings
+        _ => return None,
+    };
+    Some(expanded)
+}
@@ -0,0 +1,383 @@
+// The sbi module is a built-in SBI implementation, the interface between an
+// S-mode kernel and the M-mode firmware, so that a kernel can run without
+// OpenSBI. An ecall from S-mode is serviced by the emulator instead of taking a
+// trap: a7 is the extension ID (EID), a6 the function ID (FID) and a0 to a5 the
+// arguments. The error is returned in a0 and the value in a1.
+//
+// The extensions of the SBI specification v2.0 which are implemented:
+//
+// - Base
+// - TIME: the timer interrupt of the CLINT goes to S-mode as STIP
+// - IPI, RFENCE and HSM for the only hart
+// - SRST: stops the emulator
+// - DBCN: the console on the UART
+// - the legacy extensions 0x00 to 0x08
+//
+// The kernel starts in S-mode with a0 = the hart ID and a1 = the address of the
+// DTB, and the interrupts and exceptions are delegated as OpenSBI does.
+
+use crate::bus::*;
+use crate::cpu::*;
+use crate::uart::*;
+
+// The version of the SBI specification, 2.0
+pub const SBI_SPEC_VERSION: u64 = 2 << 24;
+// The implementation ID, which is not one of the registered IDs
+pub const SBI_IMPL_ID: u64 = 0x7269_7363;
+// The version of the implementation
+pub const SBI_IMPL_VERSION: u64 = 1;
+
+// The extension IDs
+pub const SBI_EXT_LEGACY_SET_TIMER: u64 = 0x00;
+pub const SBI_EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+pub const SBI_EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+pub const SBI_EXT_LEGACY_CLEAR_IPI: u64 = 0x03;
+pub const SBI_EXT_LEGACY_SEND_IPI: u64 = 0x04;
+pub const SBI_EXT_LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+pub const SBI_EXT_LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+pub const SBI_EXT_LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+pub const SBI_EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+pub const SBI_EXT_BASE: u64 = 0x10;
+// "TIME"
+pub const SBI_EXT_TIME: u64 = 0x5449_4d45;
+// "sPI"
+pub const SBI_EXT_IPI: u64 = 0x73_5049;
+// "RFNC"
+pub const SBI_EXT_RFENCE: u64 = 0x5246_4e43;
+// "HSM"
+pub const SBI_EXT_HSM: u64 = 0x48_534d;
+// "SRST"
+pub const SBI_EXT_SRST: u64 = 0x5352_5354;
+// "DBCN"
+pub const SBI_EXT_DBCN: u64 = 0x4442_434e;
+
+// The errors, returned in a0
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// The states of a hart in HSM
+pub const SBI_HSM_STATE_STARTED: u64 = 0;
+
+// The reset types of SRST
+pub const SBI_SRST_TYPE_SHUTDOWN: u64 = 0;
+pub const SBI_SRST_TYPE_COLD_REBOOT: u64 = 1;
+pub const SBI_SRST_TYPE_WARM_REBOOT: u64 = 2;
+// The reasons of SRST from 2 to 0xefff_ffff are reserved.
+const SBI_SRST_REASON_SYSTEM_FAILURE: u64 = 1;
+const SBI_SRST_REASON_VENDOR: u64 = 0xf000_0000;
+
+// The exceptions delegated to S-mode: instruction address misaligned, breakpoint,
+// ecall from U-mode and the page faults, as OpenSBI does. Illegal instructions are
+// delegated too, because OpenSBI forwards the ones it does not emulate.
+pub const SBI_MEDELEG: u64 = 1 << 0 | 1 << 2 | 1 << 3 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15;
+// The interrupts delegated to S-mode: the supervisor ones
+pub const SBI_MIDELEG: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// How the kernel asked to reset the system
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+// An SBI call, with its result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub eid: u64,
+    pub fid: u64,
+    // a0 to a5
+    pub args: [u64; 6],
+    pub error: i64,
+    pub value: u64,
+    // The reset asked by SRST or the legacy shutdown, with the reason
+    pub reset: Option<(ResetType, u64)>,
+}
+
+impl Call {
+    // The name of the function, e.g. "set_timer"
+    pub fn name(&self) -> &'static str {
+        match (self.eid, self.fid) {
+            (SBI_EXT_LEGACY_SET_TIMER, _) => "legacy_set_timer",
+            (SBI_EXT_LEGACY_CONSOLE_PUTCHAR, _) => "legacy_console_putchar",
+            (SBI_EXT_LEGACY_CONSOLE_GETCHAR, _) => "legacy_console_getchar",
+            (SBI_EXT_LEGACY_CLEAR_IPI, _) => "legacy_clear_ipi",
+            (SBI_EXT_LEGACY_SEND_IPI, _) => "legacy_send_ipi",
+            (SBI_EXT_LEGACY_REMOTE_FENCE_I, _) => "legacy_remote_fence_i",
+            (SBI_EXT_LEGACY_REMOTE_SFENCE_VMA, _) => "legacy_remote_sfence_vma",
+            (SBI_EXT_LEGACY_REMOTE_SFENCE_VMA_ASID, _) => "legacy_remote_sfence_vma_asid",
+            (SBI_EXT_LEGACY_SHUTDOWN, _) => "legacy_shutdown",
+            (SBI_EXT_BASE, 0) => "get_spec_version",
+            (SBI_EXT_BASE, 1) => "get_impl_id",
+            (SBI_EXT_BASE, 2) => "get_impl_version",
+            (SBI_EXT_BASE, 3) => "probe_extension",
+            (SBI_EXT_BASE, 4) => "get_mvendorid",
+            (SBI_EXT_BASE, 5) => "get_marchid",
+            (SBI_EXT_BASE, 6) => "get_mimpid",
+            (SBI_EXT_TIME, 0) => "set_timer",
+            (SBI_EXT_IPI, 0) => "send_ipi",
+            (SBI_EXT_RFENCE, 0) => "remote_fence_i",
+            (SBI_EXT_RFENCE, 1) => "remote_sfence_vma",
+            (SBI_EXT_RFENCE, 2) => "remote_sfence_vma_asid",
+            (SBI_EXT_HSM, 0) => "hart_start",
+            (SBI_EXT_HSM, 1) => "hart_stop",
+            (SBI_EXT_HSM, 2) => "hart_get_status",
+            (SBI_EXT_HSM, 3) => "hart_suspend",
+            (SBI_EXT_SRST, 0) => "system_reset",
+            (SBI_EXT_DBCN, 0) => "debug_console_write",
+            (SBI_EXT_DBCN, 1) => "debug_console_read",
+            (SBI_EXT_DBCN, 2) => "debug_console_write_byte",
+            _ => "unknown",
+        }
+    }
+
+    // A legacy call returns only a value in a0.
+    pub fn is_legacy(&self) -> bool {
+        self.eid <= SBI_EXT_LEGACY_SHUTDOWN
+    }
+}
+
+// Return true if the extension is implemented, for probe_extension.
+pub fn is_supported(eid: u64) -> bool {
+    matches!(
+        eid,
+        SBI_EXT_LEGACY_SET_TIMER..=SBI_EXT_LEGACY_SHUTDOWN
+            | SBI_EXT_BASE
+            | SBI_EXT_TIME
+            | SBI_EXT_IPI
+            | SBI_EXT_RFENCE
+            | SBI_EXT_HSM
+            | SBI_EXT_SRST
+            | SBI_EXT_DBCN
+    )
+}
+
+// Set up the cpu as the firmware would before it starts the kernel in S-mode.
+pub fn init(cpu: &mut Cpu) {
+    cpu.sbi = true;
+    cpu.mode = Mode::Surpervisor;
+    cpu.csrs[MEDELEG] = SBI_MEDELEG;
+    cpu.csrs[MIDELEG] = SBI_MIDELEG;
+    // The kernel can read cycle, time and instret.
+    cpu.csrs[MCOUNTEREN] = 0b111;
+    // No timer interrupt until the kernel sets the timer
+    cpu.bus.clint.set_mtimecmp(cpu.hartid(), u64::MAX);
+}
+
+// Return true if a hart mask of send_ipi or the rfence calls includes hart 0, the
+// only hart. A base of -1 means all the harts.
+fn includes_hart0(mask: u64, base: u64) -> bool {
+    base == u64::MAX || (base == 0 && mask & 1 != 0)
+}
+
+// Write a byte to the console.
+pub fn putchar(cpu: &mut Cpu, byte: u8) {
+    if cpu.bus.devices.uart.is_some() {
+        cpu.bus
+            .uart
+            .store(UART_THR, 8, byte as u64)
+            .expect("failed to write to the UART");
+    }
+}
+
+// Read a byte from the console, if one was typed.
+pub fn getchar(cpu: &mut Cpu) -> Option<u8> {
+    cpu.bus.devices.uart?;
+    let lsr = cpu.bus.uart.load(UART_LSR, 8).expect("failed to read the UART");
+    if lsr as u8 & UART_LSR_RX == 0 {
+        return None;
+    }
+    Some(cpu.bus.uart.load(UART_RHR, 8).expect("failed to read the UART") as u8)
+}
+
+// Write `length` bytes at the physical address `addr` to the console. Return the
+// number of bytes written, or None if they are not in memory.
+fn write_console(cpu: &mut Cpu, addr: u64, length: u64) -> Option<u64> {
+    let mut bytes = Vec::new();
+    for i in 0..length {
+        bytes.push(cpu.bus.load(addr.wrapping_add(i), 8).ok()? as u8);
+    }
+    for byte in bytes {
+        putchar(cpu, byte);
+    }
+    Some(length)
+}
+
+// Read at most `length` bytes from the console to the physical address `addr`.
+// Return the number of bytes read, or None if the buffer is not in memory.
+fn read_console(cpu: &mut Cpu, addr: u64, length: u64) -> Option<u64> {
+    let mut count = 0;
+    while count < length {
+        // Check that the byte can be stored before taking it from the UART.
+        cpu.bus.load(addr.wrapping_add(count), 8).ok()?;
+        let byte = match getchar(cpu) {
+            Some(byte) => byte,
+            None => break,
+        };
+        cpu.bus.store(addr.wrapping_add(count), 8, byte as u64).ok()?;
+        count += 1;
+    }
+    Some(count)
+}
+
+// Program the timer: the timer interrupt is pending from when mtime reaches
+// `time`, and is not pending until then.
+fn set_timer(cpu: &mut Cpu, time: u64) {
+    cpu.bus.clint.set_mtimecmp(cpu.hartid(), time);
+    cpu.csrs[MIP] &= !MIP_STIP;
+}
+
+// Service the ecall from S-mode which just raised EnvironmentCallFromSMode, with
+// the pc after the ecall, and write the result to a0 and a1.
+pub fn call(cpu: &mut Cpu) -> Call {
+    let regs = cpu.regs;
+    let mut call = Call {
+        eid: regs[17],
+        fid: regs[16],
+        args: [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]],
+        error: SBI_SUCCESS,
+        value: 0,
+        reset: None,
+    };
+    let args = call.args;
+    let (error, value) = match (call.eid, call.fid) {
+        (SBI_EXT_LEGACY_SET_TIMER, _) | (SBI_EXT_TIME, 0) => {
+            set_timer(cpu, args[0]);
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_LEGACY_CONSOLE_PUTCHAR, _) => {
+            putchar(cpu, args[0] as u8);
+            (SBI_SUCCESS, 0)
+        }
+        // The legacy getchar returns the byte, or -1 if nothing was typed.
+        (SBI_EXT_LEGACY_CONSOLE_GETCHAR, _) => match getchar(cpu) {
+            Some(byte) => (byte as i64, 0),
+            None => (-1, 0),
+        },
+        (SBI_EXT_LEGACY_CLEAR_IPI, _) => {
+            cpu.csrs[MIP] &= !MIP_SSIP;
+            (SBI_SUCCESS, 0)
+        }
+        // The legacy calls take the address of a hart mask, 0 for all the harts.
+        (SBI_EXT_LEGACY_SEND_IPI, _) => {
+            let mask = match args[0] {
+                0 => Ok(1),
+                addr => cpu.bus.load(addr, 64),
+            };
+            match mask {
+                Ok(mask) => {
+                    if mask & 1 != 0 {
+                        cpu.csrs[MIP] |= MIP_SSIP;
+                    }
+                    (SBI_SUCCESS, 0)
+                }
+                Err(_) => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        }
+        // There is no instruction cache, so that only the TLB is flushed.
+        (SBI_EXT_LEGACY_REMOTE_FENCE_I, _)
+        | (SBI_EXT_LEGACY_REMOTE_SFENCE_VMA, _)
+        | (SBI_EXT_LEGACY_REMOTE_SFENCE_VMA_ASID, _) => {
+            cpu.tlb.flush();
+            (SBI_SUCCESS, 0)
+        }
+        (SBI_EXT_LEGACY_SHUTDOWN, _) => {
+            call.reset = Some((ResetType::Shutdown, 0));
+            (SBI_SUCCESS, 0)
+        }
+
+        (SBI_EXT_BASE, 0) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+        (SBI_EXT_BASE, 1) => (SBI_SUCCESS, SBI_IMPL_ID),
+        (SBI_EXT_BASE, 2) => (SBI_SUCCESS, SBI_IMPL_VERSION),
+        (SBI_EXT_BASE, 3) => (SBI_SUCCESS, is_supported(args[0]) as u64),
+        // mvendorid, marchid and mimpid are 0: not implemented.
+        (SBI_EXT_BASE, 4..=6) => (SBI_SUCCESS, 0),
+
+        (SBI_EXT_IPI, 0) => {
+            if includes_hart0(args[0], args[1]) {
+                cpu.csrs[MIP] |= MIP_SSIP;
+                (SBI_SUCCESS, 0)
+            } else if args[0] == 0 {
+                (SBI_SUCCESS, 0)
+            } else {
+                (SBI_ERR_INVALID_PARAM, 0)
+            }
+        }
+
+        (SBI_EXT_RFENCE, 0..=2) => {
+            if includes_hart0(args[0], args[1]) {
+                cpu.tlb.flush();
+                (SBI_SUCCESS, 0)
+            } else if args[0] == 0 {
+                (SBI_SUCCESS, 0)
+            } else {
+                (SBI_ERR_INVALID_PARAM, 0)
+            }
+        }
+
+        // hart_start: hart 0 is the only hart, and it is running.
+        (SBI_EXT_HSM, 0) => match args[0] {
+            0 => (SBI_ERR_ALREADY_AVAILABLE, 0),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // hart_stop: the last hart cannot be stopped.
+        (SBI_EXT_HSM, 1) => (SBI_ERR_FAILED, 0),
+        (SBI_EXT_HSM, 2) => match args[0] {
+            0 => (SBI_SUCCESS, SBI_HSM_STATE_STARTED),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // hart_suspend: a retentive suspend returns at once like wfi, and a
+        // non-retentive one is not supported.
+        (SBI_EXT_HSM, 3) => match args[0] {
+            0 => (SBI_SUCCESS, 0),
+            0x8000_0000 => (SBI_ERR_NOT_SUPPORTED, 0),
+            _ => (SBI_ERR_INVALID_PARAM, 0),
+        },
+
+        (SBI_EXT_SRST, 0) => {
+            let reset_type = match args[0] {
+                SBI_SRST_TYPE_SHUTDOWN => Some(ResetType::Shutdown),
+                SBI_SRST_TYPE_COLD_REBOOT => Some(ResetType::ColdReboot),
+                SBI_SRST_TYPE_WARM_REBOOT => Some(ResetType::WarmReboot),
+                _ => None,
+            };
+            let reason = args[1] & 0xffff_ffff;
+            match reset_type {
+                Some(reset_type)
+                    if reason <= SBI_SRST_REASON_SYSTEM_FAILURE
+                        || reason >= SBI_SRST_REASON_VENDOR =>
+                {
+                    call.reset = Some((reset_type, reason));
+                    (SBI_SUCCESS, 0)
+                }
+                _ => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        }
+
+        // The address of the buffer is in a1 (the low bits) and a2 (the high bits).
+        (SBI_EXT_DBCN, 0) => match write_console(cpu, args[1] | args[2] << 32, args[0]) {
+            Some(count) => (SBI_SUCCESS, count),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        (SBI_EXT_DBCN, 1) => match read_console(cpu, args[1] | args[2] << 32, args[0]) {
+            Some(count) => (SBI_SUCCESS, count),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        (SBI_EXT_DBCN, 2) => {
+            putchar(cpu, args[0] as u8);
+            (SBI_SUCCESS, 0)
+        }
+
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    call.error = error;
+    call.value = value;
+    cpu.regs[10] = error as u64;
+    if !call.is_legacy() {
+        cpu.regs[11] = value;
+    }
+    call
+}
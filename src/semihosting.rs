@@ -0,0 +1,525 @@
+// The semihosting module services the semihosting calls of a bare-metal program,
+// with which it does its I/O on the host, e.g. printf, files, argv and exit. The
+// calls are the ARM-compatible ones of the RISC-V semihosting specification: a
+// call is an ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7`, all of them
+// uncompressed. a0 is the operation and a1 the address of its parameter block,
+// whose fields are 64 bits, or its only parameter. The result is returned in a0.
+//
+// The operations which are implemented:
+//
+// - SYS_OPEN, SYS_CLOSE, SYS_READ, SYS_WRITE, SYS_SEEK, SYS_FLEN and SYS_ISTTY on
+//   files, the console (":tt") and ":semihosting-features"
+// - SYS_WRITEC, SYS_WRITE0 and SYS_READC on the console
+// - SYS_REMOVE, SYS_RENAME and SYS_TMPNAM
+// - SYS_CLOCK, SYS_ELAPSED and SYS_TICKFREQ by mtime, SYS_TIME by the date of
+//   the CLINT, which a record of the run has
+// - SYS_ISERROR, SYS_ERRNO, SYS_GET_CMDLINE and SYS_HEAPINFO
+// - SYS_EXIT and SYS_EXIT_EXTENDED, which stop the emulator
+//
+// The files are the ones in a directory of the host, the root, and a program
+// cannot reach the files out of it, neither by an absolute path, `..` nor a
+// symbolic link. SYS_SYSTEM is not supported for the same reason. The console is
+// the UART, as for the built-in SBI.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::clint::*;
+use crate::cpu::*;
+use crate::sbi::{getchar, putchar};
+
+// The instructions around the ebreak of a call
+pub const SEMIHOSTING_ENTRY: u64 = 0x01f01013;
+pub const SEMIHOSTING_EBREAK: u64 = 0x00100073;
+pub const SEMIHOSTING_EXIT: u64 = 0x40705013;
+
+// The operations
+pub const SYS_OPEN: u64 = 0x01;
+pub const SYS_CLOSE: u64 = 0x02;
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_WRITE: u64 = 0x05;
+pub const SYS_READ: u64 = 0x06;
+pub const SYS_READC: u64 = 0x07;
+pub const SYS_ISERROR: u64 = 0x08;
+pub const SYS_ISTTY: u64 = 0x09;
+pub const SYS_SEEK: u64 = 0x0a;
+pub const SYS_FLEN: u64 = 0x0c;
+pub const SYS_TMPNAM: u64 = 0x0d;
+pub const SYS_REMOVE: u64 = 0x0e;
+pub const SYS_RENAME: u64 = 0x0f;
+pub const SYS_CLOCK: u64 = 0x10;
+pub const SYS_TIME: u64 = 0x11;
+pub const SYS_SYSTEM: u64 = 0x12;
+pub const SYS_ERRNO: u64 = 0x13;
+pub const SYS_GET_CMDLINE: u64 = 0x15;
+pub const SYS_HEAPINFO: u64 = 0x16;
+pub const SYS_EXIT: u64 = 0x18;
+pub const SYS_EXIT_EXTENDED: u64 = 0x20;
+pub const SYS_ELAPSED: u64 = 0x30;
+pub const SYS_TICKFREQ: u64 = 0x31;
+
+// The reason of SYS_EXIT for a normal exit, whose subcode is the exit code
+pub const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// The special file names of SYS_OPEN
+pub const CONSOLE_NAME: &str = ":tt";
+pub const FEATURES_NAME: &str = ":semihosting-features";
+// The contents of ":semihosting-features": the magic and the feature byte, with
+// SYS_EXIT_EXTENDED supported
+const FEATURES: [u8; 5] = [b'S', b'H', b'F', b'B', 0x01];
+
+// The error numbers of SYS_ERRNO which are not from the host
+pub const EIO: u64 = 5;
+pub const EBADF: u64 = 9;
+pub const EACCES: u64 = 13;
+pub const EFAULT: u64 = 14;
+pub const EINVAL: u64 = 22;
+pub const ENOSYS: u64 = 38;
+
+// The error number of a host error
+fn errno(e: &io::Error) -> u64 {
+    e.raw_os_error().map_or(EIO, |n| n as u64)
+}
+
+// What a handle of SYS_OPEN refers to
+enum Handle {
+    Console,
+    File(File),
+    // ":semihosting-features" with the position in it
+    Features(u64),
+}
+
+// A semihosting call, with its result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub op: u64,
+    // a1
+    pub arg: u64,
+    pub result: u64,
+    // The exit code of SYS_EXIT and SYS_EXIT_EXTENDED
+    pub exit: Option<u64>,
+    // True if the call waits for the console input. The ebreak does not retire
+    // and the call is made again.
+    pub wait: bool,
+}
+
+impl Call {
+    // The name of the operation, e.g. "SYS_WRITE"
+    pub fn name(&self) -> &'static str {
+        match self.op {
+            SYS_OPEN => "SYS_OPEN",
+            SYS_CLOSE => "SYS_CLOSE",
+            SYS_WRITEC => "SYS_WRITEC",
+            SYS_WRITE0 => "SYS_WRITE0",
+            SYS_WRITE => "SYS_WRITE",
+            SYS_READ => "SYS_READ",
+            SYS_READC => "SYS_READC",
+            SYS_ISERROR => "SYS_ISERROR",
+            SYS_ISTTY => "SYS_ISTTY",
+            SYS_SEEK => "SYS_SEEK",
+            SYS_FLEN => "SYS_FLEN",
+            SYS_TMPNAM => "SYS_TMPNAM",
+            SYS_REMOVE => "SYS_REMOVE",
+            SYS_RENAME => "SYS_RENAME",
+            SYS_CLOCK => "SYS_CLOCK",
+            SYS_TIME => "SYS_TIME",
+            SYS_SYSTEM => "SYS_SYSTEM",
+            SYS_ERRNO => "SYS_ERRNO",
+            SYS_GET_CMDLINE => "SYS_GET_CMDLINE",
+            SYS_HEAPINFO => "SYS_HEAPINFO",
+            SYS_EXIT => "SYS_EXIT",
+            SYS_EXIT_EXTENDED => "SYS_EXIT_EXTENDED",
+            SYS_ELAPSED => "SYS_ELAPSED",
+            SYS_TICKFREQ => "SYS_TICKFREQ",
+            _ => "unknown",
+        }
+    }
+}
+
+// Return true if the ebreak `inst` at `pc` is a semihosting call.
+pub fn is_call(cpu: &mut Cpu, inst: u64, pc: u64) -> bool {
+    inst == SEMIHOSTING_EBREAK
+        && cpu.fetch_at(pc.wrapping_sub(4)) == Ok(SEMIHOSTING_ENTRY)
+        && cpu.fetch_at(pc.wrapping_add(4)) == Ok(SEMIHOSTING_EXIT)
+}
+
+// The field `i` of the parameter block at `block`
+fn field(cpu: &mut Cpu, block: u64, i: u64) -> Result<u64, u64> {
+    cpu.load(block.wrapping_add(8 * i), 64).map_err(|_| EFAULT)
+}
+
+fn read_bytes(cpu: &mut Cpu, addr: u64, length: u64) -> Result<Vec<u8>, u64> {
+    (0..length)
+        .map(|i| cpu.load(addr.wrapping_add(i), 8).map(|byte| byte as u8).map_err(|_| EFAULT))
+        .collect()
+}
+
+fn write_bytes(cpu: &mut Cpu, addr: u64, bytes: &[u8]) -> Result<(), u64> {
+    for (i, byte) in bytes.iter().enumerate() {
+        cpu.store(addr.wrapping_add(i as u64), 8, *byte as u64).map_err(|_| EFAULT)?;
+    }
+    Ok(())
+}
+
+// The NUL-terminated string at `addr`
+fn read_string(cpu: &mut Cpu, addr: u64) -> Result<Vec<u8>, u64> {
+    let mut bytes = Vec::new();
+    loop {
+        match cpu.load(addr.wrapping_add(bytes.len() as u64), 8) {
+            Ok(0) => return Ok(bytes),
+            Ok(byte) => bytes.push(byte as u8),
+            Err(_) => return Err(EFAULT),
+        }
+    }
+}
+
+pub struct Semihosting {
+    // The directory of the files, canonicalized
+    root: PathBuf,
+    // The command line of SYS_GET_CMDLINE
+    cmdline: String,
+    // The open files by their handles
+    handles: Vec<Option<Handle>>,
+    // The error number of the last call which failed
+    errno: u64,
+}
+
+impl Semihosting {
+    // Service the calls of the program with the files in `root` and the command
+    // line `cmdline`, e.g. "hello.elf arg".
+    pub fn new(root: &Path, cmdline: &str) -> io::Result<Self> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", root.display(), e)))?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self {
+            root,
+            cmdline: cmdline.to_string(),
+            handles: Vec::new(),
+            errno: 0,
+        })
+    }
+
+    // The directory of the files
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // The path of a file name of the program. Only relative paths without `..` are
+    // in the root, and a symbolic link must not lead out of it either, so the path
+    // or, for a new file, its directory is resolved.
+    fn resolve(&self, name: &[u8]) -> Result<PathBuf, u64> {
+        let name = std::str::from_utf8(name).map_err(|_| EINVAL)?;
+        let relative = Path::new(name);
+        let escapes = relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if name.is_empty() || escapes {
+            return Err(EACCES);
+        }
+        let path = self.root.join(relative);
+        let real = if path.symlink_metadata().is_ok() {
+            path.canonicalize()
+        } else {
+            path.parent().unwrap_or(&self.root).canonicalize()
+        };
+        match real {
+            Ok(real) if real.starts_with(&self.root) => Ok(path),
+            Ok(_) => Err(EACCES),
+            Err(e) => Err(errno(&e)),
+        }
+    }
+
+    // Add a handle at the first free number.
+    fn add_handle(&mut self, handle: Handle) -> u64 {
+        match self.handles.iter().position(Option::is_none) {
+            Some(i) => {
+                self.handles[i] = Some(handle);
+                i as u64
+            }
+            None => {
+                self.handles.push(Some(handle));
+                self.handles.len() as u64 - 1
+            }
+        }
+    }
+
+    fn handle(&mut self, handle: u64) -> Result<&mut Handle, u64> {
+        match self.handles.get_mut(handle as usize) {
+            Some(Some(handle)) => Ok(handle),
+            _ => Err(EBADF),
+        }
+    }
+
+    // SYS_OPEN with the mode of fopen: 0 to 3 are "r", 4 to 7 "w" and 8 to 11 "a",
+    // and the modes with bit 1 set are the ones with "+".
+    fn open(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let name = field(cpu, block, 0)?;
+        let mode = field(cpu, block, 1)?;
+        let length = field(cpu, block, 2)?;
+        let name = read_bytes(cpu, name, length)?;
+        if mode > 11 {
+            return Err(EINVAL);
+        }
+        if name == CONSOLE_NAME.as_bytes() {
+            return Ok(self.add_handle(Handle::Console));
+        }
+        if name == FEATURES_NAME.as_bytes() {
+            if mode >= 4 {
+                return Err(EACCES);
+            }
+            return Ok(self.add_handle(Handle::Features(0)));
+        }
+        let path = self.resolve(&name)?;
+        let update = mode & 2 != 0;
+        let mut options = OpenOptions::new();
+        match mode / 4 {
+            0 => options.read(true).write(update),
+            1 => options.read(update).write(true).create(true).truncate(true),
+            _ => options.read(update).append(true).create(true),
+        };
+        let file = options.open(path).map_err(|e| errno(&e))?;
+        Ok(self.add_handle(Handle::File(file)))
+    }
+
+    // SYS_WRITE returns the number of bytes which are not written.
+    fn write(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let handle = field(cpu, block, 0)?;
+        let buffer = field(cpu, block, 1)?;
+        let length = field(cpu, block, 2)?;
+        let bytes = read_bytes(cpu, buffer, length)?;
+        match self.handle(handle)? {
+            Handle::Console => {
+                for byte in bytes {
+                    putchar(cpu, byte);
+                }
+                Ok(0)
+            }
+            Handle::File(file) => file.write_all(&bytes).map(|_| 0).map_err(|e| errno(&e)),
+            Handle::Features(_) => Err(EBADF),
+        }
+    }
+
+    // SYS_READ returns the number of bytes which are not read, all of them at the
+    // end of the file. The console gives the bytes typed so far, and None to wait
+    // until one is typed.
+    fn read(&mut self, cpu: &mut Cpu, block: u64) -> Result<Option<u64>, u64> {
+        let handle = field(cpu, block, 0)?;
+        let buffer = field(cpu, block, 1)?;
+        let length = field(cpu, block, 2)?;
+        let bytes = match self.handle(handle)? {
+            Handle::Console => {
+                let mut bytes = Vec::new();
+                while (bytes.len() as u64) < length {
+                    match getchar(cpu) {
+                        Some(byte) => bytes.push(byte),
+                        None => break,
+                    }
+                }
+                if bytes.is_empty() && length != 0 {
+                    return Ok(None);
+                }
+                bytes
+            }
+            Handle::File(file) => {
+                let mut bytes = Vec::new();
+                file.take(length).read_to_end(&mut bytes).map_err(|e| errno(&e))?;
+                bytes
+            }
+            Handle::Features(position) => {
+                let start = (*position).min(FEATURES.len() as u64);
+                let end = start.saturating_add(length).min(FEATURES.len() as u64);
+                *position = end;
+                FEATURES[start as usize..end as usize].to_vec()
+            }
+        };
+        write_bytes(cpu, buffer, &bytes)?;
+        Ok(Some(length - bytes.len() as u64))
+    }
+
+    // SYS_SEEK to an absolute position
+    fn seek(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let handle = field(cpu, block, 0)?;
+        let position = field(cpu, block, 1)?;
+        match self.handle(handle)? {
+            Handle::Console => Err(EINVAL),
+            Handle::File(file) => {
+                file.seek(SeekFrom::Start(position)).map(|_| 0).map_err(|e| errno(&e))
+            }
+            Handle::Features(current) => {
+                *current = position;
+                Ok(0)
+            }
+        }
+    }
+
+    fn flen(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let handle = field(cpu, block, 0)?;
+        match self.handle(handle)? {
+            Handle::Console => Err(EINVAL),
+            Handle::File(file) => file.metadata().map(|m| m.len()).map_err(|e| errno(&e)),
+            Handle::Features(_) => Ok(FEATURES.len() as u64),
+        }
+    }
+
+    // SYS_TMPNAM writes a name of a file in the root for the ID 0 to 255.
+    fn tmpnam(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let buffer = field(cpu, block, 0)?;
+        let id = field(cpu, block, 1)?;
+        let length = field(cpu, block, 2)?;
+        let name = format!("tmp{:03}\0", id & 0xff);
+        if (name.len() as u64) > length {
+            return Err(EINVAL);
+        }
+        write_bytes(cpu, buffer, name.as_bytes())?;
+        Ok(0)
+    }
+
+    fn remove(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let name = field(cpu, block, 0)?;
+        let length = field(cpu, block, 1)?;
+        let name = read_bytes(cpu, name, length)?;
+        let path = self.resolve(&name)?;
+        fs::remove_file(path).map(|_| 0).map_err(|e| errno(&e))
+    }
+
+    fn rename(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let from = field(cpu, block, 0)?;
+        let from_length = field(cpu, block, 1)?;
+        let to = field(cpu, block, 2)?;
+        let to_length = field(cpu, block, 3)?;
+        let from = read_bytes(cpu, from, from_length)?;
+        let to = read_bytes(cpu, to, to_length)?;
+        let from = self.resolve(&from)?;
+        let to = self.resolve(&to)?;
+        fs::rename(from, to).map(|_| 0).map_err(|e| errno(&e))
+    }
+
+    // SYS_GET_CMDLINE writes the command line with a NUL if it fits in the buffer,
+    // and its length to the second field.
+    fn get_cmdline(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, u64> {
+        let buffer = field(cpu, block, 0)?;
+        let length = field(cpu, block, 1)?;
+        let mut cmdline = self.cmdline.clone().into_bytes();
+        if cmdline.len() as u64 >= length {
+            return Err(EINVAL);
+        }
+        cpu.store(block.wrapping_add(8), 64, cmdline.len() as u64).map_err(|_| EFAULT)?;
+        cmdline.push(0);
+        write_bytes(cpu, buffer, &cmdline)?;
+        Ok(0)
+    }
+
+    // Service the call at the ebreak which just raised Breakpoint, with the pc
+    // after the ebreak, and write the result to a0.
+    pub fn call(&mut self, cpu: &mut Cpu) -> Call {
+        let op = cpu.regs[10];
+        let arg = cpu.regs[11];
+        let mut call = Call {
+            op,
+            arg,
+            result: 0,
+            exit: None,
+            wait: false,
+        };
+        // The operations which fail return -1 and set the error number, except
+        // SYS_REMOVE and SYS_RENAME, which return it.
+        let result = match op {
+            SYS_OPEN => self.open(cpu, arg),
+            SYS_CLOSE => field(cpu, arg, 0).and_then(|handle| {
+                self.handle(handle)?;
+                self.handles[handle as usize] = None;
+                Ok(0)
+            }),
+            SYS_WRITEC => cpu.load(arg, 8).map_err(|_| EFAULT).map(|byte| {
+                putchar(cpu, byte as u8);
+                cpu.regs[10]
+            }),
+            SYS_WRITE0 => read_string(cpu, arg).map(|bytes| {
+                for byte in bytes {
+                    putchar(cpu, byte);
+                }
+                cpu.regs[10]
+            }),
+            SYS_WRITE => self.write(cpu, arg),
+            SYS_READ => match self.read(cpu, arg) {
+                Ok(Some(count)) => Ok(count),
+                Ok(None) => {
+                    call.wait = true;
+                    return call;
+                }
+                Err(errno) => Err(errno),
+            },
+            SYS_READC => match getchar(cpu) {
+                Some(byte) => Ok(byte as u64),
+                None => {
+                    call.wait = true;
+                    return call;
+                }
+            },
+            SYS_ISERROR => field(cpu, arg, 0).map(|status| ((status as i64) < 0) as u64),
+            SYS_ISTTY => field(cpu, arg, 0).and_then(|handle| match self.handle(handle)? {
+                Handle::Console => Ok(1),
+                _ => Ok(0),
+            }),
+            SYS_SEEK => self.seek(cpu, arg),
+            SYS_FLEN => self.flen(cpu, arg),
+            SYS_TMPNAM => self.tmpnam(cpu, arg),
+            SYS_REMOVE | SYS_RENAME => {
+                let result = match op {
+                    SYS_REMOVE => self.remove(cpu, arg),
+                    _ => self.rename(cpu, arg),
+                };
+                Ok(result.unwrap_or_else(|errno| {
+                    self.errno = errno;
+                    errno
+                }))
+            }
+            // The centiseconds since the start of the machine
+            SYS_CLOCK => Ok(cpu.bus.clint.mtime() / (TIMEBASE_FREQUENCY / 100)),
+            // The seconds since the Unix epoch
+            SYS_TIME => Ok(cpu.bus.clint.date() / 1_000_000_000),
+            // A command of the host could reach out of the root.
+            SYS_SYSTEM => Err(ENOSYS),
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => self.get_cmdline(cpu, arg),
+            // The heap and the stack are unknown, so that the C library uses its
+            // defaults: the block of 4 fields is zeroed.
+            SYS_HEAPINFO => field(cpu, arg, 0).and_then(|block| {
+                write_bytes(cpu, block, &[0; 32])?;
+                Ok(0)
+            }),
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                field(cpu, arg, 0).and_then(|reason| match reason {
+                    ADP_STOPPED_APPLICATION_EXIT => field(cpu, arg, 1),
+                    // Any other reason is an error, e.g. ADP_Stopped_RunTimeErrorUnknown.
+                    _ => Ok(1),
+                })
+                .map(|code| {
+                    call.exit = Some(code);
+                    0
+                })
+            }
+            SYS_ELAPSED => cpu.store(arg, 64, cpu.bus.clint.mtime()).map(|_| 0).map_err(|_| EFAULT),
+            SYS_TICKFREQ => Ok(TIMEBASE_FREQUENCY),
+            _ => Err(ENOSYS),
+        };
+        call.result = match result {
+            Ok(result) => result,
+            Err(errno) => {
+                self.errno = errno;
+                u64::MAX
+            }
+        };
+        cpu.regs[10] = call.result;
+        call
+    }
+}
+
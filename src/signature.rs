@@ -0,0 +1,72 @@
+// The signature module runs a test of riscv-arch-test
+// (https://github.com/riscv-non-isa/riscv-arch-test) and writes its signature,
+// the memory between the `begin_signature` and `end_signature` symbols, which
+// the framework compares with the signature of the reference model.
+//
+// The test halts by writing to `tohost` (RVMODEL_HALT of the Spike model). The
+// signature is written in the format of `spike +signature=<file>`: one line per
+// `granularity` bytes, printed as a hexadecimal number, i.e. the byte at the
+// highest address first. If the signature is not a multiple of `granularity`
+// bytes, the last line is padded with zeros, as Spike does.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::emulator::*;
+use crate::elf::*;
+use crate::riscv_tests::*;
+
+// The default number of bytes per line, the same as Spike.
+pub const DEFAULT_GRANULARITY: u64 = 4;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Run the ELF file `binary` until it halts and write its signature to `signature`.
+pub fn run_signature(binary: &[u8], signature: &str, granularity: u64) -> io::Result<Outcome> {
+    if granularity == 0 || !granularity.is_multiple_of(4) {
+        return Err(invalid(format!(
+            "the signature granularity must be a multiple of 4: {}",
+            granularity
+        )));
+    }
+    let elf = Elf::parse(binary)?;
+    let symbol = |name: &str| {
+        elf.symbol(name)
+            .ok_or_else(|| invalid(format!("no `{}` symbol in the ELF file", name)))
+    };
+    let begin = symbol("begin_signature")?;
+    let end = symbol("end_signature")?;
+    let tohost = symbol("tohost")?;
+
+    let mut emulator = Emulator::builder()
+        .binary(binary.to_vec())
+        .stop_on_exception(false)
+        .build()?;
+    let outcome = run_until_tohost(&mut emulator, tohost);
+
+    // The signature is written even if the test did not halt, so that the
+    // framework can show where it differs.
+    let mut output = BufWriter::new(File::create(signature)?);
+    let mut addr = begin;
+    while addr < end {
+        let mut line = String::new();
+        for offset in (0..granularity / 4).rev().map(|i| i * 4) {
+            if addr + offset >= end {
+                line.push_str("00000000");
+                continue;
+            }
+            let word = emulator
+                .load(addr + offset, 32)
+                .map_err(|_| invalid(format!("the signature at 0x{:x} is not in memory", addr)))?;
+            line.push_str(&format!("{:08x}", word));
+        }
+        writeln!(output, "{}", line)?;
+        addr += granularity;
+    }
+    output.flush()?;
+    Ok(outcome)
+}
@@ -0,0 +1,177 @@
+// The snapshot module saves the whole state of the machine to a file and restores
+// it, so that a run can start from e.g. a booted kernel instead of from reset.
+//
+// A snapshot file is the magic number, the version of the format, the state of
+// the cpu, the memory and each device, written by their `save` methods and read
+// back in the same order by their `restore` methods, and then the number of
+// instructions executed. All integers are little-endian.
+//
+// A program which calls the host by semihosting, Linux system calls or the HTIF
+// has state on the host, e.g. open files, which a snapshot cannot hold, so it
+// cannot be saved or restored.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use crate::emulator::Emulator;
+
+// The magic number at the start of a snapshot file.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RVEMUSNP";
+// The version of the format. Increment it when the state of any part changes,
+// since old snapshots cannot be read anymore.
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid snapshot: {}", message))
+}
+
+// Serializes the state into a buffer.
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+// Deserializes the state from a buffer.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    // Bytes with their length
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    // A tag to find where a part of the state starts, which makes a corrupted
+    // file fail early with a useful message.
+    pub fn section(&mut self, name: &str) {
+        self.bytes(name.as_bytes());
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, size: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(size)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| invalid("unexpected end of file".to_string()))?;
+        self.pos += size;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let size = self.u64()? as usize;
+        self.take(size)
+    }
+
+    // Check the tag written by Writer::section.
+    pub fn section(&mut self, name: &str) -> io::Result<()> {
+        let tag = self.bytes()?;
+        if tag != name.as_bytes() {
+            return Err(invalid(format!(
+                "expected the state of {}, found {:?}",
+                name,
+                String::from_utf8_lossy(tag)
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn check_host_calls(emulator: &Emulator) -> io::Result<()> {
+    if emulator.calls_host() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a program which calls the host cannot be saved or restored",
+        ));
+    }
+    Ok(())
+}
+
+// Save the state of the machine to a file.
+pub fn save(emulator: &Emulator, path: &str) -> io::Result<()> {
+    check_host_calls(emulator)?;
+    let mut writer = Writer::new();
+    emulator.cpu.save(&mut writer);
+    writer.section("emulator");
+    writer.u64(emulator.instruction_count());
+    let mut file = File::create(path)?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    file.write_all(&writer.into_inner())?;
+    Ok(())
+}
+
+// Restore the state of the machine from a file.
+pub fn restore(emulator: &mut Emulator, path: &str) -> io::Result<()> {
+    check_host_calls(emulator)?;
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut reader = Reader::new(&data);
+    if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(invalid("no snapshot magic number".to_string()));
+    }
+    let version = reader.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(format!(
+            "version {} is not supported, expected {}",
+            version, SNAPSHOT_VERSION
+        )));
+    }
+    emulator.cpu.restore(&mut reader)?;
+    reader.section("emulator")?;
+    emulator.set_instruction_count(reader.u64()?);
+    Ok(())
+}
@@ -0,0 +1,64 @@
+// The symbols module looks up the symbol of an address in the symbol table of
+// the loaded ELF file, to print addresses as `function+offset` like objdump.
+
+use crate::elf::*;
+
+#[derive(Default)]
+pub struct Symbols {
+    // (address, size, name), sorted by address
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+    pub fn new(elf: &Elf) -> Self {
+        // Only functions and labels, i.e. symbols without a type, are code
+        // addresses. Sections, files and data are skipped.
+        let mut symbols: Vec<_> = elf
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind == STT_FUNC || symbol.kind == STT_NOTYPE)
+            .filter(|symbol| symbol.value != 0)
+            .map(|symbol| (symbol.value, symbol.size, symbol.name.clone()))
+            .collect();
+        // Of the symbols at the same address, lookup() finds the last one, so a
+        // symbol with a size (a function) is preferred to a label.
+        symbols.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+        Self { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // Return `function+offset` of an address, or None if the address is not in a
+    // function.
+    pub fn lookup(&self, addr: u64) -> Option<String> {
+        let index = self.symbols.partition_point(|symbol| symbol.0 <= addr);
+        let (value, size, name) = self.symbols.get(index.checked_sub(1)?)?;
+        // A label has no size, so it covers everything up to the next symbol.
+        if *size != 0 && addr >= value + size {
+            return None;
+        }
+        if addr == *value {
+            Some(name.clone())
+        } else {
+            Some(format!("{}+0x{:x}", name, addr - value))
+        }
+    }
+
+    // Return the address of a symbol.
+    pub fn address(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.2 == name)
+            .map(|symbol| symbol.0)
+    }
+
+    // Format an address followed by its symbol, e.g. `0x80000010 <main+0x10>`.
+    pub fn format(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some(symbol) => format!("{:#x} <{}>", addr, symbol),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
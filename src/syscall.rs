@@ -0,0 +1,1166 @@
+// The syscall module runs a statically linked riscv64 Linux program as a process,
+// as qemu-user does, so that e.g. the tests of a compiler run without booting a
+// kernel. The program starts in U-mode at its entry with the initial stack of
+// Linux: argc, argv, envp and the auxiliary vector. Its ecalls are the system
+// calls of Linux, which the emulator does on the host: a7 is the number, a0 to a5
+// the arguments, and the result or -errno is returned in a0.
+//
+// The memory is flat, without paging, from USER_MEMORY_BASE to USER_STACK_TOP, so
+// that the addresses of the system calls are the physical ones.
+// The program is loaded at the addresses of its segments, the heap of brk follows
+// it, the stack is at the top and mmap allocates downward from below the stack.
+// There are no devices.
+//
+// The files are the ones of the host, and the paths are relative to the working
+// directory of the program, which starts as the one of the emulator. Only one
+// thread runs: clone, fork and the signal handlers are not implemented, and a
+// signal sent by the program to itself, e.g. by abort, ends it with 128 + the
+// signal as the exit code, as a shell reports it. The clocks count the time of
+// mtime, CLOCK_REALTIME and gettimeofday from the date of the host at the start
+// (see Clint::date), and getrandom and AT_RANDOM give the same bytes on every
+// run, so that a run can be reproduced.
+//
+// The system calls of the host files are also the ones a program forwards to the
+// host, e.g. by the HTIF syscall proxy of a proxy kernel, see the htif module.
+
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+
+use crate::clint::*;
+use crate::cpu::*;
+use crate::elf::*;
+use crate::memory::*;
+use crate::trap::*;
+
+// The memory of a program: the addresses below USER_MEMORY_BASE fault, as in
+// Linux, which does not map the first 64KB.
+pub const USER_MEMORY_BASE: u64 = 0x1_0000;
+pub const USER_STACK_TOP: u64 = 0x1_0000_0000;
+pub const USER_MEMORY_SIZE: u64 = USER_STACK_TOP - USER_MEMORY_BASE;
+// The size of the stack, below which mmap allocates
+pub const USER_STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+// The ID of the process and of its only thread
+pub const USER_PID: u64 = 1;
+
+// The system calls, by their numbers on riscv64
+pub const NR_GETCWD: u64 = 17;
+pub const NR_DUP: u64 = 23;
+pub const NR_DUP3: u64 = 24;
+pub const NR_FCNTL: u64 = 25;
+pub const NR_IOCTL: u64 = 29;
+pub const NR_MKDIRAT: u64 = 34;
+pub const NR_UNLINKAT: u64 = 35;
+pub const NR_LINKAT: u64 = 37;
+pub const NR_RENAMEAT: u64 = 38;
+pub const NR_FTRUNCATE: u64 = 46;
+pub const NR_FACCESSAT: u64 = 48;
+pub const NR_CHDIR: u64 = 49;
+pub const NR_OPENAT: u64 = 56;
+pub const NR_CLOSE: u64 = 57;
+pub const NR_LSEEK: u64 = 62;
+pub const NR_READ: u64 = 63;
+pub const NR_WRITE: u64 = 64;
+pub const NR_READV: u64 = 65;
+pub const NR_WRITEV: u64 = 66;
+pub const NR_PREAD64: u64 = 67;
+pub const NR_PWRITE64: u64 = 68;
+pub const NR_READLINKAT: u64 = 78;
+pub const NR_NEWFSTATAT: u64 = 79;
+pub const NR_FSTAT: u64 = 80;
+pub const NR_EXIT: u64 = 93;
+pub const NR_EXIT_GROUP: u64 = 94;
+pub const NR_SET_TID_ADDRESS: u64 = 96;
+pub const NR_FUTEX: u64 = 98;
+pub const NR_SET_ROBUST_LIST: u64 = 99;
+pub const NR_NANOSLEEP: u64 = 101;
+pub const NR_CLOCK_GETTIME: u64 = 113;
+pub const NR_CLOCK_GETRES: u64 = 114;
+pub const NR_CLOCK_NANOSLEEP: u64 = 115;
+pub const NR_SCHED_GETAFFINITY: u64 = 123;
+pub const NR_SCHED_YIELD: u64 = 124;
+pub const NR_KILL: u64 = 129;
+pub const NR_TKILL: u64 = 130;
+pub const NR_TGKILL: u64 = 131;
+pub const NR_SIGALTSTACK: u64 = 132;
+pub const NR_RT_SIGACTION: u64 = 134;
+pub const NR_RT_SIGPROCMASK: u64 = 135;
+pub const NR_TIMES: u64 = 153;
+pub const NR_UNAME: u64 = 160;
+pub const NR_GETRLIMIT: u64 = 163;
+pub const NR_SETRLIMIT: u64 = 164;
+pub const NR_GETRUSAGE: u64 = 165;
+pub const NR_UMASK: u64 = 166;
+pub const NR_GETTIMEOFDAY: u64 = 169;
+pub const NR_GETPID: u64 = 172;
+pub const NR_GETPPID: u64 = 173;
+pub const NR_GETUID: u64 = 174;
+pub const NR_GETEUID: u64 = 175;
+pub const NR_GETGID: u64 = 176;
+pub const NR_GETEGID: u64 = 177;
+pub const NR_GETTID: u64 = 178;
+pub const NR_BRK: u64 = 214;
+pub const NR_MUNMAP: u64 = 215;
+pub const NR_MREMAP: u64 = 216;
+pub const NR_MMAP: u64 = 222;
+pub const NR_MPROTECT: u64 = 226;
+pub const NR_MADVISE: u64 = 233;
+pub const NR_RISCV_FLUSH_ICACHE: u64 = 259;
+pub const NR_PRLIMIT64: u64 = 261;
+pub const NR_RENAMEAT2: u64 = 276;
+pub const NR_GETRANDOM: u64 = 278;
+
+// The error numbers, the same as the ones of the host
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ESPIPE: i64 = 29;
+pub const ERANGE: i64 = 34;
+pub const ENAMETOOLONG: i64 = 36;
+pub const ENOSYS: i64 = 38;
+
+// The dirfd of the working directory
+const AT_FDCWD: u64 = -100i64 as u64;
+// The flags of the *at calls
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// The flags of openat
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+// The commands of fcntl
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+// The flags of mmap
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// The operations of futex, without FUTEX_PRIVATE_FLAG
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAKE_BITSET: u64 = 10;
+
+// The clocks of clock_gettime which give the date
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+
+// The resources of prlimit64 which are limited
+const RLIMIT_STACK: u64 = 3;
+const RLIMIT_NOFILE: u64 = 7;
+const RLIM_INFINITY: u64 = u64::MAX;
+// The number of file descriptors
+const MAX_FILES: u64 = 1024;
+
+// The entries of the auxiliary vector
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+// The ticks of times and AT_CLKTCK per second
+const CLOCK_TICKS: u64 = 100;
+// The mode of the console in stat: a character device, rw--w----
+const CONSOLE_MODE: u64 = 0o020620;
+
+// The largest read or write done at once. A larger one is done partly, as Linux
+// may do.
+const MAX_IO_SIZE: u64 = 1024 * 1024;
+// The longest path, with its NUL
+const PATH_MAX: u64 = 4096;
+
+// The error number of a host error
+fn errno(e: &io::Error) -> i64 {
+    e.raw_os_error().map_or(EIO, |n| n as i64)
+}
+
+fn page_align(size: u64) -> u64 {
+    size.wrapping_add(PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)
+}
+
+// A file descriptor of the program
+enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File {
+        file: File,
+        // The path it was opened by, for the *at calls relative to a directory
+        path: PathBuf,
+        // The flags of openat, for F_GETFL
+        flags: u64,
+    },
+}
+
+impl Descriptor {
+    fn try_clone(&self) -> Result<Descriptor, i64> {
+        Ok(match self {
+            Descriptor::Stdin => Descriptor::Stdin,
+            Descriptor::Stdout => Descriptor::Stdout,
+            Descriptor::Stderr => Descriptor::Stderr,
+            Descriptor::File { file, path, flags } => Descriptor::File {
+                file: file.try_clone().map_err(|e| errno(&e))?,
+                path: path.clone(),
+                flags: *flags,
+            },
+        })
+    }
+}
+
+// A system call, with its result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    // a7
+    pub number: u64,
+    // a0 to a5
+    pub args: [u64; 6],
+    // The value or -errno
+    pub result: i64,
+    // The exit code of exit and exit_group, or of a signal to the process
+    pub exit: Option<u64>,
+}
+
+impl Call {
+    // The name of the system call, e.g. "write"
+    pub fn name(&self) -> &'static str {
+        match self.number {
+            NR_GETCWD => "getcwd",
+            NR_DUP => "dup",
+            NR_DUP3 => "dup3",
+            NR_FCNTL => "fcntl",
+            NR_IOCTL => "ioctl",
+            NR_MKDIRAT => "mkdirat",
+            NR_UNLINKAT => "unlinkat",
+            NR_LINKAT => "linkat",
+            NR_RENAMEAT => "renameat",
+            NR_FTRUNCATE => "ftruncate",
+            NR_FACCESSAT => "faccessat",
+            NR_CHDIR => "chdir",
+            NR_OPENAT => "openat",
+            NR_CLOSE => "close",
+            NR_LSEEK => "lseek",
+            NR_READ => "read",
+            NR_WRITE => "write",
+            NR_READV => "readv",
+            NR_WRITEV => "writev",
+            NR_PREAD64 => "pread64",
+            NR_PWRITE64 => "pwrite64",
+            NR_READLINKAT => "readlinkat",
+            NR_NEWFSTATAT => "newfstatat",
+            NR_FSTAT => "fstat",
+            NR_EXIT => "exit",
+            NR_EXIT_GROUP => "exit_group",
+            NR_SET_TID_ADDRESS => "set_tid_address",
+            NR_FUTEX => "futex",
+            NR_SET_ROBUST_LIST => "set_robust_list",
+            NR_NANOSLEEP => "nanosleep",
+            NR_CLOCK_GETTIME => "clock_gettime",
+            NR_CLOCK_GETRES => "clock_getres",
+            NR_CLOCK_NANOSLEEP => "clock_nanosleep",
+            NR_SCHED_GETAFFINITY => "sched_getaffinity",
+            NR_SCHED_YIELD => "sched_yield",
+            NR_KILL => "kill",
+            NR_TKILL => "tkill",
+            NR_TGKILL => "tgkill",
+            NR_SIGALTSTACK => "sigaltstack",
+            NR_RT_SIGACTION => "rt_sigaction",
+            NR_RT_SIGPROCMASK => "rt_sigprocmask",
+            NR_TIMES => "times",
+            NR_UNAME => "uname",
+            NR_GETRLIMIT => "getrlimit",
+            NR_SETRLIMIT => "setrlimit",
+            NR_GETRUSAGE => "getrusage",
+            NR_UMASK => "umask",
+            NR_GETTIMEOFDAY => "gettimeofday",
+            NR_GETPID => "getpid",
+            NR_GETPPID => "getppid",
+            NR_GETUID => "getuid",
+            NR_GETEUID => "geteuid",
+            NR_GETGID => "getgid",
+            NR_GETEGID => "getegid",
+            NR_GETTID => "gettid",
+            NR_BRK => "brk",
+            NR_MUNMAP => "munmap",
+            NR_MREMAP => "mremap",
+            NR_MMAP => "mmap",
+            NR_MPROTECT => "mprotect",
+            NR_MADVISE => "madvise",
+            NR_RISCV_FLUSH_ICACHE => "riscv_flush_icache",
+            NR_PRLIMIT64 => "prlimit64",
+            NR_RENAMEAT2 => "renameat2",
+            NR_GETRANDOM => "getrandom",
+            _ => "unknown",
+        }
+    }
+}
+
+// The signal which Linux sends for an exception of a program, e.g. SIGSEGV for an
+// access fault, whose exit code is 128 + the signal.
+pub fn signal(exception: &Exception) -> u64 {
+    match exception {
+        Exception::IllegalInstruction => 4,
+        Exception::Breakpoint => 5,
+        Exception::InstructionAddresMisaligned
+        | Exception::LoadAddressMisaligned
+        | Exception::StoreAMOAddressMisaligned => 7,
+        _ => 11,
+    }
+}
+
+// The memory of the system calls is accessed by physical addresses.
+pub fn read_bytes(cpu: &mut Cpu, addr: u64, length: u64) -> Result<Vec<u8>, i64> {
+    (0..length)
+        .map(|i| cpu.bus.load(addr.wrapping_add(i), 8).map(|byte| byte as u8).map_err(|_| EFAULT))
+        .collect()
+}
+
+pub fn write_bytes(cpu: &mut Cpu, addr: u64, bytes: &[u8]) -> Result<(), i64> {
+    for (i, byte) in bytes.iter().enumerate() {
+        cpu.bus.store(addr.wrapping_add(i as u64), 8, *byte as u64).map_err(|_| EFAULT)?;
+    }
+    Ok(())
+}
+
+pub fn write_words(cpu: &mut Cpu, addr: u64, words: &[u64]) -> Result<(), i64> {
+    for (i, word) in words.iter().enumerate() {
+        cpu.bus.store(addr.wrapping_add(8 * i as u64), 64, *word).map_err(|_| EFAULT)?;
+    }
+    Ok(())
+}
+
+// The NUL-terminated string at `addr`, e.g. a path
+fn read_string(cpu: &mut Cpu, addr: u64) -> Result<String, i64> {
+    let mut bytes = Vec::new();
+    loop {
+        if bytes.len() as u64 == PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        match cpu.bus.load(addr.wrapping_add(bytes.len() as u64), 8) {
+            Ok(0) => return String::from_utf8(bytes).map_err(|_| EINVAL),
+            Ok(byte) => bytes.push(byte as u8),
+            Err(_) => return Err(EFAULT),
+        }
+    }
+}
+
+// Write a struct timespec or timeval of the nanoseconds, whose second field is in
+// `unit` nanoseconds.
+fn write_time(cpu: &mut Cpu, addr: u64, nanoseconds: u128, unit: u128) -> Result<(), i64> {
+    let seconds = (nanoseconds / 1_000_000_000) as u64;
+    let fraction = (nanoseconds % 1_000_000_000 / unit) as u64;
+    write_words(cpu, addr, &[seconds, fraction])
+}
+
+// The nanoseconds since the start of the machine by mtime
+fn machine_time(cpu: &Cpu) -> u128 {
+    cpu.bus.clint.mtime() as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128
+}
+
+// Write the struct stat of riscv64 for the metadata of a file. Without
+// `nanoseconds`, the nanoseconds of the times are 0, as in the struct stat of the
+// front-end server of Spike, which has padding there.
+fn write_stat(cpu: &mut Cpu, addr: u64, metadata: &Metadata, nanoseconds: bool) -> Result<(), i64> {
+    let nanoseconds = |value: i64| if nanoseconds { value as u64 } else { 0 };
+    let words = [
+        metadata.dev(),
+        metadata.ino(),
+        // st_mode and st_nlink
+        metadata.mode() as u64 | (metadata.nlink() as u32 as u64) << 32,
+        // st_uid and st_gid
+        metadata.uid() as u64 | (metadata.gid() as u64) << 32,
+        metadata.rdev(),
+        0,
+        metadata.size(),
+        metadata.blksize() & 0xffff_ffff,
+        metadata.blocks(),
+        metadata.atime() as u64,
+        nanoseconds(metadata.atime_nsec()),
+        metadata.mtime() as u64,
+        nanoseconds(metadata.mtime_nsec()),
+        metadata.ctime() as u64,
+        nanoseconds(metadata.ctime_nsec()),
+        0,
+    ];
+    write_words(cpu, addr, &words)
+}
+
+// Write the struct stat of the console, a character device.
+fn write_console_stat(cpu: &mut Cpu, addr: u64) -> Result<(), i64> {
+    let mut words = [0; 16];
+    words[2] = CONSOLE_MODE | 1 << 32;
+    words[7] = 1024;
+    write_words(cpu, addr, &words)
+}
+
+pub struct Process {
+    files: Vec<Option<Descriptor>>,
+    // The working directory, which relative paths are relative to
+    cwd: PathBuf,
+    // The path of the executable, for /proc/self/exe
+    exe: PathBuf,
+    // The start and the end of the heap
+    brk_start: u64,
+    brk: u64,
+    // The lowest address allocated by mmap
+    mmap_base: u64,
+    umask: u64,
+    // The state of the generator of getrandom
+    random: u64,
+    // The input of fd 0, the console by default, which is the input of the UART
+    stdin: Option<Box<dyn Read>>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    // False for the syscall proxy, whose struct stat has no nanoseconds
+    stat_nanoseconds: bool,
+}
+
+impl Process {
+    // Start the program loaded from `elf` in U-mode with the arguments and the
+    // environment, e.g. "HOME=/root", on the initial stack.
+    pub fn new(cpu: &mut Cpu, elf: &Elf, argv: &[String], envp: &[String]) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+        if elf.interpreter {
+            return Err(invalid("the program is dynamically linked, only static ones run"));
+        }
+        let program_headers = elf
+            .program_headers
+            .ok_or_else(|| invalid("the program headers are not loaded by a segment"))?;
+        let end = elf
+            .segments
+            .iter()
+            .map(|segment| segment.addr + segment.mem_size)
+            .max()
+            .unwrap_or(USER_MEMORY_BASE);
+        let cwd = std::env::current_dir()?;
+        let exe = cwd.join(argv.first().map_or("", String::as_str));
+
+        // The strings, the random bytes and then the vectors, from the top
+        let mut sp = USER_STACK_TOP;
+        let mut push = |cpu: &mut Cpu, bytes: &[u8]| {
+            sp -= bytes.len() as u64;
+            write_bytes(cpu, sp, bytes).map(|_| sp)
+        };
+        let stack_error = |_| invalid("the initial stack is not in memory");
+        let mut strings = Vec::new();
+        for string in argv.iter().chain(envp.iter()) {
+            let string = [string.as_bytes(), &[0]].concat();
+            strings.push(push(cpu, &string).map_err(stack_error)?);
+        }
+        let random = push(cpu, b"riscvemu random.").map_err(stack_error)?;
+        let execfn = strings.first().copied().unwrap_or(0);
+        // The single-letter extensions, as the bits of misa
+        let hwcap = cpu.csrs[MISA] & ((1 << 26) - 1) & !(1 << (b's' - b'a') | 1 << (b'u' - b'a'));
+        let auxv = [
+            (AT_PHDR, program_headers),
+            (AT_PHENT, elf.program_header_size),
+            (AT_PHNUM, elf.program_header_count),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, hwcap),
+            (AT_CLKTCK, CLOCK_TICKS),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend_from_slice(&strings[..argv.len()]);
+        words.push(0);
+        words.extend_from_slice(&strings[argv.len()..]);
+        words.push(0);
+        for (key, value) in auxv.iter() {
+            words.extend_from_slice(&[*key, *value]);
+        }
+        // sp is aligned to 16 bytes.
+        let sp = (sp - 8 * words.len() as u64) & !0xf;
+        write_words(cpu, sp, &words).map_err(stack_error)?;
+
+        cpu.regs[2] = sp;
+        cpu.pc = elf.entry;
+        cpu.mode = Mode::User;
+        // Linux starts a process with the floating-point unit on, in the initial state.
+        if cpu.has_extension(b'f') {
+            cpu.csrs[MSTATUS] |= FS_INITIAL;
+        }
+        // The program reads cycle, time and instret.
+        cpu.csrs[MCOUNTEREN] = 0b111;
+        cpu.csrs[SCOUNTEREN] = 0b111;
+        let brk = page_align(end);
+        Ok(Self {
+            files: vec![Some(Descriptor::Stdin), Some(Descriptor::Stdout), Some(Descriptor::Stderr)],
+            cwd,
+            exe,
+            brk_start: brk,
+            brk,
+            mmap_base: USER_STACK_TOP - USER_STACK_SIZE,
+            umask: 0o022,
+            random: 0x2545_f491_4f6c_dd1d,
+            stdin: None,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            stat_nanoseconds: true,
+        })
+    }
+
+    // A process of the host files only, for a program which runs on its own and
+    // forwards the system calls to the host, e.g. a proxy kernel by HTIF
+    pub fn proxy() -> io::Result<Self> {
+        Ok(Self {
+            files: vec![Some(Descriptor::Stdin), Some(Descriptor::Stdout), Some(Descriptor::Stderr)],
+            cwd: std::env::current_dir()?,
+            exe: PathBuf::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_base: 0,
+            umask: 0o022,
+            random: 0x2545_f491_4f6c_dd1d,
+            stdin: None,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            stat_nanoseconds: false,
+        })
+    }
+
+    // Read fd 0 from `stdin` instead of the console.
+    pub fn set_stdin(&mut self, stdin: Box<dyn Read>) {
+        self.stdin = Some(stdin);
+    }
+
+    // Write fd 1 to `stdout` instead of the stdout of the host.
+    pub fn set_stdout(&mut self, stdout: Box<dyn Write>) {
+        self.stdout = stdout;
+    }
+
+    // Write fd 2 to `stderr` instead of the stderr of the host.
+    pub fn set_stderr(&mut self, stderr: Box<dyn Write>) {
+        self.stderr = stderr;
+    }
+
+    // The current end of the heap
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    // True if `fd` reads the console, i.e. the input of the emulator
+    pub fn is_console_input(&self, fd: u64) -> bool {
+        self.stdin.is_none() && matches!(self.files.get(fd as usize), Some(Some(Descriptor::Stdin)))
+    }
+
+    // Add a descriptor at the lowest free number from `min`.
+    fn add_file(&mut self, descriptor: Descriptor, min: u64) -> Result<u64, i64> {
+        let min = min as usize;
+        if min >= MAX_FILES as usize {
+            return Err(EINVAL);
+        }
+        if self.files.len() < min {
+            self.files.resize_with(min, || None);
+        }
+        match self.files[min..].iter().position(Option::is_none) {
+            Some(i) => {
+                self.files[min + i] = Some(descriptor);
+                Ok((min + i) as u64)
+            }
+            None if self.files.len() < MAX_FILES as usize => {
+                self.files.push(Some(descriptor));
+                Ok(self.files.len() as u64 - 1)
+            }
+            None => Err(EINVAL),
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut Descriptor, i64> {
+        match self.files.get_mut(fd as usize) {
+            Some(Some(descriptor)) => Ok(descriptor),
+            _ => Err(EBADF),
+        }
+    }
+
+    // The path of `path` relative to the directory `dirfd`
+    fn path(&mut self, cpu: &mut Cpu, dirfd: u64, path: u64) -> Result<PathBuf, i64> {
+        let path = PathBuf::from(read_string(cpu, path)?);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        if path.as_os_str().is_empty() {
+            return Err(ENOENT);
+        }
+        match dirfd {
+            AT_FDCWD => Ok(self.cwd.join(path)),
+            _ => match self.file(dirfd)? {
+                Descriptor::File { path: dir, .. } => Ok(dir.join(path)),
+                _ => Err(ENOTTY),
+            },
+        }
+    }
+
+    fn read(&mut self, cpu: &mut Cpu, fd: u64, buffer: u64, length: u64) -> Result<u64, i64> {
+        let length = length.min(MAX_IO_SIZE) as usize;
+        let bytes = match self.file(fd)? {
+            Descriptor::Stdin => match self.stdin.as_mut() {
+                Some(stdin) => {
+                    let mut bytes = vec![0; length];
+                    let count = stdin.read(&mut bytes).map_err(|e| errno(&e))?;
+                    bytes.truncate(count);
+                    bytes
+                }
+                // Wait for a byte, and then take the ones typed so far.
+                None => {
+                    let mut bytes = Vec::new();
+                    if length != 0 {
+                        if let Some(byte) = cpu.bus.uart.wait_input() {
+                            bytes.push(byte);
+                            while bytes.len() < length {
+                                match cpu.bus.uart.take_input() {
+                                    Some(byte) => bytes.push(byte),
+                                    None => break,
+                                }
+                            }
+                        }
+                    }
+                    bytes
+                }
+            },
+            Descriptor::File { file, .. } => {
+                let mut bytes = vec![0; length];
+                let count = file.read(&mut bytes).map_err(|e| errno(&e))?;
+                bytes.truncate(count);
+                bytes
+            }
+            _ => return Err(EBADF),
+        };
+        write_bytes(cpu, buffer, &bytes)?;
+        Ok(bytes.len() as u64)
+    }
+
+    fn write(&mut self, cpu: &mut Cpu, fd: u64, buffer: u64, length: u64) -> Result<u64, i64> {
+        let bytes = read_bytes(cpu, buffer, length.min(MAX_IO_SIZE))?;
+        let output = match self.file(fd)? {
+            Descriptor::Stdout => &mut self.stdout,
+            Descriptor::Stderr => &mut self.stderr,
+            Descriptor::File { file, .. } => {
+                return file.write(&bytes).map(|count| count as u64).map_err(|e| errno(&e))
+            }
+            Descriptor::Stdin => return Err(EBADF),
+        };
+        output.write_all(&bytes).and_then(|_| output.flush()).map_err(|e| errno(&e))?;
+        Ok(bytes.len() as u64)
+    }
+
+    // readv and writev with the array of struct iovec at `iov`
+    fn vector(&mut self, cpu: &mut Cpu, write: bool, fd: u64, iov: u64, count: u64) -> Result<u64, i64> {
+        let mut total = 0;
+        for i in 0..count.min(MAX_FILES) {
+            let base = cpu.bus.load(iov + 16 * i, 64).map_err(|_| EFAULT)?;
+            let length = cpu.bus.load(iov + 16 * i + 8, 64).map_err(|_| EFAULT)?;
+            let done = match write {
+                true => self.write(cpu, fd, base, length)?,
+                false => self.read(cpu, fd, base, length)?,
+            };
+            total += done;
+            if done < length {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    // pread64 and pwrite64 at `offset`, which do not move the file offset
+    fn positioned(
+        &mut self,
+        cpu: &mut Cpu,
+        write: bool,
+        fd: u64,
+        buffer: u64,
+        length: u64,
+        offset: u64,
+    ) -> Result<u64, i64> {
+        let length = length.min(MAX_IO_SIZE);
+        let bytes = match write {
+            true => read_bytes(cpu, buffer, length)?,
+            false => vec![0; length as usize],
+        };
+        let file = match self.file(fd)? {
+            Descriptor::File { file, .. } => file,
+            _ => return Err(ESPIPE),
+        };
+        if write {
+            return file.write_at(&bytes, offset).map(|count| count as u64).map_err(|e| errno(&e));
+        }
+        let mut bytes = bytes;
+        let count = file.read_at(&mut bytes, offset).map_err(|e| errno(&e))?;
+        write_bytes(cpu, buffer, &bytes[..count])?;
+        Ok(count as u64)
+    }
+
+    fn openat(&mut self, cpu: &mut Cpu, dirfd: u64, path: u64, flags: u64, mode: u64) -> Result<u64, i64> {
+        let path = self.path(cpu, dirfd, path)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        if flags & O_APPEND != 0 {
+            options.append(true);
+        }
+        if flags & O_TRUNC != 0 {
+            options.truncate(true);
+        }
+        if flags & O_CREAT != 0 {
+            // create needs write access, which the flags give.
+            options.write(true);
+            match flags & O_EXCL {
+                0 => options.create(true),
+                _ => options.create_new(true),
+            };
+        }
+        options.mode((mode & !self.umask) as u32);
+        let file = options.open(&path).map_err(|e| errno(&e))?;
+        self.add_file(Descriptor::File { file, path, flags }, 0)
+    }
+
+    fn fcntl(&mut self, fd: u64, command: u64, arg: u64) -> Result<u64, i64> {
+        match command {
+            F_DUPFD | F_DUPFD_CLOEXEC => {
+                let descriptor = self.file(fd)?.try_clone()?;
+                self.add_file(descriptor, arg)
+            }
+            F_GETFD | F_SETFD | F_SETFL => self.file(fd).map(|_| 0),
+            F_GETFL => match self.file(fd)? {
+                Descriptor::Stdin => Ok(0),
+                Descriptor::Stdout | Descriptor::Stderr => Ok(O_WRONLY),
+                Descriptor::File { flags, .. } => Ok(*flags & (O_ACCMODE | O_APPEND)),
+            },
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, i64> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        match self.file(fd)? {
+            Descriptor::File { file, .. } => file.seek(position).map_err(|e| errno(&e)),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    fn fstat(&mut self, cpu: &mut Cpu, fd: u64, stat: u64) -> Result<u64, i64> {
+        match self.file(fd)? {
+            Descriptor::File { file, .. } => {
+                let metadata = file.metadata().map_err(|e| errno(&e))?;
+                write_stat(cpu, stat, &metadata, self.stat_nanoseconds)?;
+            }
+            _ => write_console_stat(cpu, stat)?,
+        }
+        Ok(0)
+    }
+
+    fn newfstatat(
+        &mut self,
+        cpu: &mut Cpu,
+        dirfd: u64,
+        path: u64,
+        stat: u64,
+        flags: u64,
+    ) -> Result<u64, i64> {
+        if flags & AT_EMPTY_PATH != 0 && cpu.bus.load(path, 8) == Ok(0) {
+            return match dirfd {
+                AT_FDCWD => {
+                    let metadata = fs::metadata(&self.cwd).map_err(|e| errno(&e))?;
+                    write_stat(cpu, stat, &metadata, self.stat_nanoseconds).map(|_| 0)
+                }
+                _ => self.fstat(cpu, dirfd, stat),
+            };
+        }
+        let path = self.path(cpu, dirfd, path)?;
+        let metadata = match flags & AT_SYMLINK_NOFOLLOW {
+            0 => fs::metadata(path),
+            _ => fs::symlink_metadata(path),
+        };
+        let metadata = metadata.map_err(|e| errno(&e))?;
+        write_stat(cpu, stat, &metadata, self.stat_nanoseconds)?;
+        Ok(0)
+    }
+
+    fn readlinkat(
+        &mut self,
+        cpu: &mut Cpu,
+        dirfd: u64,
+        path: u64,
+        buffer: u64,
+        size: u64,
+    ) -> Result<u64, i64> {
+        let path = self.path(cpu, dirfd, path)?;
+        let target = match path.to_str() {
+            Some("/proc/self/exe") => self.exe.clone(),
+            _ => fs::read_link(path).map_err(|e| errno(&e))?,
+        };
+        let target = target.to_string_lossy().into_owned().into_bytes();
+        let count = target.len().min(size as usize);
+        write_bytes(cpu, buffer, &target[..count])?;
+        Ok(count as u64)
+    }
+
+    fn getcwd(&mut self, cpu: &mut Cpu, buffer: u64, size: u64) -> Result<u64, i64> {
+        let cwd = [self.cwd.to_string_lossy().as_bytes(), &[0]].concat();
+        if cwd.len() as u64 > size {
+            return Err(ERANGE);
+        }
+        write_bytes(cpu, buffer, &cwd)?;
+        Ok(cwd.len() as u64)
+    }
+
+    // brk moves the end of the heap, which cannot go below its start nor into the
+    // mappings of mmap. The result is the end, which is unchanged on a failure.
+    fn set_brk(&mut self, cpu: &mut Cpu, brk: u64) -> u64 {
+        if brk < self.brk_start || page_align(brk) > self.mmap_base {
+            return self.brk;
+        }
+        // The memory freed by the program is zero when the heap grows again.
+        if brk < self.brk {
+            cpu.bus.memory.clear(brk, self.brk - brk);
+        }
+        self.brk = brk;
+        brk
+    }
+
+    // mmap of anonymous memory, or of a copy of a file: a shared mapping of a file
+    // is not written back. The address is only followed with MAP_FIXED.
+    fn mmap(&mut self, cpu: &mut Cpu, args: [u64; 6]) -> Result<u64, i64> {
+        let [addr, length, _, flags, fd, offset] = args;
+        if length == 0 || length > USER_MEMORY_SIZE {
+            return Err(EINVAL);
+        }
+        let length = page_align(length);
+        let addr = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE as u64) {
+                return Err(EINVAL);
+            }
+            if addr < USER_MEMORY_BASE || addr.checked_add(length).is_none_or(|end| end > USER_STACK_TOP) {
+                return Err(ENOMEM);
+            }
+            addr
+        } else {
+            match self.mmap_base.checked_sub(length) {
+                Some(addr) if addr >= page_align(self.brk) => {
+                    self.mmap_base = addr;
+                    addr
+                }
+                _ => return Err(ENOMEM),
+            }
+        };
+        let mut bytes = Vec::new();
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = match self.file(fd)? {
+                Descriptor::File { file, .. } => file,
+                _ => return Err(EACCES),
+            };
+            let size = file.metadata().map_err(|e| errno(&e))?.len();
+            bytes = vec![0; size.saturating_sub(offset).min(length) as usize];
+            file.read_exact_at(&mut bytes, offset).map_err(|e| errno(&e))?;
+        }
+        cpu.bus.memory.clear(addr, length);
+        cpu.bus.memory.write_bytes(addr, &bytes);
+        Ok(addr)
+    }
+
+    // munmap zeroes the memory, and gives the range back to mmap if it is the
+    // lowest mapping.
+    fn munmap(&mut self, cpu: &mut Cpu, addr: u64, length: u64) -> Result<u64, i64> {
+        let length = page_align(length);
+        let in_memory = addr >= USER_MEMORY_BASE
+            && addr.checked_add(length).is_some_and(|end| end <= USER_STACK_TOP);
+        if !addr.is_multiple_of(PAGE_SIZE as u64) || length == 0 || !in_memory {
+            return Err(EINVAL);
+        }
+        cpu.bus.memory.clear(addr, length);
+        if addr == self.mmap_base {
+            self.mmap_base = (addr + length).min(USER_STACK_TOP - USER_STACK_SIZE);
+        }
+        Ok(0)
+    }
+
+    // The next bytes of getrandom, by xorshift64*
+    fn random_bytes(&mut self, length: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(length + 8);
+        while bytes.len() < length {
+            self.random ^= self.random >> 12;
+            self.random ^= self.random << 25;
+            self.random ^= self.random >> 27;
+            bytes.extend_from_slice(&self.random.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes());
+        }
+        bytes.truncate(length);
+        bytes
+    }
+
+    // Service the ecall from U-mode which just raised EnvironmentCallFromUMode, with
+    // the pc after the ecall, and write the result to a0.
+    pub fn call(&mut self, cpu: &mut Cpu) -> Call {
+        let regs = cpu.regs;
+        let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+        let call = self.syscall(cpu, regs[17], args);
+        cpu.regs[10] = call.result as u64;
+        call
+    }
+
+    // Do the system call of the number with the arguments.
+    pub fn syscall(&mut self, cpu: &mut Cpu, number: u64, args: [u64; 6]) -> Call {
+        let mut call = Call {
+            number,
+            args,
+            result: 0,
+            exit: None,
+        };
+        let result = match call.number {
+            NR_READ => self.read(cpu, args[0], args[1], args[2]),
+            NR_WRITE => self.write(cpu, args[0], args[1], args[2]),
+            NR_READV => self.vector(cpu, false, args[0], args[1], args[2]),
+            NR_WRITEV => self.vector(cpu, true, args[0], args[1], args[2]),
+            NR_PREAD64 => self.positioned(cpu, false, args[0], args[1], args[2], args[3]),
+            NR_PWRITE64 => self.positioned(cpu, true, args[0], args[1], args[2], args[3]),
+            NR_OPENAT => self.openat(cpu, args[0], args[1], args[2], args[3]),
+            NR_CLOSE => match self.files.get_mut(args[0] as usize).and_then(Option::take) {
+                Some(_) => Ok(0),
+                None => Err(EBADF),
+            },
+            NR_LSEEK => self.lseek(args[0], args[1], args[2]),
+            NR_DUP => self
+                .file(args[0])
+                .and_then(|descriptor| descriptor.try_clone())
+                .and_then(|descriptor| self.add_file(descriptor, 0)),
+            NR_DUP3 if args[0] == args[1] => Err(EINVAL),
+            NR_DUP3 => self.file(args[0]).and_then(|descriptor| descriptor.try_clone()).and_then(|descriptor| {
+                if args[1] >= MAX_FILES {
+                    return Err(EBADF);
+                }
+                if self.files.len() <= args[1] as usize {
+                    self.files.resize_with(args[1] as usize + 1, || None);
+                }
+                self.files[args[1] as usize] = Some(descriptor);
+                Ok(args[1])
+            }),
+            NR_FCNTL => self.fcntl(args[0], args[1], args[2]),
+            // The console is not a terminal, so that no terminal ioctl succeeds.
+            NR_IOCTL => self.file(args[0]).and(Err(ENOTTY)),
+            NR_FSTAT => self.fstat(cpu, args[0], args[1]),
+            NR_NEWFSTATAT => self.newfstatat(cpu, args[0], args[1], args[2], args[3]),
+            NR_READLINKAT => self.readlinkat(cpu, args[0], args[1], args[2], args[3]),
+            NR_FACCESSAT => self.path(cpu, args[0], args[1]).and_then(|path| {
+                let metadata = fs::metadata(path).map_err(|e| errno(&e))?;
+                // W_OK
+                if args[2] & 2 != 0 && metadata.permissions().readonly() {
+                    return Err(EACCES);
+                }
+                Ok(0)
+            }),
+            NR_FTRUNCATE => match self.file(args[0]) {
+                Ok(Descriptor::File { file, .. }) => file.set_len(args[1]).map(|_| 0).map_err(|e| errno(&e)),
+                Ok(_) => Err(EINVAL),
+                Err(errno) => Err(errno),
+            },
+            NR_MKDIRAT => self
+                .path(cpu, args[0], args[1])
+                .and_then(|path| fs::create_dir(path).map(|_| 0).map_err(|e| errno(&e))),
+            NR_UNLINKAT => self.path(cpu, args[0], args[1]).and_then(|path| {
+                let removed = match args[2] & AT_REMOVEDIR {
+                    0 => fs::remove_file(path),
+                    _ => fs::remove_dir(path),
+                };
+                removed.map(|_| 0).map_err(|e| errno(&e))
+            }),
+            NR_LINKAT => {
+                let from = self.path(cpu, args[0], args[1]);
+                let to = self.path(cpu, args[2], args[3]);
+                match (from, to) {
+                    // AT_SYMLINK_FOLLOW and AT_EMPTY_PATH are not supported.
+                    _ if args[4] != 0 => Err(EINVAL),
+                    (Ok(from), Ok(to)) => fs::hard_link(from, to).map(|_| 0).map_err(|e| errno(&e)),
+                    (Err(errno), _) | (_, Err(errno)) => Err(errno),
+                }
+            }
+            NR_RENAMEAT | NR_RENAMEAT2 => {
+                let flags = if call.number == NR_RENAMEAT2 { args[4] } else { 0 };
+                let from = self.path(cpu, args[0], args[1]);
+                let to = self.path(cpu, args[2], args[3]);
+                match (from, to) {
+                    _ if flags != 0 => Err(EINVAL),
+                    (Ok(from), Ok(to)) => fs::rename(from, to).map(|_| 0).map_err(|e| errno(&e)),
+                    (Err(errno), _) | (_, Err(errno)) => Err(errno),
+                }
+            }
+            NR_GETCWD => self.getcwd(cpu, args[0], args[1]),
+            NR_CHDIR => self.path(cpu, AT_FDCWD, args[0]).and_then(|path| {
+                let path = path.canonicalize().map_err(|e| errno(&e))?;
+                if !path.is_dir() {
+                    // ENOTDIR
+                    return Err(20);
+                }
+                self.cwd = path;
+                Ok(0)
+            }),
+            NR_UMASK => {
+                let umask = self.umask;
+                self.umask = args[0] & 0o777;
+                Ok(umask)
+            }
+
+            NR_EXIT | NR_EXIT_GROUP => {
+                call.exit = Some(args[0] & 0xff);
+                Ok(0)
+            }
+            // A signal to the process ends it, except the signal 0, which checks it.
+            NR_KILL | NR_TKILL | NR_TGKILL => {
+                let (pid, signal) = match call.number {
+                    NR_TGKILL => (args[1], args[2]),
+                    _ => (args[0], args[1]),
+                };
+                match pid {
+                    USER_PID | 0 => {
+                        if signal != 0 {
+                            call.exit = Some(128 + (signal & 0x7f));
+                        }
+                        Ok(0)
+                    }
+                    // ESRCH
+                    _ => Err(3),
+                }
+            }
+            NR_SET_TID_ADDRESS | NR_GETPID | NR_GETTID => Ok(USER_PID),
+            NR_GETPPID | NR_GETUID | NR_GETEUID | NR_GETGID | NR_GETEGID => Ok(0),
+            // The only thread never waits: the value has changed or nobody would wake it.
+            NR_FUTEX => match args[1] & 0x7f {
+                FUTEX_WAKE | FUTEX_WAKE_BITSET => Ok(0),
+                FUTEX_WAIT | FUTEX_WAIT_BITSET => Err(EAGAIN),
+                _ => Err(ENOSYS),
+            },
+            NR_SET_ROBUST_LIST | NR_SIGALTSTACK | NR_SCHED_YIELD | NR_SETRLIMIT => Ok(0),
+            NR_MPROTECT | NR_MADVISE | NR_RISCV_FLUSH_ICACHE => Ok(0),
+            // No signal is delivered: the handlers and the masks stay empty.
+            NR_RT_SIGACTION => match args[2] {
+                0 => Ok(0),
+                old => write_words(cpu, old, &[0; 3]).map(|_| 0),
+            },
+            NR_RT_SIGPROCMASK => match args[2] {
+                0 => Ok(0),
+                old => write_words(cpu, old, &[0]).map(|_| 0),
+            },
+            // A sleep returns at once, since the time is the one of the machine.
+            NR_NANOSLEEP | NR_CLOCK_NANOSLEEP => Ok(0),
+            NR_CLOCK_GETTIME => {
+                let time = match args[0] {
+                    CLOCK_REALTIME | CLOCK_REALTIME_COARSE => cpu.bus.clint.date() as u128,
+                    _ => machine_time(cpu),
+                };
+                write_time(cpu, args[1], time, 1).map(|_| 0)
+            }
+            NR_CLOCK_GETRES => match args[1] {
+                0 => Ok(0),
+                res => write_words(cpu, res, &[0, 1_000_000_000 / TIMEBASE_FREQUENCY]).map(|_| 0),
+            },
+            NR_GETTIMEOFDAY => match args[0] {
+                0 => Ok(0),
+                tv => write_time(cpu, tv, cpu.bus.clint.date() as u128, 1000).map(|_| 0),
+            },
+            NR_TIMES => {
+                let ticks = (machine_time(cpu) * CLOCK_TICKS as u128 / 1_000_000_000) as u64;
+                match args[0] {
+                    0 => Ok(ticks),
+                    tms => write_words(cpu, tms, &[ticks, 0, 0, 0]).map(|_| ticks),
+                }
+            }
+            NR_GETRUSAGE => {
+                let mut usage = [0; 18];
+                let time = machine_time(cpu);
+                usage[0] = (time / 1_000_000_000) as u64;
+                usage[1] = (time % 1_000_000_000 / 1000) as u64;
+                write_words(cpu, args[1], &usage).map(|_| 0)
+            }
+            NR_SCHED_GETAFFINITY => match args[1] {
+                0..=7 => Err(EINVAL),
+                _ => write_words(cpu, args[2], &[1]).map(|_| 8),
+            },
+            NR_UNAME => {
+                let fields = ["Linux", "riscvemu", "6.1.0", "#1", "riscv64", "(none)"];
+                let mut utsname = vec![0; 65 * fields.len()];
+                for (i, field) in fields.iter().enumerate() {
+                    utsname[65 * i..65 * i + field.len()].copy_from_slice(field.as_bytes());
+                }
+                write_bytes(cpu, args[0], &utsname).map(|_| 0)
+            }
+            NR_GETRLIMIT | NR_PRLIMIT64 => {
+                let (resource, old) = match call.number {
+                    NR_PRLIMIT64 => (args[1], args[3]),
+                    _ => (args[0], args[1]),
+                };
+                let limit = match resource {
+                    RLIMIT_STACK => USER_STACK_SIZE,
+                    RLIMIT_NOFILE => MAX_FILES,
+                    _ => RLIM_INFINITY,
+                };
+                match old {
+                    0 => Ok(0),
+                    old => write_words(cpu, old, &[limit, limit]).map(|_| 0),
+                }
+            }
+            NR_GETRANDOM => {
+                let bytes = self.random_bytes(args[1].min(MAX_IO_SIZE) as usize);
+                write_bytes(cpu, args[0], &bytes).map(|_| bytes.len() as u64)
+            }
+
+            NR_BRK => Ok(self.set_brk(cpu, args[0])),
+            NR_MMAP => self.mmap(cpu, args),
+            NR_MUNMAP => self.munmap(cpu, args[0], args[1]),
+            // The C library copies the mapping to a new one instead.
+            NR_MREMAP => Err(ENOMEM),
+            _ => Err(ENOSYS),
+        };
+        call.result = match result {
+            Ok(value) => value as i64,
+            Err(errno) => -errno,
+        };
+        call
+    }
+}
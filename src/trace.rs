@@ -0,0 +1,441 @@
+// The trace module writes an instruction trace in the format of Spike's
+// `-l` and `--log-commits` options, so that a run of this emulator can be
+// diffed line by line against a run of Spike.
+//
+//   core   0: 0x0000000080000000 (0x00500e93) li      t4, 5
+//   core   0: 3 0x0000000080000000 (0x00500e93) x29 0x0000000000000005
+//
+// The first line is printed before an instruction is executed and the second
+// line, the commit line, after the instruction retires. A commit line has the
+// privilege mode, the pc, the raw instruction and every register, CSR and
+// memory write done by the instruction. The implicit writes of fflags and mstatus
+// by the floating-point instructions are not printed.
+
+use std::fmt;
+use std::io::Write;
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::rvc::*;
+use crate::sbi::Call;
+use crate::semihosting;
+use crate::syscall;
+use crate::trap::*;
+
+// An architectural side effect of a retired instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Effect {
+    // A write to an integer register: (register number, value)
+    Reg(usize, u64),
+    // A write to a floating-point register: (register number, value)
+    FReg(usize, u64),
+    // A write to a CSR: (CSR address, value)
+    Csr(usize, u64),
+    // A load from memory: address
+    Load(u64),
+    // A store to memory: (address, value, size in bytes)
+    Store(u64, u64, u64),
+}
+
+// A retired instruction, i.e. one commit line.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    // The hart which executed the instruction
+    pub hart: u64,
+    pub mode: u64,
+    pub pc: u64,
+    pub inst: u64,
+    pub effects: Vec<Effect>,
+}
+
+// The state captured before an instruction is executed, which is needed to build
+// the record once the instruction retires.
+pub struct Commit {
+    pc: u64,
+    inst: u64,
+    mode: Mode,
+    // Memory accesses: (address, value of a store, size in bytes)
+    mem: Vec<(u64, Option<u64>, u64)>,
+}
+
+pub struct Tracer {
+    output: Box<dyn Write>,
+    // Print the disassembly of every instruction (`spike -l`).
+    disassembly: bool,
+    // Print a commit line for every retired instruction (`spike --log-commits`).
+    commits: bool,
+    // Append the symbol of the pc to disassembly and exception lines. This breaks
+    // the line by line compatibility with Spike, so it is off by default.
+    symbols: bool,
+}
+
+// Spike's `trap_t::name()` for exceptions.
+fn exception_name(exception: &Exception) -> &'static str {
+    match exception {
+        Exception::InstructionAddresMisaligned => "trap_instruction_address_misaligned",
+        Exception::InstructionAccessFault => "trap_instruction_access_fault",
+        Exception::IllegalInstruction => "trap_illegal_instruction",
+        Exception::Breakpoint => "trap_breakpoint",
+        Exception::LoadAddressMisaligned => "trap_load_address_misaligned",
+        Exception::LoadAccessFault => "trap_load_access_fault",
+        Exception::StoreAMOAddressMisaligned => "trap_store_address_misaligned",
+        Exception::StoreAMOAccessFault => "trap_store_access_fault",
+        Exception::EnvironmentCallFromUMode => "trap_user_ecall",
+        Exception::EnvironmentCallFromSMode => "trap_supervisor_ecall",
+        Exception::EnvironmentCallFromMMode => "trap_machine_ecall",
+        Exception::InstructionPageFault => "trap_instruction_page_fault",
+        Exception::LoadPageFault => "trap_load_page_fault",
+        Exception::StoreAMOPageFault => "trap_store_page_fault",
+    }
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, disassembly: bool, commits: bool, symbols: bool) -> Self {
+        Self {
+            output,
+            disassembly,
+            commits,
+            symbols,
+        }
+    }
+
+    // Return ` <function+offset>` of an address if symbols are enabled and known.
+    fn symbol(&self, cpu: &Cpu, addr: u64) -> String {
+        match cpu.symbols.lookup(addr) {
+            Some(symbol) if self.symbols => format!(" <{}>", symbol),
+            _ => String::new(),
+        }
+    }
+
+    // Print the disassembly of an instruction before it is executed, while `cpu.pc`
+    // still points to the instruction.
+    pub fn disassemble(&mut self, cpu: &Cpu, inst: u64) {
+        if self.disassembly {
+            let symbol = self.symbol(cpu, cpu.pc);
+            self.write(format!(
+                "core {:3}: 0x{:016x} (0x{:0width$x}) {}{}",
+                cpu.hartid(),
+                cpu.pc,
+                inst,
+                disassemble(decompress(inst)),
+                symbol,
+                width = (inst_len(inst) * 2) as usize,
+            ));
+        }
+    }
+
+    // Print the commit line of an instruction which retired without an exception.
+    pub fn retire(&mut self, record: &Record) {
+        if self.commits {
+            self.write(record.to_string());
+        }
+    }
+
+    // Print an exception raised at `epc`.
+    pub fn exception(&mut self, cpu: &Cpu, exception: &Exception, epc: u64) {
+        let symbol = self.symbol(cpu, epc);
+        self.write(format!(
+            "core {:4}: exception {}, epc 0x{:016x}{}",
+            cpu.hartid(),
+            exception_name(exception),
+            epc,
+            symbol
+        ));
+        match exception {
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => {}
+            _ => {
+                let tval = if cpu.mode == Mode::Machine {
+                    cpu.csrs[MTVAL]
+                } else {
+                    cpu.csrs[STVAL]
+                };
+                self.write(format!("core {:4}:           tval 0x{:016x}", cpu.hartid(), tval));
+            }
+        }
+    }
+
+    // Print an interrupt taken by `hart` at `epc`.
+    pub fn interrupt(&mut self, hart: usize, interrupt: &Interrupt, epc: u64) {
+        self.write(format!(
+            "core {:4}: exception interrupt #{}, epc 0x{:016x}",
+            hart,
+            interrupt.exception_code(),
+            epc
+        ));
+    }
+
+    // Print an SBI call of `hart` serviced by the built-in SBI, with its first
+    // three arguments and its result.
+    pub fn sbi_call(&mut self, hart: usize, call: &Call) {
+        self.write(format!(
+            "core {:4}: sbi {} (eid 0x{:x}, fid {}) a0 0x{:x} a1 0x{:x} a2 0x{:x} -> error {}, value 0x{:x}",
+            hart,
+            call.name(),
+            call.eid,
+            call.fid,
+            call.args[0],
+            call.args[1],
+            call.args[2],
+            call.error,
+            call.value
+        ));
+    }
+
+    // Print a semihosting call of `hart` with a1 and its result.
+    pub fn semihosting_call(&mut self, hart: usize, call: &semihosting::Call) {
+        self.write(format!(
+            "core {:4}: semihosting {} (0x{:x}) a1 0x{:x} -> 0x{:x}",
+            hart,
+            call.name(),
+            call.op,
+            call.arg,
+            call.result
+        ));
+    }
+
+    // Print a system call of a Linux program with its arguments and its result.
+    pub fn syscall(&mut self, hart: usize, call: &syscall::Call) {
+        let args: Vec<String> = call.args.iter().map(|arg| format!("0x{:x}", arg)).collect();
+        self.write(format!(
+            "core {:4}: syscall {} ({}) {} -> {}",
+            hart,
+            call.name(),
+            call.number,
+            args.join(" "),
+            call.result
+        ));
+    }
+
+    fn write(&mut self, line: String) {
+        writeln!(self.output, "{}", line).expect("failed to write a trace");
+    }
+}
+
+impl Commit {
+    // Capture the state needed for the record. This must be called before the
+    // instruction is executed, while `cpu.pc` still points to the instruction.
+    pub fn begin(cpu: &Cpu, inst: u64) -> Self {
+        // A compressed instruction accesses memory as its expansion.
+        let expanded = decompress(inst);
+        let opcode = expanded & 0x0000007f;
+        let funct3 = (expanded & 0x00007000) >> 12;
+        let rs1 = ((expanded & 0x000f8000) >> 15) as usize;
+        let rs2 = ((expanded & 0x01f00000) >> 20) as usize;
+        let mut mem = Vec::new();
+        match opcode {
+            0x03 | 0x07 => {
+                let imm = ((expanded as i32 as i64) >> 20) as u64;
+                mem.push((cpu.regs[rs1].wrapping_add(imm), None, 1 << (funct3 & 0b11)));
+            }
+            0x23 | 0x27 => {
+                let imm = (((expanded & 0xfe000000) as i32 as i64 >> 20) as u64) | ((expanded >> 7) & 0x1f);
+                let size = 1 << (funct3 & 0b11);
+                let mask = if size == 8 { u64::MAX } else { (1 << (size * 8)) - 1 };
+                let value = if opcode == 0x27 { cpu.fregs[rs2] } else { cpu.regs[rs2] };
+                mem.push((cpu.regs[rs1].wrapping_add(imm), Some(value & mask), size));
+            }
+            0x2f => {
+                let funct5 = (expanded >> 27) & 0x1f;
+                let size = if funct3 == 0x2 { 4 } else { 8 };
+                // lr only loads and sc only stores. The value of an AMO store is known
+                // after the instruction is executed.
+                if funct5 != 0x03 {
+                    mem.push((cpu.regs[rs1], None, size));
+                }
+                if funct5 != 0x02 {
+                    mem.push((cpu.regs[rs1], Some(cpu.regs[rs2]), size));
+                }
+            }
+            _ => {}
+        }
+
+        Self {
+            pc: cpu.pc,
+            inst,
+            mode: cpu.mode,
+            mem,
+        }
+    }
+
+    // The memory accesses of the instruction: (address, size in bytes, true if it
+    // is a store)
+    pub fn accesses(&self) -> impl Iterator<Item = (u64, u64, bool)> + '_ {
+        self.mem
+            .iter()
+            .map(|(addr, value, size)| (*addr, *size, value.is_some()))
+    }
+
+    // Build the record of the instruction after it retired without an exception.
+    pub fn finish(self, cpu: &mut Cpu) -> Record {
+        let inst = decompress(self.inst);
+        let opcode = inst & 0x0000007f;
+        let funct3 = (inst & 0x00007000) >> 12;
+        let funct7 = (inst & 0xfe000000) >> 25;
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        let mut effects = Vec::new();
+
+        // CSR writes are printed before the register write, as Spike does.
+        if opcode == 0x73 {
+            let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+            let csr_write = match funct3 {
+                // csrrw and csrrwi always write the CSR.
+                0x1 | 0x5 => Some(csr_addr),
+                // The others only write the CSR if rs1 (or uimm) is not zero.
+                0x2 | 0x3 | 0x6 | 0x7 if rs1 != 0 => Some(csr_addr),
+                0x0 => match (rs2, funct7) {
+                    (0x2, 0x8) => Some(SSTATUS),
+                    (0x2, 0x18) => Some(MSTATUS),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(addr) = csr_write {
+                effects.push(Effect::Csr(addr, cpu.read_csr(addr)));
+            }
+        }
+
+        // The floating-point compares, fclass, fmv.x and the conversions to an
+        // integer write an integer register, and the other floating-point
+        // instructions but the stores write a floating-point register.
+        let float_to_int = opcode == 0x53 && matches!(funct7 >> 2, 0x14 | 0x18 | 0x1c);
+        let writes_rd = match opcode {
+            0x03 | 0x13 | 0x17 | 0x1b | 0x2f | 0x33 | 0x37 | 0x3b | 0x67 | 0x6f => true,
+            0x73 => funct3 != 0x0,
+            _ => float_to_int,
+        };
+        // Spike does not print writes to x0.
+        if writes_rd && rd != 0 {
+            effects.push(Effect::Reg(rd, cpu.regs[rd]));
+        }
+        if matches!(opcode, 0x07 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53) && !float_to_int {
+            effects.push(Effect::FReg(rd, cpu.fregs[rd]));
+        }
+
+        for (addr, value, size) in self.mem {
+            match value {
+                None => effects.push(Effect::Load(addr)),
+                Some(value) => {
+                    let value = match opcode {
+                        // sc only stores if it succeeded, i.e. rd is 0.
+                        0x2f if (inst >> 27) & 0x1f == 0x03 => {
+                            if cpu.regs[rd] != 0 {
+                                continue;
+                            }
+                            value
+                        }
+                        // The value stored by an AMO depends on the loaded value.
+                        0x2f => cpu.load(addr, size * 8).unwrap_or(value),
+                        _ => value,
+                    };
+                    effects.push(Effect::Store(addr, value, size));
+                }
+            }
+        }
+
+        Record {
+            hart: cpu.hartid() as u64,
+            mode: self.mode as u64,
+            pc: self.pc,
+            inst: self.inst,
+            effects,
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "core {:3}: {} 0x{:016x} (0x{:0width$x})",
+            self.hart,
+            self.mode,
+            self.pc,
+            self.inst,
+            width = (inst_len(self.inst) * 2) as usize,
+        )?;
+        for effect in self.effects.iter() {
+            match *effect {
+                Effect::Reg(reg, value) => write!(f, " x{:<2} 0x{:016x}", reg, value)?,
+                Effect::FReg(reg, value) => write!(f, " f{:<2} 0x{:016x}", reg, value)?,
+                Effect::Csr(addr, value) => write!(
+                    f,
+                    " c{}_{} 0x{:016x}",
+                    addr,
+                    csr_name(addr).unwrap_or("unknown"),
+                    value
+                )?,
+                Effect::Load(addr) => write!(f, " mem 0x{:016x}", addr)?,
+                Effect::Store(addr, value, size) => write!(
+                    f,
+                    " mem 0x{:016x} 0x{:0width$x}",
+                    addr,
+                    value,
+                    width = (size * 2) as usize
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    // Parse a commit line such as
+    //   core   0: 3 0x0000000080000000 (0x00500e93) x29 0x0000000000000005
+    // Return None if the line is not a commit line, e.g. a disassembly line or an
+    // exception line.
+    pub fn parse(line: &str) -> Option<Record> {
+        let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+
+        let rest = line.trim_start().strip_prefix("core")?;
+        let (hart, rest) = rest.split_once(':')?;
+        let hart = hart.trim().parse::<u64>().ok()?;
+        let mut tokens = rest.split_whitespace();
+        let mode = tokens.next()?.parse::<u64>().ok()?;
+        let pc = hex(tokens.next()?)?;
+        let inst = tokens.next()?;
+        let inst = hex(inst.strip_prefix('(')?.strip_suffix(')')?)?;
+
+        let mut effects = Vec::new();
+        while let Some(token) = tokens.next() {
+            if token == "mem" {
+                let addr = hex(tokens.next()?)?;
+                // A store has a value after the address, a load does not.
+                let mut peek = tokens.clone();
+                match peek.next() {
+                    Some(value) if value.starts_with("0x") => {
+                        let size = ((value.len() - 2) / 2) as u64;
+                        effects.push(Effect::Store(addr, hex(value)?, size));
+                        tokens = peek;
+                    }
+                    _ => effects.push(Effect::Load(addr)),
+                }
+            } else if let Some(reg) = token.strip_prefix('x') {
+                let reg = reg.parse::<usize>().ok()?;
+                effects.push(Effect::Reg(reg, hex(tokens.next()?)?));
+            } else if let Some(reg) = token.strip_prefix('f') {
+                let reg = reg.parse::<usize>().ok()?;
+                effects.push(Effect::FReg(reg, hex(tokens.next()?)?));
+            } else if let Some(csr) = token.strip_prefix('c') {
+                // "c768_mstatus"
+                let addr = csr.split('_').next()?.parse::<usize>().ok()?;
+                effects.push(Effect::Csr(addr, hex(tokens.next()?)?));
+            } else {
+                // Ignore writes to other register files, e.g. vector registers, which
+                // this emulator does not have.
+                tokens.next();
+            }
+        }
+
+        Some(Record {
+            hart,
+            mode,
+            pc,
+            inst,
+            effects,
+        })
+    }
+}
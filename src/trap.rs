@@ -2,7 +2,7 @@
 
 #![allow(dead_code)]
 use crate::cpu::*;
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 
 // All kind of exception, an unusual condition occurring at run
 // time associcated with an instruction in the current hardware thread
@@ -24,24 +24,64 @@ pub enum Exception {
     StoreAMOPageFault,
 }
 
+// All kind of interrupts, an external asynchronous event that may
+// cause a hardware thread to experience an unexpected transfer of control.
+#[derive(Debug)]
+pub enum Interrupt {
+    UserSoftwareInterrupt,
+    SupervisorSoftwareInterrupt,
+    MachineSoftwareInterrupt,
+    UserTimerInterrupt,
+    SupervisorTimerInterrupt,
+    MachineTimerInterrupt,
+    UserExternalInterrupt,
+    SupervisorExternalInterrupt,
+    MachineExternalInterrupt,
+}
+
 // The transfer of control to a trap hander caused 
 // by either an interrupt
 pub trait Trap {
     // Return an exception code that identifys the lastst exception
     fn exception_code(&self) -> u64;
 
+    // Return true if the trap is an interrupt. An interrupt is taken after an
+    // instruction has completed, so the pc already points to the next instruction.
+    // An exception is taken with the pc of the instruction which raised it.
+    fn is_interrupt(&self) -> bool {
+        false
+    }
+
+    // Return the value written to mtval or stval.
+    fn tval(&self, _cpu: &Cpu) -> u64 {
+        0
+    }
+
     // Trap handler
     fn take_trap(&self, cpu: &mut Cpu){
-        let exception_pc = cpu.pc.wrapping_sub(4);
+        let exception_pc = cpu.pc;
         let previous_mode = cpu.mode;
         
-        let cause = self.exception_code();
-        if (previous_mode <= Mode::Surpervisor) && ((cpu.csrs[MEDELEG] >> cause) & 1 != 0) {
+        let code = self.exception_code();
+        // The interrupt bit is the most significant bit of the xcause registers.
+        let cause = if self.is_interrupt() { (1 << 63) | code } else { code };
+        let delegation = if self.is_interrupt() { cpu.csrs[MIDELEG] } else { cpu.csrs[MEDELEG] };
+        let tval = self.tval(cpu);
+        // The address of the trap handler: the base in xtvec, plus 4 * cause for an
+        // interrupt in vectored mode (MODE = 1, the lower 2 bits).
+        let handler = |tvec: u64| {
+            if tvec & 0b11 == 1 && self.is_interrupt() {
+                (tvec & !0b11).wrapping_add(4 * code)
+            } else {
+                tvec & !0b11
+            }
+        };
+        if (previous_mode <= Mode::Surpervisor) && ((delegation >> code) & 1 != 0) {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Surpervisor;
 
             // Set the program counter to the surpervisor trap-handler base address (stvec)
-            cpu.pc = cpu.csrs[STVEC] & !1;
+            cpu.pc = handler(cpu.csrs[STVEC]);
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -66,32 +106,33 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // stval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other exceptions, stval is set to zero."
-            cpu.csrs[STVAL] = 0;
+            cpu.csrs[STVAL] = tval;
 
             // Set a privious interrupt-enable bit for supervisor mode (SPIE, 5) to the value
-            // of a global interrupt-enable bit for supervisor mode (SIE, 1).
-            cpu.csrs[SSTATUS] = if((cpu.csrs[SSTATUS] >> 1) & 1) == 1 {
-                cpu.csrs[SSTATUS] | (1<<5)
+            // of a global interrupt-enable bit for supervisor mode (SIE, 1). sstatus is a
+            // view of mstatus.
+            cpu.csrs[MSTATUS] = if(cpu.csrs[MSTATUS] & MSTATUS_SIE) != 0 {
+                cpu.csrs[MSTATUS] | MSTATUS_SPIE
             }else{
-                cpu.csrs[SSTATUS] & !(1 << 5)
+                cpu.csrs[MSTATUS] & !MSTATUS_SPIE
             };
 
             // Set a global interrupt-enable bit for supervisor mode (SIE, 1) to 0.
-            cpu.csrs[SSTATUS] = cpu.csrs[SSTATUS] & !(1 << 1);
+            cpu.csrs[MSTATUS] &= !MSTATUS_SIE;
             
             // 4.1.1 Supervisor Status Register (sstatus)
             // "When a trap is taken, SPP is set to 0 if the trap originated from user mode, or
             // 1 otherwise."
             match previous_mode {
-                Mode::User => cpu.csrs[SSTATUS] = cpu.csrs[SSTATUS] & (!1 << 8),
-                _ => cpu.csrs[SSTATUS] = cpu.csrs[SSTATUS] | (1 << 8), 
+                Mode::User => cpu.csrs[MSTATUS] &= !MSTATUS_SPP,
+                _ => cpu.csrs[MSTATUS] |= MSTATUS_SPP, 
             }
         }else{
             // Handle the trap in M-mode.
             cpu.mode = Mode::Machine;
             
             // Set the program counter to the machine trap-handler base address (mtvec).
-            cpu.pc = cpu.csrs[MTVEC] & !1;
+            cpu.pc = handler(cpu.csrs[MTVEC]);
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -117,7 +158,7 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // mtval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other traps, mtval is set to zero."
-            cpu.csrs[MTVAL] = 0;
+            cpu.csrs[MTVAL] = tval;
 
             // Set a privious interrupt-enable bit for supervisor mode (MPIE, 7) to the value
             // of a global interrupt-enable bit for supervisor mode (MIE, 3).
@@ -127,9 +168,10 @@ pub trait Trap {
                 cpu.csrs[MSTATUS] & !(1 << 7)
             };
             // Set a global interrupt-enable bit for supervisor mode (MIE, 3) to 0.
-            cpu.csrs[MSTATUS] = cpu.csrs[MSTATUS] & !(1 << 3);
-            // Set a privious privilege mode for surpervisor mode (MPP, 11..13) to 0.
-            cpu.csrs[MSTATUS] = cpu.csrs[MSTATUS] & !(0b11 << 11);
+            cpu.csrs[MSTATUS] &= !(1 << 3);
+            // Set a privious privilege mode for machine mode (MPP, 11..13) to the mode
+            // the trap was taken from.
+            cpu.csrs[MSTATUS] = (cpu.csrs[MSTATUS] & !MSTATUS_MPP) | ((previous_mode as u64) << 11);
         }
     }
 
@@ -154,4 +196,42 @@ impl Trap for Exception {
             Exception::StoreAMOPageFault => 15,
         }
     }
-}
\ No newline at end of file
+
+    // The faulting address of an access, or the instruction of an illegal
+    // instruction exception
+    fn tval(&self, cpu: &Cpu) -> u64 {
+        match self {
+            Exception::InstructionAddresMisaligned
+            | Exception::InstructionAccessFault
+            | Exception::IllegalInstruction
+            | Exception::LoadAddressMisaligned
+            | Exception::LoadAccessFault
+            | Exception::StoreAMOAddressMisaligned
+            | Exception::StoreAMOAccessFault
+            | Exception::InstructionPageFault
+            | Exception::LoadPageFault
+            | Exception::StoreAMOPageFault => cpu.tval,
+            _ => 0,
+        }
+    }
+}
+
+impl Trap for Interrupt {
+    fn exception_code(&self) -> u64 {
+        match self {
+            Interrupt::UserSoftwareInterrupt => 0,
+            Interrupt::SupervisorSoftwareInterrupt => 1,
+            Interrupt::MachineSoftwareInterrupt => 3,
+            Interrupt::UserTimerInterrupt => 4,
+            Interrupt::SupervisorTimerInterrupt => 5,
+            Interrupt::MachineTimerInterrupt => 7,
+            Interrupt::UserExternalInterrupt => 8,
+            Interrupt::SupervisorExternalInterrupt => 9,
+            Interrupt::MachineExternalInterrupt => 11,
+        }
+    }
+
+    fn is_interrupt(&self) -> bool {
+        true
+    }
+}
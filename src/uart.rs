@@ -4,19 +4,35 @@
 
 use std::io;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 // The interrupt request of UART
 pub const UART_IRQ: u64 = 10;
 
 // Recive holding register (for input bytes)
-pub const UART_RHR: u64 = UART_BASE + 0;
+pub const UART_RHR: u64 = UART_BASE;
 // Transmit holding register (for output bytes)
-pub const UART_THR: u64 = UART_BASE + 0;
+pub const UART_THR: u64 = UART_BASE;
+// Divisor latch, the low and the high byte, at RHR and IER while LCR.DLAB is set
+pub const UART_DLL: u64 = UART_BASE;
+pub const UART_DLM: u64 = UART_BASE + 1;
+// Interrupt enable register
+pub const UART_IER: u64 = UART_BASE + 1;
+// Interrupt identification register (read) and FIFO control register (write)
+pub const UART_IIR: u64 = UART_BASE + 2;
+pub const UART_FCR: u64 = UART_BASE + 2;
 // Line control register
 pub const UART_LCR: u64 = UART_BASE + 3;
+// Modem control register
+pub const UART_MCR: u64 = UART_BASE + 4;
 // Line status register.
 // LSR BIT 0:
 //     0 = no data in receive holding register or FIFO.
@@ -25,17 +41,153 @@ pub const UART_LCR: u64 = UART_BASE + 3;
 //     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 //     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
 pub const UART_LSR: u64 = UART_BASE +5;
+// Modem status register
+pub const UART_MSR: u64 = UART_BASE + 6;
+// Scratch register
+pub const UART_SCR: u64 = UART_BASE + 7;
 
 // The receiver(RX) bit;
 pub const UART_LSR_RX: u8 = 1;
 // The transmitter (TX) bit.
 pub const UART_LSR_TX: u8 = 1 << 5;
+// The transmitter is empty.
+pub const UART_LSR_TEMT: u8 = 1 << 6;
 
-pub struct Uart {
-    // 8 bit buffer for input and output
-    buffer: u8,
-    // Line status register
+// The interrupts enabled by IER: received data available and transmitter holding
+// register empty
+pub const UART_IER_RDI: u8 = 1;
+pub const UART_IER_THRI: u8 = 1 << 1;
+
+// The interrupts identified by IIR
+pub const UART_IIR_NO_INT: u8 = 0x01;
+pub const UART_IIR_THRI: u8 = 0x02;
+pub const UART_IIR_RDI: u8 = 0x04;
+// The bits of IIR set while the FIFO is enabled
+const UART_IIR_FIFO: u8 = 0xc0;
+
+// The bits of FCR
+const UART_FCR_ENABLE: u8 = 1;
+const UART_FCR_CLEAR_RX: u8 = 1 << 1;
+
+// The divisor latch access bit of LCR
+const UART_LCR_DLAB: u8 = 1 << 7;
+// The loopback bit of MCR
+const UART_MCR_LOOP: u8 = 1 << 4;
+// The modem status without loopback: data carrier detect, data set ready and
+// clear to send
+const UART_MSR_DEFAULT: u8 = 0xb0;
+
+// Ctrl-A, the first key of the key sequence to enter the monitor (Ctrl-A c)
+pub const CONSOLE_ESCAPE: u8 = 0x01;
+
+// The UART registers. LSR.THRE and LSR.TEMT are always set because a byte is
+// transmitted as soon as it is written.
+#[derive(Default)]
+struct Registers {
+    rhr: u8,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
     lsr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // True if the transmitter holding register empty interrupt is pending. It is
+    // set when THR is written or the interrupt is enabled, and cleared when IIR
+    // reports it.
+    thre_pending: bool,
+}
+
+impl Registers {
+    // The interrupt with the highest priority, or UART_IIR_NO_INT
+    fn interrupt(&self) -> u8 {
+        if self.ier & UART_IER_RDI != 0 && self.lsr & UART_LSR_RX != 0 {
+            UART_IIR_RDI
+        } else if self.ier & UART_IER_THRI != 0 && self.thre_pending {
+            UART_IIR_THRI
+        } else {
+            UART_IIR_NO_INT
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.rhr,
+            self.ier,
+            self.fcr,
+            self.lcr,
+            self.mcr,
+            self.lsr,
+            self.scr,
+            self.dll,
+            self.dlm,
+            self.thre_pending as u8,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [rhr, ier, fcr, lcr, mcr, lsr, scr, dll, dlm, thre_pending] => Some(Self {
+                rhr,
+                ier,
+                fcr,
+                lcr,
+                mcr,
+                lsr,
+                scr,
+                dll,
+                dlm,
+                thre_pending: thre_pending != 0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Put a byte to the receive holding register. If the guest has not read the
+// previous byte yet, the byte waits in the FIFO.
+fn receive_locked(uart: &mut Registers, fifo: &Mutex<VecDeque<u8>>, byte: u8) {
+    if uart.lsr & UART_LSR_RX != 0 {
+        fifo.lock().expect("failed to get the UART FIFO").push_back(byte);
+        return;
+    }
+    uart.rhr = byte;
+    // Data has been receive.
+    uart.lsr |= UART_LSR_RX;
+}
+
+// Put a byte received from stdin to the receive holding register.
+fn receive(uart: &UartState, interrupting: &AtomicBool, byte: u8) {
+    let (uart, fifo) = uart;
+    let mut uart = uart.lock().expect("failed to get an UART object");
+    receive_locked(&mut uart, fifo, byte);
+    interrupting.store(uart.interrupt() != UART_IIR_NO_INT, Ordering::Release);
+}
+
+// The UART registers and the receive FIFO. The registers are always locked first.
+type UartState = (Mutex<Registers>, Mutex<VecDeque<u8>>);
+
+pub struct Uart {
+    // Pair of the UART registers and the receive FIFO, shared with the reader thread.
+    uart: Arc<UartState>,
+    // The level of the interrupt, kept up to date when the registers change
+    interrupting: Arc<AtomicBool>,
+    // Where the transmitted bytes go, stdout by default
+    output: Box<dyn Write + Send>,
+    // Set when Ctrl-A c was typed on the console
+    monitor_requested: Arc<AtomicBool>,
+    // True while the input goes to the monitor instead of the guest
+    monitor_active: Arc<AtomicBool>,
+    // The input to the monitor
+    monitor_input: Receiver<u8>,
+    // True if the input waits in `host_input` until the emulator takes it,
+    // instead of being received by the reader thread at any time
+    deferred: Arc<AtomicBool>,
+    // The input to the guest not taken yet while `deferred` is true
+    host_input: Receiver<u8>,
+    // The input given by `type_input`, taken before `host_input`
+    typed_input: Mutex<VecDeque<u8>>,
 }
 
 impl Device for Uart {
@@ -48,78 +200,279 @@ impl Device for Uart {
 
     fn store(&mut self, addr: u64,size:u64, value: u64) -> Result<(),Exception> {
         match size {
-            8 => Ok(self.store8(addr,value)),
+            8 => {
+                self.store8(addr,value);
+                Ok(())
+            },
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    fn save(&self, writer: &mut Writer) {
+        writer.section("uart");
+        let (uart, fifo) = &*self.uart;
+        let uart = uart.lock().expect("failed to get an UART object");
+        writer.bytes(&uart.to_bytes());
+        let fifo = fifo.lock().expect("failed to get the UART FIFO");
+        writer.bytes(&fifo.iter().copied().collect::<Vec<_>>());
+        writer.bool(self.interrupting.load(Ordering::Acquire));
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("uart")?;
+        let registers = reader.bytes()?;
+        let (uart, fifo) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        *uart = Registers::from_bytes(registers)
+            .ok_or_else(|| invalid("the size of the UART registers is wrong".to_string()))?;
+        let mut fifo = fifo.lock().expect("failed to get the UART FIFO");
+        *fifo = reader.bytes()?.iter().copied().collect();
+        self.interrupting.store(reader.bool()?, Ordering::Release);
+        Ok(())
+    }
+
+    /// Return true while an enabled interrupt is pending. The interrupt is cleared
+    /// by the guest, e.g. by reading RHR or IIR.
+    fn is_interrupting(&mut self) -> bool {
+        self.interrupting.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Uart {
     pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
+        let registers = Registers {
             // Transmitter hold register is empty.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
-        }
+            lsr: UART_LSR_TX | UART_LSR_TEMT,
+            ..Registers::default()
+        };
+        let uart = Arc::new((Mutex::new(registers), Mutex::new(VecDeque::new())));
+        let interrupting = Arc::new(AtomicBool::new(false));
 
         let mut byte = [0; 1];
         let cloned_uart = uart.clone();
         let cloned_interrupting = interrupting.clone();
-        let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
-                    }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
+        let monitor_requested = Arc::new(AtomicBool::new(false));
+        let monitor_active = Arc::new(AtomicBool::new(false));
+        let cloned_requested = monitor_requested.clone();
+        let cloned_active = monitor_active.clone();
+        let (monitor_sender, monitor_input) = mpsc::channel();
+        let deferred = Arc::new(AtomicBool::new(false));
+        let cloned_deferred = deferred.clone();
+        let (host_sender, host_input) = mpsc::channel();
+        let _uart_thread_for_read = thread::spawn(move || {
+            // True after Ctrl-A, waiting for the second key of a key sequence
+            let mut escape = false;
+            let deliver = |byte| {
+                if cloned_deferred.load(Ordering::Acquire) {
+                    // The receiver is only dropped with the UART.
+                    let _ = host_sender.send(byte);
+                } else {
+                    receive(&cloned_uart, &cloned_interrupting, byte);
                 }
-                Err(e) => {
-                    println!("{}", e);
+            };
+            loop {
+                match io::stdin().read(&mut byte) {
+                    // Stop reading at the end of the input, e.g. when stdin is /dev/null.
+                    Ok(0) => break,
+                    Ok(_) => {
+                        // The monitor reads the input instead of the guest.
+                        if cloned_active.load(Ordering::Acquire) {
+                            if monitor_sender.send(byte[0]).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        if escape {
+                            escape = false;
+                            match byte[0] {
+                                // Ctrl-A c enters the monitor.
+                                b'c' => {
+                                    cloned_active.store(true, Ordering::Release);
+                                    cloned_requested.store(true, Ordering::Release);
+                                    continue;
+                                }
+                                // Ctrl-A Ctrl-A sends Ctrl-A to the guest.
+                                CONSOLE_ESCAPE => {}
+                                _ => deliver(CONSOLE_ESCAPE),
+                            }
+                        } else if byte[0] == CONSOLE_ESCAPE {
+                            escape = true;
+                            continue;
+                        }
+                        deliver(byte[0]);
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                    }
                 }
             }
         });
-        Self { uart, interrupting }
+        Self {
+            uart,
+            interrupting,
+            output: Box::new(io::stdout()),
+            monitor_requested,
+            monitor_active,
+            monitor_input,
+            deferred,
+            host_input,
+            typed_input: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Return true if the key sequence to enter the monitor was typed. Clear the
+    /// request by swapping a value.
+    pub fn take_monitor_request(&self) -> bool {
+        self.monitor_requested.swap(false, Ordering::Acquire)
+    }
+
+    /// Send the input to the monitor instead of the guest while `active` is true.
+    pub fn set_monitor_active(&self, active: bool) {
+        self.monitor_active.store(active, Ordering::Release);
+    }
+
+    /// Read a line of the input to the monitor. Return None at the end of the input.
+    pub fn read_monitor_line(&self) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.monitor_input.recv() {
+                Ok(b'\n') => break,
+                Ok(byte) => line.push(byte),
+                Err(_) if line.is_empty() => return None,
+                Err(_) => break,
+            }
+        }
+        Some(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string())
+    }
+
+    /// Keep the input from stdin until `take_input` while `deferred` is true, so that
+    /// the emulator decides at which instruction the guest receives it.
+    pub fn set_deferred_input(&self, deferred: bool) {
+        self.deferred.store(deferred, Ordering::Release);
+    }
+
+    /// Take a byte of the deferred input. Return None if nothing was typed.
+    pub fn take_input(&self) -> Option<u8> {
+        self.pop_typed_input().or_else(|| self.host_input.try_recv().ok())
+    }
+
+    /// Wait for a byte of the deferred input. Return None at the end of the input.
+    pub fn wait_input(&self) -> Option<u8> {
+        self.pop_typed_input().or_else(|| self.host_input.recv().ok())
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire);
+    fn pop_typed_input(&self) -> Option<u8> {
+        self.typed_input.lock().expect("failed to get the typed input").pop_front()
+    }
+
+    /// Type a byte as if it came from stdin, e.g. to script the input of a run.
+    pub fn type_input(&self, byte: u8) {
+        if self.deferred.load(Ordering::Acquire) {
+            self.typed_input.lock().expect("failed to get the typed input").push_back(byte);
+        } else {
+            self.receive(byte);
         }
-    
+    }
+
+    /// Put a byte to the receive holding register, or to the FIFO if it is full.
+    pub fn receive(&self, byte: u8) {
+        receive(&self.uart, &self.interrupting, byte);
+    }
 
-        fn load8(&mut self, addr: u64) -> u64 {
-        let (uart, cvar) = &*self.uart;
+    /// Send the transmitted bytes to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    fn load8(&mut self, addr: u64) -> u64 {
+        let (uart, fifo) = &*self.uart;
         let mut uart = uart.lock().expect("failed to get an UART object");
-        match addr {
+        let dlab = uart.lcr & UART_LCR_DLAB != 0;
+        let value = match addr {
+            UART_DLL if dlab => uart.dll,
+            UART_DLM if dlab => uart.dlm,
             UART_RHR => {
-                cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                uart[(UART_RHR - UART_BASE) as usize] as u64
+                let value = uart.rhr;
+                // Move the next byte from the FIFO to the receive holding register.
+                match fifo.lock().expect("failed to get the UART FIFO").pop_front() {
+                    Some(byte) => uart.rhr = byte,
+                    None => uart.lsr &= !UART_LSR_RX,
+                }
+                value
             }
-            _ => uart[(addr - UART_BASE) as usize] as u64,
-        }
+            UART_IER => uart.ier,
+            UART_IIR => {
+                let interrupt = uart.interrupt();
+                // Reading IIR clears the transmitter holding register empty interrupt.
+                if interrupt == UART_IIR_THRI {
+                    uart.thre_pending = false;
+                }
+                let fifo = if uart.fcr & UART_FCR_ENABLE != 0 { UART_IIR_FIFO } else { 0 };
+                interrupt | fifo
+            }
+            UART_LCR => uart.lcr,
+            UART_MCR => uart.mcr,
+            UART_LSR => uart.lsr,
+            UART_MSR => {
+                if uart.mcr & UART_MCR_LOOP != 0 {
+                    // In the loopback mode, the outputs of MCR are the inputs of MSR:
+                    // DTR to DSR, RTS to CTS, OUT1 to RI and OUT2 to DCD.
+                    (uart.mcr & 0x1) << 5 | (uart.mcr & 0x2) << 3 | (uart.mcr & 0xc) << 4
+                } else {
+                    UART_MSR_DEFAULT
+                }
+            }
+            UART_SCR => uart.scr,
+            _ => 0,
+        };
+        self.interrupting.store(uart.interrupt() != UART_IIR_NO_INT, Ordering::Release);
+        value as u64
     }
 
     fn store8(&mut self, addr: u64, value: u64) {
-        let (uart, _cvar) = &*self.uart;
+        let (uart, fifo) = &*self.uart;
         let mut uart = uart.lock().expect("failed to get an UART object");
+        let value = value as u8;
+        let dlab = uart.lcr & UART_LCR_DLAB != 0;
         match addr {
+            UART_DLL if dlab => uart.dll = value,
+            UART_DLM if dlab => uart.dlm = value,
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().expect("failed to flush stdout");
+                if uart.mcr & UART_MCR_LOOP != 0 {
+                    // The byte goes back to the receiver in the loopback mode.
+                    receive_locked(&mut uart, fifo, value);
+                } else {
+                    self.output.write_all(&[value]).expect("failed to write the UART output");
+                    self.output.flush().expect("failed to flush the UART output");
+                }
+                uart.thre_pending = true;
+            }
+            UART_IER => {
+                // Enabling the interrupt raises it, because THR is always empty.
+                if value & UART_IER_THRI != 0 && uart.ier & UART_IER_THRI == 0 {
+                    uart.thre_pending = true;
+                }
+                uart.ier = value & 0x0f;
             }
-            _ => {
-                uart[(addr - UART_BASE) as usize] = value as u8;
+            UART_FCR => {
+                if value & UART_FCR_CLEAR_RX != 0 {
+                    fifo.lock().expect("failed to get the UART FIFO").clear();
+                    uart.lsr &= !UART_LSR_RX;
+                }
+                uart.fcr = value & 0xc9;
             }
+            UART_LCR => uart.lcr = value,
+            UART_MCR => uart.mcr = value & 0x1f,
+            UART_SCR => uart.scr = value,
+            // LSR and MSR are read-only.
+            _ => {}
         }
+        self.interrupting.store(uart.interrupt() != UART_IIR_NO_INT, Ordering::Release);
     }
 }
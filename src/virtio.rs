@@ -1,11 +1,21 @@
 // The virtio module contains a virtualization standard for network and disk device drivers.
-// This is the "legacy" virtio interface.
+// This is the block device of the virtio-mmio transport, with the "legacy" interface
+// (version 1) by default, or the one of virtio 1.0 (version 2).
 //
 // The virtio spec:
 // https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+//
+// The driver puts a request in the available ring of the queue as a chain of
+// descriptors: the header with the type and the sector, the buffers of the data,
+// and the byte where the device writes the status. When the driver notifies the
+// queue, the device does the requests, puts them in the used ring and raises its
+// interrupt until the driver acknowledges it. See also
+// https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c
+
+use std::io;
 
 use crate::bus::*;
-use crate::cpu::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 // The interrupt request of virtio
@@ -16,44 +26,100 @@ const VRING_DESC_SIZE: u64 = 16;
 // It must be a power of two
 const DESC_NUM: u64 = 8;
 
-// Always return 0x747269976
-pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE + 0x000;
+// Always return 0x74726976
+pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE;
 // The version. 1 is legacy
 pub const VIRTIO_VERSION: u64 = VIRTIO_BASE + 0x004;
 // device type; 1 is net, 2 is desk;
 pub const VIRTIO_DEVICE_ID: u64 = VIRTIO_BASE + 0x008;
 // Always return 0x554d4551
 pub const VIRTIO_VENDOR_ID: u64 = VIRTIO_BASE + 0x00c;
-// Device features
+// Device features, the 32 bits selected by DEVICE_FEATURES_SEL
 pub const VIRTIO_DEVICE_FEATURES: u64 = VIRTIO_BASE + 0x010;
-// Driver feature
-pub const VIRTIO_DEVIVER_FEATURES: u64 = VIRTIO_BASE + 0x020;
-// Page size for PFN, write-only
+pub const VIRTIO_DEVICE_FEATURES_SEL: u64 = VIRTIO_BASE + 0x014;
+// Driver feature, the 32 bits selected by DRIVER_FEATURES_SEL
+pub const VIRTIO_DRIVER_FEATURES: u64 = VIRTIO_BASE + 0x020;
+pub const VIRTIO_DRIVER_FEATURES_SEL: u64 = VIRTIO_BASE + 0x024;
+// Page size for PFN, write-only, legacy
 pub const VIRTIO_GUEST_PAGE_SIZE: u64 = VIRTIO_BASE + 0x028;
 // Select queue, write-only
 pub const VIRTIO_QUEUE_SEL: u64 = VIRTIO_BASE + 0x030;
 // Max size of current queue, read-only. In QEMU, `VIRTIO_COUNT = 8`.
-pub const VIRTIO_QUEUE_NUM_MAX: u64 = VARTIO_BASE + 0x034;
+pub const VIRTIO_QUEUE_NUM_MAX: u64 = VIRTIO_BASE + 0x034;
 // Size of current queue, write-only.
-pub const VIRTIO_QUEUE_NUM: u64 = VARTIO_BASE + 0x038;
-// physical page number for queue, read and write
-pub const VIRTIO_QUEUE_PFN: u64 = VARTIO_BASE + 0x040;
+pub const VIRTIO_QUEUE_NUM: u64 = VIRTIO_BASE + 0x038;
+// The alignment of the used ring, write-only, legacy
+pub const VIRTIO_QUEUE_ALIGN: u64 = VIRTIO_BASE + 0x03c;
+// physical page number for queue, read and write, legacy
+pub const VIRTIO_QUEUE_PFN: u64 = VIRTIO_BASE + 0x040;
+// The queue is ready, read and write, version 2
+pub const VIRTIO_QUEUE_READY: u64 = VIRTIO_BASE + 0x044;
 // Notify the queue number, write-only
-pub const VIRTIO_NOTIFY: u64 = VARTIO_BASE + 0x050;
+pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
+// Why the device interrupts, read-only, and the bits to clear, write-only
+pub const VIRTIO_INTERRUPT_STATUS: u64 = VIRTIO_BASE + 0x060;
+pub const VIRTIO_INTERRUPT_ACK: u64 = VIRTIO_BASE + 0x064;
 // Device status, read and write. Reading from this register returns the current device status flags.
 // Writing non-zero values to this register sets the status flags, indicating the OS/driver
 // progress. Writing zero (0x0) to this register triggers a device reset.
-pub const VIRTIO_STATUS: u64 = VARTIO_BASE + 0x070;
+pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
+// The addresses of the descriptor table, the available ring and the used ring,
+// write-only, version 2
+pub const VIRTIO_QUEUE_DESC_LOW: u64 = VIRTIO_BASE + 0x080;
+pub const VIRTIO_QUEUE_DESC_HIGH: u64 = VIRTIO_BASE + 0x084;
+pub const VIRTIO_QUEUE_DRIVER_LOW: u64 = VIRTIO_BASE + 0x090;
+pub const VIRTIO_QUEUE_DRIVER_HIGH: u64 = VIRTIO_BASE + 0x094;
+pub const VIRTIO_QUEUE_DEVICE_LOW: u64 = VIRTIO_BASE + 0x0a0;
+pub const VIRTIO_QUEUE_DEVICE_HIGH: u64 = VIRTIO_BASE + 0x0a4;
+// The configuration of the block device. It starts with the capacity in sectors.
+pub const VIRTIO_CONFIG: u64 = VIRTIO_BASE + 0x100;
+
+// The feature of the devices of virtio 1.0, bit 32
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// The bit of INTERRUPT_STATUS for a used buffer
+pub const VIRTIO_INT_USED_RING: u32 = 1;
+// The bit of STATUS for a device which must be reset, e.g. after a bad request
+pub const VIRTIO_STATUS_NEEDS_RESET: u32 = 0x40;
+
+// The flags of a descriptor: the next field is valid, and the device writes the buffer.
+pub const VRING_DESC_F_NEXT: u64 = 1;
+pub const VRING_DESC_F_WRITE: u64 = 2;
+
+// The types of the requests: read, write and flush. The others are not supported.
+pub const VIRTIO_BLK_T_IN: u64 = 0;
+pub const VIRTIO_BLK_T_OUT: u64 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u64 = 4;
+// The status of a request
+pub const VIRTIO_BLK_S_OK: u64 = 0;
+pub const VIRTIO_BLK_S_IOERR: u64 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u64 = 2;
+
+// The size of a sector, the unit of the capacity and of the requests
+pub const SECTOR_SIZE: u64 = 512;
 
 // paravirtualized driver for io virtualization
 pub struct Virtio {
-    id: u64,
-    device:driver_features: u32,
+    // The version of the interface, 1 for legacy or 2
+    version: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
     page_size: u32,
     queue_sel: u32,
     queue_num: u32,
+    queue_align: u32,
     queue_pfn: u32,
-    queue_notify: u32,
+    queue_ready: u32,
+    // The addresses of the rings given by the registers of version 2
+    queue_desc: u64,
+    queue_driver: u64,
+    queue_device: u64,
+    // The index in the available ring of the next request
+    last_avail: u16,
+    // The driver notified the queue, and the requests are not done yet.
+    notified: bool,
+    interrupt_status: u32,
     status: u32,
     disk: Vec<u8>
 }
@@ -61,6 +127,7 @@ pub struct Virtio {
 impl Device for Virtio {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64,Exception> {
         match size {
+            _ if addr >= VIRTIO_CONFIG => Ok(self.load_config(addr, size)),
             32 => Ok(self.load32(addr)),
             _ => Err(Exception::LoadAccessFault),
         }
@@ -68,10 +135,66 @@ impl Device for Virtio {
 
     fn store(&mut self, addr: u64, size:u64, value:u64) -> Result<(),Exception> {
         match size {
-            32 => Ok(self.store(addr)),
+            // The configuration is read-only.
+            _ if addr >= VIRTIO_CONFIG => Ok(()),
+            32 => {
+                self.store32(addr, value);
+                Ok(())
+            },
             _ => Err(Exception::StoreAMOAccessFault),
         }
     }
+
+    // Save the registers and the disk, which has the writes of the guest.
+    fn save(&self, writer: &mut Writer) {
+        writer.section("virtio");
+        writer.u32(self.version);
+        writer.u32(self.device_features_sel);
+        writer.u64(self.driver_features);
+        writer.u32(self.driver_features_sel);
+        writer.u32(self.page_size);
+        writer.u32(self.queue_sel);
+        writer.u32(self.queue_num);
+        writer.u32(self.queue_align);
+        writer.u32(self.queue_pfn);
+        writer.u32(self.queue_ready);
+        writer.u64(self.queue_desc);
+        writer.u64(self.queue_driver);
+        writer.u64(self.queue_device);
+        writer.u32(self.last_avail as u32);
+        writer.u8(self.notified as u8);
+        writer.u32(self.interrupt_status);
+        writer.u32(self.status);
+        writer.bytes(&self.disk);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        reader.section("virtio")?;
+        self.version = reader.u32()?;
+        self.device_features_sel = reader.u32()?;
+        self.driver_features = reader.u64()?;
+        self.driver_features_sel = reader.u32()?;
+        self.page_size = reader.u32()?;
+        self.queue_sel = reader.u32()?;
+        self.queue_num = reader.u32()?;
+        self.queue_align = reader.u32()?;
+        self.queue_pfn = reader.u32()?;
+        self.queue_ready = reader.u32()?;
+        self.queue_desc = reader.u64()?;
+        self.queue_driver = reader.u64()?;
+        self.queue_device = reader.u64()?;
+        self.last_avail = reader.u32()? as u16;
+        self.notified = reader.u8()? != 0;
+        self.interrupt_status = reader.u32()?;
+        self.status = reader.u32()?;
+        self.disk = reader.bytes()?.to_vec();
+        Ok(())
+    }
+
+    // The interrupt is raised until the driver acknowledges the used buffers.
+    fn is_interrupting(&mut self) -> bool {
+        self.interrupt_status != 0
+    }
 }
 
 impl Virtio {
@@ -79,39 +202,105 @@ impl Virtio {
         let mut disk = Vec::new();
         disk.extend(disk_image.iter().cloned());
 
-        Self {
-            id: 0,
-            driver_feature: 0,
+        let mut virtio = Self {
+            version: 1,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
             page_size: 0,
             queue_sel: 0,
             queue_num: 0,
+            queue_align: 0,
             queue_pfn: 0,
-            queue_notify: 9999, //🍫
+            queue_ready: 0,
+            queue_desc: 0,
+            queue_driver: 0,
+            queue_device: 0,
+            last_avail: 0,
+            notified: false,
+            interrupt_status: 0,
             status: 0,
             disk,
-        }
+        };
+        virtio.reset();
+        virtio
+    }
+
+    // The version of the interface, 1 for legacy or 2
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    // The contents of the disk
+    pub fn disk(&self) -> &[u8] {
+        &self.disk
     }
 
-    // return true if an interrupt is pending
-    pub fn is_interrupting(&mut self) -> bool {
-        if self.queue_notify != 9999 {
-            self.queue_notify = 9999;
-            return true;
+    // Replace the contents of the disk, e.g. with a recorded one.
+    pub fn set_disk(&mut self, disk: Vec<u8>) {
+        self.disk = disk;
+    }
+
+    // Reset the device, when the driver writes 0 to the status.
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.page_size = 4096;
+        self.queue_sel = 0;
+        self.queue_num = 0;
+        self.queue_align = 4096;
+        self.queue_pfn = 0;
+        self.queue_ready = 0;
+        self.queue_desc = 0;
+        self.queue_driver = 0;
+        self.queue_device = 0;
+        self.last_avail = 0;
+        self.notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    // The features of the device. A device of version 2 has VIRTIO_F_VERSION_1.
+    fn device_features(&self) -> u64 {
+        match self.version {
+            1 => 0,
+            _ => VIRTIO_F_VERSION_1,
         }
-        false
+    }
+
+    // Load from the configuration, the capacity and then zeros, in any size.
+    fn load_config(&self, addr: u64, size: u64) -> u64 {
+        let capacity = (self.disk.len() as u64 / SECTOR_SIZE).to_le_bytes();
+        let offset = addr - VIRTIO_CONFIG;
+        (0..size / 8).fold(0, |value, i| {
+            let byte = capacity.get((offset + i) as usize).copied().unwrap_or(0);
+            value | (byte as u64) << (i * 8)
+        })
     }
 
     // Load 4 bytes from virtio only if the addr is valid. otherwise. return 0
     pub fn load32(&self, addr: u64) -> u64 {
+        // Only queue 0 exists.
+        let queue = self.queue_sel == 0;
         match addr {
-            VIRTIO_MAGIC => 0x7426976,
-            VIRTIO_VERSION => 0x1,
+            VIRTIO_MAGIC => 0x74726976,
+            VIRTIO_VERSION => self.version as u64,
             VIRTIO_DEVICE_ID => 0x02,
-            VIRTIO_VENDER_ID => 0x554d4551,
-            VIRTIO_DEVICE_FEATURES => 0, //🍫
-            VIRTIO_DRIVER_FEATURES => self.driver_features as u64,
-            VIRTIO_QUEUE_NUM_MAX => 8,
-            VIRTIO_QUEUE_PFN => self.queue_pfn as u64,
+            VIRTIO_VENDOR_ID => 0x554d4551,
+            VIRTIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() & 0xffff_ffff,
+                1 => self.device_features() >> 32,
+                _ => 0,
+            },
+            VIRTIO_QUEUE_NUM_MAX if queue => DESC_NUM,
+            VIRTIO_QUEUE_PFN if queue && self.version == 1 => self.queue_pfn as u64,
+            VIRTIO_QUEUE_READY if queue && self.version != 1 => self.queue_ready as u64,
+            VIRTIO_INTERRUPT_STATUS => self.interrupt_status as u64,
             VIRTIO_STATUS => self.status as u64,
             _ => 0,
         }
@@ -120,132 +309,170 @@ impl Virtio {
     // store 4 bytes to virtio only if the addr is valid. Otherwise, does nothing
     pub fn store32(&mut self,addr: u64, value:u64) {
         let val = value as u32;
+        // Set the low or the high 32 bits of an address.
+        let set_low = |addr: &mut u64| *addr = (*addr & !0xffff_ffff) | val as u64;
+        let set_high = |addr: &mut u64| *addr = (*addr & 0xffff_ffff) | (val as u64) << 32;
+        let queue = self.queue_sel == 0;
+        let modern = self.version != 1;
         match addr {
-            VIRTIO_DEVICE_FEATURES => self.driver_features = val,
-            VIRTIO_GUEST_PAGE_SIZE => self.page_size = val;
-            VIRTIO_QUEUE_SEL => self.queue_sel = val;
-            VIRTIO_QUEUE_NUM => self.queue_num = val;
-            VIRTIO_QUEUE_PFN => self.queue_pfn = val;
-            VIRTIO_QUEUE_NOTIFY => self.queue_notify = val;
-            VIRTIO_STATUS => self.status = val;
+            VIRTIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features),
+                1 => set_high(&mut self.driver_features),
+                _ => {}
+            },
+            VIRTIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_QUEUE_NUM if queue => self.queue_num = val,
+            VIRTIO_QUEUE_ALIGN if queue => self.queue_align = val,
+            VIRTIO_QUEUE_PFN if queue => self.queue_pfn = val,
+            VIRTIO_QUEUE_READY if queue => self.queue_ready = val & 1,
+            VIRTIO_QUEUE_NOTIFY if val == 0 => self.notified = true,
+            VIRTIO_INTERRUPT_ACK => self.interrupt_status &= !val,
+            VIRTIO_STATUS if val == 0 => self.reset(),
+            VIRTIO_STATUS => self.status = val,
+            VIRTIO_QUEUE_DESC_LOW if queue && modern => set_low(&mut self.queue_desc),
+            VIRTIO_QUEUE_DESC_HIGH if queue && modern => set_high(&mut self.queue_desc),
+            VIRTIO_QUEUE_DRIVER_LOW if queue && modern => set_low(&mut self.queue_driver),
+            VIRTIO_QUEUE_DRIVER_HIGH if queue && modern => set_high(&mut self.queue_driver),
+            VIRTIO_QUEUE_DEVICE_LOW if queue && modern => set_low(&mut self.queue_device),
+            VIRTIO_QUEUE_DEVICE_HIGH if queue && modern => set_high(&mut self.queue_device),
             _ => {}
         }
     }
 
-    fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        // 🍫
-    }
-
-    fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64;
-    }
-
-    fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64;
-    }
-
-    fn write_disk(&self, addr: u64, value:u64) {
-        self.disk[addr as usize] = value as u8;
-    }
-
-    // Access the disk via virtio. This is an associated function which takes a `cpu` object to
-    // read and write with a memory directly (DMA).
-    pub fn disk_access(cpu: &mut Cpu) {
-        // See more information in
-        // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c
-
-        // the spec says that legacy block operations use three
-        // descriptors: one for type/reserved/sector, one for
-        // the data, one for a 1-byte status result.
-
-        // desc = pages -- num * VRingDesc
-        // avail = pages + 0x40 -- 2 * uint16, then num * uint16
-        // used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
-        let desc_addr = cpu.bus.virstio.desc_addr();
-        let avail_addr = cpu.bus.virstio.desc_addr() + 0x40;
-        let used_addr = cpu.bus.virtio.desc_addr() + 4096;
-
-        // avail[0] is flags
-        // avail[1] tells the device how far to look in avail [2...],
-        let offset = cpu
-            .bus
-            .load(avail_addr.wrapping_add(1),16)
-            .expect("failed to read offset");
-        // avail[2...] are desc[] indices the device should process.
-        // we only tell device the first index in our chain of descriptors.
-        let index = cpu
-            .bus
-            .load(
-                avail_addr.wrapping_add(offset % DESC_NUM).wrapping_add(2),
-                16
-            )
-            .expect("failed to read index");
-
-        // Read VRingDesc, virtio descriptions.
-        let desc_addr0 = desc_addr + VRING_DESC_SIZE * index;
-        let addr0 = cpu
-            .bus
-            .load(desc_addr0, 64)
-            .expect("failed to read an address field in a descriptor");
-
-        // The next filed can be accessed by offset 14 ( 8 + 4 + 2 ) bytes
-        let next0 = cpu
-            .bus
-            .load(desc_addr0.wrapping_add(14),16)
-            .expect("failed to read a next field in a description");
-
-        // Read `VRingDesc` again, virtio descriptors.
-        let desc_addr1 = desc_addr + VRING_DESC_SIZE * next0;
-        let addr1 = cpu
-            .bus
-            .load(desc_addr1, 64)
-            .expect("failed to read an address field in a descriptor");
-        let len1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(8),32)
-            .expect("failed to read a flags field in a descriptor");
-        let flags1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(12),16)
-            .expect("failed to read a flags field in a descriptor");
-        // Read `virtio_blk_outhdr`. Add 8 because of its structure.
-        let blk_sector = cpu
-            .bus
-            .load(addr0.wrapping_add(8),64)
-            .expect("failed to read a sector field in a virtio_blk_outhdr");
-        
-        // Write to a device if the second bit `flag1` is set.
-        match (flags1 & 2 ) == 0 {
-            true => {
-                // Read memory data and write it to a disk dirextly (DMA)
-                for i in 0..len1 as u64 {
-                    let data = cpu
-                        .bus
-                        .load(addr1 + i,8)
-                        .expect("failed to read from memory");
-                    cpu.bus.virtio.write_disk(blk_sector * 512 + i,data);
-                }
+    // Return true if the driver notified the queue since the last call.
+    pub fn take_notification(&mut self) -> bool {
+        std::mem::take(&mut self.notified)
+    }
+
+    // The addresses of the descriptor table, the available ring and the used ring,
+    // or None if the queue is not set up.
+    fn rings(&self) -> Option<(u64, u64, u64)> {
+        if self.queue_num == 0 || self.queue_num as u64 > DESC_NUM {
+            return None;
+        }
+        if self.version != 1 {
+            return match self.queue_ready {
+                0 => None,
+                _ => Some((self.queue_desc, self.queue_driver, self.queue_device)),
+            };
+        }
+        if self.queue_pfn == 0 || !self.queue_align.is_power_of_two() {
+            return None;
+        }
+        // In the legacy layout, the available ring follows the descriptors in the
+        // pages at QUEUE_PFN, and the used ring is at the next multiple of
+        // QUEUE_ALIGN. The available ring has the flags, the index, the ring and
+        // the used event, 2 bytes each.
+        let num = self.queue_num as u64;
+        let desc = self.queue_pfn as u64 * self.page_size as u64;
+        let avail = desc + VRING_DESC_SIZE * num;
+        let align = self.queue_align as u64;
+        let used = (avail + 2 * (3 + num) + align - 1) & !(align - 1);
+        Some((desc, avail, used))
+    }
+
+    // Do the requests of the available ring. This is an associated function which
+    // takes the `bus` to read and write the memory directly (DMA). A request which
+    // the device cannot read makes the device need a reset.
+    pub fn process_queue(bus: &mut Bus) {
+        let (desc, avail, used) = match bus.virtio.rings() {
+            Some(rings) => rings,
+            None => return,
+        };
+        if Self::process_rings(bus, desc, avail, used).is_err() {
+            bus.virtio.status |= VIRTIO_STATUS_NEEDS_RESET;
+        }
+    }
+
+    fn process_rings(bus: &mut Bus, desc: u64, avail: u64, used: u64) -> Result<(), Exception> {
+        let num = bus.virtio.queue_num as u64;
+        // The available ring: flags, idx and ring[num] of 2 bytes each
+        let avail_idx = bus.load(avail + 2, 16)? as u16;
+        while bus.virtio.last_avail != avail_idx {
+            let head = bus.load(avail + 4 + 2 * (bus.virtio.last_avail as u64 % num), 16)?;
+            let written = Self::request(bus, desc, head)?;
+            // The used ring: flags and idx of 2 bytes, and ring[num] of the index
+            // of the head and the number of bytes written, 4 bytes each
+            let used_idx = bus.load(used + 2, 16)?;
+            let elem = used + 4 + 8 * (used_idx % num);
+            bus.store(elem, 32, head)?;
+            bus.store(elem + 4, 32, written)?;
+            bus.store(used + 2, 16, used_idx.wrapping_add(1))?;
+            bus.virtio.last_avail = bus.virtio.last_avail.wrapping_add(1);
+            bus.virtio.interrupt_status |= VIRTIO_INT_USED_RING;
+        }
+        Ok(())
+    }
+
+    // Do the request of the chain of descriptors from `head`. Return the number of
+    // bytes written to the memory.
+    fn request(bus: &mut Bus, desc: u64, head: u64) -> Result<u64, Exception> {
+        // The descriptors: the address, the length, the flags and the next one,
+        // 8, 4, 2 and 2 bytes
+        let num = bus.virtio.queue_num as u64;
+        let mut chain = Vec::new();
+        let mut index = head;
+        loop {
+            // A loop or a descriptor out of the table is a bad request.
+            if index >= num || chain.len() as u64 >= num {
+                return Err(Exception::LoadAccessFault);
             }
-            false => {
-                for i in 0..len1 as u64 {
-                    let data = cpu.bus.virtio.read_disk(blk_sector * 512 + i);
-                    cpu.bus
-                        .store(addr1 + i,8,data)
-                        .expect("failed to write to memory");
-                }
+            let addr = desc + VRING_DESC_SIZE * index;
+            let flags = bus.load(addr + 12, 16)?;
+            chain.push((bus.load(addr, 64)?, bus.load(addr + 8, 32)?, flags));
+            if flags & VRING_DESC_F_NEXT == 0 {
+                break;
             }
+            index = bus.load(addr + 14, 16)?;
+        }
+        // The header has the type, a reserved field and the sector: 4, 4 and 8 bytes.
+        let (header, status) = match (chain.first(), chain.last()) {
+            (Some(header), Some(status)) if chain.len() >= 2 && header.1 >= 16 => (header.0, status.0),
+            _ => return Err(Exception::LoadAccessFault),
         };
+        let kind = bus.load(header, 32)?;
+        let mut offset = bus.load(header + 8, 64)?.wrapping_mul(SECTOR_SIZE);
 
-        // Write id to `UsedArea`. Add 2 because of its structure.
-        // struct UsedArea {
-        //   uint16 flags;
-        //   uint16 id;
-        //   struct VRingUsedElem elems[NUM];
-        // };
-        let new_id = cpu.bus.virtio.get_new_id();
-        cpu.bus
-            .store(used_addr.wrapping_add(2),16,new_id % 8)
-            .expect("failed to write to memory");
-    }
-}
\ No newline at end of file
+        let mut result = match kind {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        // The status byte is written.
+        let mut written = 1;
+        for (addr, len, flags) in chain[1..chain.len() - 1].iter().copied() {
+            if result != VIRTIO_BLK_S_OK {
+                break;
+            }
+            let end = match offset.checked_add(len) {
+                Some(end) if end <= bus.virtio.disk.len() as u64 => end,
+                _ => {
+                    result = VIRTIO_BLK_S_IOERR;
+                    break;
+                }
+            };
+            match (kind, flags & VRING_DESC_F_WRITE != 0) {
+                // Read the disk to a buffer the device writes.
+                (VIRTIO_BLK_T_IN, true) => {
+                    for i in 0..len {
+                        let byte = bus.virtio.disk[(offset + i) as usize];
+                        bus.store(addr + i, 8, byte as u64)?;
+                    }
+                    written += len;
+                }
+                // Write a buffer the device reads to the disk.
+                (VIRTIO_BLK_T_OUT, false) => {
+                    for i in 0..len {
+                        bus.virtio.disk[(offset + i) as usize] = bus.load(addr + i, 8)? as u8;
+                    }
+                }
+                _ => result = VIRTIO_BLK_S_IOERR,
+            }
+            offset = end;
+        }
+        bus.store(status, 8, result)?;
+        Ok(written)
+    }
+}
@@ -1,3 +1,4 @@
 cargo run test/add-addi.bin
 cargo run test/fib.bin
 cargo run test/csr.bin
+cargo run -- --riscv-tests tests/isa-tests/bin
@@ -0,0 +1,98 @@
+// Boot from the reset ROM into firmware, which starts a kernel.
+
+use riscvemu::boot::*;
+use riscvemu::bus::*;
+use riscvemu::emulator::*;
+
+// loop: j loop
+const LOOP: [u8; 4] = [0x6f, 0x00, 0x00, 0x00];
+
+#[test]
+fn firmware_and_kernel() {
+    let mut emulator = Emulator::builder()
+        .memory_size(0x800000)
+        .firmware(LOOP.to_vec())
+        .binary(vec![0x13, 0, 0, 0])
+        .build()
+        .unwrap();
+    let (dtb, _) = emulator.device_tree().unwrap();
+    assert_eq!(emulator.pc(), ROM_BASE);
+    assert_eq!(emulator.reg(2), 0);
+
+    // The reset code jumps to the firmware.
+    for _ in 0..6 {
+        assert_eq!(emulator.step(), None);
+    }
+    assert_eq!(emulator.pc(), MEMORY_BASE);
+    assert_eq!(emulator.reg(10), 0);
+    assert_eq!(emulator.reg(11), dtb);
+
+    // The fw_dynamic info tells where the kernel is.
+    let info = emulator.reg(12);
+    let mut word = |i: u64| emulator.load(info + i * 8, 64).unwrap();
+    assert_eq!(word(0), FW_DYNAMIC_INFO_MAGIC);
+    assert_eq!(word(1), FW_DYNAMIC_INFO_VERSION);
+    assert_eq!(word(2), MEMORY_BASE + DEFAULT_KERNEL_OFFSET);
+    // S-mode
+    assert_eq!(word(3), 1);
+    assert_eq!(word(5), 0);
+    assert_eq!(emulator.load(MEMORY_BASE + DEFAULT_KERNEL_OFFSET, 32), Ok(0x13));
+
+    // The reset ROM cannot be written.
+    assert!(emulator.store(ROM_BASE, 32, 0).is_err());
+}
+
+#[test]
+fn kernel_addr() {
+    let mut emulator = Emulator::builder()
+        .firmware(LOOP.to_vec())
+        .binary(vec![0x13, 0, 0, 0])
+        .kernel_addr(MEMORY_BASE + 0x40_0000)
+        .build()
+        .unwrap();
+    for _ in 0..6 {
+        emulator.step();
+    }
+    assert_eq!(emulator.load(emulator.reg(12) + 16, 64), Ok(MEMORY_BASE + 0x40_0000));
+    assert_eq!(emulator.load(MEMORY_BASE + 0x40_0000, 32), Ok(0x13));
+}
+
+#[test]
+fn reset_vector_without_firmware() {
+    let mut emulator = Emulator::builder().binary(LOOP.to_vec()).reset_vector(true).build().unwrap();
+    assert_eq!(emulator.pc(), ROM_BASE);
+    assert_eq!(emulator.run(6), StopReason::InstructionLimit);
+    assert_eq!(emulator.pc(), MEMORY_BASE);
+    assert_eq!(emulator.reg(11), emulator.device_tree().unwrap().0);
+}
+
+// The header of a Linux Image which is loaded at `text_offset`
+fn linux_image(text_offset: u64) -> Vec<u8> {
+    let mut image = vec![0; 0x40];
+    image[..4].copy_from_slice(&LOOP);
+    image[0x8..0x10].copy_from_slice(&text_offset.to_le_bytes());
+    image[0x30..0x38].copy_from_slice(b"RISCV\0\0\0");
+    image[0x38..0x3c].copy_from_slice(b"RSC\x05");
+    image
+}
+
+#[test]
+fn linux_image_without_firmware() {
+    let image = linux_image(0x20_0000);
+    assert_eq!(linux_image_offset(&image), Some(0x20_0000));
+    assert_eq!(linux_image_offset(&LOOP), None);
+
+    let emulator = Emulator::builder()
+        .memory_size(0x800000)
+        .binary(image)
+        .sbi(true)
+        .build()
+        .unwrap();
+    assert_eq!(emulator.pc(), MEMORY_BASE + 0x20_0000);
+    assert_eq!(emulator.reg(10), 0);
+    assert_eq!(Some(emulator.reg(11)), emulator.device_tree().map(|(addr, _)| addr));
+
+    // An Image which does not fit is an error.
+    let image = linux_image(0x800000);
+    assert!(Emulator::builder().memory_size(0x800000).binary(image).build().is_err());
+}
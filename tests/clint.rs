@@ -0,0 +1,47 @@
+// Advance mtime of the CLINT by the retired instructions.
+
+use riscvemu::clint::*;
+use riscvemu::emulator::*;
+
+mod common;
+use common::program;
+
+// loop: rdtime a0
+// j loop
+const LOOP: [u32; 2] = [0xc0102573, 0xffdff06f];
+
+fn emulator(time_mode: TimeMode) -> Emulator {
+    let binary = program(&LOOP);
+    Emulator::builder().binary(binary).time_mode(time_mode).build().unwrap()
+}
+
+#[test]
+fn deterministic() {
+    for n in [1, 3, DEFAULT_INSTRUCTIONS_PER_TICK].iter() {
+        let mut emulator = emulator(TimeMode::Deterministic(*n));
+        assert_eq!(emulator.cpu.bus.clint.mtime(), 0);
+        // mtime advances by exactly 1 when the nth instruction retires.
+        for count in 1..=10 * n {
+            assert_eq!(emulator.run(1), StopReason::InstructionLimit);
+            assert_eq!(emulator.cpu.bus.clint.mtime(), count / n, "{} instructions per tick", n);
+        }
+    }
+}
+
+#[test]
+fn same_runs() {
+    // rdtime reads the same values in every run.
+    let run = || {
+        let mut emulator = emulator(TimeMode::Deterministic(7));
+        let mut times = Vec::new();
+        for _ in 0..500 {
+            emulator.run(2);
+            times.push(emulator.reg(10));
+        }
+        (times, emulator.cpu.bus.clint.mtime())
+    };
+    let (times, mtime) = run();
+    assert_eq!(mtime, 1000 / 7);
+    assert_eq!(times[499], 998 / 7);
+    assert_eq!(run(), (times, mtime));
+}
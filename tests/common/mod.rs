@@ -0,0 +1,26 @@
+// The fixtures which the integration tests share. Each test file which needs
+// them has `mod common;`, and uses only some of them.
+#![allow(dead_code)]
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// A flat binary of the instructions
+pub fn program(insts: &[u32]) -> Vec<u8> {
+    insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+
+// What the emulator writes, e.g. to the console or the trace, shared with the
+// test
+#[derive(Clone, Default)]
+pub struct Output(pub Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
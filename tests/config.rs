@@ -0,0 +1,148 @@
+// Parse machine descriptions and build machines from them.
+
+use std::path::Path;
+
+use riscvemu::bus::*;
+use riscvemu::config::*;
+use riscvemu::cpu::MISA;
+use riscvemu::emulator::*;
+
+#[test]
+fn parse() {
+    let text = r#"
+# A small machine
+[machine]
+harts = 1
+isa = "rv64i_zicsr" # no A
+semihosting = "sandbox"
+
+[memory]
+base = 0x4000_0000
+size = "64M"
+
+[images]
+kernel = "Image"
+disk = "/images/fs.img"
+
+[devices]
+uart = 0x1000_0000
+clint = 0x200_0000
+
+[[regions]]
+kind = "rom"
+base = 0x1000
+size = 0xf000
+image = "reset.bin"
+
+[[regions]]
+base = 0x1_0000_0000
+size = "4G"
+"#;
+    let config = Config::parse(text, Path::new("machines")).unwrap();
+    assert_eq!(config.isa, "rv64i_zicsr");
+    assert_eq!(config.memory_base, 0x4000_0000);
+    assert_eq!(config.memory_size, 64 << 20);
+    assert_eq!(config.kernel.as_deref(), Some(Path::new("machines/Image")));
+    assert_eq!(config.disk.as_deref(), Some(Path::new("/images/fs.img")));
+    assert_eq!(config.firmware, None);
+    assert_eq!(config.semihosting.as_deref(), Some(Path::new("machines/sandbox")));
+    assert_eq!(
+        config.devices,
+        DeviceMap {
+            clint: Some(CLINT_BASE),
+            plic: None,
+            uart: Some(UART_BASE),
+            virtio: None,
+        }
+    );
+    assert_eq!(
+        config.regions,
+        vec![
+            RegionConfig {
+                kind: RegionKind::Rom,
+                base: ROM_BASE,
+                size: ROM_SIZE,
+                image: Some("machines/reset.bin".into()),
+            },
+            RegionConfig {
+                kind: RegionKind::Ram,
+                base: 0x1_0000_0000,
+                size: 4 << 30,
+                image: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn defaults() {
+    let config = Config::parse("", Path::new("")).unwrap();
+    assert_eq!(config, Config::default());
+    assert_eq!(config.devices, DeviceMap::default());
+}
+
+#[test]
+fn errors() {
+    let error = |text| Config::parse(text, Path::new("")).unwrap_err();
+    assert_eq!(error("[memory]\nsize = \"1X\""), "2: invalid size 1X");
+    assert_eq!(error("[memory]\nbase = \"0x1000\""), "2: base must be an integer");
+    assert_eq!(error("\n[disks]"), "2: unknown table [disks]");
+    assert_eq!(error("[machine]\ncores = 2"), "2: unknown key cores in [machine]");
+    assert_eq!(error("[devices]\nrtc = 0x101000"), "2: unknown device rtc");
+    assert_eq!(error("harts = 1"), "1: harts is not in a table");
+    assert_eq!(error("[[regions]]\nkind = \"flash\""), "2: kind must be \"ram\", \"rom\" or \"write-ignored\"");
+}
+
+#[test]
+fn sizes() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("128M"), Some(128 << 20));
+    assert_eq!(parse_size("2g"), Some(2 << 30));
+    assert_eq!(parse_size("0x10K"), Some(16 << 10));
+    assert_eq!(parse_size("M"), None);
+}
+
+#[test]
+fn memory_base() {
+    // addi a0, zero, 42
+    let binary = 0x02a00513u32.to_le_bytes().to_vec();
+    let mut emulator = Emulator::builder()
+        .memory_base(0x4000_0000)
+        .memory_size(0x10000)
+        .binary(binary)
+        .build()
+        .unwrap();
+    assert_eq!(emulator.pc(), 0x4000_0000);
+    // The stack is below the DTB at the end of the memory.
+    assert_eq!(emulator.reg(2), emulator.reg(11));
+    assert!(emulator.reg(2) > 0x4000_0000 && emulator.reg(2) < 0x4001_0000);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.reg(10), 42);
+    assert!(emulator.load(MEMORY_BASE, 8).is_err());
+}
+
+#[test]
+fn devices() {
+    let devices = DeviceMap {
+        uart: Some(0x2000_0000),
+        ..DeviceMap::none()
+    };
+    let mut emulator = Emulator::builder().memory_size(0x10000).devices(devices).build().unwrap();
+    // The line status register of the UART, transmitter empty
+    assert_eq!(emulator.load(0x2000_0005, 8).unwrap() & 0x20, 0x20);
+    assert!(emulator.load(UART_BASE + 5, 8).is_err());
+    assert!(emulator.load(CLINT_BASE, 64).is_err());
+}
+
+#[test]
+fn isa() {
+    let emulator = Emulator::builder().isa("rv64ia_zicsr").build().unwrap();
+    assert_eq!(emulator.csr(MISA), 2 << 62 | 1 << 20 | 1 << 18 | 1 << 8 | 1);
+    assert!(Emulator::builder().isa("rv32i").build().is_err());
+    let emulator = Emulator::builder().isa("rv64ifd").build().unwrap();
+    assert_eq!(emulator.csr(MISA), 2 << 62 | 1 << 20 | 1 << 18 | 1 << 8 | 1 << 5 | 1 << 3);
+    assert!(Emulator::builder().isa("rv64id").build().is_err());
+    assert!(Emulator::builder().isa("rv64iv").build().is_err());
+    assert!(Emulator::builder().harts(0).build().is_err());
+    assert!(Emulator::builder().harts(MAX_HARTS + 1).build().is_err());
+}
@@ -0,0 +1,132 @@
+// Attach devices of the user to the bus.
+
+use std::io;
+
+use riscvemu::bus::*;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::plic::{PLIC_PRIORITY, PLIC_SCLAIM, PLIC_SENABLE};
+use riscvemu::snapshot::*;
+use riscvemu::trap::Exception;
+
+// A register which counts the stores to it and raises an interrupt at each store
+#[derive(Default)]
+struct Counter {
+    count: u64,
+    interrupting: bool,
+}
+
+impl Device for Counter {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match (addr, size) {
+            (0, 64) => Ok(self.count),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    fn store(&mut self, addr: u64, size: u64, _value: u64) -> Result<(), Exception> {
+        match (addr, size) {
+            (0, 64) => {
+                self.count += 1;
+                self.interrupting = true;
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault),
+        }
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        std::mem::take(&mut self.interrupting)
+    }
+
+    fn save(&self, writer: &mut Writer) {
+        writer.u64(self.count);
+    }
+
+    fn restore(&mut self, reader: &mut Reader) -> io::Result<()> {
+        self.count = reader.u64()?;
+        Ok(())
+    }
+}
+
+const COUNTER_BASE: u64 = 0x4000_0000;
+const COUNTER_IRQ: u64 = 5;
+
+fn emulator() -> Emulator {
+    Emulator::builder()
+        .memory_size(0x10000)
+        .device("counter", COUNTER_BASE, 0x1000, Some(COUNTER_IRQ), Box::new(Counter::default()))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn load_and_store() {
+    let mut emulator = emulator();
+    // The device sees the offsets from its base.
+    assert_eq!(emulator.store(COUNTER_BASE, 64, 0), Ok(()));
+    assert_eq!(emulator.store(COUNTER_BASE, 64, 0), Ok(()));
+    assert_eq!(emulator.load(COUNTER_BASE, 64), Ok(2));
+    assert_eq!(emulator.load(COUNTER_BASE + 8, 64), Err(Exception::LoadAccessFault));
+    assert_eq!(emulator.load(COUNTER_BASE + 0x1000, 64), Err(Exception::LoadAccessFault));
+}
+
+#[test]
+fn interrupt() {
+    // nop
+    let mut emulator = Emulator::builder()
+        .binary(0x00000013u32.to_le_bytes().to_vec())
+        .device("counter", COUNTER_BASE, 0x1000, Some(COUNTER_IRQ), Box::new(Counter::default()))
+        .build()
+        .unwrap();
+    // The source interrupts S-mode through the PLIC.
+    emulator.store(PLIC_PRIORITY + 4 * COUNTER_IRQ, 32, 1).unwrap();
+    emulator.store(PLIC_SENABLE, 32, 1 << COUNTER_IRQ).unwrap();
+    // Interrupts are enabled, but not the external one, so that no trap is taken.
+    emulator.set_csr(MSTATUS, 1 << 3);
+    emulator.store(COUNTER_BASE, 64, 0).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_ne!(emulator.csr(MIP) & MIP_SEIP, 0);
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(COUNTER_IRQ));
+}
+
+#[test]
+fn snapshot() {
+    let mut emulator = emulator();
+    emulator.store(COUNTER_BASE, 64, 0).unwrap();
+    let mut writer = Writer::new();
+    emulator.cpu.bus.save_devices(&mut writer);
+    let state = writer.into_inner();
+    emulator.store(COUNTER_BASE, 64, 0).unwrap();
+    emulator.cpu.bus.restore_devices(&mut Reader::new(&state)).unwrap();
+    assert_eq!(emulator.load(COUNTER_BASE, 64), Ok(1));
+}
+
+#[test]
+fn overlaps() {
+    let attach = |base, size| {
+        Emulator::builder()
+            .memory_size(0x10000)
+            .device("counter", base, size, None, Box::new(Counter::default()))
+            .build()
+    };
+    assert!(attach(UART_BASE + 0x80, 0x100).is_err());
+    assert!(attach(MEMORY_BASE - 0x800, 0x1000).is_err());
+    assert!(attach(0x3000_0000, 0x1000).is_ok());
+    assert!(attach(COUNTER_BASE, 0).is_err());
+
+    let devices = DeviceMap {
+        virtio: Some(UART_BASE),
+        ..DeviceMap::default()
+    };
+    assert!(Emulator::builder().devices(devices).build().is_err());
+
+    // The memory wraps around at the end of the address space.
+    let error = Emulator::builder()
+        .memory_base(0xffff_ffff_ffff_0000)
+        .memory_size(0x20000)
+        .build()
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("overflows"), "{}", error);
+}
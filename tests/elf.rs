@@ -0,0 +1,134 @@
+// Read ELF files and reject the ones which are not RISC-V executables.
+
+use std::io;
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::elf::*;
+
+// An ELF file with a segment of 8 bytes of code, loaded at MEMORY_BASE with 8
+// bytes of bss, and a symbol table with `_start`.
+fn elf() -> Vec<u8> {
+    let mut elf = vec![0; 0x200];
+    elf[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV and the version
+    elf[0x10..0x18].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+    elf[0x18..0x20].copy_from_slice(&MEMORY_BASE.to_le_bytes());
+    // e_phoff and e_shoff
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    elf[0x28..0x30].copy_from_slice(&0x180u64.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize and e_shnum
+    for (i, field) in [64u16, 56, 1, 64, 2].iter().enumerate() {
+        elf[0x34 + 2 * i..0x36 + 2 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    // PT_LOAD of the code at 0x100
+    let load = [1 | 5 << 32, 0x100, MEMORY_BASE, MEMORY_BASE, 8, 16, 0x1000];
+    for (i, field) in load.iter().enumerate() {
+        elf[0x40 + 8 * i..0x48 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    // addi a0, zero, 42
+    // loop: j loop
+    elf[0x100..0x108].copy_from_slice(&[0x13, 0x05, 0xa0, 0x02, 0x6f, 0x00, 0x00, 0x00]);
+
+    // The string table at 0x108 and the symbol table at 0x118 of STT_FUNC
+    elf[0x108..0x110].copy_from_slice(b"\0_start\0");
+    elf[0x130..0x134].copy_from_slice(&1u32.to_le_bytes());
+    elf[0x134] = 0x12;
+    elf[0x138..0x140].copy_from_slice(&MEMORY_BASE.to_le_bytes());
+    elf[0x140..0x148].copy_from_slice(&8u64.to_le_bytes());
+    // The section headers: SHT_SYMTAB linked to SHT_STRTAB
+    let symtab = [2u64 << 32, 0, 0, 0x118, 48, 1 << 32 | 1, 0, 24];
+    let strtab = [3u64 << 32, 0, 0, 0x108, 8, 0, 0, 0];
+    for (i, field) in symtab.iter().chain(strtab.iter()).enumerate() {
+        elf[0x180 + 8 * i..0x188 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    elf
+}
+
+// Parse the file and return the message of the error.
+fn error(binary: &[u8]) -> String {
+    match Elf::parse(binary) {
+        Ok(_) => panic!("the file was not rejected"),
+        Err(e) => {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            e.to_string()
+        }
+    }
+}
+
+#[test]
+fn parse() {
+    let elf = Elf::parse(&elf()).unwrap();
+    assert_eq!(elf.entry, MEMORY_BASE);
+    assert_eq!(elf.segments.len(), 1);
+    assert_eq!(elf.segments[0].addr, MEMORY_BASE);
+    assert_eq!(elf.segments[0].data, [0x13, 0x05, 0xa0, 0x02, 0x6f, 0x00, 0x00, 0x00]);
+    assert_eq!(elf.segments[0].mem_size, 16);
+    assert_eq!(elf.symbol("_start"), Some(MEMORY_BASE));
+    assert_eq!(elf.symbols[0].kind, STT_FUNC);
+    assert_eq!(elf.symbol("main"), None);
+    assert!(!elf.interpreter);
+}
+
+#[test]
+fn header() {
+    assert!(!is_elf(b"\x7fEL"));
+    assert!(error(b"#!/bin/sh\n").contains("no ELF magic number"));
+
+    let mut binary = elf();
+    // ELFCLASS32
+    binary[4] = 1;
+    assert!(error(&binary).contains("not a 64-bit ELF file"));
+
+    let mut binary = elf();
+    // ELFDATA2MSB
+    binary[5] = 2;
+    assert!(error(&binary).contains("not a little-endian ELF file"));
+
+    let mut binary = elf();
+    // EM_X86_64
+    binary[0x12] = 62;
+    assert!(error(&binary).contains("not a RISC-V ELF file (e_machine is 62)"));
+
+    let mut binary = elf();
+    // ET_DYN
+    binary[0x10] = 3;
+    assert!(error(&binary).contains("not an executable file"));
+}
+
+#[test]
+fn truncated() {
+    let binary = elf();
+    // The ELF header, the program header, the segment and the section headers
+    for size in [0x13, 0x30, 0x3e, 0x60, 0x104, 0x1c0].iter() {
+        assert!(error(&binary[..*size]).contains("unexpected end of file"), "{} bytes", size);
+    }
+}
+
+#[test]
+fn out_of_range() {
+    // Offsets whose sum with a size or an index overflows are rejected instead of
+    // wrapping around.
+    let cases: [(usize, u64); 5] = [
+        // p_offset
+        (0x48, u64::MAX - 4),
+        // e_phoff
+        (0x20, u64::MAX - 1),
+        // e_shoff
+        (0x28, u64::MAX - 1),
+        // sh_offset of the symbol table
+        (0x198, u64::MAX - 1),
+        // sh_offset of the string table
+        (0x1d8, u64::MAX - 4),
+    ];
+    for (field, value) in cases.iter() {
+        let mut binary = elf();
+        binary[*field..*field + 8].copy_from_slice(&value.to_le_bytes());
+        let message = error(&binary);
+        assert!(message.contains("offset out of range"), "0x{:x}: {}", field, message);
+    }
+
+    // A segment larger in the file than in memory
+    let mut binary = elf();
+    binary[0x60..0x68].copy_from_slice(&32u64.to_le_bytes());
+    assert!(error(&binary).contains("larger in the file than in memory"));
+}
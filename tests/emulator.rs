@@ -0,0 +1,104 @@
+// Drive programs with the Emulator API of the library and check the state.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::emulator::*;
+use riscvemu::riscv_tests::DEFAULT_TOHOST;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::program;
+
+// addi a0, zero, 42
+// addi a1, a0, 1
+// auipc t0, 0
+// sd a1, 64(t0)
+// loop: j loop
+const STORE: [u32; 5] = [0x02a00513, 0x00150593, 0x00000297, 0x04b2b023, 0x0000006f];
+
+#[test]
+fn step() {
+    let mut emulator = Emulator::builder().binary(program(&STORE)).build().unwrap();
+    assert_eq!(emulator.pc(), MEMORY_BASE);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 8);
+    assert_eq!(emulator.reg(10), 42);
+    assert_eq!(emulator.reg(11), 43);
+    assert_eq!(emulator.instruction_count(), 2);
+}
+
+#[test]
+fn run_until_breakpoint() {
+    let mut emulator = Emulator::builder().binary(program(&STORE)).build().unwrap();
+    emulator.add_breakpoint(MEMORY_BASE + 16);
+    assert_eq!(emulator.run(100), StopReason::Breakpoint(MEMORY_BASE + 16));
+    assert_eq!(emulator.read_memory(MEMORY_BASE + 8 + 64, 8).unwrap(), 43u64.to_le_bytes());
+    // The run resumes from the breakpoint.
+    assert_eq!(emulator.run(10), StopReason::Breakpoint(MEMORY_BASE + 16));
+    emulator.remove_breakpoint(MEMORY_BASE + 16);
+    assert_eq!(emulator.run(10), StopReason::InstructionLimit);
+}
+
+#[test]
+fn run_until_condition() {
+    let mut emulator = Emulator::builder().binary(program(&STORE)).build().unwrap();
+    let reason = emulator.run_until(100, |emulator| emulator.reg(11) == 43);
+    assert_eq!(reason, StopReason::Condition);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 8);
+}
+
+#[test]
+fn exception() {
+    // ebreak
+    let mut emulator = Emulator::builder().binary(program(&[0x00100073])).build().unwrap();
+    assert_eq!(emulator.run(10), StopReason::Exception(Exception::Breakpoint, MEMORY_BASE));
+}
+
+#[test]
+fn memory_size() {
+    let mut emulator = Emulator::builder().memory_size(0x10000).build().unwrap();
+    assert!(emulator.write_memory(MEMORY_BASE + 0xfff8, &[1; 8]).is_ok());
+    assert!(emulator.load(MEMORY_BASE + 0x10000, 8).is_err());
+    assert!(Emulator::builder().memory_size(100).build().is_err());
+}
+
+#[test]
+fn riscv_test() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/isa-tests/bin/rv64ui-add.elf");
+    let binary = std::fs::read(path).unwrap();
+    let mut emulator = Emulator::builder().binary(binary).stop_on_exception(false).build().unwrap();
+    // The test is not a flat binary, whose tohost would be at DEFAULT_TOHOST.
+    let tohost = emulator.symbol("tohost").unwrap();
+    assert_ne!(tohost, DEFAULT_TOHOST);
+    emulator.set_tohost(tohost).unwrap();
+    assert_eq!(emulator.run(1_000_000), StopReason::ToHost(1));
+}
+
+#[test]
+fn zero_register() {
+    let mut emulator = Emulator::builder().binary(program(&STORE)).build().unwrap();
+    emulator.cpu.regs[0] = 1;
+    assert_eq!(emulator.reg(0), 0);
+}
+
+#[test]
+fn wrapping_memory() {
+    let mut emulator = Emulator::builder().build().unwrap();
+    // The addresses wrap around at the end of the address space.
+    assert!(emulator.read_memory(u64::MAX, 2).is_err());
+    assert!(emulator.write_memory(u64::MAX, &[0; 2]).is_err());
+}
+
+#[test]
+fn invalid_options() {
+    let builder = || Emulator::builder().binary(program(&STORE));
+    let error = builder().record("a.rec").replay("b.rec").build().err().unwrap();
+    assert_eq!(error.to_string(), "the events cannot be recorded and replayed together");
+    let error = builder().harts(2).lockstep(Box::new(Cursor::new(""))).build().err().unwrap();
+    assert_eq!(error.to_string(), "lockstep supports only 1 hart");
+    let error = builder().restore("/nonexistent.snap").build().err().unwrap();
+    assert!(error.to_string().starts_with("/nonexistent.snap: "), "{}", error);
+}
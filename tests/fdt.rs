@@ -0,0 +1,115 @@
+// Generate the device tree of the machine and start the program with it.
+
+use std::convert::TryInto;
+
+use riscvemu::bus::*;
+use riscvemu::emulator::*;
+use riscvemu::fdt::*;
+
+fn be32(dtb: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(dtb[offset..offset + 4].try_into().unwrap())
+}
+
+// The value of the first property named `name`
+fn property<'a>(dtb: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let structure = be32(dtb, 8) as usize;
+    let strings = be32(dtb, 12) as usize;
+    let mut p = structure;
+    loop {
+        let token = be32(dtb, p);
+        p += 4;
+        match token {
+            // FDT_BEGIN_NODE
+            1 => {
+                let end = p + dtb[p..].iter().position(|byte| *byte == 0).unwrap();
+                p = (end + 4) & !3;
+            }
+            // FDT_PROP
+            3 => {
+                let length = be32(dtb, p) as usize;
+                let offset = strings + be32(dtb, p + 4) as usize;
+                let value = &dtb[p + 8..p + 8 + length];
+                p = (p + 8 + length + 3) & !3;
+                let end = offset + dtb[offset..].iter().position(|byte| *byte == 0).unwrap();
+                if &dtb[offset..end] == name.as_bytes() {
+                    return Some(value);
+                }
+            }
+            // FDT_END
+            9 => return None,
+            _ => {}
+        }
+    }
+}
+
+fn device_tree(emulator: &mut Emulator) -> Vec<u8> {
+    let (addr, size) = emulator.device_tree().unwrap();
+    emulator.read_memory(addr, size).unwrap()
+}
+
+#[test]
+fn boot_registers() {
+    let mut emulator = Emulator::builder().memory_size(0x100000).build().unwrap();
+    let (addr, _) = emulator.device_tree().unwrap();
+    assert_eq!(emulator.reg(10), 0);
+    assert_eq!(emulator.reg(11), addr);
+    assert_eq!(emulator.reg(2), addr);
+    assert_eq!(addr % 4096, 0);
+
+    let dtb = device_tree(&mut emulator);
+    assert!(is_dtb(&dtb));
+    assert_eq!(be32(&dtb, 4) as usize, dtb.len());
+    assert_eq!(property(&dtb, "reg"), Some(&[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0][..]));
+    assert_eq!(property(&dtb, "riscv,isa"), Some(&b"rv64imafdc\0"[..]));
+    assert_eq!(property(&dtb, "stdout-path"), Some(&b"/soc/serial@10000000\0"[..]));
+    assert_eq!(property(&dtb, "bootargs"), None);
+}
+
+#[test]
+fn chosen() {
+    let mut emulator = Emulator::builder()
+        .memory_size(0x100000)
+        .bootargs("console=ttyS0 rdinit=/init")
+        .initrd(vec![1; 5000], None)
+        .build()
+        .unwrap();
+    let dtb = device_tree(&mut emulator);
+    assert_eq!(property(&dtb, "bootargs"), Some(&b"console=ttyS0 rdinit=/init\0"[..]));
+    // The initrd is at the end of the memory, and the DTB below it.
+    let start = MEMORY_BASE + 0x100000 - 0x2000;
+    assert_eq!(property(&dtb, "linux,initrd-start"), Some(&start.to_be_bytes()[..]));
+    assert_eq!(property(&dtb, "linux,initrd-end"), Some(&(start + 5000).to_be_bytes()[..]));
+    assert_eq!(emulator.read_memory(start, 1).unwrap(), [1]);
+    assert!(emulator.reg(11) < start);
+}
+
+#[test]
+fn devices() {
+    let devices = DeviceMap {
+        uart: Some(0x2000_0000),
+        ..DeviceMap::none()
+    };
+    let mut emulator = Emulator::builder().memory_size(0x100000).devices(devices).build().unwrap();
+    let dtb = device_tree(&mut emulator);
+    assert_eq!(property(&dtb, "compatible"), Some(&b"riscv-virtio\0"[..]));
+    assert_eq!(property(&dtb, "stdout-path"), Some(&b"/soc/serial@20000000\0"[..]));
+    assert_eq!(property(&dtb, "clock-frequency"), Some(&3_686_400u32.to_be_bytes()[..]));
+    assert_eq!(property(&dtb, "interrupt-parent"), None);
+}
+
+#[test]
+fn user_device_tree() {
+    let dtb = Fdt::new().finish();
+    let mut emulator = Emulator::builder()
+        .memory_size(0x100000)
+        .device_tree(DeviceTree::Blob(dtb.clone()))
+        .build()
+        .unwrap();
+    assert_eq!(device_tree(&mut emulator), dtb);
+    assert!(Emulator::builder().device_tree(DeviceTree::Blob(vec![0; 64])).build().is_err());
+
+    let emulator = Emulator::builder().memory_size(0x100000).device_tree(DeviceTree::None).build().unwrap();
+    assert_eq!(emulator.device_tree(), None);
+    assert_eq!(emulator.reg(11), 0);
+    assert_eq!(emulator.reg(2), MEMORY_BASE + 0x100000);
+}
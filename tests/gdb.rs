@@ -0,0 +1,179 @@
+// Debug programs with the GDB stub over a scripted connection.
+
+use std::io::{self, Cursor, Read, Write};
+
+use riscvemu::emulator::*;
+use riscvemu::gdb::*;
+use riscvemu::syscall::*;
+
+mod common;
+use common::{Output, program};
+
+// A connection which sends the packets of a session to the stub
+struct Session {
+    input: Cursor<Vec<u8>>,
+    output: Output,
+}
+
+impl Read for Session {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Session {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Session {
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Send the packets to the stub, acknowledging each reply, and return the result
+// of the session and the replies.
+fn session(emulator: &mut Emulator, packets: &[&str]) -> (Option<u64>, Vec<String>) {
+    let mut input = Vec::new();
+    for packet in packets.iter() {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        input.extend_from_slice(format!("${}#{:02x}+", packet, checksum).as_bytes());
+    }
+    let output = Output::default();
+    let session = Session {
+        input: Cursor::new(input),
+        output: output.clone(),
+    };
+    let result = GdbStub::new(Box::new(session)).run(emulator).unwrap();
+
+    // The data of the packets, without the acknowledgements and the checksums
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let replies = output
+        .split('$')
+        .skip(1)
+        .map(|packet| packet.split('#').next().unwrap().to_string())
+        .collect();
+    (result, replies)
+}
+
+#[test]
+fn reverse() {
+    let insts = [
+        // addi a0, a0, 1
+        0x00150513,
+        // addi a0, a0, 1
+        0x00150513,
+        // addi a0, a0, 1
+        0x00150513,
+        // An illegal instruction
+        0x00000000,
+    ];
+    let mut emulator = Emulator::builder().binary(program(&insts)).build().unwrap();
+    let (result, replies) = session(
+        &mut emulator,
+        &["qSupported", "s", "s", "pa", "bs", "pa", "c", "k"],
+    );
+    assert_eq!(result, None);
+    assert!(replies[0].ends_with(";ReverseStep+;ReverseContinue+"));
+    assert_eq!(replies[1..3], ["S05", "S05"]);
+    assert_eq!(replies[3], "0200000000000000");
+    assert_eq!(replies[4], "S05");
+    assert_eq!(replies[5], "0100000000000000");
+    // The exception stops the emulator with SIGILL.
+    assert_eq!(replies[6], "S04");
+    assert_eq!(emulator.reg(10), 3);
+}
+
+#[test]
+fn exit() {
+    let insts = [
+        // addi a0, zero, 7
+        0x00700513,
+        // addi a7, zero, 94
+        0x05e00893,
+        // ecall
+        0x00000073,
+    ];
+    // The code of a Linux program starts after its ELF header.
+    let mut elf = vec![0; 0x100];
+    elf[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV and the version
+    elf[0x10..0x18].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+    elf[0x18..0x20].copy_from_slice(&(USER_MEMORY_BASE + 0x78).to_le_bytes());
+    // e_phoff, e_ehsize, e_phentsize and e_phnum
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    elf[0x34..0x36].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+    elf[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+    // PT_LOAD of the whole file, rx
+    let load = [1 | 5 << 32, 0, USER_MEMORY_BASE, USER_MEMORY_BASE, 0x100, 0x100, 0x1000];
+    for (i, field) in load.iter().enumerate() {
+        elf[0x40 + 8 * i..0x48 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    elf[0x78..0x84].copy_from_slice(&program(&insts));
+    let argv = vec!["prog".to_string()];
+    let mut emulator = Emulator::builder().binary(elf).user(&argv, &[]).build().unwrap();
+
+    // The system calls are done by the emulator, so the program cannot run
+    // backward, and the exit is reported to GDB.
+    let (result, replies) = session(&mut emulator, &["qSupported", "bs", "c"]);
+    assert_eq!(result, Some(7));
+    assert!(!replies[0].contains("Reverse"));
+    assert_eq!(replies[1..], ["", "W07"]);
+}
+
+#[test]
+fn escaped_packet() {
+    let mut emulator = Emulator::builder().binary(program(&[0x0000006f])).build().unwrap();
+    // `}C` is an escaped `c`, and the checksum is the one of the bytes as sent.
+    let (_, replies) = session(&mut emulator, &["qAtta}Ched", "k"]);
+    assert_eq!(replies, ["1"]);
+}
+
+#[test]
+fn zero_register() {
+    let insts = [
+        // addi zero, zero, 5
+        0x00500013,
+        // loop: j loop
+        0x0000006f,
+    ];
+    let mut emulator = Emulator::builder().binary(program(&insts)).build().unwrap();
+    let (_, replies) = session(&mut emulator, &["s", "p0", "g", "k"]);
+    assert_eq!(replies[1], "0000000000000000");
+    assert!(replies[2].starts_with("0000000000000000"));
+}
+
+#[test]
+fn overflowing_ranges() {
+    let mut emulator = Emulator::builder().binary(program(&[0x0000006f])).build().unwrap();
+    let (_, replies) = session(
+        &mut emulator,
+        &[
+            "Z2,ffffffffffffff00,200",
+            "qXfer:features:read:target.xml:1,ffffffffffffffff",
+            "k",
+        ],
+    );
+    assert_eq!(replies, ["E01", "E01"]);
+}
+
+#[test]
+fn harts() {
+    // addi a0, a0, 1
+    let mut emulator = Emulator::builder().binary(program(&[0x00150513])).harts(2).build().unwrap();
+
+    // The harts take turns, which GDB would see as one thread.
+    let session = Session {
+        input: Cursor::new(b"$qSupported#37+".to_vec()),
+        output: Output::default(),
+    };
+    let error = GdbStub::new(Box::new(session)).run(&mut emulator).unwrap_err();
+    assert_eq!(error.to_string(), "GDB supports only 1 hart");
+}
@@ -0,0 +1,102 @@
+// Run several harts, which take turns to execute an instruction each.
+
+use riscvemu::bus::*;
+use riscvemu::clint::*;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::plic::*;
+use riscvemu::uart::*;
+
+mod common;
+use common::program;
+
+// The data the programs access, in s0
+const DATA: u64 = MEMORY_BASE + 0x1000;
+
+fn with_harts(harts: usize, insts: &[u32]) -> Emulator {
+    Emulator::builder()
+        .memory_size(0x10000)
+        .harts(harts)
+        .binary(program(insts))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn hart_ids() {
+    let mut emulator = with_harts(3, &[
+        // auipc s0, 1
+        0x00001417,
+        // csrr t0, mhartid
+        0xf14022f3,
+        // slli t1, t0, 3
+        0x00329313,
+        // add t1, t1, s0
+        0x00830333,
+        // sd a0, 0(t1)
+        0x00a33023,
+        // j .
+        0x0000006f,
+    ]);
+    assert_eq!(emulator.cpu.hart_count(), 3);
+    assert_eq!(emulator.run(15), StopReason::InstructionLimit);
+    // Every hart started with its hart ID in a0, and ran 5 instructions.
+    for hart in 0..3 {
+        assert_eq!(emulator.load(DATA + 8 * hart, 64), Ok(hart));
+    }
+    assert_eq!(emulator.cpu.hartid(), 0);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 20);
+    assert_eq!(emulator.csr(MINSTRET), 5);
+}
+
+#[test]
+fn interrupts_of_each_hart() {
+    // nop, j .-4
+    let mut emulator = with_harts(2, &[0x00000013, 0xffdff06f]);
+    // The software and timer interrupts of hart 1 only
+    emulator.store(CLINT_MSIP + 4, 32, 1).unwrap();
+    emulator.store(CLINT_MTIMECMP, 64, u64::MAX).unwrap();
+    emulator.store(CLINT_MTIMECMP + 8, 64, 0).unwrap();
+    // The UART interrupt goes to S-mode of hart 1.
+    emulator.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+    let senable = PLIC_ENABLE + PLIC_ENABLE_STRIDE * plic_context(1, PLIC_CONTEXT_S) as u64;
+    emulator.store(senable, 32, 1 << UART_IRQ).unwrap();
+    emulator.store(UART_IER, 8, UART_IER_RDI as u64).unwrap();
+    emulator.cpu.bus.uart.receive(b'a');
+
+    assert_eq!(emulator.run(2), StopReason::InstructionLimit);
+    let pending = MIP_MSIP | MIP_MTIP | MIP_SEIP;
+    assert_eq!(emulator.csr(MIP) & pending, 0);
+    emulator.cpu.switch_hart(1);
+    assert_eq!(emulator.csr(MIP) & pending, pending);
+
+    // Hart 1 claims the interrupt from its context.
+    let sclaim = PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE * plic_context(1, PLIC_CONTEXT_S) as u64 + 4;
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(0));
+    assert_eq!(emulator.load(sclaim, 32), Ok(UART_IRQ));
+}
+
+#[test]
+fn reservations() {
+    let insts = [
+        // auipc s0, 1
+        0x00001417,
+        // lr.d t0, (s0)
+        0x100432af,
+        // sd zero, 0(s0)
+        0x00043023,
+        // sc.d t1, t0, (s0)
+        0x1854332f,
+    ];
+    // The store of a hart does not break its own reservation.
+    let mut emulator = with_harts(1, &insts);
+    assert_eq!(emulator.run(4), StopReason::InstructionLimit);
+    assert_eq!(emulator.reg(6), 0);
+
+    // The store of the other hart does, and so both sc fail.
+    let mut emulator = with_harts(2, &insts);
+    assert_eq!(emulator.run(8), StopReason::InstructionLimit);
+    assert_eq!(emulator.reg(6), 1);
+    emulator.cpu.switch_hart(1);
+    assert_eq!(emulator.reg(6), 1);
+}
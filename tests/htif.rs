@@ -0,0 +1,246 @@
+// Talk to a program for Spike by the HTIF tohost and fromhost.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use riscvemu::bus::*;
+use riscvemu::emulator::*;
+use riscvemu::htif::*;
+use riscvemu::syscall::*;
+
+mod common;
+use common::Output;
+
+// j .
+const LOOP: u32 = 0x0000006f;
+
+// The program loops at CODE, with tohost and fromhost after it. The tests write the
+// commands to tohost.
+const CODE: u64 = MEMORY_BASE + 0x100;
+const TOHOST: u64 = MEMORY_BASE + 0x200;
+const FROMHOST: u64 = MEMORY_BASE + 0x208;
+// The words of a system call and its buffer, after the ELF file
+const MAGIC: u64 = MEMORY_BASE + 0x1000;
+const BUFFER: u64 = MEMORY_BASE + 0x2000;
+
+// An ELF file of the loop with tohost and fromhost as data, as riscv-pk has them,
+// or without symbols.
+fn elf(symbols: bool) -> Vec<u8> {
+    let mut elf = vec![0; 0x4c0];
+    elf[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV and the version
+    elf[0x10..0x18].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+    elf[0x18..0x20].copy_from_slice(&CODE.to_le_bytes());
+    // e_phoff and e_shoff
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    elf[0x28..0x30].copy_from_slice(&0x400u64.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize and e_shnum
+    let sections: u16 = if symbols { 3 } else { 0 };
+    for (i, field) in [64u16, 56, 1, 64, sections].iter().enumerate() {
+        elf[0x34 + 2 * i..0x36 + 2 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    // PT_LOAD of the file up to the symbols
+    let load = [1 | 7 << 32, 0, MEMORY_BASE, MEMORY_BASE, 0x300, 0x300, 0x1000];
+    for (i, field) in load.iter().enumerate() {
+        elf[0x40 + 8 * i..0x48 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    elf[0x100..0x104].copy_from_slice(&LOOP.to_le_bytes());
+
+    // The string table at 0x300 and the symbol table at 0x340, of STT_OBJECT and
+    // STB_GLOBAL
+    elf[0x300..0x311].copy_from_slice(b"\0tohost\0fromhost\0");
+    for (i, (name, value)) in [(1u32, TOHOST), (8, FROMHOST)].iter().enumerate() {
+        let symbol = 0x358 + 24 * i;
+        elf[symbol..symbol + 4].copy_from_slice(&name.to_le_bytes());
+        elf[symbol + 4] = 0x11;
+        elf[symbol + 8..symbol + 16].copy_from_slice(&value.to_le_bytes());
+        elf[symbol + 16..symbol + 24].copy_from_slice(&8u64.to_le_bytes());
+    }
+    // The section headers: SHT_SYMTAB linked to SHT_STRTAB
+    let symtab = [2u64 << 32, 0, 0, 0x340, 72, 2, 0, 24];
+    let strtab = [3u64 << 32, 0, 0, 0x300, 17, 0, 0, 0];
+    for (i, field) in symtab.iter().chain(strtab.iter()).enumerate() {
+        elf[0x440 + 8 * i..0x448 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    elf
+}
+
+fn emulator() -> (Emulator, Output, Output) {
+    let argv: Vec<String> = ["pk", "hello", "a"].iter().map(|arg| arg.to_string()).collect();
+    let mut emulator = Emulator::builder().binary(elf(true)).htif(&argv).build().unwrap();
+    let console = Output::default();
+    emulator.cpu.bus.uart.set_output(Box::new(console.clone()));
+    let stdout = Output::default();
+    emulator.process_mut().unwrap().set_stdout(Box::new(stdout.clone()));
+    (emulator, console, stdout)
+}
+
+// Write the command to tohost and run an instruction.
+fn send(emulator: &mut Emulator, value: u64) -> StopReason {
+    emulator.store(TOHOST, 64, value).unwrap();
+    emulator.run(1)
+}
+
+// Forward the system call by the syscall proxy and return its result.
+fn syscall(emulator: &mut Emulator, number: u64, args: &[u64]) -> i64 {
+    emulator.store(MAGIC, 64, number).unwrap();
+    for (i, arg) in args.iter().enumerate() {
+        emulator.store(MAGIC + 8 * (i as u64 + 1), 64, *arg).unwrap();
+    }
+    assert_eq!(send(emulator, MAGIC), StopReason::InstructionLimit);
+    assert_eq!(emulator.load(TOHOST, 64), Ok(0));
+    assert_eq!(emulator.load(FROMHOST, 64), Ok(command(HTIF_DEVICE_SYSCALL, 0, 1)));
+    emulator.store(FROMHOST, 64, 0).unwrap();
+    emulator.load(MAGIC, 64).unwrap() as i64
+}
+
+#[test]
+fn console() {
+    let (mut emulator, console, _) = emulator();
+    send(&mut emulator, command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR, b'h' as u64));
+    assert_eq!(emulator.load(TOHOST, 64), Ok(0));
+    send(&mut emulator, command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR, b'i' as u64));
+    assert_eq!(*console.0.lock().unwrap(), b"hi");
+    // The second response waits until the program reads the first.
+    let response = command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR, 0x100 | b'h' as u64);
+    assert_eq!(emulator.load(FROMHOST, 64), Ok(response));
+    emulator.store(FROMHOST, 64, 0).unwrap();
+    emulator.run(1);
+    let response = command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR, 0x100 | b'i' as u64);
+    assert_eq!(emulator.load(FROMHOST, 64), Ok(response));
+    emulator.store(FROMHOST, 64, 0).unwrap();
+
+    // A read is answered when a byte is typed.
+    send(&mut emulator, command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR, 0));
+    emulator.run(10);
+    assert_eq!(emulator.load(FROMHOST, 64), Ok(0));
+    emulator.cpu.bus.uart.receive(b'x');
+    emulator.run(1);
+    let response = command(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR, 0x100 | b'x' as u64);
+    assert_eq!(emulator.load(FROMHOST, 64), Ok(response));
+}
+
+#[test]
+fn syscall_proxy() {
+    let (mut emulator, _, stdout) = emulator();
+    emulator.write_memory(BUFFER, b"hello").unwrap();
+    assert_eq!(syscall(&mut emulator, NR_WRITE, &[1, BUFFER, 5]), 5);
+    assert_eq!(*stdout.0.lock().unwrap(), b"hello");
+    assert_eq!(syscall(&mut emulator, NR_CLOSE, &[7]), -EBADF);
+    // The memory is the one of the proxy kernel.
+    assert_eq!(syscall(&mut emulator, NR_BRK, &[0]), -ENOSYS);
+
+    // argc, argv, a NULL envp and the strings
+    assert_eq!(syscall(&mut emulator, SYS_GETMAINVARS, &[BUFFER, 0x100]), 0);
+    assert_eq!(emulator.load(BUFFER, 64), Ok(3));
+    let argv1 = emulator.load(BUFFER + 16, 64).unwrap();
+    assert_eq!(emulator.read_memory(argv1, 6), Ok(b"hello\0".to_vec()));
+    assert_eq!(emulator.load(BUFFER + 32, 64), Ok(0));
+    assert_eq!(emulator.load(BUFFER + 40, 64), Ok(0));
+    assert_eq!(syscall(&mut emulator, SYS_GETMAINVARS, &[BUFFER, 16]), -ENOMEM);
+
+    // A read of the console waits for input in tohost.
+    emulator.store(MAGIC, 64, NR_READ).unwrap();
+    emulator.store(MAGIC + 8, 64, 0).unwrap();
+    emulator.store(MAGIC + 16, 64, BUFFER).unwrap();
+    emulator.store(MAGIC + 24, 64, 4).unwrap();
+    send(&mut emulator, MAGIC);
+    emulator.run(10);
+    assert_eq!(emulator.load(TOHOST, 64), Ok(MAGIC));
+    emulator.cpu.bus.uart.receive(b'y');
+    emulator.run(1);
+    assert_eq!(emulator.load(TOHOST, 64), Ok(0));
+    assert_eq!(emulator.load(MAGIC, 64), Ok(1));
+    assert_eq!(emulator.read_memory(BUFFER, 1), Ok(b"y".to_vec()));
+}
+
+// AT_FDCWD of openat
+const AT_FDCWD: u64 = -100i64 as u64;
+
+// Write the path with its NUL to `addr` and return the length, which the front-end
+// server takes after the path.
+fn path(emulator: &mut Emulator, addr: u64, path: &str) -> u64 {
+    let mut bytes = path.as_bytes().to_vec();
+    bytes.push(0);
+    emulator.write_memory(addr, &bytes).unwrap();
+    bytes.len() as u64
+}
+
+#[test]
+fn files() {
+    let (mut emulator, _, _) = emulator();
+    let directory = env::temp_dir().join(format!("riscvemu-htif-{}", process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    let file = directory.join("file");
+    fs::write(&file, b"hello from the host").unwrap();
+
+    // openat(dirfd, path, length, flags, mode) with O_RDONLY
+    let length = path(&mut emulator, BUFFER, file.to_str().unwrap());
+    let fd = syscall(&mut emulator, NR_OPENAT, &[AT_FDCWD, BUFFER, length, 0, 0]);
+    assert_eq!(fd, 3);
+
+    // The struct stat of the front-end server, with the size at 48 and no
+    // nanoseconds of the times
+    let stat = BUFFER + 0x100;
+    emulator.write_memory(stat, &[0xff; 128]).unwrap();
+    assert_eq!(syscall(&mut emulator, NR_FSTAT, &[3, stat]), 0);
+    assert_eq!(emulator.load(stat + 48, 64), Ok(19));
+    assert_eq!(emulator.load(stat + 16, 32).unwrap() & 0o170000, 0o100000);
+    for pad in [80, 96, 112].iter() {
+        assert_eq!(emulator.load(stat + pad, 64), Ok(0));
+    }
+
+    let data = BUFFER + 0x200;
+    assert_eq!(syscall(&mut emulator, NR_READ, &[3, data, 64]), 19);
+    assert_eq!(emulator.read_memory(data, 19), Ok(b"hello from the host".to_vec()));
+    assert_eq!(syscall(&mut emulator, NR_CLOSE, &[3]), 0);
+
+    // fstatat(dirfd, path, length, stat, flags) and faccessat(dirfd, path, length,
+    // mode) with F_OK
+    assert_eq!(syscall(&mut emulator, NR_NEWFSTATAT, &[AT_FDCWD, BUFFER, length, stat, 0]), 0);
+    assert_eq!(emulator.load(stat + 48, 64), Ok(19));
+    assert_eq!(syscall(&mut emulator, NR_FACCESSAT, &[AT_FDCWD, BUFFER, length, 0]), 0);
+
+    // linkat(olddirfd, oldpath, oldlength, newdirfd, newpath, newlength, flags)
+    let link = directory.join("link");
+    let link_length = path(&mut emulator, BUFFER + 0x300, link.to_str().unwrap());
+    let args = [AT_FDCWD, BUFFER, length, AT_FDCWD, BUFFER + 0x300, link_length, 0];
+    assert_eq!(syscall(&mut emulator, NR_LINKAT, &args), 0);
+    assert_eq!(fs::read(&link).unwrap(), b"hello from the host");
+
+    // unlinkat(dirfd, path, length, flags) and mkdirat(dirfd, path, length, mode)
+    assert_eq!(syscall(&mut emulator, NR_UNLINKAT, &[AT_FDCWD, BUFFER, length, 0]), 0);
+    assert!(!file.exists());
+    assert_eq!(syscall(&mut emulator, NR_MKDIRAT, &[AT_FDCWD, BUFFER, length, 0o755]), 0);
+    assert!(file.is_dir());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn exit() {
+    let (mut emulator, _, _) = emulator();
+    // The exit code is in the payload above bit 0, e.g. the failed test of the
+    // riscv-tests.
+    assert_eq!(send(&mut emulator, 3 << 1 | 1), StopReason::Exit(3));
+    assert_eq!(emulator.load(TOHOST, 64), Ok(0));
+    assert_eq!(send(&mut emulator, 1), StopReason::Exit(0));
+    // exit_group by the syscall proxy
+    emulator.store(MAGIC, 64, NR_EXIT_GROUP).unwrap();
+    emulator.store(MAGIC + 8, 64, 2).unwrap();
+    assert_eq!(send(&mut emulator, MAGIC), StopReason::Exit(2));
+}
+
+#[test]
+fn device_tree() {
+    let (mut emulator, _, _) = emulator();
+    // The proxy kernel finds the HTIF by the node of Spike.
+    let (addr, size) = emulator.device_tree().unwrap();
+    let dtb = emulator.read_memory(addr, size).unwrap();
+    assert!(dtb.windows(9).any(|window| window == b"ucb,htif0"));
+
+    // Without the tohost symbol, there is no HTIF.
+    let emulator = Emulator::builder().binary(elf(false)).htif(&[]).build();
+    assert!(emulator.is_err());
+}
@@ -0,0 +1,21 @@
+#!/bin/sh
+# Build the tests in rv64*/ into ELF executables in bin/, named by suite and
+# test (e.g. rv64ui-add.elf). Only llvm-mc and python3 are needed: elf.py does
+# the work of the linker. The tests are loaded at 0x80000000.
+set -e
+cd "$(dirname "$0")"
+rm -f bin/*
+for src in rv64*/*.S; do
+    suite=$(dirname "$src")
+    name=$(basename "$src" .S)
+    obj=$(mktemp)
+    case $suite in
+        rv64uc) ext=+m,+a,+f,+d,+c ;;
+        rv64uf|rv64ud) ext=+m,+a,+f,+d,-c ;;
+        *) ext=+m,+a,-c ;;
+    esac
+    llvm-mc -triple=riscv64 -mattr=$ext,-relax -filetype=obj -I env "$src" -o "$obj"
+    python3 elf.py "$obj" "bin/$suite-$name.elf"
+    chmod 644 "bin/$suite-$name.elf"
+    rm -f "$obj"
+done
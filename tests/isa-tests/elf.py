@@ -0,0 +1,104 @@
+#!/usr/bin/env python3
+# Turn a test assembled by llvm-mc into an ELF executable, as a linker would with
+# riscv-tests' env/p/link.ld: the .text.init section of the object is loaded at
+# 0x80000000, where the test starts at _start, and its labels are kept in the
+# symbol table, so that the runner finds `tohost` by its symbol.
+#
+#   elf.py test.o test.elf
+
+import struct
+import sys
+
+BASE = 0x80000000
+# The offset of the segment in the file, aligned to a page
+SEGMENT_OFFSET = 0x1000
+
+EM_RISCV = 243
+ET_EXEC = 2
+PT_LOAD = 1
+PF_RWX = 7
+SHT_PROGBITS = 1
+SHT_SYMTAB = 2
+SHT_STRTAB = 3
+SHT_RELA = 4
+SHF_WRITE_ALLOC_EXECINSTR = 7
+STT_SECTION = 3
+
+
+def sections(obj):
+    shoff, = struct.unpack_from('<Q', obj, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from('<HHH', obj, 0x3a)
+    headers = [struct.unpack_from('<IIQQQQIIQQ', obj, shoff + i * shentsize) for i in range(shnum)]
+    names = headers[shstrndx]
+    for header in headers:
+        name = obj[names[4] + header[0]:].split(b'\0')[0].decode()
+        yield name, header
+
+
+def main(source, target):
+    obj = open(source, 'rb').read()
+    headers = list(sections(obj))
+    index = next(i for i, (name, _) in enumerate(headers) if name == '.text.init')
+    text = headers[index][1]
+    code = obj[text[4]:text[4] + text[5]]
+    # There is no linker to resolve relocations, so a test uses the absolute
+    # addresses of test.S, or gets addresses with jal, or with la of a label in
+    # .text.init, which the assembler resolves without -relax.
+    if any(header[1] == SHT_RELA and header[7] == index and header[5] for _, header in headers):
+        sys.exit('{}: relocations are not supported'.format(source))
+    symtab = next(header for name, header in headers if header[1] == SHT_SYMTAB)
+    strtab = headers[symtab[6]][1]
+
+    # The labels of .text.init, without the temporary ones of the assembler
+    symbols = []
+    for offset in range(symtab[4], symtab[4] + symtab[5], 24):
+        name, info, other, shndx, value, size = struct.unpack_from('<IBBHQQ', obj, offset)
+        name = obj[strtab[4] + name:].split(b'\0')[0]
+        if shndx != index or info & 0xf == STT_SECTION or not name or name.startswith(b'.L'):
+            continue
+        symbols.append((name, info, other, BASE + value, size))
+    entry = next(value for name, _, _, value, _ in symbols if name == b'_start')
+
+    # .text.init, .symtab, .strtab and .shstrtab after the segment
+    strings = b'\0'
+    table = bytes(24)
+    for name, info, other, value, size in symbols:
+        table += struct.pack('<IBBHQQ', len(strings), info, other, 1, value, size)
+        strings += name + b'\0'
+    shstrings = b'\0.text.init\0.symtab\0.strtab\0.shstrtab\0'
+    data = bytearray(SEGMENT_OFFSET) + code
+    symtab_offset = len(data)
+    data += table
+    strtab_offset = len(data)
+    data += strings
+    shstrtab_offset = len(data)
+    data += shstrings
+    while len(data) % 8:
+        data.append(0)
+    shoff = len(data)
+
+    # The local symbols come first, and sh_info is the index of the first global one.
+    first_global = 1 + sum(1 for symbol in symbols if symbol[1] >> 4 == 0)
+    section_headers = [
+        bytes(64),
+        struct.pack('<IIQQQQIIQQ', 1, SHT_PROGBITS, SHF_WRITE_ALLOC_EXECINSTR, BASE,
+                    SEGMENT_OFFSET, len(code), 0, 0, 4, 0),
+        struct.pack('<IIQQQQIIQQ', 12, SHT_SYMTAB, 0, 0, symtab_offset, len(table), 3,
+                    first_global, 8, 24),
+        struct.pack('<IIQQQQIIQQ', 20, SHT_STRTAB, 0, 0, strtab_offset, len(strings), 0, 0, 1, 0),
+        struct.pack('<IIQQQQIIQQ', 28, SHT_STRTAB, 0, 0, shstrtab_offset, len(shstrings), 0, 0,
+                    1, 0),
+    ]
+    for header in section_headers:
+        data += header
+
+    ident = b'\x7fELF\x02\x01\x01' + bytes(9)
+    data[0:64] = ident + struct.pack('<HHIQQQIHHHHHH', ET_EXEC, EM_RISCV, 1, entry, 64, shoff,
+                                     0, 64, 56, 1, 64, len(section_headers), 4)
+    data[64:120] = struct.pack('<IIQQQQQQ', PT_LOAD, PF_RWX, SEGMENT_OFFSET, BASE, BASE,
+                               len(code), len(code), 0x1000)
+    open(target, 'wb').write(data)
+
+
+if __name__ == '__main__':
+    main(sys.argv[1], sys.argv[2])
@@ -0,0 +1,203 @@
+// Execute the instructions of the M, A and C extensions and the CSRs Linux needs.
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::program;
+
+// The data the programs access, in s0
+const DATA: u64 = MEMORY_BASE + 0x1000;
+
+fn with_program(binary: Vec<u8>) -> Emulator {
+    let mut emulator = Emulator::builder().memory_size(0x10000).binary(binary).build().unwrap();
+    emulator.set_reg(8, DATA);
+    emulator
+}
+
+#[test]
+fn multiply_and_divide() {
+    let mut emulator = with_program(program(&[
+        // li a0, -6
+        0xffa00513,
+        // li a1, 4
+        0x00400593,
+        // mul a2, a0, a1
+        0x02b50633,
+        // mulh a3, a0, a1
+        0x02b516b3,
+        // div a4, a0, a1
+        0x02b54733,
+        // rem a5, a0, a1
+        0x02b567b3,
+        // divu a6, a0, zero
+        0x02055833,
+        // remw a7, a0, zero
+        0x020568bb,
+    ]));
+    assert_eq!(emulator.run(8), StopReason::InstructionLimit);
+    assert_eq!(emulator.reg(12) as i64, -24);
+    assert_eq!(emulator.reg(13) as i64, -1);
+    assert_eq!(emulator.reg(14) as i64, -1);
+    assert_eq!(emulator.reg(15) as i64, -2);
+    // A division by zero does not trap: the quotient is all ones and the remainder
+    // is the dividend.
+    assert_eq!(emulator.reg(16), u64::MAX);
+    assert_eq!(emulator.reg(17) as i64, -6);
+}
+
+#[test]
+fn atomics() {
+    let mut emulator = with_program(program(&[
+        // li a0, -6
+        0xffa00513,
+        // li a1, 4
+        0x00400593,
+        // lr.d t0, (s0)
+        0x100432af,
+        // sc.d t1, a1, (s0)
+        0x18b4332f,
+        // sc.d t2, a1, (s0)
+        0x18b433af,
+        // amoadd.w t3, a1, (s0)
+        0x00b42e2f,
+        // amoswap.d t4, a0, (s0)
+        0x08a43eaf,
+        // amomaxu.d t5, a1, (s0)
+        0xe0b43f2f,
+        // amoadd.w t3, a1, (s1)
+        0x00b4ae2f,
+    ]));
+    emulator.store(DATA, 64, 10).unwrap();
+    emulator.set_reg(9, DATA + 2);
+    assert_eq!(emulator.run(8), StopReason::InstructionLimit);
+    assert_eq!(emulator.reg(5), 10);
+    // The first sc succeeds and clears the reservation, so the second one fails.
+    assert_eq!(emulator.reg(6), 0);
+    assert_eq!(emulator.reg(7), 1);
+    assert_eq!(emulator.reg(28), 4);
+    assert_eq!(emulator.reg(29), 8);
+    assert_eq!(emulator.reg(30) as i64, -6);
+    assert_eq!(emulator.load(DATA, 64).map(|value| value as i64), Ok(-6));
+    // An AMO must be aligned.
+    let pc = emulator.pc();
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::StoreAMOAddressMisaligned, pc)));
+    assert_eq!(emulator.csr(MTVAL), DATA + 2);
+}
+
+#[test]
+fn compressed() {
+    let binary = [
+        // c.li a0, 5
+        0x4515u16,
+        // c.addi a0, 3
+        0x050d,
+        // addi a1, a0, 1
+        0x0593,
+        0x0015,
+        // c.mv a2, a1
+        0x862e,
+        // c.slli a2, 2
+        0x060a,
+        // c.sd a2, 8(s0)
+        0xe410,
+        // c.ld a3, 8(s0)
+        0x6414,
+        // c.j 6
+        0xa019,
+        // c.li a0, 0
+        0x4501,
+        // c.nop
+        0x0001,
+        // c.ebreak
+        0x9002,
+    ];
+    let mut emulator = with_program(binary.iter().flat_map(|half| half.to_le_bytes()).collect());
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 2);
+    assert_eq!(emulator.run(100), StopReason::Exception(Exception::Breakpoint, MEMORY_BASE + 22));
+    assert_eq!(emulator.reg(10), 8);
+    assert_eq!(emulator.reg(11), 9);
+    assert_eq!(emulator.reg(12), 36);
+    assert_eq!(emulator.reg(13), 36);
+    assert_eq!(emulator.csr(MEPC), MEMORY_BASE + 22);
+    assert_eq!(emulator.instruction_count(), 9);
+}
+
+#[test]
+fn illegal_instruction() {
+    // The instruction is the tval of an illegal instruction exception.
+    let mut emulator = with_program(program(&[0xffff_ffff]));
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::IllegalInstruction, MEMORY_BASE)));
+    assert_eq!(emulator.csr(MTVAL), 0xffff_ffff);
+
+    // 0x0000 is an illegal compressed instruction.
+    let mut emulator = with_program(vec![0, 0]);
+    emulator.set_csr(MTVAL, 1);
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::IllegalInstruction, MEMORY_BASE)));
+    assert_eq!(emulator.csr(MTVAL), 0);
+
+    // mstatus cannot be accessed from S-mode.
+    // csrr a0, mstatus
+    let mut emulator = with_program(program(&[0x30002573]));
+    emulator.cpu.mode = Mode::Surpervisor;
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::IllegalInstruction, MEMORY_BASE)));
+}
+
+#[test]
+fn status() {
+    let mut emulator = with_program(Vec::new());
+    // sstatus is a view of mstatus, which does not have the machine fields.
+    emulator.set_csr(SSTATUS, MSTATUS_SIE | MSTATUS_MIE);
+    assert_eq!(emulator.csr(MSTATUS) & (MSTATUS_SIE | MSTATUS_MIE), MSTATUS_SIE);
+    assert_eq!(emulator.csr(SSTATUS) & MSTATUS_UXL, 2 << 32);
+    // MPP cannot be 2, which is not a mode.
+    emulator.set_csr(MSTATUS, 2 << 11);
+    assert_eq!(emulator.csr(MSTATUS) & MSTATUS_MPP, 0);
+    // sie is mie masked by mideleg.
+    emulator.set_csr(MIDELEG, MIP_STIP);
+    emulator.set_csr(SIE, MIP_STIP | MIP_SSIP);
+    assert_eq!(emulator.csr(MIE), MIP_STIP);
+}
+
+#[test]
+fn stimecmp() {
+    let mut emulator = with_program(program(&[0x00000013; 40]));
+    // With Sstc enabled, the supervisor timer interrupt is pending from when time
+    // reaches stimecmp.
+    emulator.set_csr(MENVCFG, MENVCFG_STCE);
+    emulator.set_csr(STIMECMP, 2);
+    assert_eq!(emulator.run(10), StopReason::InstructionLimit);
+    assert_eq!(emulator.csr(MIP) & MIP_STIP, 0);
+    assert_eq!(emulator.run(20), StopReason::InstructionLimit);
+    assert_ne!(emulator.csr(MIP) & MIP_STIP, 0);
+    emulator.set_csr(STIMECMP, u64::MAX);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.csr(MIP) & MIP_STIP, 0);
+
+    // S-mode cannot access stimecmp unless menvcfg and mcounteren enable it.
+    // csrr a0, stimecmp
+    let mut emulator = with_program(program(&[0x14d02573, 0x14d02573]));
+    emulator.cpu.mode = Mode::Surpervisor;
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::IllegalInstruction, MEMORY_BASE)));
+    emulator.set_csr(MENVCFG, MENVCFG_STCE);
+    emulator.set_csr(MCOUNTEREN, 0b10);
+    emulator.set_csr(STIMECMP, 42);
+    emulator.set_pc(MEMORY_BASE + 4);
+    emulator.cpu.mode = Mode::Surpervisor;
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.reg(10), 42);
+}
+
+#[test]
+fn zero_register() {
+    let mut emulator = with_program(program(&[
+        // addi zero, zero, 5
+        0x00500013,
+    ]));
+    assert_eq!(emulator.run(1), StopReason::InstructionLimit);
+    // The write to x0 is dropped.
+    assert_eq!(emulator.cpu.regs[0], 0);
+}
@@ -0,0 +1,74 @@
+// Run the ISA tests in tests/isa-tests/bin with the riscv-tests runner, so that
+// every change to the CPU is checked against them.
+//
+// The tests are written for this emulator in the format of riscv-tests, and are
+// not the tests of that suite. They are ELF executables built from
+// tests/isa-tests/rv64*/ by tests/isa-tests/build.sh, and the runner finds their
+// `tohost` by its symbol.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn run_suite(suite: &str) {
+    let isa = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/isa-tests/bin");
+    let tests: Vec<_> = std::fs::read_dir(&isa)
+        .expect("failed to read tests/isa-tests/bin")
+        .map(|entry| entry.expect("failed to read a test").path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&format!("{}-", suite)))
+        })
+        .collect();
+    assert!(!tests.is_empty(), "no tests for {}", suite);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_riscvemu"))
+        .arg("--riscv-tests")
+        .args(&tests)
+        // The UART reads stdin in a thread, which must not wait for a terminal.
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run riscvemu");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{} failed:\n{}", suite, stdout);
+}
+
+#[test]
+fn rv64ui() {
+    run_suite("rv64ui");
+}
+
+#[test]
+fn rv64um() {
+    run_suite("rv64um");
+}
+
+#[test]
+fn rv64ua() {
+    run_suite("rv64ua");
+}
+
+#[test]
+fn rv64uc() {
+    run_suite("rv64uc");
+}
+
+#[test]
+fn rv64uf() {
+    run_suite("rv64uf");
+}
+
+#[test]
+fn rv64ud() {
+    run_suite("rv64ud");
+}
+
+#[test]
+fn rv64mi() {
+    run_suite("rv64mi");
+}
+
+#[test]
+fn rv64si() {
+    run_suite("rv64si");
+}
@@ -0,0 +1,59 @@
+// Boot a Linux kernel to the console string of its init process. The kernel and
+// the initramfs are not vendored, so the test is ignored unless they are given:
+//
+//   RISCVEMU_LINUX_IMAGE=path/to/Image RISCVEMU_INITRAMFS=path/to/rootfs.cpio \
+//       cargo test --release --test linux -- --ignored
+
+use std::env;
+use std::fs;
+
+use riscvemu::emulator::*;
+
+mod common;
+use common::Output;
+
+// The line the kernel prints when it starts the init process of the initramfs
+const INIT_MESSAGE: &str = "Run /init as init process";
+// The number of instructions the boot may take
+const BOOT_LIMIT: u64 = 2_000_000_000;
+// The number of instructions between the checks of the console
+const CHECK_INTERVAL: u64 = 1_000_000;
+
+#[test]
+#[ignore]
+fn boot_to_init() {
+    let read = |var: &str| {
+        let path = env::var(var).unwrap_or_else(|_| panic!("{} is not set", var));
+        fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+    };
+    let mut emulator = Emulator::builder()
+        .memory_size(256 * 1024 * 1024)
+        .binary(read("RISCVEMU_LINUX_IMAGE"))
+        .initrd(read("RISCVEMU_INITRAMFS"), None)
+        .bootargs("console=ttyS0 earlycon=sbi")
+        .sbi(true)
+        .stop_on_exception(false)
+        .build()
+        .unwrap();
+    let console = Output::default();
+    emulator.cpu.bus.uart.set_output(Box::new(console.clone()));
+
+    let mut count = 0;
+    while count < BOOT_LIMIT {
+        let reason = emulator.run(CHECK_INTERVAL);
+        count += CHECK_INTERVAL;
+        let output = String::from_utf8_lossy(&console.0.lock().unwrap()).into_owned();
+        if output.contains(INIT_MESSAGE) {
+            return;
+        }
+        if reason != StopReason::InstructionLimit {
+            panic!("the kernel stopped: {:?}\n{}", reason, output);
+        }
+    }
+    panic!(
+        "no `{}` after {} instructions\n{}",
+        INIT_MESSAGE,
+        BOOT_LIMIT,
+        String::from_utf8_lossy(&console.0.lock().unwrap())
+    );
+}
@@ -0,0 +1,151 @@
+// Compare the emulator against a reference commit log.
+
+use std::io::Cursor;
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::lockstep::*;
+use riscvemu::trace::*;
+
+mod common;
+use common::{Output, program};
+
+// A reference log of Spike: the boot ROM, which is skipped, then an add, a CSR
+// write, a store and an exception
+const REFERENCE: &str = "\
+core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000
+core   0: 3 0x0000000000001004 (0x02028593) x11 0x0000000000001020
+core   0: 3 0x0000000080000000 (0x00a00513) x10 0x000000000000000a
+core   0: 3 0x0000000080000004 (0x00150513) x10 0x000000000000000b
+core   0: 3 0x0000000080000008 (0x34051073) c832_mscratch 0x000000000000000b
+core   0: 3 0x000000008000000c (0x00a5b023) mem 0x0000000080001000 0x000000000000000b
+core   0: exception trap_illegal_instruction, epc 0x0000000080000010
+core   0:           tval 0x0000000000000000
+";
+
+fn record(pc: u64, inst: u64, effects: Vec<Effect>) -> Record {
+    Record {
+        hart: 0,
+        mode: 3,
+        pc,
+        inst,
+        effects,
+    }
+}
+
+// Check the record which matches the reference at 0x80000000. The emulator starts
+// there with the registers which the boot ROM set.
+fn lockstep(cpu: &mut Cpu) -> Lockstep {
+    cpu.regs[5] = 0x1000;
+    cpu.regs[11] = 0x1020;
+    let mut lockstep = Lockstep::new(Box::new(Cursor::new(REFERENCE)), cpu);
+    cpu.regs[10] = 10;
+    let li = record(0x80000000, 0x00a00513, vec![Effect::Reg(10, 10)]);
+    assert_eq!(lockstep.check(cpu, &li), Ok(()));
+    lockstep
+}
+
+#[test]
+fn register() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut lockstep = lockstep(&mut cpu);
+    cpu.regs[10] = 12;
+    let addi = record(0x80000004, 0x00150513, vec![Effect::Reg(10, 12)]);
+    let mismatch = lockstep.check(&cpu, &addi).unwrap_err();
+    assert_eq!(mismatch.count, 1);
+    assert_eq!(mismatch.line, 4);
+    assert_eq!(mismatch.expected.unwrap().pc, 0x80000004);
+    assert_eq!(
+        mismatch.differences,
+        vec!["x10(a0): expected 0x000000000000000b, actual 0x000000000000000c"]
+    );
+}
+
+#[test]
+fn csr() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut lockstep = lockstep(&mut cpu);
+    cpu.regs[10] = 11;
+    let addi = record(0x80000004, 0x00150513, vec![Effect::Reg(10, 11)]);
+    assert_eq!(lockstep.check(&cpu, &addi), Ok(()));
+    cpu.write_csr(MSCRATCH, 8);
+    let csrw = record(0x80000008, 0x34051073, vec![Effect::Csr(MSCRATCH, 8)]);
+    let mismatch = lockstep.check(&cpu, &csrw).unwrap_err();
+    assert_eq!(mismatch.count, 2);
+    assert_eq!(
+        mismatch.differences,
+        vec!["mscratch: expected 0x000000000000000b, actual 0x0000000000000008"]
+    );
+}
+
+#[test]
+fn store() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut lockstep = lockstep(&mut cpu);
+    cpu.regs[10] = 11;
+    let addi = record(0x80000004, 0x00150513, vec![Effect::Reg(10, 11)]);
+    assert_eq!(lockstep.check(&cpu, &addi), Ok(()));
+    cpu.write_csr(MSCRATCH, 11);
+    let csrw = record(0x80000008, 0x34051073, vec![Effect::Csr(MSCRATCH, 11)]);
+    assert_eq!(lockstep.check(&cpu, &csrw), Ok(()));
+    // A store of a word instead of a doubleword
+    let sw = record(0x8000000c, 0x00a5a023, vec![Effect::Store(0x80001000, 11, 4)]);
+    let mismatch = lockstep.check(&cpu, &sw).unwrap_err();
+    assert_eq!(mismatch.count, 3);
+    assert_eq!(mismatch.differences.len(), 2);
+    assert!(mismatch.differences[0].starts_with("instruction: expected 0x00a5b023 (sd"));
+    assert!(mismatch.differences[1].starts_with("stores: expected [Store(80001000, b, 8)]"));
+
+    // The exception is not a commit line, so the reference log ends there.
+    let next = record(0x80000010, 0x00000000, Vec::new());
+    let mismatch = lockstep.check(&cpu, &next).unwrap_err();
+    assert_eq!(mismatch.expected, None);
+    assert_eq!(mismatch.line, 8);
+    assert_eq!(mismatch.differences, vec!["the reference log ended"]);
+}
+
+// Run the program with a commit log to a buffer, which is the reference.
+fn reference(program: &[u8], count: u64) -> String {
+    let log = Output::default();
+    let mut emulator = Emulator::builder().binary(program.to_vec()).build().unwrap();
+    emulator.set_tracer(Tracer::new(Box::new(log.clone()), false, true, false));
+    assert_eq!(emulator.run(count), StopReason::InstructionLimit);
+    let log = log.0.lock().unwrap().clone();
+    String::from_utf8(log).unwrap()
+}
+
+#[test]
+fn emulator() {
+    // addi a0, zero, 42
+    // addi a1, a0, 1
+    // auipc t0, 0
+    // sd a1, 64(t0)
+    // loop: j loop
+    let insts: [u32; 5] = [0x02a00513, 0x00150593, 0x00000297, 0x04b2b023, 0x0000006f];
+    let binary = program(&insts);
+    let log = reference(&binary, 5);
+    let lockstep = |log: String| {
+        let mut emulator = Emulator::builder().binary(binary.clone()).build().unwrap();
+        let lockstep = Lockstep::new(Box::new(Cursor::new(log)), &emulator.cpu);
+        emulator.set_lockstep(lockstep);
+        emulator.run(5)
+    };
+    assert_eq!(lockstep(log.clone()), StopReason::InstructionLimit);
+
+    // The reference stores 44 instead of 43 in a1.
+    let diverged = log.replace("x11 0x000000000000002b", "x11 0x000000000000002c");
+    assert_ne!(diverged, log);
+    let mismatch = match lockstep(diverged) {
+        StopReason::Lockstep(mismatch) => mismatch,
+        reason => panic!("unexpected stop: {:?}", reason),
+    };
+    assert_eq!(mismatch.count, 1);
+    assert_eq!(mismatch.line, 2);
+    assert_eq!(mismatch.actual.pc, MEMORY_BASE + 4);
+    assert_eq!(mismatch.actual.effects, vec![Effect::Reg(11, 43)]);
+    assert_eq!(
+        mismatch.differences,
+        vec!["x11(a1): expected 0x000000000000002c, actual 0x000000000000002b"]
+    );
+}
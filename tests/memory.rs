@@ -0,0 +1,96 @@
+// Access the sparse memory and the memory regions.
+
+use riscvemu::bus::*;
+use riscvemu::emulator::*;
+use riscvemu::memory::*;
+use riscvemu::trap::Exception;
+
+#[test]
+fn lazy_allocation() {
+    // 16GB cost nothing until the pages are written.
+    let mut memory = Memory::new(vec![1, 2, 3], MEMORY_BASE, 16 << 30);
+    assert_eq!(memory.allocated_pages().collect::<Vec<_>>(), vec![0]);
+    assert_eq!(memory.load64(MEMORY_BASE + (8 << 30)), 0);
+    assert_eq!(memory.allocated_pages().count(), 1);
+    memory.store32(MEMORY_BASE + (8 << 30), 0xdeadbeef);
+    assert_eq!(memory.load32(MEMORY_BASE + (8 << 30)), 0xdeadbeef);
+    assert_eq!(memory.allocated_pages().count(), 2);
+    assert_eq!(memory.load32(MEMORY_BASE), 0x030201);
+}
+
+#[test]
+fn across_pages() {
+    let mut memory = Memory::new(Vec::new(), MEMORY_BASE, 4 * PAGE_SIZE as u64);
+    let addr = MEMORY_BASE + PAGE_SIZE as u64 - 3;
+    memory.store64(addr, 0x0807060504030201);
+    assert_eq!(memory.load64(addr), 0x0807060504030201);
+    assert_eq!(memory.load16(addr + 2), 0x0403);
+    assert_eq!(memory.take_dirty_pages(), vec![0, 1]);
+
+    let mut bytes = [0; 8];
+    memory.read_bytes(addr, &mut bytes);
+    assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test]
+fn pages_of_zero() {
+    let mut memory = Memory::new(Vec::new(), MEMORY_BASE, 4 * PAGE_SIZE as u64);
+    memory.write_bytes(MEMORY_BASE, &[0; 2 * PAGE_SIZE]);
+    assert_eq!(memory.allocated_pages().count(), 0);
+    memory.store8(MEMORY_BASE + 5, 1);
+    memory.set_page(0, &[0; PAGE_SIZE]);
+    assert_eq!(memory.allocated_pages().count(), 0);
+}
+
+#[test]
+fn large_guest() {
+    let mut emulator = Emulator::builder().memory_size(8 << 30).build().unwrap();
+    assert!(emulator.write_memory(MEMORY_BASE + (8 << 30) - 8, &[1; 8]).is_ok());
+    assert_eq!(emulator.cpu.bus.memory.allocated_pages().count(), 1);
+}
+
+#[test]
+fn regions() {
+    // addi a0, zero, 42
+    let rom = 0x02a00513u32.to_le_bytes().to_vec();
+    let mut emulator = Emulator::builder()
+        .memory_size(0x10000)
+        .region(RegionKind::Rom, ROM_BASE, ROM_SIZE, rom)
+        .region(RegionKind::WriteIgnored, 0x2000_0000, 0x1000, vec![7])
+        .region(RegionKind::Ram, 0x1_0000_0000, 0x1_0000_0000, Vec::new())
+        .build()
+        .unwrap();
+    // The code in the ROM runs and the ROM cannot be written.
+    emulator.set_pc(ROM_BASE);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.reg(10), 42);
+    assert_eq!(emulator.store(ROM_BASE, 32, 0), Err(Exception::StoreAMOAccessFault));
+    assert_eq!(emulator.load(ROM_BASE, 32), Ok(0x02a00513));
+
+    assert_eq!(emulator.store(0x2000_0000, 8, 1), Ok(()));
+    assert_eq!(emulator.load(0x2000_0000, 8), Ok(7));
+
+    assert_eq!(emulator.store(0x1_8000_0000, 64, 3), Ok(()));
+    assert_eq!(emulator.load(0x1_8000_0000, 64), Ok(3));
+}
+
+#[test]
+fn access_faults() {
+    let mut emulator = Emulator::builder().memory_size(0x10000).build().unwrap();
+    // An access which is partly out of the memory faults instead of panicking.
+    assert_eq!(emulator.load(MEMORY_BASE + 0xfffc, 64), Err(Exception::LoadAccessFault));
+    assert_eq!(emulator.store(MEMORY_BASE + 0xffff, 16, 0), Err(Exception::StoreAMOAccessFault));
+    assert_eq!(emulator.load(MEMORY_BASE + 0xfff8, 64), Ok(0));
+}
+
+#[test]
+fn overlapping_regions() {
+    let build = |base| {
+        Emulator::builder()
+            .memory_size(0x10000)
+            .region(RegionKind::Rom, base, 0x1000, Vec::new())
+            .build()
+    };
+    assert!(build(MEMORY_BASE + 0xf000).is_err());
+    assert!(build(MEMORY_BASE - 0x1000).is_ok());
+}
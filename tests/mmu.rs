@@ -0,0 +1,116 @@
+// Translate the addresses of S-mode with Sv39 page tables.
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::mmu::*;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::program;
+
+// The page tables: the root, and the tables of levels 1 and 0 for the low addresses
+const ROOT: u64 = MEMORY_BASE + 0x2000;
+const LEVEL1: u64 = MEMORY_BASE + 0x3000;
+const LEVEL0: u64 = MEMORY_BASE + 0x4000;
+// The page mapped at the virtual address PAGE
+const DATA: u64 = MEMORY_BASE + 0x5000;
+const PAGE: u64 = 0x1000;
+
+fn pte(addr: u64, flags: u64) -> u64 {
+    (addr >> PAGE_SHIFT) << 10 | flags
+}
+
+// An emulator in S-mode with Sv39, where the memory is mapped to itself by a 1GB
+// page and PAGE is a read-only page of DATA.
+fn sv39(insts: &[u32]) -> Emulator {
+    let mut emulator = Emulator::builder().memory_size(0x10000).binary(program(insts)).build().unwrap();
+    let rwx = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+    emulator.store(ROOT + 8 * (MEMORY_BASE >> 30), 64, pte(MEMORY_BASE, rwx)).unwrap();
+    emulator.store(ROOT, 64, pte(LEVEL1, PTE_V)).unwrap();
+    emulator.store(LEVEL1, 64, pte(LEVEL0, PTE_V)).unwrap();
+    emulator.store(LEVEL0 + 8, 64, pte(DATA, PTE_V | PTE_R)).unwrap();
+    emulator.store(DATA, 64, 42).unwrap();
+    emulator.set_csr(SATP, SATP_MODE_SV39 << SATP_MODE_SHIFT | ROOT >> PAGE_SHIFT);
+    emulator.cpu.mode = Mode::Surpervisor;
+    emulator
+}
+
+#[test]
+fn translate() {
+    let mut emulator = sv39(&[
+        // ld a0, 0(t0)
+        0x0002b503,
+        // sd a0, 0(t0)
+        0x00a2b023,
+    ]);
+    emulator.set_reg(5, PAGE);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.reg(10), 42);
+    // The access set the A bit of the PTE.
+    assert_eq!(emulator.load(LEVEL0 + 8, 64), Ok(pte(DATA, PTE_V | PTE_R | PTE_A)));
+
+    // The page is read-only.
+    assert_eq!(
+        emulator.step(),
+        Some(StopReason::Exception(Exception::StoreAMOPageFault, MEMORY_BASE + 4))
+    );
+    assert_eq!(emulator.csr(MTVAL), PAGE);
+    assert_eq!(emulator.csr(MEPC), MEMORY_BASE + 4);
+}
+
+#[test]
+fn page_fault() {
+    let mut emulator = sv39(&[
+        // ld a1, 0(t1)
+        0x00033583,
+    ]);
+    // The page is not mapped.
+    emulator.set_reg(6, PAGE * 2);
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::LoadPageFault, MEMORY_BASE)));
+    assert_eq!(emulator.csr(MTVAL), PAGE * 2);
+
+    // The address is not sign-extended from bit 38.
+    let mut emulator = sv39(&[0x00033583]);
+    emulator.set_reg(6, 1 << 40 | PAGE);
+    assert_eq!(emulator.step(), Some(StopReason::Exception(Exception::LoadPageFault, MEMORY_BASE)));
+}
+
+#[test]
+fn machine_mode() {
+    // M-mode does not translate, and the PTE is not accessed.
+    let mut emulator = sv39(&[0x0002b503]);
+    emulator.cpu.mode = Mode::Machine;
+    emulator.set_reg(5, DATA);
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.reg(10), 42);
+    assert_eq!(emulator.load(LEVEL0 + 8, 64), Ok(pte(DATA, PTE_V | PTE_R)));
+}
+
+#[test]
+fn satp() {
+    let mut emulator = sv39(&[]);
+    // Sv48 is not supported, so the write is ignored.
+    emulator.set_csr(SATP, 9 << SATP_MODE_SHIFT);
+    assert_eq!(emulator.csr(SATP) >> SATP_MODE_SHIFT, SATP_MODE_SV39);
+    emulator.set_csr(SATP, 0);
+    assert_eq!(emulator.csr(SATP), 0);
+}
+
+#[test]
+fn sfence_vma() {
+    let mut emulator = sv39(&[
+        // ld a0, 0(t0)
+        0x0002b503,
+        // sfence.vma
+        0x12000073,
+        // ld a0, 0(t0)
+        0x0002b503,
+    ]);
+    emulator.set_reg(5, PAGE);
+    assert_eq!(emulator.step(), None);
+    // The new mapping is used after sfence.vma.
+    emulator.store(LEVEL0 + 8, 64, pte(ROOT, PTE_V | PTE_R | PTE_A)).unwrap();
+    assert_eq!(emulator.run(2), StopReason::InstructionLimit);
+    assert_eq!(emulator.reg(10), pte(LEVEL1, PTE_V));
+}
@@ -0,0 +1,63 @@
+// Parse the commands of the monitor and dump memory.
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::cpu::*;
+use riscvemu::monitor::*;
+
+fn parse(line: &str) -> Command {
+    Command::parse(line).unwrap().unwrap()
+}
+
+#[test]
+fn step() {
+    assert_eq!(parse("step"), Command::Step(1));
+    assert_eq!(parse("s 10"), Command::Step(10));
+    assert_eq!(parse("  step   0x20 "), Command::Step(0x20));
+    assert_eq!(Command::parse("step 0"), Err("invalid number of instructions: 0".to_string()));
+    assert_eq!(Command::parse("s ten"), Err("invalid number of instructions: ten".to_string()));
+    assert!(Command::parse("step 1 2").unwrap_err().starts_with("unknown command: step 1 2"));
+    assert_eq!(parse("c"), Command::Continue);
+    assert_eq!(parse("quit"), Command::Quit);
+    assert_eq!(Command::parse("   "), Ok(None));
+}
+
+#[test]
+fn breakpoints() {
+    assert_eq!(parse("break"), Command::Breakpoints);
+    assert_eq!(parse("b main"), Command::Break("main".to_string()));
+    assert_eq!(parse("break 0x80000000"), Command::Break("0x80000000".to_string()));
+    assert_eq!(parse("d main"), Command::Delete("main".to_string()));
+    assert!(Command::parse("delete").is_err());
+}
+
+#[test]
+fn memory() {
+    assert_eq!(parse("x 0x80000000"), Command::Examine("0x80000000".to_string(), 64));
+    assert_eq!(parse("x buffer 0x10"), Command::Examine("buffer".to_string(), 16));
+    assert_eq!(Command::parse("x buffer many"), Err("invalid address or length".to_string()));
+    assert_eq!(parse("w 0x80001000 0xff"), Command::Write("0x80001000".to_string(), 0xff, 8));
+    assert_eq!(parse("write 0x80001000 7 2"), Command::Write("0x80001000".to_string(), 7, 2));
+    assert!(Command::parse("write 0x80001000 7 3").is_err());
+    assert!(Command::parse("write 0x80001000 seven").is_err());
+    assert_eq!(parse("set a0 0x10"), Command::Set("a0".to_string(), 0x10));
+    assert_eq!(Command::parse("set a0 x"), Err("invalid value: x".to_string()));
+}
+
+#[test]
+fn dump() {
+    let mut cpu = Cpu::new((0..20).collect(), Vec::new());
+    assert_eq!(
+        dump_memory(&mut cpu, MEMORY_BASE, 20),
+        vec![
+            "0000000080000000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f",
+            "0000000080000010: 10 11 12 13",
+        ]
+    );
+    assert_eq!(dump_memory(&mut cpu, MEMORY_BASE + 2, 0), Vec::<String>::new());
+
+    // The dump stops at the first byte out of memory.
+    assert_eq!(
+        dump_memory(&mut cpu, MEMORY_BASE - 2, 4),
+        vec!["000000007ffffffe:", "cannot access memory at 0x7ffffffe"]
+    );
+}
@@ -0,0 +1,113 @@
+// Route the interrupts of the devices through the PLIC.
+
+use riscvemu::bus::*;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::plic::*;
+use riscvemu::uart::*;
+
+mod common;
+use common::program;
+
+// nop, j .-4
+const LOOP: [u32; 2] = [0x00000013, 0xffdff06f];
+
+fn emulator() -> Emulator {
+    let binary = program(&LOOP);
+    Emulator::builder().memory_size(0x10000).binary(binary).build().unwrap()
+}
+
+// The address of the claim/complete register of the M-mode context
+const PLIC_MCLAIM: u64 = PLIC_THRESHOLD + 4;
+
+// Enable the UART interrupt for the S-mode context.
+fn enable_uart(emulator: &mut Emulator) {
+    emulator.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+    emulator.store(PLIC_SENABLE, 32, 1 << UART_IRQ).unwrap();
+    emulator.store(UART_IER, 8, UART_IER_RDI as u64).unwrap();
+}
+
+#[test]
+fn registers() {
+    let mut emulator = emulator();
+    // The priorities have 3 bits, and source 0 does not exist.
+    emulator.store(PLIC_PRIORITY + 4, 32, 0xff).unwrap();
+    assert_eq!(emulator.load(PLIC_PRIORITY + 4, 32), Ok(7));
+    emulator.store(PLIC_PRIORITY, 32, 1).unwrap();
+    assert_eq!(emulator.load(PLIC_PRIORITY, 32), Ok(0));
+    // Source 0 cannot be enabled.
+    emulator.store(PLIC_SENABLE, 32, 0xffff_ffff).unwrap();
+    assert_eq!(emulator.load(PLIC_SENABLE, 32), Ok(0xffff_fffe));
+    emulator.store(PLIC_SPIORITY, 32, 3).unwrap();
+    assert_eq!(emulator.load(PLIC_SPIORITY, 32), Ok(3));
+    // Nothing to claim
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(0));
+    assert_eq!(emulator.load(PLIC_PENDING, 32), Ok(0));
+    // Only 32-bit accesses
+    assert!(emulator.load(PLIC_PENDING, 64).is_err());
+}
+
+#[test]
+fn claim_and_complete() {
+    let mut emulator = emulator();
+    enable_uart(&mut emulator);
+    emulator.cpu.bus.uart.receive(b'a');
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.load(PLIC_PENDING, 32), Ok(1 << UART_IRQ));
+    assert_ne!(emulator.csr(MIP) & MIP_SEIP, 0);
+    // The M-mode context does not enable the source.
+    assert_eq!(emulator.csr(MIP) & MIP_MEIP, 0);
+    assert_eq!(emulator.load(PLIC_MCLAIM, 32), Ok(0));
+
+    // The source is not pending while it is claimed, even though the UART still
+    // raises its interrupt.
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(UART_IRQ));
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.load(PLIC_PENDING, 32), Ok(0));
+    assert_eq!(emulator.csr(MIP) & MIP_SEIP, 0);
+
+    // After the completion, the source is pending again until the byte is read.
+    emulator.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_ne!(emulator.csr(MIP) & MIP_SEIP, 0);
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(UART_IRQ));
+    assert_eq!(emulator.load(UART_RHR, 8), Ok(b'a' as u64));
+    emulator.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.csr(MIP) & MIP_SEIP, 0);
+}
+
+#[test]
+fn threshold() {
+    let mut emulator = emulator();
+    enable_uart(&mut emulator);
+    // An interrupt needs a priority above the threshold.
+    emulator.store(PLIC_SPIORITY, 32, 1).unwrap();
+    emulator.cpu.bus.uart.receive(b'a');
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.csr(MIP) & MIP_SEIP, 0);
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(0));
+    emulator.store(PLIC_SPIORITY, 32, 0).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_ne!(emulator.csr(MIP) & MIP_SEIP, 0);
+}
+
+#[test]
+fn trap() {
+    let mut emulator = emulator();
+    enable_uart(&mut emulator);
+    // The kernel in S-mode takes the delegated external interrupt.
+    emulator.set_csr(MIDELEG, MIP_SEIP);
+    emulator.set_csr(STVEC, MEMORY_BASE + 0x100);
+    emulator.set_csr(SIE, MIP_SEIP);
+    emulator.set_csr(SSTATUS, MSTATUS_SIE);
+    emulator.cpu.mode = Mode::Surpervisor;
+    assert_eq!(emulator.run(10), StopReason::InstructionLimit);
+    assert_eq!(emulator.csr(SCAUSE), 0);
+
+    emulator.cpu.bus.uart.receive(b'a');
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 0x100);
+    assert_eq!(emulator.csr(SCAUSE), 1 << 63 | 9);
+    assert_eq!(emulator.mode(), Mode::Surpervisor);
+}
@@ -0,0 +1,148 @@
+// Record the console input and the host time of a run and replay them.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::clint::*;
+use riscvemu::emulator::*;
+use riscvemu::replay::*;
+
+mod common;
+use common::program;
+
+// The program stores the bytes received by the UART from BUFFER.
+const BUFFER: u64 = MEMORY_BASE + 0x104;
+
+fn emulator(time_mode: TimeMode) -> Emulator {
+    let insts: [u32; 10] = [
+        // lui t0, 0x10000
+        0x100002b7,
+        // auipc t1, 0
+        0x00000317,
+        // addi t1, t1, 0x100
+        0x10030313,
+        // loop: lbu t2, 5(t0)
+        0x0052c383,
+        // andi t2, t2, 1
+        0x0013f393,
+        // beqz t2, loop
+        0xfe038ce3,
+        // lbu t2, 0(t0)
+        0x0002c383,
+        // sb t2, 0(t1)
+        0x00730023,
+        // addi t1, t1, 1
+        0x00130313,
+        // j loop
+        0xfe9ff06f,
+    ];
+    Emulator::builder().binary(program(&insts)).time_mode(time_mode).build().unwrap()
+}
+
+fn path(test: &str) -> String {
+    let path = env::temp_dir().join(format!("riscvemu-replay-{}-{}.rec", process::id(), test));
+    path.to_str().unwrap().to_string()
+}
+
+// Record a run in which "abc" is typed, a byte every 500 instructions. Return the
+// registers, mtime and the received bytes at the end.
+fn record(path: &str) -> (Vec<u64>, u64, Vec<u8>) {
+    let mut emulator = emulator(TimeMode::RealTime);
+    let recording = Recording::record(&emulator.cpu, path).unwrap();
+    emulator.set_recording(recording);
+    for byte in b"abc".iter() {
+        assert_eq!(emulator.run(500), StopReason::InstructionLimit);
+        emulator.cpu.bus.uart.type_input(*byte);
+    }
+    assert_eq!(emulator.run(2500), StopReason::InstructionLimit);
+    emulator.finish().unwrap();
+    let mtime = emulator.cpu.bus.clint.mtime();
+    (emulator.cpu.regs.to_vec(), mtime, emulator.read_memory(BUFFER, 4).unwrap())
+}
+
+fn replay(path: &str, time_mode: TimeMode, count: u64) -> (StopReason, Emulator) {
+    let mut emulator = emulator(time_mode);
+    let recording = Recording::replay(&emulator.cpu, path).unwrap();
+    emulator.set_recording(recording);
+    // The typed input is ignored.
+    emulator.cpu.bus.uart.type_input(b'z');
+    let reason = emulator.run(count);
+    (reason, emulator)
+}
+
+#[test]
+fn round_trip() {
+    let path = path("round-trip");
+    let (regs, mtime, buffer) = record(&path);
+    assert_eq!(buffer, b"abc\0");
+
+    // Every replay runs like the recorded run, up to the same instruction.
+    for _ in 0..2 {
+        let (reason, mut emulator) = replay(&path, TimeMode::RealTime, 4000);
+        assert_eq!(reason, StopReason::InstructionLimit);
+        assert_eq!(emulator.cpu.regs.to_vec(), regs);
+        assert_eq!(emulator.cpu.bus.clint.mtime(), mtime);
+        assert_eq!(emulator.read_memory(BUFFER, 4).unwrap(), buffer);
+        emulator.finish().unwrap();
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn diverged() {
+    let path = path("diverged");
+    record(&path);
+
+    // The run ends before all the events were replayed.
+    let (reason, mut emulator) = replay(&path, TimeMode::RealTime, 1200);
+    assert_eq!(reason, StopReason::InstructionLimit);
+    let error = emulator.finish().unwrap_err();
+    assert!(error.to_string().contains("events not replayed"), "{}", error);
+
+    // The machine does not read the host time as the recorded one did.
+    let (reason, _) = replay(&path, TimeMode::Deterministic(100), 4000);
+    assert_eq!(
+        reason,
+        StopReason::Replay("invalid record: the host time was not read at instruction 0".to_string())
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupted() {
+    let path = path("corrupted");
+    // A disk event whose size is much larger than the file
+    let mut data = b"RVEMUREC".to_vec();
+    data.extend_from_slice(&RECORD_VERSION.to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.push(2);
+    data.extend_from_slice(&(u64::MAX / 2).to_le_bytes());
+    data.extend_from_slice(b"disk");
+    fs::write(&path, data).unwrap();
+    let emulator = emulator(TimeMode::RealTime);
+    let error = Recording::replay(&emulator.cpu, &path).err().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(error.to_string().contains("ends after 4 bytes"), "{}", error);
+}
+
+#[test]
+fn start_date() {
+    let path = path("start-date");
+    let mut emulator = emulator(TimeMode::Deterministic(1));
+    emulator.cpu.bus.clint.set_start_date(1_000_000_000);
+    let recording = Recording::record(&emulator.cpu, &path).unwrap();
+    emulator.set_recording(recording);
+    assert_eq!(emulator.run(100), StopReason::InstructionLimit);
+    emulator.finish().unwrap();
+    let date = emulator.cpu.bus.clint.date();
+
+    // The replay counts the date from the recorded start.
+    let (reason, mut emulator) = replay(&path, TimeMode::Deterministic(1), 100);
+    assert_eq!(reason, StopReason::InstructionLimit);
+    assert_eq!(emulator.cpu.bus.clint.start_date(), 1_000_000_000);
+    assert_eq!(emulator.cpu.bus.clint.date(), date);
+    emulator.finish().unwrap();
+    fs::remove_file(&path).unwrap();
+}
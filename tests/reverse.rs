@@ -0,0 +1,81 @@
+// Run a program backward with the history of the debugger.
+
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::emulator::*;
+use riscvemu::replay::*;
+use riscvemu::reverse::*;
+use riscvemu::trace::*;
+
+mod common;
+use common::{Output, program};
+
+impl Output {
+    fn lines(&self) -> usize {
+        self.0.lock().unwrap().iter().filter(|byte| **byte == b'\n').count()
+    }
+}
+
+fn emulator(insts: &[u32]) -> (Emulator, Output) {
+    let mut emulator = Emulator::builder().binary(program(insts)).build().unwrap();
+    let log = Output::default();
+    emulator.set_tracer(Tracer::new(Box::new(log.clone()), false, true, false));
+    let recording = Recording::history(&emulator.cpu);
+    emulator.set_recording(recording);
+    (emulator, log)
+}
+
+#[test]
+fn go_back() {
+    // loop: addi a0, a0, 1
+    // j loop
+    let (mut emulator, log) = emulator(&[0x00150513, 0xffdff06f]);
+    let mut history = History::new();
+    for _ in 0..10 {
+        assert_eq!(history.step(&mut emulator), None);
+    }
+    assert_eq!(emulator.reg(10), 5);
+    assert_eq!(history.origin(), Some(0));
+    assert_eq!(history.checkpoints_before(10), vec![0]);
+
+    // Back to the state before the fourth instruction, the second jump
+    history.go_to(&mut emulator, 3);
+    assert_eq!(emulator.instruction_count(), 3);
+    assert_eq!(emulator.reg(10), 2);
+    assert_eq!(emulator.pc(), MEMORY_BASE + 4);
+
+    // The instructions executed again are not traced again.
+    assert_eq!(log.lines(), 10);
+    for _ in 3..10 {
+        assert_eq!(history.step(&mut emulator), None);
+    }
+    assert_eq!(emulator.reg(10), 5);
+    assert_eq!(log.lines(), 10);
+    history.step(&mut emulator);
+    assert_eq!(emulator.reg(10), 6);
+    assert_eq!(log.lines(), 11);
+
+    // A register changed in the past discards the history after it.
+    history.go_to(&mut emulator, 4);
+    emulator.set_reg(10, 100);
+    history.discard_future(&mut emulator);
+    history.go_to(&mut emulator, 10);
+    assert_eq!(emulator.instruction_count(), 4);
+    assert_eq!(emulator.reg(10), 100);
+    history.step(&mut emulator);
+    assert_eq!(emulator.reg(10), 101);
+    assert_eq!(log.lines(), 12);
+}
+
+#[test]
+fn stop() {
+    // li a0, 2
+    // ebreak
+    let (mut emulator, _) = emulator(&[0x00200513, 0x00100073]);
+    let mut history = History::new();
+    assert_eq!(history.step(&mut emulator), None);
+    // The reasons to stop of Emulator::step are returned.
+    assert!(matches!(history.step(&mut emulator), Some(StopReason::Exception(_, _))));
+    history.go_to(&mut emulator, 1);
+    assert!(matches!(history.step(&mut emulator), Some(StopReason::Exception(_, _))));
+}
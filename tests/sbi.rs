@@ -0,0 +1,186 @@
+// Run S-mode kernels with the built-in SBI.
+
+
+use riscvemu::bus::*;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::sbi::*;
+use riscvemu::trace::*;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::{Output, program};
+
+// ecall
+const ECALL: u32 = 0x00000073;
+// j .
+const LOOP: u32 = 0x0000006f;
+
+// A kernel which loops, with a call at CALL which the tests jump to
+const CALL: u64 = MEMORY_BASE + 4;
+
+fn emulator() -> Emulator {
+    let binary = program(&[LOOP, ECALL, LOOP]);
+    Emulator::builder()
+        .memory_size(0x10000)
+        .binary(binary)
+        .sbi(true)
+        .build()
+        .unwrap()
+}
+
+// Make the call (eid, fid) with the arguments and return a0 and a1.
+fn call(emulator: &mut Emulator, eid: u64, fid: u64, args: &[u64]) -> (i64, u64) {
+    emulator.set_pc(CALL);
+    emulator.set_reg(17, eid);
+    emulator.set_reg(16, fid);
+    for (i, arg) in args.iter().enumerate() {
+        emulator.set_reg(10 + i, *arg);
+    }
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.pc(), CALL + 4);
+    (emulator.reg(10) as i64, emulator.reg(11))
+}
+
+#[test]
+fn start() {
+    let emulator = emulator();
+    assert_eq!(emulator.mode(), Mode::Surpervisor);
+    assert_eq!(emulator.reg(10), 0);
+    assert_eq!(Some(emulator.reg(11)), emulator.device_tree().map(|(addr, _)| addr));
+    assert_eq!(emulator.csr(MIDELEG), MIP_SSIP | MIP_STIP | MIP_SEIP);
+    assert_ne!(emulator.csr(MEDELEG) & 1 << 8, 0);
+
+    // The built-in SBI replaces firmware.
+    let firmware = Emulator::builder().firmware(vec![0; 4]).binary(vec![0; 4]).sbi(true).build();
+    assert!(firmware.is_err());
+}
+
+#[test]
+fn base() {
+    let mut emulator = emulator();
+    assert_eq!(call(&mut emulator, SBI_EXT_BASE, 0, &[]), (SBI_SUCCESS, SBI_SPEC_VERSION));
+    assert_eq!(call(&mut emulator, SBI_EXT_BASE, 1, &[]), (SBI_SUCCESS, SBI_IMPL_ID));
+    assert_eq!(call(&mut emulator, SBI_EXT_BASE, 3, &[SBI_EXT_TIME]), (SBI_SUCCESS, 1));
+    assert_eq!(call(&mut emulator, SBI_EXT_BASE, 3, &[SBI_EXT_DBCN]), (SBI_SUCCESS, 1));
+    // PMU
+    assert_eq!(call(&mut emulator, SBI_EXT_BASE, 3, &[0x504d55]), (SBI_SUCCESS, 0));
+    assert_eq!(call(&mut emulator, 0x504d55, 0, &[]).0, SBI_ERR_NOT_SUPPORTED);
+}
+
+#[test]
+fn timer() {
+    let mut emulator = emulator();
+    emulator.set_csr(STVEC, MEMORY_BASE);
+    emulator.set_csr(SIE, MIP_STIP);
+    emulator.set_csr(SSTATUS, 1 << 1);
+    // No interrupt until the timer is set
+    assert_eq!(emulator.run(100), StopReason::InstructionLimit);
+    assert_eq!(emulator.csr(SCAUSE), 0);
+
+    let time = emulator.csr(TIME) + 5;
+    assert_eq!(call(&mut emulator, SBI_EXT_TIME, 0, &[time]), (SBI_SUCCESS, 0));
+    assert_eq!(emulator.csr(MIP) & MIP_STIP, 0);
+    let reason = emulator.run_until(1000, |emulator| emulator.csr(SCAUSE) != 0);
+    assert_eq!(reason, StopReason::Condition);
+    assert_eq!(emulator.csr(SCAUSE), 1 << 63 | 5);
+    assert_eq!(emulator.mode(), Mode::Surpervisor);
+    assert!(emulator.csr(TIME) >= time);
+}
+
+#[test]
+fn ipi() {
+    let mut emulator = emulator();
+    assert_eq!(call(&mut emulator, SBI_EXT_IPI, 0, &[1, 0]), (SBI_SUCCESS, 0));
+    assert_ne!(emulator.csr(MIP) & MIP_SSIP, 0);
+    assert_eq!(call(&mut emulator, SBI_EXT_LEGACY_CLEAR_IPI, 0, &[]).0, SBI_SUCCESS);
+    assert_eq!(emulator.csr(MIP) & MIP_SSIP, 0);
+    assert_eq!(call(&mut emulator, SBI_EXT_IPI, 0, &[1, 1]).0, SBI_ERR_INVALID_PARAM);
+    assert_eq!(call(&mut emulator, SBI_EXT_RFENCE, 1, &[0, u64::MAX, 0, 0]).0, SBI_SUCCESS);
+}
+
+#[test]
+fn hsm() {
+    let mut emulator = emulator();
+    assert_eq!(
+        call(&mut emulator, SBI_EXT_HSM, 2, &[0]),
+        (SBI_SUCCESS, SBI_HSM_STATE_STARTED)
+    );
+    assert_eq!(call(&mut emulator, SBI_EXT_HSM, 2, &[1]).0, SBI_ERR_INVALID_PARAM);
+    assert_eq!(call(&mut emulator, SBI_EXT_HSM, 0, &[0, 0, 0]).0, SBI_ERR_ALREADY_AVAILABLE);
+    assert_eq!(call(&mut emulator, SBI_EXT_HSM, 1, &[]).0, SBI_ERR_FAILED);
+}
+
+#[test]
+fn console() {
+    let mut emulator = emulator();
+    // getchar returns -1 until a byte is typed.
+    assert_eq!(call(&mut emulator, SBI_EXT_LEGACY_CONSOLE_GETCHAR, 0, &[]).0, -1);
+    emulator.cpu.bus.uart.receive(b'x');
+    emulator.cpu.bus.uart.receive(b'y');
+    assert_eq!(call(&mut emulator, SBI_EXT_LEGACY_CONSOLE_GETCHAR, 0, &[]).0, b'x' as i64);
+
+    let buffer = MEMORY_BASE + 0x1000;
+    assert_eq!(call(&mut emulator, SBI_EXT_DBCN, 1, &[8, buffer, 0]), (SBI_SUCCESS, 1));
+    assert_eq!(emulator.load(buffer, 8), Ok(b'y' as u64));
+
+    emulator.write_memory(buffer, b"sbi\n").unwrap();
+    assert_eq!(call(&mut emulator, SBI_EXT_DBCN, 0, &[4, buffer, 0]), (SBI_SUCCESS, 4));
+    assert_eq!(call(&mut emulator, SBI_EXT_DBCN, 0, &[4, 0, 0]).0, SBI_ERR_INVALID_PARAM);
+}
+
+#[test]
+fn system_reset() {
+    let mut emulator = emulator();
+    assert_eq!(call(&mut emulator, SBI_EXT_SRST, 0, &[3, 0]).0, SBI_ERR_INVALID_PARAM);
+    emulator.set_pc(CALL);
+    emulator.set_reg(17, SBI_EXT_SRST);
+    emulator.set_reg(10, SBI_SRST_TYPE_COLD_REBOOT);
+    emulator.set_reg(11, 1);
+    assert_eq!(emulator.step(), Some(StopReason::SystemReset(ResetType::ColdReboot, 1)));
+
+    emulator.set_pc(CALL);
+    emulator.set_reg(17, SBI_EXT_LEGACY_SHUTDOWN);
+    assert_eq!(emulator.step(), Some(StopReason::SystemReset(ResetType::Shutdown, 0)));
+}
+
+#[test]
+fn trace() {
+    let mut emulator = emulator();
+    let output = Output::default();
+    emulator.set_tracer(Tracer::new(Box::new(output.clone()), false, false, false));
+    call(&mut emulator, SBI_EXT_BASE, 0, &[]);
+
+    // The built-in SBI runs 1 hart, but the lines have the hart like the others.
+    let mut tracer = Tracer::new(Box::new(output.clone()), false, false, false);
+    let call = Call {
+        eid: SBI_EXT_HSM,
+        fid: 2,
+        args: [12, 0, 0, 0, 0, 0],
+        error: SBI_SUCCESS,
+        value: SBI_HSM_STATE_STARTED,
+        reset: None,
+    };
+    tracer.sbi_call(12, &call);
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(
+        output.lines().collect::<Vec<_>>(),
+        [
+            "core    0: sbi get_spec_version (eid 0x10, fid 0) a0 0x0 a1 0x8000f000 a2 0x0 -> error 0, value 0x2000000",
+            "core   12: sbi hart_get_status (eid 0x48534d, fid 2) a0 0xc a1 0x0 a2 0x0 -> error 0, value 0x0",
+        ]
+    );
+}
+
+#[test]
+fn without_sbi() {
+    // The ecall traps to M-mode, where there is no firmware.
+    let mut emulator = Emulator::builder().binary(ECALL.to_le_bytes().to_vec()).build().unwrap();
+    emulator.cpu.mode = Mode::Surpervisor;
+    assert_eq!(
+        emulator.step(),
+        Some(StopReason::Exception(Exception::EnvironmentCallFromSMode, MEMORY_BASE))
+    );
+    assert_eq!(emulator.mode(), Mode::Machine);
+}
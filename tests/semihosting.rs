@@ -0,0 +1,230 @@
+// Make the semihosting calls of a bare-metal program.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use riscvemu::bus::*;
+use riscvemu::clint::TIMEBASE_FREQUENCY;
+use riscvemu::emulator::*;
+use riscvemu::semihosting::*;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::Output;
+
+// j .
+const LOOP: u32 = 0x0000006f;
+
+// The program loops, with a call at CALL which the tests jump to.
+const CALL: u64 = MEMORY_BASE + 4;
+// The parameter block, the strings and the buffers of the calls
+const BLOCK: u64 = MEMORY_BASE + 0x1000;
+const NAME: u64 = MEMORY_BASE + 0x2000;
+const BUFFER: u64 = MEMORY_BASE + 0x3000;
+
+// An empty directory for the files of a test
+fn root(test: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("riscvemu-semihosting-{}-{}", process::id(), test));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn emulator(root: &Path) -> (Emulator, Output) {
+    let insts = [
+        LOOP,
+        // slli zero, zero, 0x1f
+        SEMIHOSTING_ENTRY as u32,
+        // ebreak
+        SEMIHOSTING_EBREAK as u32,
+        // srai zero, zero, 7
+        SEMIHOSTING_EXIT as u32,
+        LOOP,
+        // ebreak
+        SEMIHOSTING_EBREAK as u32,
+    ];
+    let binary = common::program(&insts);
+    let mut emulator = Emulator::builder()
+        .memory_size(0x10000)
+        .binary(binary)
+        .semihosting(root, "hello.elf a b")
+        .build()
+        .unwrap();
+    let output = Output::default();
+    emulator.cpu.bus.uart.set_output(Box::new(output.clone()));
+    (emulator, output)
+}
+
+// Make the call with a1 and return a0.
+fn call(emulator: &mut Emulator, op: u64, arg: u64) -> u64 {
+    emulator.set_pc(CALL);
+    emulator.set_reg(10, op);
+    emulator.set_reg(11, arg);
+    assert_eq!(emulator.run(2), StopReason::InstructionLimit);
+    assert_eq!(emulator.pc(), CALL + 8);
+    emulator.reg(10)
+}
+
+// Make the call with the parameter block of the fields.
+fn call_with(emulator: &mut Emulator, op: u64, fields: &[u64]) -> u64 {
+    for (i, field) in fields.iter().enumerate() {
+        emulator.store(BLOCK + 8 * i as u64, 64, *field).unwrap();
+    }
+    call(emulator, op, BLOCK)
+}
+
+fn open(emulator: &mut Emulator, name: &str, mode: u64) -> u64 {
+    emulator.write_memory(NAME, name.as_bytes()).unwrap();
+    call_with(emulator, SYS_OPEN, &[NAME, mode, name.len() as u64])
+}
+
+#[test]
+fn console() {
+    let root = root("console");
+    let (mut emulator, output) = emulator(&root);
+    emulator.write_memory(NAME, b"hello\0").unwrap();
+    call(&mut emulator, SYS_WRITE0, NAME);
+    call(&mut emulator, SYS_WRITEC, NAME + 4);
+    // ":tt" with "w"
+    let stdout = open(&mut emulator, ":tt", 4);
+    assert_eq!(call_with(&mut emulator, SYS_ISTTY, &[stdout]), 1);
+    emulator.write_memory(BUFFER, b" world\n").unwrap();
+    assert_eq!(call_with(&mut emulator, SYS_WRITE, &[stdout, BUFFER, 7]), 0);
+    assert_eq!(*output.0.lock().unwrap(), b"helloo world\n");
+
+    // A read returns what was typed, and waits until something is.
+    emulator.cpu.bus.uart.receive(b'x');
+    assert_eq!(call(&mut emulator, SYS_READC, 0), b'x' as u64);
+    let stdin = open(&mut emulator, ":tt", 0);
+    emulator.set_pc(CALL);
+    emulator.store(BLOCK, 64, stdin).unwrap();
+    emulator.store(BLOCK + 8, 64, BUFFER).unwrap();
+    emulator.store(BLOCK + 16, 64, 4).unwrap();
+    emulator.set_reg(10, SYS_READ);
+    emulator.set_reg(11, BLOCK);
+    assert_eq!(emulator.run(10), StopReason::InstructionLimit);
+    assert_eq!(emulator.pc(), CALL + 4);
+    emulator.cpu.bus.uart.receive(b'y');
+    emulator.cpu.bus.uart.receive(b'z');
+    assert_eq!(emulator.run(1), StopReason::InstructionLimit);
+    assert_eq!(emulator.pc(), CALL + 8);
+    // 2 of the 4 bytes are not read.
+    assert_eq!(emulator.reg(10), 2);
+    assert_eq!(emulator.read_memory(BUFFER, 2), Ok(b"yz".to_vec()));
+}
+
+#[test]
+fn time() {
+    let root = root("time");
+    let (mut emulator, _) = emulator(&root);
+    // The date counts from the start of the machine by mtime.
+    emulator.cpu.bus.clint.set_start_date(1_700_000_000_000_000_000);
+    assert_eq!(call(&mut emulator, SYS_TIME, 0), 1_700_000_000);
+    emulator.cpu.bus.clint.set_mtime(3 * TIMEBASE_FREQUENCY);
+    assert_eq!(call(&mut emulator, SYS_TIME, 0), 1_700_000_003);
+}
+
+#[test]
+fn files() {
+    let root = root("files");
+    let (mut emulator, _) = emulator(&root);
+    // "w"
+    let file = open(&mut emulator, "data.txt", 4);
+    assert_eq!(call_with(&mut emulator, SYS_ISTTY, &[file]), 0);
+    emulator.write_memory(BUFFER, b"0123456789").unwrap();
+    assert_eq!(call_with(&mut emulator, SYS_WRITE, &[file, BUFFER, 10]), 0);
+    assert_eq!(call_with(&mut emulator, SYS_CLOSE, &[file]), 0);
+    assert_eq!(fs::read(root.join("data.txt")).unwrap(), b"0123456789");
+    // The handle is closed.
+    assert_eq!(call_with(&mut emulator, SYS_CLOSE, &[file]), u64::MAX);
+    assert_eq!(call(&mut emulator, SYS_ERRNO, 0), EBADF);
+
+    // "rb"
+    let file = open(&mut emulator, "./data.txt", 1);
+    assert_eq!(call_with(&mut emulator, SYS_FLEN, &[file]), 10);
+    assert_eq!(call_with(&mut emulator, SYS_SEEK, &[file, 6]), 0);
+    assert_eq!(call_with(&mut emulator, SYS_READ, &[file, BUFFER, 8]), 4);
+    assert_eq!(emulator.read_memory(BUFFER, 4), Ok(b"6789".to_vec()));
+    // At the end of the file, nothing is read.
+    assert_eq!(call_with(&mut emulator, SYS_READ, &[file, BUFFER, 8]), 8);
+    assert_eq!(call_with(&mut emulator, SYS_CLOSE, &[file]), 0);
+
+    emulator.write_memory(NAME, b"data.txtnew.txt").unwrap();
+    assert_eq!(call_with(&mut emulator, SYS_RENAME, &[NAME, 8, NAME + 8, 7]), 0);
+    assert!(root.join("new.txt").exists());
+    assert_eq!(call_with(&mut emulator, SYS_REMOVE, &[NAME + 8, 7]), 0);
+    assert!(!root.join("new.txt").exists());
+    // SYS_REMOVE returns the error number.
+    assert_eq!(call_with(&mut emulator, SYS_REMOVE, &[NAME + 8, 7]), 2);
+
+    // A missing file is an error with ENOENT.
+    let status = open(&mut emulator, "missing.txt", 0);
+    assert_eq!(status, u64::MAX);
+    assert_eq!(call_with(&mut emulator, SYS_ISERROR, &[status]), 1);
+    assert_eq!(call(&mut emulator, SYS_ERRNO, 0), 2);
+
+    assert_eq!(call_with(&mut emulator, SYS_TMPNAM, &[BUFFER, 7, 16]), 0);
+    assert_eq!(emulator.read_memory(BUFFER, 7), Ok(b"tmp007\0".to_vec()));
+}
+
+#[test]
+fn sandbox() {
+    let root = root("sandbox");
+    let outside = root.with_extension("outside");
+    fs::write(&outside, b"secret").unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+    let (mut emulator, _) = emulator(&root);
+
+    let escapes = [
+        "../riscvemu-semihosting-outside".to_string(),
+        outside.display().to_string(),
+        "link".to_string(),
+    ];
+    for name in escapes.iter() {
+        assert_eq!(open(&mut emulator, name, 0), u64::MAX, "{}", name);
+        assert_eq!(call(&mut emulator, SYS_ERRNO, 0), EACCES, "{}", name);
+    }
+    emulator.write_memory(NAME, b"link").unwrap();
+    assert_eq!(call_with(&mut emulator, SYS_REMOVE, &[NAME, 4]), EACCES);
+    assert!(outside.exists());
+    emulator.write_memory(NAME, b"echo").unwrap();
+    assert_eq!(call_with(&mut emulator, SYS_SYSTEM, &[NAME, 4]), u64::MAX);
+    fs::remove_file(outside).unwrap();
+
+    // The root must be a directory.
+    let emulator = Emulator::builder().semihosting(&root.join("missing"), "").build();
+    assert!(emulator.is_err());
+}
+
+#[test]
+fn program() {
+    let root = root("program");
+    let (mut emulator, _) = emulator(&root);
+    let features = open(&mut emulator, FEATURES_NAME, 0);
+    assert_eq!(call_with(&mut emulator, SYS_FLEN, &[features]), 5);
+    assert_eq!(call_with(&mut emulator, SYS_READ, &[features, BUFFER, 8]), 3);
+    assert_eq!(emulator.read_memory(BUFFER, 5), Ok(b"SHFB\x01".to_vec()));
+
+    assert_eq!(call_with(&mut emulator, SYS_GET_CMDLINE, &[BUFFER, 64]), 0);
+    assert_eq!(emulator.load(BLOCK + 8, 64), Ok(13));
+    assert_eq!(emulator.read_memory(BUFFER, 14), Ok(b"hello.elf a b\0".to_vec()));
+    // The buffer is too small for the NUL.
+    assert_eq!(call_with(&mut emulator, SYS_GET_CMDLINE, &[BUFFER, 13]), u64::MAX);
+    assert_eq!(call(&mut emulator, SYS_TICKFREQ, 0), 10_000_000);
+
+    // An ebreak out of the sequence is a breakpoint.
+    emulator.set_pc(MEMORY_BASE + 20);
+    let reason = emulator.run(1);
+    assert_eq!(reason, StopReason::Exception(Exception::Breakpoint, MEMORY_BASE + 20));
+
+    // The exit code is the subcode of ADP_Stopped_ApplicationExit.
+    emulator.store(BLOCK, 64, ADP_STOPPED_APPLICATION_EXIT).unwrap();
+    emulator.store(BLOCK + 8, 64, 3).unwrap();
+    emulator.set_pc(CALL);
+    emulator.set_reg(10, SYS_EXIT);
+    emulator.set_reg(11, BLOCK);
+    assert_eq!(emulator.run(10), StopReason::Exit(3));
+    assert_eq!(emulator.pc(), CALL + 8);
+}
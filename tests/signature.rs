@@ -0,0 +1,128 @@
+// Run a test of riscv-arch-test and write its signature.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use riscvemu::bus::MEMORY_BASE;
+use riscvemu::riscv_tests::Outcome;
+use riscvemu::signature::*;
+
+mod common;
+use common::program;
+
+const CODE: u64 = MEMORY_BASE + 0x100;
+const TOHOST: u64 = MEMORY_BASE + 0x200;
+// The signature is 3 words.
+const BEGIN: u64 = MEMORY_BASE + 0x300;
+const END: u64 = MEMORY_BASE + 0x30c;
+
+// lui t0, 0x12345
+// addi t0, t0, 0x678
+// auipc t1, 0
+// sw t0, 0x1f8(t1)
+// li t0, 1
+// sd t0, 0xf8(t1)
+// loop: j loop
+const HALT: [u32; 7] = [
+    0x123452b7, 0x67828293, 0x00000317, 0x1e532c23, 0x00100293, 0x0e533c23, 0x0000006f,
+];
+
+// An ELF file which writes the first word of the signature and halts by tohost,
+// with the symbols of the arch tests. Without `halt`, the program loops.
+fn elf(halt: bool) -> Vec<u8> {
+    let mut elf = vec![0; 0x500];
+    elf[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV and the version
+    elf[0x10..0x18].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+    elf[0x18..0x20].copy_from_slice(&CODE.to_le_bytes());
+    // e_phoff and e_shoff
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    elf[0x28..0x30].copy_from_slice(&0x480u64.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize and e_shnum
+    for (i, field) in [64u16, 56, 1, 64, 2].iter().enumerate() {
+        elf[0x34 + 2 * i..0x36 + 2 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    // PT_LOAD of the file up to the symbols
+    let load = [1 | 7 << 32, 0, MEMORY_BASE, MEMORY_BASE, 0x340, 0x340, 0x1000];
+    for (i, field) in load.iter().enumerate() {
+        elf[0x40 + 8 * i..0x48 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    let insts = if halt { &HALT[..] } else { &HALT[6..] };
+    for (i, inst) in insts.iter().enumerate() {
+        elf[0x100 + 4 * i..0x104 + 4 * i].copy_from_slice(&inst.to_le_bytes());
+    }
+    // The signature and the word after it
+    for (i, word) in [0u32, 0xdeadbeef, 0x00c0ffee, 0xffffffff].iter().enumerate() {
+        elf[0x300 + 4 * i..0x304 + 4 * i].copy_from_slice(&word.to_le_bytes());
+    }
+
+    // The string table at 0x340 and the symbol table at 0x380
+    elf[0x340..0x366].copy_from_slice(b"\0tohost\0begin_signature\0end_signature\0");
+    let symbols = [(1u32, TOHOST), (8, BEGIN), (24, END)];
+    for (i, (name, value)) in symbols.iter().enumerate() {
+        let symbol = 0x398 + 24 * i;
+        elf[symbol..symbol + 4].copy_from_slice(&name.to_le_bytes());
+        elf[symbol + 4] = 0x10;
+        elf[symbol + 8..symbol + 16].copy_from_slice(&value.to_le_bytes());
+    }
+    // The section headers: SHT_SYMTAB linked to SHT_STRTAB
+    let symtab = [2u64 << 32, 0, 0, 0x380, 96, 1 << 32 | 1, 0, 24];
+    let strtab = [3u64 << 32, 0, 0, 0x340, 38, 0, 0, 0];
+    for (i, field) in symtab.iter().chain(strtab.iter()).enumerate() {
+        elf[0x480 + 8 * i..0x488 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    elf
+}
+
+// Write the signature of the program to a file and return it.
+fn write_signature(name: &str, binary: &[u8], granularity: u64) -> (Outcome, String) {
+    let path: PathBuf = env::temp_dir().join(format!("riscvemu-{}-{}.signature", name, std::process::id()));
+    let outcome = run_signature(binary, path.to_str().unwrap(), granularity).unwrap();
+    let signature = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    (outcome, signature)
+}
+
+#[test]
+fn words() {
+    let (outcome, signature) = write_signature("words", &elf(true), DEFAULT_GRANULARITY);
+    assert_eq!(outcome, Outcome::Pass);
+    assert_eq!(signature, "12345678\ndeadbeef\n00c0ffee\n");
+}
+
+#[test]
+fn granularity() {
+    // The word at the highest address is first.
+    let (_, signature) = write_signature("16", &elf(true), 16);
+    assert_eq!(signature, "0000000000c0ffeedeadbeef12345678\n");
+    // The last line is padded with zeros, not with the word after the signature.
+    let (_, signature) = write_signature("8", &elf(true), 8);
+    assert_eq!(signature, "deadbeef12345678\n0000000000c0ffee\n");
+
+    let path = env::temp_dir().join("riscvemu-granularity.signature");
+    for granularity in [0, 6].iter() {
+        let error = run_signature(&elf(true), path.to_str().unwrap(), *granularity).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn timeout() {
+    // The signature of a test which does not halt is written as well.
+    let (outcome, signature) = write_signature("timeout", &elf(false), DEFAULT_GRANULARITY);
+    assert_eq!(outcome, Outcome::Timeout);
+    assert_eq!(signature, "00000000\ndeadbeef\n00c0ffee\n");
+}
+
+#[test]
+fn symbols() {
+    // Without the signature symbols, e.g. a flat binary
+    let path = env::temp_dir().join("riscvemu-symbols.signature");
+    let mut binary = elf(true);
+    binary[0x3c..0x3e].copy_from_slice(&0u16.to_le_bytes());
+    let error = run_signature(&binary, path.to_str().unwrap(), 4).unwrap_err();
+    assert!(error.to_string().contains("begin_signature"));
+    let flat = program(&HALT);
+    assert!(run_signature(&flat, path.to_str().unwrap(), 4).is_err());
+}
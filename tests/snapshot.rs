@@ -0,0 +1,80 @@
+// Save the state of the machine to a snapshot and restore it.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use riscvemu::clint::*;
+use riscvemu::emulator::*;
+use riscvemu::snapshot::*;
+
+mod common;
+use common::program;
+
+const INSTS: [u32; 2] = [
+    // loop: addi a0, a0, 1
+    0x00150513,
+    // j loop
+    0xffdff06f,
+];
+
+fn path(test: &str) -> String {
+    let path = env::temp_dir().join(format!("riscvemu-snapshot-{}-{}.snap", process::id(), test));
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn round_trip() {
+    let path = path("round-trip");
+    let mut emulator = Emulator::builder()
+        .binary(program(&INSTS))
+        .time_mode(TimeMode::Deterministic(3))
+        .build()
+        .unwrap();
+    assert_eq!(emulator.run(100), StopReason::InstructionLimit);
+    save(&emulator, &path).unwrap();
+
+    // The instruction count and the time mode are the ones of the snapshot.
+    let mut restored = Emulator::builder().binary(program(&INSTS)).build().unwrap();
+    restore(&mut restored, &path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(restored.instruction_count(), 100);
+    assert_eq!(restored.cpu.bus.clint.time_mode(), TimeMode::Deterministic(3));
+    assert_eq!(restored.pc(), emulator.pc());
+    assert_eq!(restored.reg(10), 50);
+    assert_eq!(restored.cpu.bus.clint.mtime(), emulator.cpu.bus.clint.mtime());
+
+    // Both go on the same way.
+    emulator.run(30);
+    restored.run(30);
+    assert_eq!(restored.reg(10), emulator.reg(10));
+    assert_eq!(restored.cpu.bus.clint.mtime(), emulator.cpu.bus.clint.mtime());
+}
+
+#[test]
+fn corrupted() {
+    let path = path("corrupted");
+    // A section tag whose length is the largest u64
+    let mut data = b"RVEMUSNP".to_vec();
+    data.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    data.extend_from_slice(&u64::MAX.to_le_bytes());
+    fs::write(&path, data).unwrap();
+    let mut emulator = Emulator::builder().binary(program(&INSTS)).build().unwrap();
+    let error = restore(&mut emulator, &path).unwrap_err();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn host_calls() {
+    let path = path("host-calls");
+    let mut emulator = Emulator::builder()
+        .binary(program(&INSTS))
+        .semihosting(&env::temp_dir(), "prog")
+        .build()
+        .unwrap();
+    assert_eq!(save(&emulator, &path).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    assert_eq!(restore(&mut emulator, &path).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    assert!(fs::metadata(&path).is_err());
+}
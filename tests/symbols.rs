@@ -0,0 +1,73 @@
+// Look up the symbols of addresses in the symbol table of an ELF file.
+
+use riscvemu::elf::*;
+use riscvemu::symbols::*;
+
+// The symbol type of data, which Symbols skips
+const STT_OBJECT: u8 = 1;
+
+fn symbols() -> Symbols {
+    let symbol = |name: &str, value, size, kind| Symbol {
+        name: name.to_string(),
+        value,
+        size,
+        kind,
+    };
+    let elf = Elf {
+        entry: 0x80000000,
+        segments: Vec::new(),
+        symbols: vec![
+            // A label of the entry, and a label at the start of main
+            symbol("_start", 0x80000000, 0, STT_NOTYPE),
+            symbol(".Lmain", 0x80000010, 0, STT_NOTYPE),
+            symbol("main", 0x80000010, 0x20, STT_FUNC),
+            symbol("exit", 0x80000040, 4, STT_FUNC),
+            // Data and undefined symbols are not code.
+            symbol("buffer", 0x80001000, 0x100, STT_OBJECT),
+            symbol("undefined", 0, 0, STT_NOTYPE),
+        ],
+        program_headers: None,
+        program_header_size: 0,
+        program_header_count: 0,
+        interpreter: false,
+    };
+    Symbols::new(&elf)
+}
+
+#[test]
+fn lookup() {
+    let symbols = symbols();
+    assert_eq!(symbols.lookup(0x80000000), Some("_start".to_string()));
+    // A label covers the addresses up to the next symbol.
+    assert_eq!(symbols.lookup(0x8000000c), Some("_start+0xc".to_string()));
+    // A function is preferred to a label at the same address.
+    assert_eq!(symbols.lookup(0x80000010), Some("main".to_string()));
+    assert_eq!(symbols.lookup(0x8000002e), Some("main+0x1e".to_string()));
+    assert_eq!(symbols.lookup(0x80000042), Some("exit+0x2".to_string()));
+
+    // Before the first symbol, after the end of a function and in data
+    assert_eq!(symbols.lookup(0x7ffffffc), None);
+    assert_eq!(symbols.lookup(0x80000030), None);
+    assert_eq!(symbols.lookup(0x80001000), None);
+    assert_eq!(symbols.lookup(0), None);
+}
+
+#[test]
+fn address() {
+    let symbols = symbols();
+    assert_eq!(symbols.address("main"), Some(0x80000010));
+    assert_eq!(symbols.address("exit"), Some(0x80000040));
+    assert_eq!(symbols.address("buffer"), None);
+    assert_eq!(symbols.address("undefined"), None);
+    assert!(!symbols.is_empty());
+    assert!(Symbols::default().is_empty());
+}
+
+#[test]
+fn format() {
+    let symbols = symbols();
+    assert_eq!(symbols.format(0x80000010), "0x80000010 <main>");
+    assert_eq!(symbols.format(0x80000014), "0x80000014 <main+0x4>");
+    assert_eq!(symbols.format(0x80000030), "0x80000030");
+    assert_eq!(Symbols::default().format(0x80000000), "0x80000000");
+}
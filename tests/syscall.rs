@@ -0,0 +1,351 @@
+// Run Linux programs in U-mode with the system calls done by the emulator.
+
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process;
+
+use riscvemu::clint::TIMEBASE_FREQUENCY;
+use riscvemu::cpu::Mode;
+use riscvemu::emulator::*;
+use riscvemu::syscall::*;
+use riscvemu::trap::Exception;
+
+mod common;
+use common::Output;
+
+// The program is loaded at USER_MEMORY_BASE with its ELF header, the code at CODE
+// and the data at DATA, and then BSS_SIZE bytes of zeros.
+const CODE: u64 = USER_MEMORY_BASE + 0x100;
+const DATA: u64 = USER_MEMORY_BASE + 0x200;
+const FILE_SIZE: u64 = 0x300;
+const BSS_SIZE: u64 = 0x2000;
+
+// ecall
+const ECALL: u32 = 0x00000073;
+// j .
+const LOOP: u32 = 0x0000006f;
+
+// A static executable of the instructions and the data, or a dynamically linked one
+// if `interpreter` is true.
+fn elf(insts: &[u32], data: &[u8], interpreter: bool) -> Vec<u8> {
+    let mut elf = vec![0; FILE_SIZE as usize];
+    let phnum: u16 = if interpreter { 2 } else { 1 };
+    elf[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV and the version
+    elf[0x10..0x18].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+    elf[0x18..0x20].copy_from_slice(&CODE.to_le_bytes());
+    // e_phoff
+    elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+    // e_ehsize, e_phentsize and e_phnum
+    elf[0x34..0x36].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+    elf[0x38..0x3a].copy_from_slice(&phnum.to_le_bytes());
+    // PT_LOAD of the whole file, rwx
+    let load = [
+        1 | 7 << 32,
+        0,
+        USER_MEMORY_BASE,
+        USER_MEMORY_BASE,
+        FILE_SIZE,
+        FILE_SIZE + BSS_SIZE,
+        0x1000,
+    ];
+    for (i, field) in load.iter().enumerate() {
+        elf[0x40 + 8 * i..0x48 + 8 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    // PT_INTERP
+    if interpreter {
+        elf[0x78..0x7c].copy_from_slice(&3u32.to_le_bytes());
+    }
+    for (i, inst) in insts.iter().enumerate() {
+        elf[0x100 + 4 * i..0x104 + 4 * i].copy_from_slice(&inst.to_le_bytes());
+    }
+    elf[0x200..0x200 + data.len()].copy_from_slice(data);
+    elf
+}
+
+fn strings(strings: &[&str]) -> Vec<String> {
+    strings.iter().map(|string| string.to_string()).collect()
+}
+
+// The program makes the system calls which the tests jump to at CODE.
+fn emulator() -> (Emulator, Output) {
+    let binary = elf(&[ECALL, LOOP], &[], false);
+    let mut emulator = Emulator::builder()
+        .binary(binary)
+        .user(&strings(&["prog", "arg"]), &strings(&["A=1"]))
+        .build()
+        .unwrap();
+    let output = Output::default();
+    emulator.process_mut().unwrap().set_stdout(Box::new(output.clone()));
+    (emulator, output)
+}
+
+// Make the system call and return a0.
+fn call(emulator: &mut Emulator, number: u64, args: &[u64]) -> i64 {
+    emulator.set_pc(CODE);
+    emulator.set_reg(17, number);
+    for (i, arg) in args.iter().enumerate() {
+        emulator.set_reg(10 + i, *arg);
+    }
+    assert_eq!(emulator.run(1), StopReason::InstructionLimit);
+    assert_eq!(emulator.pc(), CODE + 4);
+    emulator.reg(10) as i64
+}
+
+// An empty directory for the files of a test
+fn directory(test: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("riscvemu-syscall-{}-{}", process::id(), test));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+#[test]
+fn hello() {
+    let insts = [
+        // addi a0, zero, 1
+        0x00100513,
+        // lui a1, 0x10
+        0x000105b7,
+        // addi a1, a1, 0x200
+        0x20058593,
+        // addi a2, zero, 6
+        0x00600613,
+        // addi a7, zero, 64
+        0x04000893,
+        ECALL,
+        // addi a0, zero, 7
+        0x00700513,
+        // addi a7, zero, 94
+        0x05e00893,
+        ECALL,
+    ];
+    let binary = elf(&insts, b"hello\n", false);
+    let mut emulator = Emulator::builder()
+        .binary(binary)
+        .user(&strings(&["hello"]), &[])
+        .build()
+        .unwrap();
+    let output = Output::default();
+    emulator.process_mut().unwrap().set_stdout(Box::new(output.clone()));
+    assert_eq!(emulator.mode(), Mode::User);
+    assert_eq!(emulator.run(100), StopReason::Exit(7));
+    assert_eq!(*output.0.lock().unwrap(), b"hello\n");
+}
+
+#[test]
+fn initial_stack() {
+    let (mut emulator, _) = emulator();
+    let sp = emulator.reg(2);
+    assert_eq!(sp % 16, 0);
+    assert_eq!(emulator.pc(), CODE);
+    let word = |emulator: &mut Emulator, i: u64| emulator.load(sp + 8 * i, 64).unwrap();
+    let string = |emulator: &mut Emulator, addr: u64, length: u64| {
+        emulator.read_memory(addr, length).unwrap()
+    };
+
+    // argc, argv, NULL, envp, NULL
+    assert_eq!(word(&mut emulator, 0), 2);
+    let (argv0, argv1, envp0) = (word(&mut emulator, 1), word(&mut emulator, 2), word(&mut emulator, 4));
+    assert_eq!(string(&mut emulator, argv0, 5), b"prog\0");
+    assert_eq!(string(&mut emulator, argv1, 4), b"arg\0");
+    assert_eq!(word(&mut emulator, 3), 0);
+    assert_eq!(string(&mut emulator, envp0, 4), b"A=1\0");
+    assert_eq!(word(&mut emulator, 5), 0);
+
+    // The auxiliary vector, up to AT_NULL
+    let mut auxv = Vec::new();
+    for i in (6..).step_by(2) {
+        let entry = (word(&mut emulator, i), word(&mut emulator, i + 1));
+        if entry.0 == 0 {
+            break;
+        }
+        auxv.push(entry);
+    }
+    let get = |key: u64| auxv.iter().find(|entry| entry.0 == key).map(|entry| entry.1);
+    // AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ and AT_ENTRY
+    assert_eq!(get(3), Some(USER_MEMORY_BASE + 0x40));
+    assert_eq!(get(4), Some(56));
+    assert_eq!(get(5), Some(1));
+    assert_eq!(get(6), Some(4096));
+    assert_eq!(get(9), Some(CODE));
+    // AT_RANDOM points to 16 bytes, and AT_EXECFN to the program.
+    assert_eq!(emulator.read_memory(get(25).unwrap(), 16).unwrap().len(), 16);
+    assert_eq!(get(31), Some(argv0));
+}
+
+#[test]
+fn memory() {
+    let (mut emulator, _) = emulator();
+    // The heap starts at the page after the bss.
+    let start = call(&mut emulator, NR_BRK, &[0]) as u64;
+    assert_eq!(start, USER_MEMORY_BASE + 0x3000);
+    assert_eq!(call(&mut emulator, NR_BRK, &[start + 0x1800]) as u64, start + 0x1800);
+    emulator.store(start + 0x1000, 64, 42).unwrap();
+    // The heap cannot go below its start, and the memory freed is zero when it
+    // grows again.
+    assert_eq!(call(&mut emulator, NR_BRK, &[start - 8]) as u64, start + 0x1800);
+    assert_eq!(call(&mut emulator, NR_BRK, &[start]) as u64, start);
+    assert_eq!(call(&mut emulator, NR_BRK, &[start + 0x2000]) as u64, start + 0x2000);
+    assert_eq!(emulator.load(start + 0x1000, 64), Ok(0));
+
+    // PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS
+    let addr = call(&mut emulator, NR_MMAP, &[0, 0x2000, 3, 0x22, u64::MAX, 0]) as u64;
+    assert_eq!(addr, USER_STACK_TOP - USER_STACK_SIZE - 0x2000);
+    emulator.store(addr, 64, 1).unwrap();
+    assert_eq!(call(&mut emulator, NR_MUNMAP, &[addr, 0x2000]), 0);
+    assert_eq!(emulator.load(addr, 64), Ok(0));
+    // The range is given again.
+    let again = call(&mut emulator, NR_MMAP, &[0, 0x1000, 3, 0x22, u64::MAX, 0]) as u64;
+    assert_eq!(again, USER_STACK_TOP - USER_STACK_SIZE - 0x1000);
+    assert_eq!(call(&mut emulator, NR_MMAP, &[0, 0, 3, 0x22, u64::MAX, 0]), -EINVAL);
+}
+
+#[test]
+fn files() {
+    let directory = directory("files");
+    let (mut emulator, output) = emulator();
+    let path = directory.join("data.txt");
+    let name = format!("{}\0", path.display());
+    emulator.write_memory(DATA, name.as_bytes()).unwrap();
+    let buffer = DATA + 0x100;
+
+    // O_WRONLY | O_CREAT | O_TRUNC
+    let fd = call(&mut emulator, NR_OPENAT, &[-100i64 as u64, DATA, 0o1101, 0o644]);
+    assert_eq!(fd, 3);
+    emulator.write_memory(buffer, b"0123456789").unwrap();
+    assert_eq!(call(&mut emulator, NR_WRITE, &[3, buffer, 10]), 10);
+    assert_eq!(call(&mut emulator, NR_CLOSE, &[3]), 0);
+    assert_eq!(call(&mut emulator, NR_CLOSE, &[3]), -EBADF);
+    assert_eq!(fs::read(&path).unwrap(), b"0123456789");
+
+    // O_RDONLY
+    let fd = call(&mut emulator, NR_OPENAT, &[-100i64 as u64, DATA, 0, 0]) as u64;
+    assert_eq!(call(&mut emulator, NR_LSEEK, &[fd, 6, 0]), 6);
+    assert_eq!(call(&mut emulator, NR_READ, &[fd, buffer, 8]), 4);
+    assert_eq!(emulator.read_memory(buffer, 4), Ok(b"6789".to_vec()));
+    assert_eq!(call(&mut emulator, NR_READ, &[fd, buffer, 8]), 0);
+    assert_eq!(call(&mut emulator, NR_PREAD64, &[fd, buffer, 2, 1]), 2);
+    assert_eq!(emulator.read_memory(buffer, 2), Ok(b"12".to_vec()));
+    // st_size of the struct stat
+    assert_eq!(call(&mut emulator, NR_FSTAT, &[fd, buffer]), 0);
+    assert_eq!(emulator.load(buffer + 48, 64), Ok(10));
+
+    // A file mapped by mmap is a copy.
+    let addr = call(&mut emulator, NR_MMAP, &[0, 0x1000, 1, 2, fd, 0]) as u64;
+    assert_eq!(emulator.read_memory(addr, 11), Ok(b"0123456789\0".to_vec()));
+
+    // dup gives the lowest free descriptor, here for stdout.
+    assert_eq!(call(&mut emulator, NR_CLOSE, &[fd]), 0);
+    assert_eq!(call(&mut emulator, NR_DUP, &[1]), 3);
+    emulator.write_memory(buffer, b"out\n").unwrap();
+    assert_eq!(call(&mut emulator, NR_WRITE, &[3, buffer, 4]), 4);
+    assert_eq!(*output.0.lock().unwrap(), b"out\n");
+
+    let missing = format!("{}\0", directory.join("missing").display());
+    emulator.write_memory(DATA, missing.as_bytes()).unwrap();
+    assert_eq!(call(&mut emulator, NR_OPENAT, &[-100i64 as u64, DATA, 0, 0]), -ENOENT);
+    assert_eq!(call(&mut emulator, NR_READ, &[99, buffer, 1]), -EBADF);
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn stdin() {
+    let (mut emulator, _) = emulator();
+    let input = Cursor::new(b"abc".to_vec());
+    emulator.process_mut().unwrap().set_stdin(Box::new(input));
+    assert_eq!(call(&mut emulator, NR_READ, &[0, DATA, 2]), 2);
+    assert_eq!(call(&mut emulator, NR_READ, &[0, DATA + 2, 2]), 1);
+    assert_eq!(emulator.read_memory(DATA, 3), Ok(b"abc".to_vec()));
+    // At the end of the input, nothing is read.
+    assert_eq!(call(&mut emulator, NR_READ, &[0, DATA, 2]), 0);
+    // The console is not a terminal.
+    assert_eq!(call(&mut emulator, NR_IOCTL, &[1, 0x5401, DATA]), -ENOTTY);
+}
+
+// CLOCK_MONOTONIC, which counts the time of the machine
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+#[test]
+fn clock() {
+    let (mut emulator, _) = emulator();
+    // The time of the machine is read twice as a struct timespec, in nanoseconds.
+    let clock_gettime = |emulator: &mut Emulator| {
+        assert_eq!(call(emulator, NR_CLOCK_GETTIME, &[CLOCK_MONOTONIC, DATA]), 0);
+        let seconds = emulator.load(DATA, 64).unwrap();
+        seconds * 1_000_000_000 + emulator.load(DATA + 8, 64).unwrap()
+    };
+    let start = clock_gettime(&mut emulator);
+    assert_eq!(emulator.run(1000), StopReason::InstructionLimit);
+    let end = clock_gettime(&mut emulator);
+    // A Linux program has no CLINT, but the time advances with the instructions:
+    // 1001 instructions are 100 ticks of mtime at 10 instructions per tick.
+    assert_eq!(start, 0);
+    assert_eq!(end, 100 * 1_000_000_000 / TIMEBASE_FREQUENCY);
+
+    // The date counts from the date of the start by mtime too.
+    emulator.cpu.bus.clint.set_start_date(1_700_000_000_500_000_000);
+    assert_eq!(call(&mut emulator, NR_CLOCK_GETTIME, &[CLOCK_REALTIME, DATA]), 0);
+    assert_eq!(emulator.load(DATA, 64), Ok(1_700_000_000));
+    assert_eq!(emulator.load(DATA + 8, 64), Ok(500_000_000 + end));
+    assert_eq!(call(&mut emulator, NR_GETTIMEOFDAY, &[DATA, 0]), 0);
+    assert_eq!(emulator.load(DATA, 64), Ok(1_700_000_000));
+    assert_eq!(emulator.load(DATA + 8, 64), Ok((500_000_000 + end) / 1000));
+}
+
+#[test]
+fn process() {
+    let (mut emulator, _) = emulator();
+    assert_eq!(call(&mut emulator, NR_GETPID, &[]), USER_PID as i64);
+    assert_eq!(call(&mut emulator, NR_GETUID, &[]), 0);
+    assert_eq!(call(&mut emulator, NR_UNAME, &[DATA]), 0);
+    assert_eq!(emulator.read_memory(DATA, 6), Ok(b"Linux\0".to_vec()));
+    assert_eq!(emulator.read_memory(DATA + 65 * 4, 8), Ok(b"riscv64\0".to_vec()));
+    // getrandom gives the same bytes on every run.
+    assert_eq!(call(&mut emulator, NR_GETRANDOM, &[DATA, 8, 0]), 8);
+    let random = emulator.load(DATA, 64).unwrap();
+    let (mut other, _) = self::emulator();
+    call(&mut other, NR_GETRANDOM, &[DATA, 8, 0]);
+    assert_eq!(other.load(DATA, 64), Ok(random));
+    // An unknown system call, e.g. clone
+    assert_eq!(call(&mut emulator, 220, &[]), -ENOSYS);
+
+    // abort sends SIGABRT to the process, which exits with 128 + 6.
+    emulator.set_pc(CODE);
+    emulator.set_reg(17, NR_TGKILL);
+    emulator.set_reg(10, USER_PID);
+    emulator.set_reg(11, USER_PID);
+    emulator.set_reg(12, 6);
+    assert_eq!(emulator.run(1), StopReason::Exit(134));
+
+    emulator.set_pc(CODE);
+    emulator.set_reg(17, NR_EXIT_GROUP);
+    emulator.set_reg(10, 3);
+    assert_eq!(emulator.run(1), StopReason::Exit(3));
+}
+
+#[test]
+fn faults() {
+    // ld a0, 0(zero)
+    let binary = elf(&[0x00003503], &[], false);
+    let mut emulator = Emulator::builder()
+        .binary(binary)
+        .user(&strings(&["fault"]), &[])
+        .build()
+        .unwrap();
+    let reason = emulator.run(1);
+    assert_eq!(reason, StopReason::Exception(Exception::LoadAccessFault, CODE));
+    // SIGSEGV
+    assert_eq!(signal(&Exception::LoadAccessFault), 11);
+
+    // Only static programs run.
+    let binary = elf(&[LOOP], &[], true);
+    let emulator = Emulator::builder().binary(binary).user(&strings(&["dynamic"]), &[]).build();
+    assert!(emulator.is_err());
+    let emulator = Emulator::builder().binary(vec![0x6f, 0, 0, 0]).user(&strings(&["raw"]), &[]).build();
+    assert!(emulator.is_err());
+}
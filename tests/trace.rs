@@ -0,0 +1,57 @@
+// Parse and print the commit lines of `spike --log-commits`.
+
+use riscvemu::trace::*;
+
+// Commit lines of Spike: a register write, a jump without effects, a CSR write, a
+// CSR write with the register write of the old value, a store, a load and a
+// compressed instruction
+const SPIKE: [&str; 8] = [
+    "core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000",
+    "core   0: 3 0x0000000080000000 (0x0500006f)",
+    "core   0: 3 0x0000000080000054 (0x30529073) c773_mtvec 0x0000000080000004",
+    "core   0: 3 0x00000000800000a4 (0x30031373) c768_mstatus 0x0000000000001880 x6  0x0000000a00000000",
+    "core   0: 3 0x0000000080002000 (0x00b53023) mem 0x0000000080001000 0x0000000000000001",
+    "core   0: 1 0x0000000080002004 (0x00052583) x11 0x0000000000000001 mem 0x0000000080001000",
+    "core   0: 0 0x0000000080002008 (0x4501) x10 0x0000000000000000",
+    "core   1: 3 0x000000008000200a (0x00b50023) mem 0x0000000080001000 0x01",
+];
+
+#[test]
+fn round_trip() {
+    for line in SPIKE.iter() {
+        let record = Record::parse(line).unwrap();
+        assert_eq!(record.to_string(), *line);
+    }
+}
+
+#[test]
+fn parse() {
+    let record = Record::parse(SPIKE[3]).unwrap();
+    assert_eq!(record.hart, 0);
+    assert_eq!(record.mode, 3);
+    assert_eq!(record.pc, 0x800000a4);
+    assert_eq!(record.inst, 0x30031373);
+    assert_eq!(record.effects, vec![Effect::Csr(0x300, 0x1880), Effect::Reg(6, 0xa00000000)]);
+
+    let effects = |line| Record::parse(line).unwrap().effects;
+    assert_eq!(effects(SPIKE[4]), vec![Effect::Store(0x80001000, 1, 8)]);
+    assert_eq!(effects(SPIKE[5]), vec![Effect::Reg(11, 1), Effect::Load(0x80001000)]);
+    assert_eq!(effects(SPIKE[7]), vec![Effect::Store(0x80001000, 1, 1)]);
+    assert_eq!(Record::parse(SPIKE[7]).unwrap().hart, 1);
+
+    // A write to a floating-point register
+    let line = "core   0: 3 0x0000000080002010 (0xf0000053) f0  0xffffffff00000000";
+    assert_eq!(effects(line), vec![Effect::FReg(0, 0xffffffff00000000)]);
+    // Writes to other register files are skipped.
+    let line = "core   0: 3 0x0000000080002014 (0x5e0572d7) v5  0x0000000000000000";
+    assert_eq!(effects(line), vec![]);
+}
+
+#[test]
+fn other_lines() {
+    // An exception, its tval and a disassembly line are not commit lines.
+    assert_eq!(Record::parse("core   0: exception trap_illegal_instruction, epc 0x0000000080002010"), None);
+    assert_eq!(Record::parse("core   0:           tval 0x0000000000000000"), None);
+    assert_eq!(Record::parse("core   0: 0x0000000080000000 (0x0500006f) j       pc + 0x50"), None);
+    assert_eq!(Record::parse(""), None);
+}
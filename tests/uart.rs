@@ -0,0 +1,91 @@
+// Drive the 16550 UART as the Linux 8250 driver does.
+
+
+use riscvemu::emulator::*;
+use riscvemu::uart::*;
+
+mod common;
+use common::Output;
+
+fn emulator() -> (Emulator, Output) {
+    let mut emulator = Emulator::builder().memory_size(0x10000).build().unwrap();
+    let output = Output::default();
+    emulator.cpu.bus.uart.set_output(Box::new(output.clone()));
+    (emulator, output)
+}
+
+#[test]
+fn transmit() {
+    let (mut emulator, output) = emulator();
+    for byte in b"ok\n" {
+        assert_eq!(emulator.load(UART_LSR, 8).map(|lsr| lsr as u8 & UART_LSR_TX), Ok(UART_LSR_TX));
+        emulator.store(UART_THR, 8, *byte as u64).unwrap();
+    }
+    assert_eq!(*output.0.lock().unwrap(), b"ok\n");
+}
+
+#[test]
+fn receive() {
+    let (mut emulator, _) = emulator();
+    assert_eq!(emulator.load(UART_LSR, 8).map(|lsr| lsr as u8 & UART_LSR_RX), Ok(0));
+    emulator.cpu.bus.uart.receive(b'a');
+    emulator.cpu.bus.uart.receive(b'b');
+    assert_eq!(emulator.load(UART_LSR, 8).map(|lsr| lsr as u8 & UART_LSR_RX), Ok(UART_LSR_RX));
+    assert_eq!(emulator.load(UART_RHR, 8), Ok(b'a' as u64));
+    assert_eq!(emulator.load(UART_RHR, 8), Ok(b'b' as u64));
+    assert_eq!(emulator.load(UART_LSR, 8).map(|lsr| lsr as u8 & UART_LSR_RX), Ok(0));
+
+    // Clearing the receive FIFO drops the input.
+    emulator.cpu.bus.uart.receive(b'c');
+    emulator.store(UART_FCR, 8, 0x7).unwrap();
+    assert_eq!(emulator.load(UART_LSR, 8).map(|lsr| lsr as u8 & UART_LSR_RX), Ok(0));
+}
+
+#[test]
+fn interrupt_identification() {
+    let (mut emulator, _) = emulator();
+    emulator.store(UART_FCR, 8, 0x1).unwrap();
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_NO_INT as u64));
+
+    // Enabling the transmitter interrupt raises it, and reading IIR clears it.
+    emulator.store(UART_IER, 8, UART_IER_THRI as u64).unwrap();
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_THRI as u64));
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_NO_INT as u64));
+    // A transmitted byte raises it again.
+    emulator.store(UART_THR, 8, b'x' as u64).unwrap();
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_THRI as u64));
+
+    // The received data interrupt has the priority, until the data is read.
+    emulator.store(UART_IER, 8, (UART_IER_RDI | UART_IER_THRI) as u64).unwrap();
+    emulator.cpu.bus.uart.receive(b'a');
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_RDI as u64));
+    assert_eq!(emulator.load(UART_RHR, 8), Ok(b'a' as u64));
+    assert_eq!(emulator.load(UART_IIR, 8), Ok(0xc0 | UART_IIR_NO_INT as u64));
+}
+
+#[test]
+fn divisor_latch_and_loopback() {
+    let (mut emulator, output) = emulator();
+    // The divisor latch is at RHR and IER while DLAB is set.
+    emulator.store(UART_LCR, 8, 0x80).unwrap();
+    emulator.store(UART_DLL, 8, 0x01).unwrap();
+    emulator.store(UART_DLM, 8, 0x02).unwrap();
+    emulator.store(UART_LCR, 8, 0x03).unwrap();
+    assert_eq!(emulator.load(UART_IER, 8), Ok(0));
+    emulator.store(UART_LCR, 8, 0x83).unwrap();
+    assert_eq!(emulator.load(UART_DLL, 8), Ok(0x01));
+    assert_eq!(emulator.load(UART_DLM, 8), Ok(0x02));
+    emulator.store(UART_LCR, 8, 0x03).unwrap();
+
+    emulator.store(UART_SCR, 8, 0x5a).unwrap();
+    assert_eq!(emulator.load(UART_SCR, 8), Ok(0x5a));
+
+    // In the loopback mode, the transmitted bytes are received, and the modem
+    // outputs are the inputs.
+    assert_eq!(emulator.load(UART_MSR, 8), Ok(0xb0));
+    emulator.store(UART_MCR, 8, 0x10 | 0x0a).unwrap();
+    assert_eq!(emulator.load(UART_MSR, 8), Ok(0x90));
+    emulator.store(UART_THR, 8, b'l' as u64).unwrap();
+    assert_eq!(emulator.load(UART_RHR, 8), Ok(b'l' as u64));
+    assert!(output.0.lock().unwrap().is_empty());
+}
@@ -0,0 +1,146 @@
+// Do the requests of a driver to the virtio block device, as xv6 does.
+
+use riscvemu::bus::*;
+use riscvemu::cpu::*;
+use riscvemu::emulator::*;
+use riscvemu::plic::*;
+use riscvemu::virtio::*;
+
+mod common;
+use common::program;
+
+// The queue: the descriptors, the available ring and the used ring
+const QUEUE: u64 = MEMORY_BASE + 0x1000;
+const QUEUE_SIZE: u64 = 8;
+// The header of the request, the data and the status
+const HEADER: u64 = MEMORY_BASE + 0x3000;
+const BUFFER: u64 = MEMORY_BASE + 0x3100;
+const STATUS: u64 = MEMORY_BASE + 0x3400;
+
+// A disk of 4 sectors, whose bytes are the number of the sector plus 1
+fn disk() -> Vec<u8> {
+    (0..4).flat_map(|sector| vec![sector as u8 + 1; SECTOR_SIZE as usize]).collect()
+}
+
+fn with_version(version: u32) -> Emulator {
+    // nop, j .-4
+    let binary = program(&[0x00000013, 0xffdff06f]);
+    Emulator::builder()
+        .memory_size(0x10000)
+        .binary(binary)
+        .disk_image(disk())
+        .virtio_version(version)
+        .build()
+        .unwrap()
+}
+
+// Put a descriptor in the table.
+fn descriptor(emulator: &mut Emulator, index: u64, addr: u64, len: u64, flags: u64, next: u64) {
+    let desc = QUEUE + 16 * index;
+    emulator.store(desc, 64, addr).unwrap();
+    emulator.store(desc + 8, 32, len).unwrap();
+    emulator.store(desc + 12, 16, flags).unwrap();
+    emulator.store(desc + 14, 16, next).unwrap();
+}
+
+// Put a request of the sector in the available ring at `avail`, with the
+// descriptors 3, 5 and 1, and notify the queue.
+fn request(emulator: &mut Emulator, avail: u64, kind: u64, sector: u64) {
+    emulator.store(HEADER, 32, kind).unwrap();
+    emulator.store(HEADER + 8, 64, sector).unwrap();
+    emulator.store(STATUS, 8, 0xff).unwrap();
+    let data = match kind {
+        VIRTIO_BLK_T_IN => VRING_DESC_F_WRITE,
+        _ => 0,
+    };
+    descriptor(emulator, 3, HEADER, 16, VRING_DESC_F_NEXT, 5);
+    descriptor(emulator, 5, BUFFER, SECTOR_SIZE, data | VRING_DESC_F_NEXT, 1);
+    descriptor(emulator, 1, STATUS, 1, VRING_DESC_F_WRITE, 0);
+    let idx = emulator.load(avail + 2, 16).unwrap();
+    emulator.store(avail + 4 + 2 * (idx % QUEUE_SIZE), 16, 3).unwrap();
+    emulator.store(avail + 2, 16, idx + 1).unwrap();
+    emulator.store(VIRTIO_QUEUE_NOTIFY, 32, 0).unwrap();
+}
+
+#[test]
+fn legacy_read() {
+    let mut emulator = with_version(1);
+    assert_eq!(emulator.load(VIRTIO_MAGIC, 32), Ok(0x74726976));
+    assert_eq!(emulator.load(VIRTIO_VERSION, 32), Ok(1));
+    assert_eq!(emulator.load(VIRTIO_DEVICE_ID, 32), Ok(2));
+    emulator.store(VIRTIO_STATUS, 32, 0xb).unwrap();
+    emulator.store(VIRTIO_GUEST_PAGE_SIZE, 32, 4096).unwrap();
+    emulator.store(VIRTIO_QUEUE_SEL, 32, 0).unwrap();
+    assert_eq!(emulator.load(VIRTIO_QUEUE_NUM_MAX, 32), Ok(QUEUE_SIZE));
+    emulator.store(VIRTIO_QUEUE_NUM, 32, QUEUE_SIZE).unwrap();
+    emulator.store(VIRTIO_QUEUE_PFN, 32, QUEUE >> 12).unwrap();
+    emulator.store(VIRTIO_STATUS, 32, 0xf).unwrap();
+
+    // The available ring follows the descriptors, and the used ring is on the
+    // next page.
+    let avail = QUEUE + 16 * QUEUE_SIZE;
+    let used = QUEUE + 0x1000;
+    request(&mut emulator, avail, VIRTIO_BLK_T_IN, 2);
+    assert_eq!(emulator.read_memory(BUFFER, SECTOR_SIZE), Ok(vec![3; SECTOR_SIZE as usize]));
+    assert_eq!(emulator.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK));
+    // The used ring has the head of the request and the bytes written.
+    assert_eq!(emulator.load(used + 2, 16), Ok(1));
+    assert_eq!(emulator.load(used + 4, 32), Ok(3));
+    assert_eq!(emulator.load(used + 8, 32), Ok(SECTOR_SIZE + 1));
+
+    // The interrupt is pending until the driver acknowledges it.
+    emulator.store(PLIC_PRIORITY + 4 * VIRTIO_IRQ, 32, 1).unwrap();
+    emulator.store(PLIC_SENABLE, 32, 1 << VIRTIO_IRQ).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_ne!(emulator.csr(MIP) & MIP_SEIP, 0);
+    assert_eq!(emulator.load(PLIC_SCLAIM, 32), Ok(VIRTIO_IRQ));
+    assert_eq!(emulator.load(VIRTIO_INTERRUPT_STATUS, 32), Ok(1));
+    emulator.store(VIRTIO_INTERRUPT_ACK, 32, 1).unwrap();
+    emulator.store(PLIC_SCLAIM, 32, VIRTIO_IRQ).unwrap();
+    assert_eq!(emulator.step(), None);
+    assert_eq!(emulator.csr(MIP) & MIP_SEIP, 0);
+
+    // A reset forgets the queue.
+    emulator.store(VIRTIO_STATUS, 32, 0).unwrap();
+    assert_eq!(emulator.load(VIRTIO_QUEUE_PFN, 32), Ok(0));
+    assert_eq!(emulator.load(VIRTIO_STATUS, 32), Ok(0));
+}
+
+#[test]
+fn modern_write() {
+    let mut emulator = with_version(2);
+    assert_eq!(emulator.load(VIRTIO_VERSION, 32), Ok(2));
+    // VIRTIO_F_VERSION_1 is bit 32 of the features.
+    emulator.store(VIRTIO_DEVICE_FEATURES_SEL, 32, 1).unwrap();
+    assert_eq!(emulator.load(VIRTIO_DEVICE_FEATURES, 32), Ok(1));
+    // The capacity in sectors, in any size
+    assert_eq!(emulator.load(VIRTIO_CONFIG, 64), Ok(4));
+    assert_eq!(emulator.load(VIRTIO_CONFIG, 8), Ok(4));
+    assert_eq!(emulator.load(VIRTIO_CONFIG + 4, 32), Ok(0));
+
+    let avail = QUEUE + 0x800;
+    let used = QUEUE + 0xc00;
+    emulator.store(VIRTIO_QUEUE_NUM, 32, QUEUE_SIZE).unwrap();
+    emulator.store(VIRTIO_QUEUE_DESC_LOW, 32, QUEUE).unwrap();
+    emulator.store(VIRTIO_QUEUE_DESC_HIGH, 32, 0).unwrap();
+    emulator.store(VIRTIO_QUEUE_DRIVER_LOW, 32, avail).unwrap();
+    emulator.store(VIRTIO_QUEUE_DEVICE_LOW, 32, used).unwrap();
+    assert_eq!(emulator.load(VIRTIO_QUEUE_READY, 32), Ok(0));
+    emulator.store(VIRTIO_QUEUE_READY, 32, 1).unwrap();
+
+    emulator.write_memory(BUFFER, &[0xaa; SECTOR_SIZE as usize]).unwrap();
+    request(&mut emulator, avail, VIRTIO_BLK_T_OUT, 1);
+    assert_eq!(emulator.load(STATUS, 8), Ok(VIRTIO_BLK_S_OK));
+    let disk = emulator.cpu.bus.virtio.disk();
+    assert_eq!(disk[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize], [0xaa; SECTOR_SIZE as usize]);
+    assert_eq!(disk[2 * SECTOR_SIZE as usize], 3);
+    // Only the status byte is written to the memory.
+    assert_eq!(emulator.load(used + 8, 32), Ok(1));
+
+    // A sector out of the disk is an error, and so is an unknown request.
+    request(&mut emulator, avail, VIRTIO_BLK_T_IN, 4);
+    assert_eq!(emulator.load(STATUS, 8), Ok(VIRTIO_BLK_S_IOERR));
+    request(&mut emulator, avail, 8, 0);
+    assert_eq!(emulator.load(STATUS, 8), Ok(VIRTIO_BLK_S_UNSUPP));
+    assert_eq!(emulator.load(used + 2, 16), Ok(3));
+}
@@ -0,0 +1,123 @@
+// Boot xv6-riscv on 3 harts and drive its shell through the console. The kernel
+// and the file system image are not vendored, so the tests are ignored unless
+// they are given:
+//
+//   RISCVEMU_XV6_KERNEL=xv6-riscv/kernel/kernel RISCVEMU_XV6_FS=xv6-riscv/fs.img \
+//       cargo test --release --test xv6 -- --ignored
+//
+// The xv6-riscv of 2022 and later drives the virtio-mmio interface of version 2,
+// which RISCVEMU_XV6_VIRTIO_VERSION=2 gives. The older ones drive the legacy one.
+
+use std::env;
+use std::fs;
+
+use riscvemu::emulator::*;
+
+mod common;
+use common::Output;
+
+// The number of harts of the xv6 Makefile
+const HARTS: usize = 3;
+// The prompt of the shell
+const PROMPT: &str = "$ ";
+// The number of instructions the boot and a command may take
+const BOOT_LIMIT: u64 = 2_000_000_000;
+const COMMAND_LIMIT: u64 = 2_000_000_000;
+// usertests takes much longer.
+const USERTESTS_LIMIT: u64 = 500_000_000_000;
+// The number of instructions between the checks of the console
+const CHECK_INTERVAL: u64 = 1_000_000;
+
+struct Xv6 {
+    emulator: Emulator,
+    console: Output,
+    // The length of the output which a wait already returned
+    seen: usize,
+}
+
+impl Xv6 {
+    // Boot to the prompt of the shell.
+    fn boot() -> Self {
+        let read = |var: &str| {
+            let path = env::var(var).unwrap_or_else(|_| panic!("{} is not set", var));
+            fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+        };
+        let virtio_version = match env::var("RISCVEMU_XV6_VIRTIO_VERSION") {
+            Ok(version) => version.parse().expect("RISCVEMU_XV6_VIRTIO_VERSION is not a number"),
+            Err(_) => 1,
+        };
+        // The kernel starts in M-mode without firmware and handles its traps.
+        let emulator = Emulator::builder()
+            .harts(HARTS)
+            .binary(read("RISCVEMU_XV6_KERNEL"))
+            .disk_image(read("RISCVEMU_XV6_FS"))
+            .virtio_version(virtio_version)
+            .stop_on_exception(false)
+            .build()
+            .unwrap();
+        let mut xv6 = Self {
+            emulator,
+            console: Output::default(),
+            seen: 0,
+        };
+        xv6.emulator.cpu.bus.uart.set_output(Box::new(xv6.console.clone()));
+        xv6.wait_for(&[PROMPT], BOOT_LIMIT);
+        xv6
+    }
+
+    fn output(&self) -> String {
+        String::from_utf8_lossy(&self.console.0.lock().unwrap()).into_owned()
+    }
+
+    // Run until the new output has one of `texts`, and return the new output up to
+    // it. Fail if the kernel panics or it takes more than `limit` instructions.
+    fn wait_for(&mut self, texts: &[&str], limit: u64) -> String {
+        let mut count = 0;
+        while count < limit {
+            let reason = self.emulator.run(CHECK_INTERVAL);
+            count += CHECK_INTERVAL;
+            let output = self.output();
+            let new = &output[self.seen..];
+            if let Some(end) = texts.iter().filter_map(|text| new.find(text).map(|i| i + text.len())).min() {
+                self.seen += end;
+                return new[..end].to_string();
+            }
+            if new.contains("panic: ") {
+                panic!("the kernel panicked\n{}", output);
+            }
+            if reason != StopReason::InstructionLimit {
+                panic!("the kernel stopped: {:?}\n{}", reason, output);
+            }
+        }
+        panic!("no {:?} after {} instructions\n{}", texts, limit, self.output());
+    }
+
+    // Type a command and return its output up to the next prompt or one of `texts`.
+    fn run(&mut self, command: &str, texts: &[&str], limit: u64) -> String {
+        for byte in command.bytes().chain(Some(b'\n')) {
+            self.emulator.cpu.bus.uart.receive(byte);
+        }
+        self.wait_for(texts, limit)
+    }
+}
+
+#[test]
+#[ignore]
+fn shell() {
+    let mut xv6 = Xv6::boot();
+    let output = xv6.run("ls", &[PROMPT], COMMAND_LIMIT);
+    assert!(output.contains("README"), "{}", output);
+    assert!(output.contains("usertests"), "{}", output);
+    // A file written by a command is read back by another one.
+    xv6.run("echo hello xv6 > greeting", &[PROMPT], COMMAND_LIMIT);
+    let output = xv6.run("cat greeting", &[PROMPT], COMMAND_LIMIT);
+    assert!(output.contains("hello xv6"), "{}", output);
+}
+
+#[test]
+#[ignore]
+fn usertests() {
+    let mut xv6 = Xv6::boot();
+    let output = xv6.run("usertests", &["ALL TESTS PASSED", "FAILED"], USERTESTS_LIMIT);
+    assert!(output.ends_with("ALL TESTS PASSED"), "{}", xv6.output());
+}